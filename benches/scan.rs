@@ -0,0 +1,92 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::{runtime::Runtime, sync::mpsc};
+
+use lachesis::{
+    conf::{self, Conf},
+    content, page,
+    plugins::Registry,
+    selftest,
+    worker::{self, ReqTarget, WorkerMessage},
+};
+
+// Fake services scanned by the worker benchmark
+const LISTENERS: usize = 100;
+
+fn html_target() -> ReqTarget {
+    let body = fs::read_to_string("./resources/test.html").unwrap();
+    ReqTarget {
+        ip: "127.0.0.1".to_string(),
+        port: 80,
+        protocol: "http".to_string(),
+        response: format!("HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n{}", body),
+        status: Some(200),
+        headers: vec![("Server".to_string(), "nginx".to_string())],
+        body,
+        ..Default::default()
+    }
+}
+
+fn detection(c: &mut Criterion) {
+    let registry = Registry::new();
+    let definitions = conf::parse_validate_definitions(&[
+        "./resources/test-definition-http.json".to_string(),
+        "./resources/test-definition-tcp.json".to_string(),
+    ])
+    .unwrap();
+    let target = html_target();
+
+    c.bench_function("detect", |b| {
+        b.iter(|| registry.detect(&target, &definitions))
+    });
+    c.bench_function("content_hash", |b| b.iter(|| content::hash(&target)));
+    c.bench_function("page_attributes", |b| b.iter(|| page::attributes(&target)));
+}
+
+// Whole worker (port checks, probes and responses) against the selftest services, the responses
+// are only counted (no detection, no db)
+fn worker_scan(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let listeners = rt.block_on(selftest::spawn(LISTENERS, 0)).unwrap();
+
+    let path = "/tmp/lachesis-bench-definitions.json";
+    fs::write(path, selftest::definitions(&listeners).to_string()).unwrap();
+    let mut conf = Conf::default();
+    conf.definitions = conf::parse_validate_definitions(&[path.to_string()]).unwrap();
+    fs::remove_file(path).unwrap();
+    conf.hosts = Arc::new(vec![(String::new(), "127.0.0.1".to_string())]);
+
+    let registry = Arc::new(Registry::new());
+    let mut group = c.benchmark_group("worker");
+    group.sample_size(10);
+    group.bench_function(format!("scan_{}_listeners", LISTENERS), |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let (tx, mut rx) = mpsc::channel(1000);
+                let (_, follow_ups) = mpsc::channel(1);
+                tokio::spawn(worker::run(
+                    tx,
+                    conf.clone(),
+                    registry.clone(),
+                    Arc::new(HashMap::new()),
+                    follow_ups,
+                ));
+
+                let mut responses = 0;
+                while let Some(msg) = rx.recv().await {
+                    match msg {
+                        WorkerMessage::Response(_) => responses += 1,
+                        WorkerMessage::Shutdown => break,
+                        _ => (),
+                    }
+                }
+                assert_eq!(responses, LISTENERS);
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, detection, worker_scan);
+criterion_main!(benches);
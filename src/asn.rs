@@ -0,0 +1,68 @@
+use ipnet::Ipv4Net;
+use tokio::runtime::Builder;
+
+use crate::net;
+
+// Prefixes announced by an autonomous system, as seen by the RIPE RIS collectors
+const RIPESTAT_URL: &str = "https://stat.ripe.net/data/announced-prefixes/data.json";
+const RIPESTAT_TIMEOUT: u64 = 30;
+
+// AS13335, as13335 or 13335
+pub fn parse_asn(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let number = if text.len() > 2 && text[..2].eq_ignore_ascii_case("as") {
+        &text[2..]
+    } else {
+        text
+    };
+    number.parse::<u32>().ok()
+}
+
+// IPv4 prefixes of a RIPEstat announced-prefixes response (the IPv6 ones are not scanned)
+pub fn parse_announced_prefixes(response: &[u8]) -> Result<Vec<Ipv4Net>, String> {
+    let response: serde_json::Value =
+        serde_json::from_slice(response).map_err(|e| format!("Invalid response: {}", e))?;
+    let prefixes = match response["data"]["prefixes"].as_array() {
+        Some(prefixes) => prefixes,
+        None => return Err("Invalid response: prefixes not found".to_string()),
+    };
+
+    Ok(prefixes
+        .iter()
+        .filter_map(|p| p["prefix"].as_str())
+        .filter_map(|p| p.parse::<Ipv4Net>().ok())
+        .collect())
+}
+
+async fn announced_prefixes(asn: u32) -> Result<Vec<Ipv4Net>, String> {
+    let url = format!("{}?resource=AS{}", RIPESTAT_URL, asn);
+    let body = net::fetch(&url, RIPESTAT_TIMEOUT).await?;
+    parse_announced_prefixes(&body)
+}
+
+// Resolves the ASNs to their announced (IPv4) prefixes. The overlapping ones (e.g. more specific
+// announcements) are aggregated, so that every host is scanned once
+pub fn resolve(asns: &[String]) -> Result<Vec<Ipv4Net>, String> {
+    // Targets are resolved while loading the conf, before the scan runtime is started
+    let rt = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut prefixes = Vec::new();
+    for text in asns {
+        let asn = match parse_asn(text) {
+            Some(asn) => asn,
+            None => return Err(format!("Invalid ASN: {}", text)),
+        };
+        let announced = rt
+            .block_on(announced_prefixes(asn))
+            .map_err(|e| format!("Unable to resolve the prefixes of AS{}: {}", asn, e))?;
+        if announced.is_empty() {
+            return Err(format!("No IPv4 prefixes announced by AS{}", asn));
+        }
+        prefixes.extend(announced);
+    }
+
+    Ok(Ipv4Net::aggregate(&prefixes))
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+// Outcomes of the half-open state deciding whether the scan resumes or pauses again
+const HALF_OPEN_SAMPLES: usize = 20;
+// Targets probed while half-open
+const HALF_OPEN_TARGETS: u64 = 5;
+// The cooldown doubles at every consecutive trip, up to 16 times the configured one
+const MAX_COOLDOWN_DOUBLINGS: u32 = 4;
+const HALF_OPEN_POLL: Duration = Duration::from_millis(200);
+
+// Circuit breaker of the scan (--breaker-threshold)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConf {
+    // Failed and timed out share (0-1) of the last outcomes pausing the scan
+    pub threshold: f32,
+    // Outcomes (requests and port checks) of the failure rate
+    pub window: usize,
+    // Pause before probing again
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerEvent {
+    // The scan is paused for the cooldown
+    Tripped {
+        failure_rate: f32,
+        cooldown: Duration,
+    },
+    // The failure rate of the half-open targets is below the threshold, the scan goes on
+    Resumed {
+        paused: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+struct BreakerState {
+    phase: Phase,
+    // Outcomes of the window, true when failed or timed out
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    // Consecutive trips (without resuming), doubling the cooldown
+    trips: u32,
+    paused_since: Option<Instant>,
+    half_open_targets: u64,
+}
+
+// Pauses the target generation while the failures and the timeouts of the last requests exceed
+// the threshold (e.g. the uplink died), instead of burning the targets. After the cooldown a few
+// targets are probed (half-open): the scan resumes if they fail less than the threshold, or
+// pauses again for a longer cooldown otherwise. The targets being probed when it trips complete
+pub struct Breaker {
+    conf: BreakerConf,
+    state: Mutex<BreakerState>,
+}
+
+impl Breaker {
+    pub fn new(conf: BreakerConf) -> Self {
+        Breaker {
+            conf,
+            state: Mutex::new(BreakerState {
+                phase: Phase::Closed,
+                outcomes: VecDeque::new(),
+                failures: 0,
+                trips: 0,
+                paused_since: None,
+                half_open_targets: 0,
+            }),
+        }
+    }
+
+    // The outcome of a request or of a port check. The ones of the targets probed before a trip
+    // are not counted while open
+    pub fn record(&self, failed: bool) -> Option<BreakerEvent> {
+        let mut state = self.state.lock().unwrap();
+        self.maybe_half_open(&mut state);
+
+        match state.phase {
+            Phase::Open { .. } => None,
+            Phase::Closed => {
+                push_outcome(&mut state, failed, self.conf.window);
+                if state.outcomes.len() < self.conf.window {
+                    return None;
+                }
+                let rate = failure_rate(&state);
+                if rate >= self.conf.threshold {
+                    Some(self.trip(&mut state, rate))
+                } else {
+                    None
+                }
+            }
+            Phase::HalfOpen { .. } => {
+                push_outcome(&mut state, failed, self.conf.window);
+                if state.outcomes.len() < HALF_OPEN_SAMPLES.min(self.conf.window) {
+                    return None;
+                }
+                Some(self.decide(&mut state))
+            }
+        }
+    }
+
+    // Waits while the scan is paused, only a few targets are let through while half-open.
+    // Without enough outcomes after a cooldown (e.g. targets without checks), the half-open state
+    // is decided with the ones collected
+    pub async fn wait(&self) -> Option<BreakerEvent> {
+        loop {
+            let delay = {
+                let mut state = self.state.lock().unwrap();
+                self.maybe_half_open(&mut state);
+                match state.phase {
+                    Phase::Closed => return None,
+                    Phase::Open { until } => until.saturating_duration_since(Instant::now()),
+                    Phase::HalfOpen { .. } if state.half_open_targets < HALF_OPEN_TARGETS => {
+                        state.half_open_targets += 1;
+                        return None;
+                    }
+                    Phase::HalfOpen { since } if since.elapsed() >= self.conf.cooldown => {
+                        return Some(self.decide(&mut state));
+                    }
+                    Phase::HalfOpen { .. } => HALF_OPEN_POLL,
+                }
+            };
+            sleep(delay).await;
+        }
+    }
+
+    fn maybe_half_open(&self, state: &mut BreakerState) {
+        if let Phase::Open { until } = state.phase {
+            if Instant::now() >= until {
+                state.phase = Phase::HalfOpen {
+                    since: Instant::now(),
+                };
+                state.half_open_targets = 0;
+            }
+        }
+    }
+
+    fn trip(&self, state: &mut BreakerState, rate: f32) -> BreakerEvent {
+        let cooldown = self.conf.cooldown * 2u32.pow(state.trips.min(MAX_COOLDOWN_DOUBLINGS));
+        state.phase = Phase::Open {
+            until: Instant::now() + cooldown,
+        };
+        state.trips += 1;
+        state.paused_since.get_or_insert_with(Instant::now);
+        state.outcomes.clear();
+        state.failures = 0;
+        BreakerEvent::Tripped {
+            failure_rate: rate,
+            cooldown,
+        }
+    }
+
+    // End of the half-open state: resumed, or paused again
+    fn decide(&self, state: &mut BreakerState) -> BreakerEvent {
+        let rate = failure_rate(state);
+        if !state.outcomes.is_empty() && rate >= self.conf.threshold {
+            return self.trip(state, rate);
+        }
+        state.phase = Phase::Closed;
+        state.trips = 0;
+        state.outcomes.clear();
+        state.failures = 0;
+        BreakerEvent::Resumed {
+            paused: state
+                .paused_since
+                .take()
+                .map(|since| since.elapsed())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn push_outcome(state: &mut BreakerState, failed: bool, window: usize) {
+    state.outcomes.push_back(failed);
+    if failed {
+        state.failures += 1;
+    }
+    if state.outcomes.len() > window && state.outcomes.pop_front() == Some(true) {
+        state.failures -= 1;
+    }
+}
+
+fn failure_rate(state: &BreakerState) -> f32 {
+    if state.outcomes.is_empty() {
+        0.0
+    } else {
+        state.failures as f32 / state.outcomes.len() as f32
+    }
+}
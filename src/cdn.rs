@@ -0,0 +1,117 @@
+use std::{fs, net::Ipv4Addr};
+
+use ipnet::Ipv4Net;
+
+// Published ranges of the CDN edges (Akamai doesn't publish its ranges, its edges are told by
+// their headers only)
+const BUILTIN_RANGES: &[(&str, &str)] = &[
+    ("Cloudflare", "173.245.48.0/20"),
+    ("Cloudflare", "103.21.244.0/22"),
+    ("Cloudflare", "103.22.200.0/22"),
+    ("Cloudflare", "103.31.4.0/22"),
+    ("Cloudflare", "141.101.64.0/18"),
+    ("Cloudflare", "108.162.192.0/18"),
+    ("Cloudflare", "190.93.240.0/20"),
+    ("Cloudflare", "188.114.96.0/20"),
+    ("Cloudflare", "197.234.240.0/22"),
+    ("Cloudflare", "198.41.128.0/17"),
+    ("Cloudflare", "162.158.0.0/15"),
+    ("Cloudflare", "104.16.0.0/13"),
+    ("Cloudflare", "104.24.0.0/14"),
+    ("Cloudflare", "172.64.0.0/13"),
+    ("Cloudflare", "131.0.72.0/22"),
+    ("Fastly", "23.235.32.0/20"),
+    ("Fastly", "43.249.72.0/22"),
+    ("Fastly", "103.244.50.0/24"),
+    ("Fastly", "103.245.222.0/23"),
+    ("Fastly", "103.245.224.0/24"),
+    ("Fastly", "104.156.80.0/20"),
+    ("Fastly", "140.248.64.0/18"),
+    ("Fastly", "140.248.128.0/17"),
+    ("Fastly", "146.75.0.0/17"),
+    ("Fastly", "151.101.0.0/16"),
+    ("Fastly", "157.52.64.0/18"),
+    ("Fastly", "167.82.0.0/17"),
+    ("Fastly", "167.82.128.0/20"),
+    ("Fastly", "167.82.160.0/20"),
+    ("Fastly", "167.82.224.0/20"),
+    ("Fastly", "172.111.64.0/18"),
+    ("Fastly", "185.31.16.0/22"),
+    ("Fastly", "199.27.72.0/21"),
+    ("Fastly", "199.232.0.0/16"),
+];
+
+// CDN/WAF ranges, the built-in ones and the ones of the --cdn-ranges file
+#[derive(Debug, Clone)]
+pub struct CdnRanges {
+    ranges: Vec<(String, Ipv4Net)>,
+}
+
+impl Default for CdnRanges {
+    fn default() -> Self {
+        CdnRanges {
+            ranges: BUILTIN_RANGES
+                .iter()
+                .map(|(provider, net)| (provider.to_string(), net.parse().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+impl CdnRanges {
+    // One "<provider> <cidr>" per line, # for the comments
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let range = match (fields.next(), fields.next(), fields.next()) {
+                (Some(provider), Some(net), None) => net
+                    .parse::<Ipv4Net>()
+                    .map(|net| (provider.to_string(), net))
+                    .ok(),
+                _ => None,
+            };
+            match range {
+                Some(range) => self.ranges.push(range),
+                None => return Err(format!("Invalid line {}: {}", n + 1, line)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn provider_by_ip(&self, ip: &str) -> Option<&str> {
+        let ip: Ipv4Addr = ip.parse().ok()?;
+        self.ranges
+            .iter()
+            .find(|(_, net)| net.contains(&ip))
+            .map(|(provider, _)| provider.as_str())
+    }
+}
+
+// CDN/WAF in front of the server, by the response headers
+pub fn provider_by_headers(headers: &[(String, String)]) -> Option<&'static str> {
+    for (name, value) in headers {
+        let name = name.to_lowercase();
+        let value = value.to_lowercase();
+        let provider = match name.as_str() {
+            "cf-ray" | "cf-cache-status" => "Cloudflare",
+            "server" if value == "cloudflare" => "Cloudflare",
+            "x-akamai-transformed" | "x-akamai-request-id" | "akamai-grn" => "Akamai",
+            "server" if value.starts_with("akamaighost") || value == "akamainetstorage" => "Akamai",
+            "x-fastly-request-id" | "fastly-debug-digest" => "Fastly",
+            "x-served-by" if value.starts_with("cache-") => "Fastly",
+            "x-amz-cf-id" | "x-amz-cf-pop" => "CloudFront",
+            "x-sucuri-id" => "Sucuri",
+            "x-iinfo" => "Imperva",
+            "x-cdn" if value.contains("incapsula") || value.contains("imperva") => "Imperva",
+            _ => continue,
+        };
+        return Some(provider);
+    }
+    None
+}
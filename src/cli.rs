@@ -0,0 +1,575 @@
+use clap::{Args, Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "Lachesis",
+    version = "v0.3.0",
+    author = "Michele Federici (@ps1dr3x) <michele@federici.tech>"
+)]
+pub struct Cli {
+    /// Loads the options from a TOML file (e.g. lachesis.toml). The parameters given on the
+    /// command line take precedence over the file values, and ${NAME} placeholders are replaced
+    /// with the environment variables values (e.g. for secrets)
+    #[clap(long, value_name = "FILE", global = true)]
+    pub config: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    // Back-compat: the scan options (and -w/--web-ui) are also accepted without a subcommand
+    #[clap(flatten)]
+    pub scan: ScanArgs,
+
+    /// Same as the ui subcommand
+    #[clap(short, long, conflicts_with_all = &["dataset", "subnet", "asn", "domain"])]
+    pub web_ui: bool,
+}
+
+impl Cli {
+    // The command to run, also when the flags are given without a subcommand
+    pub fn into_command(self) -> Result<Command, &'static str> {
+        match self.command {
+            Some(_) if self.web_ui || self.scan != ScanArgs::default() => Err(
+                "The scan options must follow the scan subcommand (e.g. lachesis scan -S <SUBNET>)",
+            ),
+            Some(command) => Ok(command),
+            None if self.web_ui => Ok(Command::Ui),
+            None => Ok(Command::Scan(self.scan)),
+        }
+    }
+}
+
+// Parsed once at startup, the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scans the targets (a DNS dataset or subnets) with the selected definitions
+    Scan(ScanArgs),
+    /// Serves a web app (and a basic API) to visualize/explore collected data
+    Ui,
+    /// Db maintenance
+    Db(DbArgs),
+    /// Converts a list of hosts to the DNS dataset format
+    Convert(ConvertArgs),
+    /// Writes a shard of the targets (a DNS dataset or subnets), to scan disjoint shards of the
+    /// same input from many processes or hosts
+    Split(SplitArgs),
+    /// Scope files tooling
+    Scope(ScopeArgs),
+    /// Definitions installed from an index
+    Defs(DefsArgs),
+    /// Replaces the binary with the latest release (GitHub), verifying its checksum and its
+    /// signature (Ed25519, public key compiled in)
+    SelfUpdate(SelfUpdateArgs),
+    /// Serves many fake HTTP and TCP services on 127.0.0.1 (synthetic load to measure the
+    /// performances), and writes the definitions to scan them
+    Selftest(SelftestArgs),
+}
+
+#[derive(Args, Debug, Default, Clone, PartialEq)]
+pub struct ScanArgs {
+    /// The full path of the DNS dataset used for the requests. The accepted format is:
+    ///
+    /// {"name":"example.com","type":"a","value":"93.184.216.34"}
+    /// {"name":"example.net","type":"a","value":"93.184.216.34"}
+    /// {"name":"example.org","type":"a","value":"93.184.216.34"}
+    ///
+    /// An example of a compatible dataset is the forward DNS dataset by Rapid7
+    /// (https://opendata.rapid7.com/sonar.fdns_v2/)
+    #[clap(
+        short = 'D',
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["subnet", "asn", "domain"]
+    )]
+    pub dataset: Option<String>,
+
+    /// Reads the dataset once before the scan, probing every ip once: with the first name of the
+    /// ip (first), or also trying the other names as its virtual hosts (vhosts)
+    #[clap(long, value_name = "MODE", possible_values = &["first", "vhosts"])]
+    pub dedup_dataset: Option<String>,
+
+    /// Writes the deduplicated dataset to this file (same format)
+    #[clap(long, value_name = "FILE")]
+    pub dedup_output: Option<String>,
+
+    /// Reads the targets from stdin as they arrive, e.g. piped from masscan -oL - or zmap. The
+    /// accepted lines are the masscan list format ("open tcp 80 1.2.3.4 1620000000"), "ip" and
+    /// "domain ip". The stream is read as fast as the targets are probed
+    #[clap(long, conflicts_with_all = &["dataset", "subnet", "asn", "domain"])]
+    pub target_stream: bool,
+
+    /// Scan one or more subnets
+    #[clap(short = 'S', long, value_name = "SUBNET", multiple_occurrences = true)]
+    pub subnet: Option<Vec<String>>,
+
+    /// Scan the (IPv4) prefixes announced by one or more autonomous systems (e.g. AS13335),
+    /// resolved with the RIPEstat API
+    #[clap(long, value_name = "ASN", multiple_occurrences = true)]
+    pub asn: Option<Vec<String>>,
+
+    /// Scan one or more domains and their subdomains found in the certificate transparency logs
+    /// (crt.sh), resolved to their IPv4 addresses. The names are used as Host headers
+    #[clap(long, value_name = "DOMAIN", multiple_occurrences = true)]
+    pub domain: Option<Vec<String>>,
+
+    /// Tries a zone transfer (AXFR) of the domains from their name servers, the names of the
+    /// zone are scanned too. The zones and the wildcard records are saved in the domain table
+    #[clap(long, requires = "domain")]
+    pub axfr: bool,
+
+    /// DNS server of the lookups instead of the system resolver (e.g. 1.1.1.1, 1.1.1.1:5353, or
+    /// tls://1.1.1.1#cloudflare-dns.com for DNS over TLS). More resolvers are used in turn
+    #[clap(long, value_name = "ADDR", multiple_occurrences = true)]
+    pub resolver: Option<Vec<String>>,
+
+    /// DNS over HTTPS url of the lookups (e.g. https://1.1.1.1/dns-query), with the --resolver ones
+    #[clap(long, value_name = "URL", multiple_occurrences = true)]
+    pub doh: Option<Vec<String>>,
+
+    /// Sets a maximum number of lookups per second sent to each resolver [default: 0]
+    #[clap(long, value_name = "NUM")]
+    pub resolver_rate: Option<u64>,
+
+    /// Scan the hosts of the subnets in a pseudo-random order (every host is still scanned once),
+    /// spreading the probes over the whole range
+    #[clap(short, long)]
+    pub randomize: bool,
+
+    /// Default: all the files in resources/definitions
+    ///
+    /// Multiple definitions can be selected (eg. --def wordpress --def vnc)
+    /// Accepted formats are:
+    ///   File name with or without extension (eg. vnc.json or vnc). The json file will be searched in directory resources/definitions/
+    ///   Full/relative path to file (eg. resources/definitions/vnc.json or /casual_path/mydef.json)
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        multiple_occurrences = true,
+        verbatim_doc_comment
+    )]
+    pub def: Option<Vec<String>>,
+
+    /// If all the existing definitions are selected (no -d/--def values provided) is possible to exclude some of them using this argument.
+    /// Accepted formats are:
+    ///   File name with or without extension (eg. vnc.json or vnc)
+    #[clap(
+        short,
+        long,
+        value_name = "FILE",
+        multiple_occurrences = true,
+        conflicts_with = "def",
+        verbatim_doc_comment
+    )]
+    pub exclude_def: Option<Vec<String>>,
+
+    /// Refuses the definitions files without a valid signature (<FILE>.sig, see defs sign)
+    #[clap(long)]
+    pub require_signed_defs: bool,
+
+    /// Trusts the signatures of this public key (hex Ed25519), in addition to the one compiled
+    /// in if any
+    #[clap(long, value_name = "KEY", multiple_occurrences = true)]
+    pub defs_public_key: Option<Vec<String>>,
+
+    /// Sets a custom user agent (http/https) [default: lachesis/0.3.0]
+    #[clap(short, long, value_name = "STRING")]
+    pub user_agent: Option<String>,
+
+    /// Rotates the user agents of this file (one per line), a different one for each request
+    #[clap(long, value_name = "FILE", conflicts_with = "user-agent")]
+    pub user_agent_file: Option<String>,
+
+    /// Sets a maximum limit of targets
+    #[clap(short, long, value_name = "NUM")]
+    pub max_targets: Option<u64>,
+
+    /// Sets a maximum timeout for each request (seconds) [default: 10]
+    #[clap(short = 't', long, value_name = "NUM")]
+    pub req_timeout: Option<u64>,
+
+    /// Sets a timeout for the tcp connections of the requests (milliseconds), within the
+    /// --req-timeout one [default: --req-timeout]
+    #[clap(long, value_name = "MS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Sets a timeout for the tls handshakes of the https requests (milliseconds), within the
+    /// --req-timeout one [default: --req-timeout]
+    #[clap(long, value_name = "MS")]
+    pub tls_timeout: Option<u64>,
+
+    /// Sets a timeout for the first byte of the responses (milliseconds, connection included),
+    /// within the --req-timeout one [default: --req-timeout]
+    #[clap(long, value_name = "MS")]
+    pub first_byte_timeout: Option<u64>,
+
+    /// Sets a maximum number of concurrent requests [default: 0]
+    #[clap(short = 'c', long, value_name = "NUM")]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Timing template (concurrency, timeouts, retries and rate limit presets, from the slowest
+    /// to the fastest). The options given explicitly take precedence over the template values
+    #[clap(
+        short = 'T',
+        long,
+        value_name = "TEMPLATE",
+        possible_values = &[
+            "paranoid", "sneaky", "polite", "normal", "aggressive", "insane",
+            "T0", "T1", "T2", "T3", "T4", "T5",
+        ]
+    )]
+    pub timing: Option<String>,
+
+    /// Scan profile: a definitions selection, the ports, a timing template and the output sinks
+    /// bundled under a name (web-quick, full-inventory, iot, or a profile of the config file).
+    /// The options given explicitly take precedence over the profile values
+    #[clap(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Sets a maximum number of requests per second (0 = unlimited) [default: 0]
+    #[clap(long, value_name = "NUM")]
+    pub max_rate: Option<u64>,
+
+    /// Serves the control API of the running scan on this address (e.g. 127.0.0.1:8001), to
+    /// change the concurrency and rate limits without restarting it (no authentication)
+    #[clap(long, value_name = "ADDR")]
+    pub control_listen: Option<String>,
+
+    /// Limits the probes of a port, on top of --max-rate and --max-concurrent-requests:
+    /// <PORT>:<RATE>[:<CONCURRENCY>] (requests per second and concurrent requests, 0 =
+    /// unlimited), e.g. 22:5:2 to probe ssh more gently
+    #[clap(
+        long,
+        value_name = "PORT:RATE[:CONCURRENCY]",
+        multiple_occurrences = true
+    )]
+    pub port_limit: Option<Vec<String>>,
+
+    /// Sets the number of retries of the timed out ports checks [default: 0]
+    #[clap(long, value_name = "NUM")]
+    pub port_retries: Option<u8>,
+
+    /// Sets a maximum number of requests per host (0 = unlimited), the remaining probes of the
+    /// host are skipped [default: 0]
+    #[clap(long, value_name = "NUM")]
+    pub host_max_requests: Option<u64>,
+
+    /// Sets a maximum number of authentication attempts per host (0 = unlimited), e.g. of the
+    /// definitions trying default credentials [default: 0]
+    #[clap(long, value_name = "NUM")]
+    pub host_max_auth: Option<u64>,
+
+    /// Sets the minimum interval between two requests to the same host (milliseconds), the
+    /// requests to the other hosts are sent meanwhile [default: 0]
+    #[clap(long, value_name = "MS")]
+    pub host_delay: Option<u64>,
+
+    /// Adds a random delay (up to MS milliseconds) to every interval of --host-delay, so the
+    /// requests to a host are not evenly spaced [default: half of --host-delay]
+    #[clap(long, value_name = "MS")]
+    pub host_jitter: Option<u64>,
+
+    /// Pauses the targets when PERCENT of the last requests and port checks failed or timed out
+    /// (e.g. the uplink died), resuming once a few targets are probed below it again [default:
+    /// disabled]
+    #[clap(long, value_name = "PERCENT")]
+    pub breaker_threshold: Option<u8>,
+
+    /// Sets the number of last requests and port checks of the --breaker-threshold rate [default:
+    /// 500]
+    #[clap(long, value_name = "NUM", requires = "breaker-threshold")]
+    pub breaker_window: Option<usize>,
+
+    /// Sets the pause of --breaker-threshold before probing again (seconds), doubled at every
+    /// consecutive pause [default: 30]
+    #[clap(long, value_name = "SECONDS", requires = "breaker-threshold")]
+    pub breaker_cooldown: Option<u64>,
+
+    /// Tries the default credentials of the definitions declaring them (e.g. admin/admin of a
+    /// router panel), the definitions with credentials are skipped otherwise
+    #[clap(long)]
+    pub try_default_creds: bool,
+
+    /// Sets the minimum interval between the default credentials attempts on the same host
+    /// (milliseconds) [default: 1000]
+    #[clap(long, value_name = "MS", requires = "try-default-creds")]
+    pub auth_delay: Option<u64>,
+
+    /// Skips the port checks of the hosts scanned more recently than DURATION (e.g. 30m, 24h,
+    /// 7d), probing the ports found open by the previous scan
+    #[clap(long, value_name = "DURATION")]
+    pub reuse_portscan: Option<String>,
+
+    /// Only checks the ports of the definitions (saved as the port scans of the hosts and
+    /// logged as the open ports), without probing them: a fast port inventory, checking all the
+    /// ports of a target at once
+    #[clap(long)]
+    pub ports_only: bool,
+
+    /// Sets a maximum size for each response (bytes), overridden by the definitions
+    /// max_response_bytes [default: 1048576]
+    #[clap(long, value_name = "NUM")]
+    pub max_response_bytes: Option<usize>,
+
+    /// Sends a HEAD / request to each web port first, the GET requests of the definitions with a
+    /// head_regex are sent only when its headers match (e.g. to save bandwidth on large bodies)
+    #[clap(long)]
+    pub head_first: bool,
+
+    /// Sends the probes from a specific source address (e.g. on multi-homed hosts)
+    #[clap(long, value_name = "IP", conflicts_with = "interface")]
+    pub source_ip: Option<String>,
+
+    /// Sends the probes from the (first IPv4) address of a network interface (e.g. a VPN
+    /// interface)
+    #[clap(long, value_name = "NAME")]
+    pub interface: Option<String>,
+
+    /// Log JSON lines (events and periodic stats) instead of the progress bars, e.g. when running
+    /// in a container. Also enabled by the environment variable LACHESIS_JSON_LOGS
+    #[clap(long)]
+    pub json_logs: bool,
+
+    /// Prints the findings (matches and open ports) to stdout as JSON lines, one per event, for
+    /// the pipelines (e.g. lachesis scan ... | jq). The progress bars are not drawn and the errors
+    /// are printed to stderr
+    #[clap(long, conflicts_with = "json-logs")]
+    pub stdout_ndjson: bool,
+
+    /// Print debug messages
+    #[clap(short = 'v', long)]
+    pub debug: bool,
+
+    /// Writes the timings (busy and idle) of the workers, network and db operations to FILE, to
+    /// diagnose the stalls of long scans. The default filter lachesis=debug is overridden by the
+    /// environment variable RUST_LOG
+    #[clap(long, value_name = "FILE")]
+    pub trace: Option<String>,
+
+    /// MaxMind DB file of the countries (e.g. GeoLite2-Country.mmdb), used to save the country
+    /// of the hosts
+    #[clap(long, value_name = "FILE")]
+    pub geoip_db: Option<String>,
+
+    /// MaxMind DB file of the autonomous systems (e.g. GeoLite2-ASN.mmdb), used to save the ASN
+    /// and AS name of the hosts
+    #[clap(long, value_name = "FILE")]
+    pub asn_db: Option<String>,
+
+    /// Refuses the targets outside the authorized networks of a signed scope file (the refused
+    /// ones are logged in <logs>/scope-audit.jsonl)
+    #[clap(long, value_name = "FILE")]
+    pub scope: Option<String>,
+
+    /// Scans the targets again every interval (see --interval), reporting the changes since the
+    /// previous scan: new hosts, ports and services, closed ports, disappeared hosts and changed
+    /// versions
+    #[clap(long)]
+    pub monitor: bool,
+
+    /// Interval between the scans in monitoring mode (e.g. 30m, 6h, 1d) [default: 6h]
+    #[clap(long, value_name = "DURATION", requires = "monitor")]
+    pub interval: Option<String>,
+
+    /// Sends the changes found in monitoring mode to a webhook (POST, JSON body)
+    #[clap(long, value_name = "URL", requires = "monitor")]
+    pub webhook: Option<String>,
+
+    /// Writes the findings to another sink too, besides the db: jsonl:<FILE>,
+    /// elasticsearch:<INDEX URL> (e.g. elasticsearch:http://localhost:9200/lachesis) or
+    /// webhook:<URL>. Every sink gets every finding, whether the others fail or not
+    #[clap(long, value_name = "SINK", multiple_occurrences = true)]
+    pub output_sink: Option<Vec<String>>,
+
+    /// Project of the scan results (e.g. a client), the web UI/API shows one project at a time
+    /// [default: default]
+    #[clap(long, value_name = "NAME")]
+    pub project: Option<String>,
+
+    /// Saves the tcp/custom exchanges of the matches as pcap files in this directory (the tcp/ip
+    /// headers are rebuilt, the payloads are the exchanged bytes)
+    #[clap(long, value_name = "DIR")]
+    pub pcap_matches: Option<String>,
+
+    /// Probes the ips of the CDN/WAF edges (built-in and --cdn-ranges ranges) with the http and
+    /// https definitions only
+    #[clap(long)]
+    pub skip_cdn: bool,
+
+    /// Additional CDN/WAF ranges, one "<provider> <cidr>" per line
+    #[clap(long, value_name = "FILE")]
+    pub cdn_ranges: Option<String>,
+
+    /// Fetches robots.txt and sitemap.xml of the web servers with matching services, saving
+    /// the disallowed paths and the number of urls as attributes of the findings
+    #[clap(long)]
+    pub fetch_robots: bool,
+
+    /// Tries the host names of this file (one per line) as Host header on the web ports of the
+    /// ip targets, saving the ones answering differently than a random name as their domains
+    #[clap(long, value_name = "FILE")]
+    pub vhost_wordlist: Option<String>,
+
+    /// Looks up the owner of the networks (/24) of the findings with RDAP, one lookup per
+    /// network and second, saving it as attributes of the findings and in the netblock table
+    #[clap(long)]
+    pub rdap: bool,
+
+    /// Saves the requests and responses of the http/s matches as HAR entries of the scan,
+    /// downloadable as a HAR file from /api/scans/<id>/har
+    #[clap(long)]
+    pub har: bool,
+
+    /// Saves the last raw response of every matching service, shown in the host view of the
+    /// web UI and served by /api/services/<id>/response
+    #[clap(long)]
+    pub store_responses: bool,
+
+    /// Saves the responses matching no definition, clustered by similarity in the web UI to
+    /// find the most common unidentified services
+    #[clap(long)]
+    pub collect_unknown: bool,
+
+    /// Saves the timings of the http/s matches (dns, connect, tls, first byte) as attributes of
+    /// the findings
+    #[clap(long)]
+    pub timing_attributes: bool,
+
+    /// Prints the scan plan (definitions, ports, targets and estimated requests) and exits
+    /// without sending any request
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct DbArgs {
+    #[clap(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Creates (or upgrades) the db schema
+    Init,
+    /// Prints the number of rows of the tables
+    Stats,
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// List of hosts, one per line: "ip" or "domain ip" (also comma or tab separated)
+    #[clap(short, long, value_name = "FILE")]
+    pub input: String,
+
+    /// DNS dataset written as JSON lines (see scan --dataset)
+    #[clap(short, long, value_name = "FILE")]
+    pub output: String,
+}
+
+#[derive(Args, Debug)]
+pub struct SplitArgs {
+    /// DNS dataset to split (see scan --dataset), the records of the shard are written as they are
+    #[clap(
+        short = 'D',
+        long,
+        value_name = "FILE",
+        conflicts_with = "subnet",
+        required_unless_present = "subnet"
+    )]
+    pub dataset: Option<String>,
+
+    /// Subnets to split, the hosts of the shard are written one per line
+    #[clap(short = 'S', long, value_name = "SUBNET", multiple_occurrences = true)]
+    pub subnet: Option<Vec<String>>,
+
+    /// Number of shards
+    #[clap(long, value_name = "NUM")]
+    pub shards: u32,
+
+    /// Shard to write, from 1 to --shards
+    #[clap(long, value_name = "NUM")]
+    pub shard: u32,
+
+    /// Output file (default: stdout)
+    #[clap(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    /// Number of fake services, half HTTP and half TCP
+    #[clap(long, value_name = "NUM", default_value = "100")]
+    pub listeners: usize,
+
+    /// First port of the services (consecutive ports), random ports if 0
+    #[clap(long, value_name = "PORT", default_value = "0")]
+    pub port: u16,
+
+    /// Definitions of the fake services, written to FILE
+    #[clap(long, value_name = "FILE", default_value = "selftest-definitions.json")]
+    pub definitions: String,
+}
+
+#[derive(Args, Debug)]
+pub struct DefsArgs {
+    #[clap(subcommand)]
+    pub command: DefsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DefsCommand {
+    /// Installs the definitions of an index in resources/definitions, verifying its signature and
+    /// their checksums
+    Update {
+        /// Url of the index, or of a Git repository with the index (index.json) at its root
+        /// (default: definitions_index in the config file)
+        #[clap(long, value_name = "URL")]
+        index: Option<String>,
+        /// Trusts the signatures of this public key (hex Ed25519), in addition to the one
+        /// compiled in if any (default: defs_public_keys in the config file)
+        #[clap(long, value_name = "KEY", multiple_occurrences = true)]
+        public_key: Option<Vec<String>>,
+    },
+    /// Lists the installed definitions files, with the versions of the ones from an index
+    List,
+    /// Proposes exclude_regex additions for the definitions from the findings marked as false
+    /// positives (with their responses stored, see scan --store-responses)
+    SuggestExclusions,
+    /// Writes the signature of a definitions file (<FILE>.sig)
+    Sign {
+        #[clap(value_name = "FILE")]
+        file: String,
+        /// File of the secret key (see defs keygen)
+        #[clap(long, value_name = "FILE")]
+        secret_key: String,
+    },
+    /// Writes the secret key of a new signing key pair, and prints the public key
+    Keygen {
+        #[clap(long, value_name = "FILE")]
+        secret_key: String,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct SelfUpdateArgs {
+    /// Only prints whether a newer release exists
+    #[clap(long)]
+    pub check_only: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ScopeArgs {
+    #[clap(subcommand)]
+    pub command: ScopeCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScopeCommand {
+    /// Prints the signature of a scope file, signed with the key in the environment variable
+    /// LACHESIS_SCOPE_KEY
+    Sign {
+        #[clap(value_name = "FILE")]
+        file: String,
+    },
+}
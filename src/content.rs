@@ -0,0 +1,114 @@
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::worker::ReqTarget;
+
+// Hashes of the content of a response, to group the identical (body_hash) and the similar
+// (simhash) pages, e.g. the same router login page served by thousands of hosts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContentHash {
+    // Sha256 of the normalized content, hex
+    pub body_hash: String,
+    // 64 bits simhash of the words of the normalized content, as saved in the db (bigint)
+    pub simhash: i64,
+}
+
+// Lowercase content with the parts that change at every request removed: hex ids and tokens
+// (e.g. session ids, CSRF tokens, nonces), numbers (e.g. dates, counters) and whitespaces
+fn normalize(content: &str) -> String {
+    let hex_re = Regex::new(r"\b[0-9a-f]{16,}\b").unwrap();
+    let num_re = Regex::new(r"[0-9]+").unwrap();
+
+    let content = content.to_lowercase();
+    let content = hex_re.replace_all(&content, "x");
+    let content = num_re.replace_all(&content, "0");
+    content.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// FNV-1a, stable across the builds (unlike the std hasher)
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// Simhash of the word pairs: similar contents have hashes differing by few bits
+fn simhash(content: &str) -> u64 {
+    let words: Vec<&str> = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    let mut weights = [0i64; 64];
+    let mut add = |feature: &str| {
+        let hash = fnv1a(feature.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    };
+    if words.len() == 1 {
+        add(words[0]);
+    }
+    for pair in words.windows(2) {
+        add(&pair.join(" "));
+    }
+
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0u64, |hash, (bit, _)| hash | 1 << bit)
+}
+
+// Number of different bits of two simhashes
+pub fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Groups of the similar contents (simhashes within max_distance bits of the first one of the
+// group) as indexes, the biggest first
+pub fn cluster(simhashes: &[i64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for (i, simhash) in simhashes.iter().enumerate() {
+        match clusters
+            .iter_mut()
+            .find(|cluster| distance(simhashes[cluster[0]], *simhash) <= max_distance)
+        {
+            Some(cluster) => cluster.push(i),
+            None => clusters.push(vec![i]),
+        }
+    }
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.len()));
+    clusters
+}
+
+// Hashes of the body (the whole response for the protocols without a body), none for the empty
+// responses
+pub fn hash(target: &ReqTarget) -> Option<ContentHash> {
+    // Without the body, the headers would group unrelated pages
+    if target.headers_only {
+        return None;
+    }
+    let content = if target.body.is_empty() {
+        &target.response
+    } else {
+        &target.body
+    };
+    let content = normalize(content);
+    if content.is_empty() {
+        return None;
+    }
+
+    Some(ContentHash {
+        body_hash: hex::encode(Sha256::digest(content.as_bytes())),
+        simhash: simhash(&content) as i64,
+    })
+}
@@ -0,0 +1,114 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_derive::Deserialize;
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::worker::Limits;
+
+// New values of the limits, the missing ones are kept
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsUpdate {
+    pub max_concurrent_requests: Option<usize>,
+    pub max_rate: Option<u64>,
+}
+
+fn limits_json(limits: &Limits) -> Response<Body> {
+    let body = json!({
+        "max_concurrent_requests": limits.max_concurrent_requests(),
+        "max_rate": limits.max_rate(),
+    });
+    Response::new(Body::from(body.to_string()))
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    let mut res = Response::new(Body::from(message.to_string()));
+    *res.status_mut() = status;
+    res
+}
+
+pub fn update(limits: &Limits, update: &LimitsUpdate) -> Result<(), &'static str> {
+    if let Some(max) = update.max_concurrent_requests {
+        limits.set_max_concurrent_requests(max)?;
+    }
+    if let Some(max_rate) = update.max_rate {
+        limits.set_max_rate(max_rate);
+    }
+    Ok(())
+}
+
+// GET /limits returns the current limits, PUT /limits sets them (e.g.
+// {"max_concurrent_requests": 100, "max_rate": 500})
+async fn handle(limits: Arc<Limits>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/limits" {
+        return Ok(error(StatusCode::NOT_FOUND, "Not found"));
+    }
+
+    match *req.method() {
+        Method::GET => Ok(limits_json(&limits)),
+        Method::PUT => {
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(_) => return Ok(error(StatusCode::BAD_REQUEST, "Invalid body")),
+            };
+            let limits_update: LimitsUpdate = match serde_json::from_slice(&body) {
+                Ok(limits_update) => limits_update,
+                Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match update(&limits, &limits_update) {
+                Ok(_) => Ok(limits_json(&limits)),
+                Err(e) => Ok(error(StatusCode::BAD_REQUEST, e)),
+            }
+        }
+        _ => Ok(error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")),
+    }
+}
+
+// Halves the limits (throttling down), the rate is halved only when limited
+#[cfg(unix)]
+fn throttle_down(limits: &Limits) {
+    let max = limits.max_concurrent_requests();
+    if max != 0 {
+        let _ = limits.set_max_concurrent_requests((max / 2).max(1));
+    }
+    let max_rate = limits.max_rate();
+    if max_rate != 0 {
+        limits.set_max_rate((max_rate / 2).max(1));
+    }
+}
+
+// Control of the running scan: the API on the given address (--control-listen) and SIGUSR2
+pub fn spawn(addr: Option<SocketAddr>, limits: Arc<Limits>) -> Result<Vec<JoinHandle<()>>, String> {
+    let mut handles = Vec::new();
+
+    if let Some(addr) = addr {
+        let server = Server::try_bind(&addr).map_err(|e| e.to_string())?;
+        let api_limits = limits.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let limits = api_limits.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(limits.clone(), req))) }
+        });
+        handles.push(tokio::spawn(async move {
+            let _ = server.serve(make_svc).await;
+        }));
+    }
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::user_defined2()).map_err(|e| e.to_string())?;
+        handles.push(tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                throttle_down(&limits);
+            }
+        }));
+    }
+
+    Ok(handles)
+}
@@ -0,0 +1,60 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    net::IpAddr,
+};
+
+use crate::{cli::ConvertArgs, worker::DatasetRecord};
+
+// A host as "ip" or "domain ip" (space, comma or tab separated). Comments (#) and blank lines
+// are skipped
+pub fn parse_host_line(line: &str) -> Option<Result<DatasetRecord, String>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let fields: Vec<&str> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|f| !f.is_empty())
+        .collect();
+    let (name, ip) = match fields.as_slice() {
+        [ip] => ("", *ip),
+        [name, ip] => (*name, *ip),
+        _ => return Some(Err(format!("Invalid line: {}", line))),
+    };
+
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => Some(Ok(DatasetRecord {
+            name: name.to_string(),
+            record_type: "a".to_string(),
+            value: ip.to_string(),
+            tags: Vec::new(),
+        })),
+        _ => Some(Err(format!("Invalid IPv4 address: {}", line))),
+    }
+}
+
+// Returns the number of written records
+pub fn run(args: &ConvertArgs) -> Result<usize, String> {
+    let input = fs::read_to_string(&args.input)
+        .map_err(|e| format!("Unable to read {}: {}", args.input, e))?;
+    let output = File::create(&args.output)
+        .map_err(|e| format!("Unable to create {}: {}", args.output, e))?;
+    let mut output = BufWriter::new(output);
+
+    let mut count = 0;
+    for (idx, line) in input.lines().enumerate() {
+        let record = match parse_host_line(line) {
+            Some(Ok(record)) => record,
+            Some(Err(err)) => return Err(format!("{} (line {})", err, idx + 1)),
+            None => continue,
+        };
+        let record = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+        writeln!(output, "{}", record).map_err(|e| e.to_string())?;
+        count += 1;
+    }
+    output.flush().map_err(|e| e.to_string())?;
+
+    Ok(count)
+}
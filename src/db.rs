@@ -1,10 +1,44 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use colored::Colorize;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio_postgres::{connect, Client, Error, NoTls};
+use tracing::instrument;
 
-use crate::{conf::DbConf, detector::DetectorResponse};
+// The total number of services (web UI pagination) is counted again after this interval
+const ROWS_COUNT_TTL: Duration = Duration::from_secs(10);
+
+// Response headers saved with the services (lowercase), the rest is only used by the detection
+const SAVED_HEADERS: &[&str] = &["server", "x-powered-by", "content-type", "www-authenticate"];
+
+// Unknown responses: saved beginning of the response, most recent ones clustered, samples of
+// every cluster
+const UNKNOWN_BANNER_CHARS: usize = 512;
+const UNKNOWN_CLUSTERED_ROWS: i64 = 20000;
+const UNKNOWN_SAMPLES: usize = 5;
+
+use crate::{
+    conf::DbConf,
+    content::{self, ContentHash},
+    detector::{self, DetectorResponse},
+    enrichment::GeoInfo,
+    error, iana,
+    rdap::Netblock,
+    triage,
+    worker::{PortsTarget, ReqTarget},
+    zone::DomainInfo,
+};
+
+// Milliseconds since the epoch (0 before it)
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ServicesRow {
@@ -17,16 +51,360 @@ struct ServicesRow {
     pub ip: String,
     pub domain: String,
     pub port: u16,
+    pub confidence: f32,
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+    pub as_name: Option<String>,
+    // Title of the web page (if any)
+    pub title: Option<String>,
+    // Tags of the target (dataset or target stream)
+    pub tags: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PaginatedServices {
     services: Vec<ServicesRow>,
     pub rows_count: i64,
+    // Cursor of the next page (if any)
+    pub next_cursor: Option<String>,
+}
+
+// Position in the services list, after the service with this first_seen and id. Unlike the
+// offsets, it doesn't skip or repeat rows when new services are inserted during the browsing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServicesCursor {
+    pub first_seen: SystemTime,
+    pub id: i64,
+}
+
+impl ServicesCursor {
+    // Format: <first_seen microseconds>-<id>
+    pub fn parse(cursor: &str) -> Result<Self, &'static str> {
+        let mut parts = cursor.splitn(2, '-');
+        let (first_seen, id) = match (parts.next(), parts.next()) {
+            (Some(first_seen), Some(id)) => (first_seen, id),
+            _ => return Err("Invalid cursor"),
+        };
+        let first_seen = first_seen.parse::<u64>().map_err(|_| "Invalid cursor")?;
+        let id = id.parse::<i64>().map_err(|_| "Invalid cursor")?;
+
+        Ok(ServicesCursor {
+            first_seen: UNIX_EPOCH + Duration::from_micros(first_seen),
+            id,
+        })
+    }
+}
+
+impl std::fmt::Display for ServicesCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let micros = self
+            .first_seen
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        write!(f, "{}-{}", micros, self.id)
+    }
+}
+
+// Filters of the services list (web UI)
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct ServicesFilter {
+    pub project_id: i64,
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+    // Case insensitive search in the services, versions, ips, domains and finding attributes (e.g.
+    // the titles of the web pages)
+    pub search: Option<String>,
+    // Services with the same content
+    pub body_hash: Option<String>,
+    // Services of the targets with this tag
+    pub tag: Option<String>,
+}
+
+impl ServicesFilter {
+    fn search_pattern(&self) -> Option<String> {
+        self.search.as_deref().map(search_pattern)
+    }
+}
+
+// Start of a stored response, enough for the headers and the first part of the body
+fn snippet(response: &[u8]) -> String {
+    String::from_utf8_lossy(&response[..response.len().min(4096)]).to_string()
+}
+
+// ILIKE pattern of a search, matching the text anywhere
+fn search_pattern(search: &str) -> String {
+    format!(
+        "%{}%",
+        search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+// Number of services by country or by AS
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GeoAggregate {
+    pub countries: Vec<(Option<String>, i64)>,
+    pub asns: Vec<(Option<i64>, Option<String>, i64)>,
+}
+
+// Services sharing the same content (body_hash)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContentGroup {
+    pub body_hash: String,
+    pub services: i64,
+    pub hosts: i64,
+    // Matching definitions of the services
+    pub names: Vec<String>,
+    pub title: Option<String>,
+    pub first_seen: u128,
+    pub last_seen: u128,
+}
+
+// Service with a content similar to the one of another service, by the distance (number of
+// different bits) of their simhashes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SimilarService {
+    pub id: i64,
+    pub service: String,
+    pub ip: String,
+    pub port: u16,
+    pub body_hash: Option<String>,
+    pub distance: i32,
+}
+
+// Responses matching no definition with a similar content (e.g. the same unknown banner)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnknownCluster {
+    pub responses: usize,
+    pub hosts: usize,
+    pub ports: Vec<u16>,
+    pub protocols: Vec<String>,
+    // A few of them (the most recent first), to write a definition
+    pub samples: Vec<UnknownSample>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnknownSample {
+    pub ip: String,
+    pub port: u16,
+    pub protocol: String,
+    pub banner: String,
+}
+
+// Stats snapshot of a scan (periodic, the last one is saved at the end of the scan)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanStatsRow {
+    pub time: u128,
+    pub stats: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanStats {
+    pub scan_id: i64,
+    pub started: u128,
+    pub finished: Option<u128>,
+    pub snapshots: Vec<ScanStatsRow>,
+}
+
+// Everything known about an ip (web UI host view)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HostSummary {
+    pub ip: String,
+    pub first_seen: u128,
+    pub last_seen: u128,
+    pub seen_count: i32,
+    // Ports of the matching services
+    pub ports: Vec<u16>,
+    // Result of the last port scan (if any)
+    pub open_ports: Vec<u16>,
+    // Conventional service names of the ports (IANA), e.g. of the open ports not fingerprinted
+    pub port_names: BTreeMap<u16, String>,
+    pub last_portscan: Option<u128>,
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+    pub as_name: Option<String>,
+    pub services: Vec<HostService>,
+    pub domains: Vec<HostDomain>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HostService {
+    pub id: i64,
+    pub first_seen: u128,
+    pub last_seen: u128,
+    pub seen_count: i32,
+    pub service: String,
+    pub version: String,
+    pub description: String,
+    pub protocol: String,
+    pub domain: String,
+    pub port: u16,
+    pub confidence: f32,
+    pub attributes: Vec<(String, Option<String>)>,
+    pub headers: Vec<(String, String)>,
+    // The raw response was saved (--store-responses)
+    pub has_response: bool,
+    // The request sent was saved
+    pub has_request: bool,
+    // Not seen by the last scan of the host since then (if inactive)
+    pub active: bool,
+    pub inactive_since: Option<u128>,
+}
+
+// Last raw response of a service (--store-responses), the head (status line and headers) of the
+// http/s ones split from the body
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredResponse {
+    pub service_id: i64,
+    pub protocol: String,
+    pub time: u128,
+    pub head: String,
+    pub body: String,
+    pub content_type: Option<String>,
+    pub truncated: bool,
+}
+
+// Last request sent to a service (secrets masked)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SentRequest {
+    pub service_id: i64,
+    pub protocol: String,
+    pub request: String,
+}
+
+// A domain of the host and the other ips sharing it (to pivot)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HostDomain {
+    pub domain: String,
+    pub first_seen: u128,
+    pub last_seen: u128,
+    pub seen_count: i32,
+    pub other_ips: Vec<String>,
+}
+
+// A domain of the domains list, with the number of its ips and services
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DomainRow {
+    pub id: i64,
+    pub domain: String,
+    pub first_seen: u128,
+    pub last_seen: u128,
+    pub seen_count: i32,
+    pub ips: i64,
+    pub services: i64,
+    // Addresses of the wildcard records, if any
+    pub wildcard_ips: Vec<String>,
+    // The zone transfer succeeded (from this nameserver)
+    pub zone_ns: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaginatedDomains {
+    pub domains: Vec<DomainRow>,
+    pub rows_count: i64,
+    // Cursor of the next page (the last domain of this one), if any
+    pub next_cursor: Option<String>,
+}
+
+// An ip of a domain (ip_domain relation), with the number of its services
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DomainIp {
+    pub ip: String,
+    pub first_seen: u128,
+    pub last_seen: u128,
+    pub seen_count: i32,
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+    pub as_name: Option<String>,
+    pub services: i64,
+}
+
+// A finding of the triage queue
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TriageFinding {
+    pub id: i64,
+    pub ip: String,
+    pub domain: Option<String>,
+    pub port: i32,
+    pub service: String,
+    pub version: String,
+    pub description: String,
+    pub severity: Option<String>,
+    pub confidence: f32,
+    pub score: f32,
+    pub state: String,
+    pub last_seen: u128,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PaginatedTriage {
+    pub findings: Vec<TriageFinding>,
+    pub rows_count: i64,
+}
+
+// Findings of a definition marked as false positives (the start of their responses, if stored)
+// and responses of its confirmed findings
+#[derive(Debug, Default)]
+pub struct TriageFeedback {
+    pub false_positives: Vec<Option<String>>,
+    pub confirmed: Vec<String>,
+}
+
+// Named filters of the records of a project (query string of the web UI filters)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedSearch {
+    pub id: i64,
+    pub created: u128,
+    pub name: String,
+    pub query: String,
+}
+
+// Result of the last port scan of an ip
+#[derive(Debug, Clone)]
+pub struct PortscanRow {
+    pub checked_ports: Vec<u16>,
+    pub open_ports: Vec<u16>,
+}
+
+// The saved headers of a response (lowercase names), the repeated ones joined (e.g. the
+// WWW-Authenticate schemes)
+pub fn saved_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut saved: Vec<(String, String)> = Vec::new();
+    for (name, value) in headers {
+        let name = name.to_lowercase();
+        if !SAVED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        match saved.iter_mut().find(|(saved_name, _)| *saved_name == name) {
+            Some((_, saved_value)) => *saved_value = format!("{}, {}", saved_value, value),
+            None => saved.push((name, value.clone())),
+        }
+    }
+    saved
+}
+
+// Head and body of a raw response, the whole response is the body of the non http/s ones
+pub fn split_response(protocol: &str, response: &str) -> (String, String) {
+    if !detector::is_http(protocol) {
+        return (String::new(), response.to_string());
+    }
+    match response
+        .find("\r\n\r\n")
+        .map(|i| (i, 4))
+        .or_else(|| response.find("\n\n").map(|i| (i, 2)))
+    {
+        Some((i, len)) => (response[..i].to_string(), response[i + len..].to_string()),
+        None => (response.to_string(), String::new()),
+    }
 }
 
 pub struct DbMan {
     client: Client,
+    // Total number of services by filter, with the time of the count
+    rows_counts: Mutex<HashMap<ServicesFilter, (Instant, i64)>>,
 }
 
 impl DbMan {
@@ -40,10 +418,10 @@ impl DbMan {
         )
         .await?;
 
+        // When the connection is lost the client is closed (see is_closed), and the persistence
+        // layer reconnects
         tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                panic!("[{}] DB connection error: {}", "ERROR".red(), e);
-            }
+            let _ = connection.await;
         });
 
         client
@@ -88,9 +466,167 @@ impl DbMan {
                     ip_id           bigserial REFERENCES ip_ports(id) NOT NULL,
                     domain          varchar(1000),
                     port            integer NOT NULL,
+                    confidence      real DEFAULT 1,
                     UNIQUE          (service, ip_id, port)
                 );
 
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS confidence real DEFAULT 1;
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS truncated boolean DEFAULT false;
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS body_hash varchar(64);
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS simhash bigint;
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS headers jsonb;
+                -- Last raw response of the service (--store-responses)
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS response bytea;
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS response_time timestamp;
+                -- Not seen by the last scan of its host (kept with its history)
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS active boolean DEFAULT true;
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS inactive_since timestamp;
+                -- Severity of the definition and priority of the finding (triage queue)
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS severity varchar(10);
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS score real DEFAULT 0;
+                -- Tags of the target (dataset or target stream)
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS tags text[] DEFAULT '{}';
+                -- Last request sent to the service (secrets masked), to reproduce the finding
+                ALTER TABLE service ADD COLUMN IF NOT EXISTS request text;
+
+                -- DNS findings of the domains targets (--domain)
+                ALTER TABLE domain ADD COLUMN IF NOT EXISTS wildcard_ips varchar(15)[];
+                ALTER TABLE domain ADD COLUMN IF NOT EXISTS zone_ns varchar(1000);
+                ALTER TABLE domain ADD COLUMN IF NOT EXISTS zone jsonb;
+
+                CREATE INDEX IF NOT EXISTS service_body_hash_idx ON service (body_hash);
+                CREATE INDEX IF NOT EXISTS service_tags_idx ON service USING GIN (tags);
+
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS checked_ports integer[];
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS open_ports integer[];
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS last_portscan timestamp;
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS country varchar(2);
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS asn bigint;
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS as_name varchar(1000);
+
+                CREATE TABLE IF NOT EXISTS scan (
+                    id              bigserial PRIMARY KEY,
+                    started         timestamp DEFAULT current_timestamp,
+                    finished        timestamp
+                );
+
+                CREATE TABLE IF NOT EXISTS scan_stats (
+                    id              bigserial PRIMARY KEY,
+                    scan_id         bigint REFERENCES scan(id) ON DELETE CASCADE NOT NULL,
+                    time            timestamp DEFAULT current_timestamp,
+                    stats           jsonb NOT NULL
+                );
+
+                --
+                -- Projects (e.g. clients): the hosts, domains and scans belong to a project, the
+                -- services and their attributes to the project of their host
+                --
+                CREATE TABLE IF NOT EXISTS project (
+                    id              bigserial PRIMARY KEY,
+                    created         timestamp DEFAULT current_timestamp,
+                    name            varchar(100) UNIQUE NOT NULL
+                );
+
+                INSERT INTO project (name) VALUES ('default') ON CONFLICT (name) DO NOTHING;
+
+                ALTER TABLE ip_ports ADD COLUMN IF NOT EXISTS project_id bigint REFERENCES project(id);
+                ALTER TABLE domain ADD COLUMN IF NOT EXISTS project_id bigint REFERENCES project(id);
+                ALTER TABLE scan ADD COLUMN IF NOT EXISTS project_id bigint REFERENCES project(id);
+
+                -- The rows saved before the projects belong to the default project
+                UPDATE ip_ports SET project_id = (SELECT id FROM project WHERE name = 'default')
+                WHERE project_id IS NULL;
+                UPDATE domain SET project_id = (SELECT id FROM project WHERE name = 'default')
+                WHERE project_id IS NULL;
+                UPDATE scan SET project_id = (SELECT id FROM project WHERE name = 'default')
+                WHERE project_id IS NULL;
+
+                ALTER TABLE ip_ports ALTER COLUMN project_id SET NOT NULL;
+                ALTER TABLE domain ALTER COLUMN project_id SET NOT NULL;
+                ALTER TABLE scan ALTER COLUMN project_id SET NOT NULL;
+
+                -- The same ip or domain can be in more projects
+                ALTER TABLE ip_ports DROP CONSTRAINT IF EXISTS ip_ports_ip_key;
+                ALTER TABLE domain DROP CONSTRAINT IF EXISTS domain_domain_key;
+                CREATE UNIQUE INDEX IF NOT EXISTS ip_ports_project_ip ON ip_ports (project_id, ip);
+                CREATE UNIQUE INDEX IF NOT EXISTS domain_project_domain ON domain (project_id, domain);
+
+                -- Registry data (RDAP) of the networks of the findings, shared by the projects
+                CREATE TABLE IF NOT EXISTS netblock (
+                    id              bigserial PRIMARY KEY,
+                    network         varchar(50) UNIQUE NOT NULL,
+                    range           varchar(100),
+                    name            varchar(1000),
+                    handle          varchar(1000),
+                    owner           varchar(1000),
+                    country         varchar(2),
+                    last_lookup     timestamp DEFAULT current_timestamp
+                );
+
+                -- HTTP exchanges (HAR entries) of the matches of a scan
+                CREATE TABLE IF NOT EXISTS har_entry (
+                    id              bigserial PRIMARY KEY,
+                    scan_id         bigint REFERENCES scan(id) ON DELETE CASCADE NOT NULL,
+                    entry           jsonb NOT NULL
+                );
+
+                -- Workflow state of the findings in the triage queue ('new' when missing), kept
+                -- apart from the service so that updating it is not a sighting
+                CREATE TABLE IF NOT EXISTS finding_triage (
+                    service_id      bigint PRIMARY KEY REFERENCES service(id) ON DELETE CASCADE,
+                    state           varchar(20) NOT NULL,
+                    updated         timestamp DEFAULT current_timestamp
+                );
+
+                CREATE INDEX IF NOT EXISTS service_score_idx ON service (score);
+
+                -- Findings marked as false positives, with the definition and the start of the
+                -- response (if stored), to suggest exclusions for the definitions
+                CREATE TABLE IF NOT EXISTS false_positive (
+                    service_id      bigint PRIMARY KEY REFERENCES service(id) ON DELETE CASCADE,
+                    created         timestamp DEFAULT current_timestamp,
+                    definition      varchar(1000) NOT NULL,
+                    snippet         text
+                );
+
+                -- Last response matching no definition of every ip, port and protocol
+                -- (--collect-unknown)
+                CREATE TABLE IF NOT EXISTS unknown_response (
+                    id              bigserial PRIMARY KEY,
+                    first_seen      timestamp DEFAULT current_timestamp,
+                    last_seen       timestamp DEFAULT current_timestamp,
+                    seen_count      integer DEFAULT 1,
+                    project_id      bigint REFERENCES project(id) ON DELETE CASCADE NOT NULL,
+                    ip              varchar(100) NOT NULL,
+                    port            integer NOT NULL,
+                    protocol        varchar(100) NOT NULL,
+                    body_hash       varchar(64) NOT NULL,
+                    simhash         bigint NOT NULL,
+                    banner          text,
+                    UNIQUE          (project_id, ip, port, protocol)
+                );
+
+                -- Saved searches of the web UI, by name
+                CREATE TABLE IF NOT EXISTS saved_search (
+                    id              bigserial PRIMARY KEY,
+                    created         timestamp DEFAULT current_timestamp,
+                    project_id      bigint REFERENCES project(id) ON DELETE CASCADE NOT NULL,
+                    name            varchar(100) NOT NULL,
+                    query           varchar(2000) NOT NULL,
+                    UNIQUE          (project_id, name)
+                );
+
+                CREATE TABLE IF NOT EXISTS finding_attribute (
+                    id              bigserial PRIMARY KEY,
+                    first_seen      timestamp DEFAULT current_timestamp,
+                    last_seen       timestamp DEFAULT current_timestamp,
+                    seen_count      integer DEFAULT 1,
+                    service_id      bigint REFERENCES service(id) ON DELETE CASCADE NOT NULL,
+                    name            varchar(1000) NOT NULL,
+                    value           text,
+                    UNIQUE          (service_id, name)
+                );
+
                 --
                 -- Trigger that updates the last_seen field at every row update
                 --
@@ -124,9 +660,18 @@ impl DbMan {
 
                 DROP TRIGGER IF EXISTS last_seen_trigger ON service;
 
+                -- Marking a service inactive is not a sighting
                 CREATE TRIGGER last_seen_trigger
                 BEFORE UPDATE ON service
                 FOR EACH ROW
+                WHEN (NEW.active IS NOT FALSE)
+                EXECUTE PROCEDURE last_seen_trigger();
+
+                DROP TRIGGER IF EXISTS last_seen_trigger ON finding_attribute;
+
+                CREATE TRIGGER last_seen_trigger
+                BEFORE UPDATE ON finding_attribute
+                FOR EACH ROW
                 EXECUTE PROCEDURE last_seen_trigger();
 
                 --
@@ -165,63 +710,129 @@ impl DbMan {
                 CREATE TRIGGER seen_count_trigger
                 BEFORE UPDATE ON service
                 FOR EACH ROW
+                WHEN (NEW.active IS NOT FALSE)
+                EXECUTE PROCEDURE seen_count_trigger();
+
+                DROP TRIGGER IF EXISTS seen_count_trigger ON finding_attribute;
+
+                CREATE TRIGGER seen_count_trigger
+                BEFORE UPDATE ON finding_attribute
+                FOR EACH ROW
                 EXECUTE PROCEDURE seen_count_trigger();
             ",
             )
             .await?;
 
-        Ok(DbMan { client })
+        Ok(DbMan::from_client(client))
+    }
+
+    // Schema already up to date (e.g. a client connected otherwise in the tests)
+    pub(crate) fn from_client(client: Client) -> Self {
+        DbMan {
+            client,
+            rows_counts: Mutex::new(HashMap::new()),
+        }
     }
 
-    async fn insert_ip_port(&self, ip: &str, port: u16) -> Result<i64, Error> {
+    async fn insert_ip_port(
+        &self,
+        project_id: i64,
+        ip: &str,
+        port: u16,
+        geo: &GeoInfo,
+    ) -> Result<i64, Error> {
         let port = port as i32; // postgres type
 
         // If the ip is not in the table yet, insert it with a new array containing this port
         // Else if the port was already detected for this ip, do nothing but trigger the update triggers
         // Else append the port to the existing array
+        // The geo-IP/ASN values are updated only when known (the databases are optional)
         let stmt = self
             .client
             .prepare(
                 "
-                INSERT INTO ip_ports (ip, ports)
-                VALUES ($1, ARRAY[$2::INTEGER])
-                ON CONFLICT (ip)
+                INSERT INTO ip_ports (ip, ports, country, asn, as_name, project_id)
+                VALUES ($1, ARRAY[$2::INTEGER], $3, $4, $5, $6)
+                ON CONFLICT (project_id, ip)
                 DO UPDATE
                 SET ports = (
                     CASE
                     WHEN array_position(ip_ports.ports, $2::INTEGER) IS NOT NULL THEN ip_ports.ports
                     ELSE array_append(ip_ports.ports, $2::INTEGER)
                     END
-                )
+                ),
+                country = COALESCE(excluded.country, ip_ports.country),
+                asn = COALESCE(excluded.asn, ip_ports.asn),
+                as_name = COALESCE(excluded.as_name, ip_ports.as_name)
                 RETURNING id
             ",
             )
             .await?;
 
-        let res = self.client.query_one(&stmt, &[&ip, &port]).await?;
+        let res = self
+            .client
+            .query_one(
+                &stmt,
+                &[
+                    &ip,
+                    &port,
+                    &geo.country,
+                    &geo.asn,
+                    &geo.as_name,
+                    &project_id,
+                ],
+            )
+            .await?;
 
         Ok(res.get(0))
     }
 
-    async fn update_or_insert_domain(&self, domain: &str) -> Result<i64, Error> {
+    async fn update_or_insert_domain(&self, project_id: i64, domain: &str) -> Result<i64, Error> {
         let stmt = self
             .client
             .prepare(
                 "
-                INSERT INTO domain (domain)
-                VALUES ($1)
-                ON CONFLICT (domain) DO UPDATE
+                INSERT INTO domain (domain, project_id)
+                VALUES ($1, $2)
+                ON CONFLICT (project_id, domain) DO UPDATE
                 -- Workaround: do nothing but trigger the update triggers
                 SET domain = excluded.domain
                 RETURNING id
             ",
             )
             .await?;
-        let res = self.client.query_one(&stmt, &[&domain]).await?;
+        let res = self
+            .client
+            .query_one(&stmt, &[&domain, &project_id])
+            .await?;
 
         Ok(res.get(0))
     }
 
+    // The zone of a previous transfer is kept when it's not tried again
+    pub async fn update_domain_dns(&self, project_id: i64, info: &DomainInfo) -> Result<(), Error> {
+        let domain_id = self
+            .update_or_insert_domain(project_id, &info.domain)
+            .await?;
+        let zone = if info.zone_ns.is_some() {
+            Some(serde_json::to_string(&info.zone).unwrap_or_default())
+        } else {
+            None
+        };
+        self.client
+            .execute(
+                "
+                UPDATE domain
+                SET wildcard_ips = $2, zone_ns = COALESCE($3, zone_ns),
+                    zone = COALESCE($4::TEXT::JSONB, zone)
+                WHERE id = $1
+            ",
+                &[&domain_id, &info.wildcard_ips, &info.zone_ns, &zone],
+            )
+            .await?;
+        Ok(())
+    }
+
     async fn update_or_insert_ip_domain_relation(
         &self,
         ip_id: &i64,
@@ -245,13 +856,44 @@ impl DbMan {
         Ok(res.get(0))
     }
 
-    pub async fn insert_service(&self, service: &DetectorResponse) -> Result<u64, Error> {
+    // Domain answering on the port of the ip (vhost discovery), with its ip relation
+    pub async fn insert_vhost(
+        &self,
+        project_id: i64,
+        target: &ReqTarget,
+        geo: &GeoInfo,
+    ) -> Result<(), Error> {
+        let ip_id = self
+            .insert_ip_port(project_id, &target.ip, target.port, geo)
+            .await?;
+        let domain_id = self
+            .update_or_insert_domain(project_id, &target.domain)
+            .await?;
+        self.update_or_insert_ip_domain_relation(&ip_id, &domain_id)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(
+        level = "debug",
+        skip(self, service, geo),
+        fields(ip = %service.target.ip, port = service.target.port)
+    )]
+    pub async fn insert_service(
+        &self,
+        project_id: i64,
+        service: &DetectorResponse,
+        geo: &GeoInfo,
+        store_response: bool,
+    ) -> Result<i64, Error> {
         let ip_id = self
-            .insert_ip_port(&service.target.ip, service.target.port)
+            .insert_ip_port(project_id, &service.target.ip, service.target.port, geo)
             .await?;
 
         if !service.target.domain.is_empty() {
-            let domain_id = self.update_or_insert_domain(&service.target.domain).await?;
+            let domain_id = self
+                .update_or_insert_domain(project_id, &service.target.domain)
+                .await?;
             self.update_or_insert_ip_domain_relation(&ip_id, &domain_id)
                 .await?;
         }
@@ -260,16 +902,42 @@ impl DbMan {
             .client
             .prepare(
                 "
-                INSERT INTO service (service, version, description, protocol, ip_id, domain, port)
-                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                INSERT INTO service (service, version, description, protocol, ip_id, domain, port, confidence, truncated, body_hash, simhash, headers, response, response_time, severity, score, tags, request)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12::TEXT::JSONB, $13,
+                    CASE WHEN $13::BYTEA IS NULL THEN NULL ELSE current_timestamp END, $14, $15,
+                    $16, $17)
                 ON CONFLICT (service, ip_id, port) DO UPDATE
-                -- Workaround: do nothing but trigger the update triggers
-                SET ip_id = excluded.ip_id
+                SET confidence = excluded.confidence, truncated = excluded.truncated,
+                    severity = excluded.severity, score = excluded.score,
+                    body_hash = excluded.body_hash, simhash = excluded.simhash,
+                    headers = excluded.headers, tags = excluded.tags,
+                    response = COALESCE(excluded.response, service.response),
+                    response_time = COALESCE(excluded.response_time, service.response_time),
+                    request = COALESCE(excluded.request, service.request),
+                    active = true, inactive_since = NULL
+                RETURNING id
             ",
             )
             .await?;
-        self.client
-            .execute(
+        let headers = saved_headers(&service.target.headers);
+        let headers = if headers.is_empty() {
+            None
+        } else {
+            let object: serde_json::Map<String, Value> = headers
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect();
+            Some(Value::Object(object).to_string())
+        };
+        // Saved with the same upsert, a second update would count the service as seen twice
+        let response = if store_response {
+            Some(service.target.response.as_bytes())
+        } else {
+            None
+        };
+        let service_id: i64 = self
+            .client
+            .query_one(
                 &stmt,
                 &[
                     &service.service,
@@ -279,16 +947,78 @@ impl DbMan {
                     &ip_id,
                     &service.target.domain,
                     &(service.target.port as i32),
+                    &service.confidence,
+                    &service.target.truncated,
+                    &service.content.as_ref().map(|content| &content.body_hash),
+                    &service.content.as_ref().map(|content| content.simhash),
+                    &headers,
+                    &response,
+                    &service.severity,
+                    &triage::score(service),
+                    &service.target.tags,
+                    &service.request,
                 ],
             )
+            .await?
+            .get(0);
+
+        // Seen again, so not resolved
+        self.client
+            .execute(
+                "
+                UPDATE finding_triage SET state = 'new', updated = current_timestamp
+                WHERE service_id = $1 AND state = 'resolved'
+            ",
+                &[&service_id],
+            )
+            .await?;
+
+        for (name, value) in &service.attributes {
+            self.update_or_insert_attribute(&service_id, name, value)
+                .await?;
+        }
+
+        Ok(service_id)
+    }
+
+    async fn update_or_insert_attribute(
+        &self,
+        service_id: &i64,
+        name: &str,
+        value: &str,
+    ) -> Result<u64, Error> {
+        let stmt = self
+            .client
+            .prepare(
+                "
+                INSERT INTO finding_attribute (service_id, name, value)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (service_id, name) DO UPDATE
+                SET value = excluded.value
+            ",
+            )
+            .await?;
+        self.client
+            .execute(&stmt, &[&service_id, &name, &value])
             .await
     }
 
+    // Page of services after the cursor (if given) or else the offset, the newest first
+    #[instrument(level = "debug", skip(self, cursor))]
     pub async fn get_paginated_services(
         &self,
         offset: i64,
         rows: i64,
-    ) -> Result<PaginatedServices, Error> {
+        cursor: Option<&ServicesCursor>,
+        filter: &ServicesFilter,
+    ) -> error::Result<PaginatedServices> {
+        let (offset, cursor_first_seen, cursor_id) = match cursor {
+            Some(cursor) => (0, Some(cursor.first_seen), Some(cursor.id)),
+            None => (offset, None, None),
+        };
+
+        // Missing filters match all the rows. The id breaks the ties of first_seen, to keep the
+        // order stable
         let stmt = self
             .client
             .prepare(
@@ -301,58 +1031,1325 @@ impl DbMan {
                     service.protocol,
                     ip_ports.ip,
                     service.domain,
-                    service.port
+                    service.port,
+                    service.confidence,
+                    ip_ports.country,
+                    ip_ports.asn,
+                    ip_ports.as_name,
+                    (
+                        SELECT value FROM finding_attribute
+                        WHERE service_id = service.id AND name = 'title'
+                    ),
+                    service.tags
                 FROM service
                 LEFT JOIN ip_ports ON service.ip_id = ip_ports.id
-                ORDER BY first_seen DESC
+                WHERE ip_ports.project_id = $7
+                    AND ($3::VARCHAR IS NULL OR ip_ports.country = $3)
+                    AND ($4::BIGINT IS NULL OR ip_ports.asn = $4)
+                    AND ($5::TIMESTAMP IS NULL OR (service.first_seen, service.id) < ($5, $6))
+                    AND ($8::VARCHAR IS NULL
+                        OR service.service ILIKE $8
+                        OR service.version ILIKE $8
+                        OR ip_ports.ip ILIKE $8
+                        OR service.domain ILIKE $8
+                        OR EXISTS (
+                            SELECT 1 FROM finding_attribute
+                            WHERE service_id = service.id AND value ILIKE $8
+                        ))
+                    AND ($9::VARCHAR IS NULL OR service.body_hash = $9)
+                    AND ($10::VARCHAR IS NULL OR $10 = ANY(service.tags))
+                ORDER BY service.first_seen DESC, service.id DESC
                 LIMIT $1
                 OFFSET $2
             ",
             )
             .await?;
 
-        let services = self.client.query(&stmt, &[&rows, &offset]).await?;
-        let services = services.iter().map(|row| {
-            Ok(ServicesRow {
-                id: row.get(0),
-                first_seen: row
-                    .get::<_, SystemTime>(1)
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis(),
-                service: row.get(2),
-                version: row.get(3),
-                description: row.get(4),
-                protocol: row.get(5),
-                ip: row.get(6),
-                domain: row.get(7),
-                port: row.get::<_, i32>(8) as u16,
-            })
-        });
+        let rows_vec = self
+            .client
+            .query(
+                &stmt,
+                &[
+                    &rows,
+                    &offset,
+                    &filter.country,
+                    &filter.asn,
+                    &cursor_first_seen,
+                    &cursor_id,
+                    &filter.project_id,
+                    &filter.search_pattern(),
+                    &filter.body_hash,
+                    &filter.tag,
+                ],
+            )
+            .await?;
 
-        let mut services_vec = Vec::new();
-        for service in services {
-            services_vec.push(service?);
+        // A full page may be followed by other rows
+        let next_cursor = match rows_vec.last() {
+            Some(last) if rows_vec.len() as i64 == rows => Some(
+                ServicesCursor {
+                    first_seen: last.get(1),
+                    id: last.get(0),
+                }
+                .to_string(),
+            ),
+            _ => None,
+        };
+
+        // An unexpected column type is an error of the request, not a panic
+        let services = rows_vec
+            .iter()
+            .map(|row| {
+                Ok(ServicesRow {
+                    id: row.try_get(0)?,
+                    first_seen: millis(row.try_get(1)?),
+                    service: row.try_get(2)?,
+                    version: row.try_get(3)?,
+                    description: row.try_get(4)?,
+                    protocol: row.try_get(5)?,
+                    ip: row.try_get(6)?,
+                    domain: row.try_get(7)?,
+                    port: row.try_get::<_, i32>(8)? as u16,
+                    confidence: row.try_get(9)?,
+                    country: row.try_get(10)?,
+                    asn: row.try_get(11)?,
+                    as_name: row.try_get(12)?,
+                    title: row.try_get(13)?,
+                    tags: row
+                        .try_get::<_, Option<Vec<String>>>(14)?
+                        .unwrap_or_default(),
+                })
+            })
+            .collect::<error::Result<Vec<ServicesRow>>>()?;
+
+        let rows_count = self.count_services(filter).await?;
+
+        Ok(PaginatedServices {
+            services,
+            rows_count,
+            next_cursor,
+        })
+    }
+
+    // Counting all the rows at every page is slow on big tables, so the counts are cached for a
+    // few seconds
+    #[instrument(level = "debug", skip(self))]
+    async fn count_services(&self, filter: &ServicesFilter) -> Result<i64, Error> {
+        if let Some((time, count)) = self.rows_counts.lock().unwrap().get(filter) {
+            if time.elapsed() < ROWS_COUNT_TTL {
+                return Ok(*count);
+            }
         }
 
+        let count = self
+            .client
+            .query_one(
+                "
+                SELECT COUNT(*)
+                FROM service
+                LEFT JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE ip_ports.project_id = $3
+                    AND ($1::VARCHAR IS NULL OR ip_ports.country = $1)
+                    AND ($2::BIGINT IS NULL OR ip_ports.asn = $2)
+                    AND ($4::VARCHAR IS NULL
+                        OR service.service ILIKE $4
+                        OR service.version ILIKE $4
+                        OR ip_ports.ip ILIKE $4
+                        OR service.domain ILIKE $4
+                        OR EXISTS (
+                            SELECT 1 FROM finding_attribute
+                            WHERE service_id = service.id AND value ILIKE $4
+                        ))
+                    AND ($5::VARCHAR IS NULL OR service.body_hash = $5)
+                    AND ($6::VARCHAR IS NULL OR $6 = ANY(service.tags))
+            ",
+                &[
+                    &filter.country,
+                    &filter.asn,
+                    &filter.project_id,
+                    &filter.search_pattern(),
+                    &filter.body_hash,
+                    &filter.tag,
+                ],
+            )
+            .await?
+            .get(0);
+
+        self.rows_counts
+            .lock()
+            .unwrap()
+            .insert(filter.clone(), (Instant::now(), count));
+
+        Ok(count)
+    }
+
+    pub async fn get_geo_aggregate(&self, project_id: i64) -> Result<GeoAggregate, Error> {
+        let countries = self
+            .client
+            .query(
+                "
+                SELECT ip_ports.country, COUNT(*) AS services
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE ip_ports.project_id = $1
+                GROUP BY ip_ports.country
+                ORDER BY services DESC
+            ",
+                &[&project_id],
+            )
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+
+        let asns = self
+            .client
+            .query(
+                "
+                SELECT ip_ports.asn, MAX(ip_ports.as_name), COUNT(*) AS services
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE ip_ports.project_id = $1
+                GROUP BY ip_ports.asn
+                ORDER BY services DESC
+            ",
+                &[&project_id],
+            )
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+
+        Ok(GeoAggregate { countries, asns })
+    }
+
+    // Groups of the services with the same content, the biggest first
+    pub async fn get_content_groups(
+        &self,
+        project_id: i64,
+        limit: i64,
+    ) -> Result<Vec<ContentGroup>, Error> {
+        let groups = self
+            .client
+            .query(
+                "
+                SELECT service.body_hash,
+                    COUNT(*) AS services,
+                    COUNT(DISTINCT service.ip_id),
+                    array_agg(DISTINCT service.service),
+                    MAX(finding_attribute.value),
+                    MIN(service.first_seen),
+                    MAX(service.last_seen)
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                LEFT JOIN finding_attribute
+                    ON finding_attribute.service_id = service.id AND finding_attribute.name = 'title'
+                WHERE ip_ports.project_id = $1 AND service.body_hash IS NOT NULL
+                GROUP BY service.body_hash
+                HAVING COUNT(*) > 1
+                ORDER BY services DESC
+                LIMIT $2
+            ",
+                &[&project_id, &limit],
+            )
+            .await?
+            .iter()
+            .map(|row| ContentGroup {
+                body_hash: row.get(0),
+                services: row.get(1),
+                hosts: row.get(2),
+                names: row.get(3),
+                title: row.get(4),
+                first_seen: millis(row.get(5)),
+                last_seen: millis(row.get(6)),
+            })
+            .collect();
+
+        Ok(groups)
+    }
+
+    // Services with a content similar to the one of a service (near-duplicates, e.g. the same
+    // page with a different hostname in it), the most similar first
+    pub async fn get_similar_services(
+        &self,
+        project_id: i64,
+        service_id: i64,
+        max_distance: i32,
+        limit: i64,
+    ) -> Result<Vec<SimilarService>, Error> {
+        // The different bits are counted on the text of the xor, bit_count() needs PostgreSQL 14
+        let similar = self
+            .client
+            .query(
+                "
+                SELECT * FROM (
+                    SELECT service.id,
+                        service.service,
+                        ip_ports.ip,
+                        service.port,
+                        service.body_hash,
+                        length(replace((service.simhash # other.simhash)::bit(64)::text, '0', ''))
+                            AS distance
+                    FROM service
+                    JOIN ip_ports ON service.ip_id = ip_ports.id
+                    JOIN service other ON other.id = $2
+                    JOIN ip_ports other_ip ON other.ip_id = other_ip.id
+                    WHERE ip_ports.project_id = $1
+                        AND other_ip.project_id = $1
+                        AND service.id != other.id
+                        AND service.simhash IS NOT NULL
+                ) AS similar
+                WHERE distance <= $3
+                ORDER BY distance, id DESC
+                LIMIT $4
+            ",
+                &[&project_id, &service_id, &max_distance, &limit],
+            )
+            .await?
+            .iter()
+            .map(|row| SimilarService {
+                id: row.get(0),
+                service: row.get(1),
+                ip: row.get(2),
+                port: row.get::<_, i32>(3) as u16,
+                body_hash: row.get(4),
+                distance: row.get(5),
+            })
+            .collect();
+
+        Ok(similar)
+    }
+
+    pub async fn get_host(&self, project_id: i64, ip: &str) -> Result<Option<HostSummary>, Error> {
+        let ports = |ports: Option<Vec<i32>>| -> Vec<u16> {
+            ports
+                .unwrap_or_default()
+                .iter()
+                .map(|p| *p as u16)
+                .collect()
+        };
+
+        let host = match self
+            .client
+            .query_opt(
+                "
+                SELECT id, first_seen, last_seen, seen_count, ports, open_ports, last_portscan,
+                    country, asn, as_name
+                FROM ip_ports
+                WHERE ip = $1 AND project_id = $2
+            ",
+                &[&ip, &project_id],
+            )
+            .await?
+        {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        let ip_id: i64 = host.get(0);
+
+        let mut services = Vec::new();
+        for row in self
+            .client
+            .query(
+                "
+                SELECT id, first_seen, last_seen, seen_count, service, version, description,
+                    protocol, domain, port, confidence, headers::TEXT, response IS NOT NULL,
+                    active IS NOT FALSE, inactive_since, request IS NOT NULL
+                FROM service
+                WHERE ip_id = $1
+                ORDER BY port, service
+            ",
+                &[&ip_id],
+            )
+            .await?
+        {
+            let service_id: i64 = row.get(0);
+            let attributes = self
+                .client
+                .query(
+                    "SELECT name, value FROM finding_attribute WHERE service_id = $1 ORDER BY name",
+                    &[&service_id],
+                )
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect();
+            let headers = row
+                .get::<_, Option<&str>>(11)
+                .and_then(|headers| {
+                    serde_json::from_str::<serde_json::Map<String, Value>>(headers).ok()
+                })
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, value)| (name, value.as_str().unwrap_or_default().to_string()))
+                .collect();
+
+            services.push(HostService {
+                id: service_id,
+                first_seen: millis(row.get(1)),
+                last_seen: millis(row.get(2)),
+                seen_count: row.get(3),
+                service: row.get(4),
+                version: row.get::<_, Option<String>>(5).unwrap_or_default(),
+                description: row.get::<_, Option<String>>(6).unwrap_or_default(),
+                protocol: row.get(7),
+                domain: row.get::<_, Option<String>>(8).unwrap_or_default(),
+                port: row.get::<_, i32>(9) as u16,
+                confidence: row.get(10),
+                attributes,
+                headers,
+                has_response: row.get(12),
+                has_request: row.get(15),
+                active: row.get(13),
+                inactive_since: row.get::<_, Option<SystemTime>>(14).map(millis),
+            });
+        }
+
+        let domains = self
+            .client
+            .query(
+                "
+                SELECT domain.domain, ip_domain.first_seen, ip_domain.last_seen,
+                    ip_domain.seen_count,
+                    ARRAY(
+                        SELECT other.ip
+                        FROM ip_domain AS other_domain
+                        JOIN ip_ports AS other ON other_domain.ip_id = other.id
+                        WHERE other_domain.domain_id = domain.id AND other.id <> $1
+                        ORDER BY other.ip
+                    )
+                FROM ip_domain
+                JOIN domain ON ip_domain.domain_id = domain.id
+                WHERE ip_domain.ip_id = $1
+                ORDER BY domain.domain
+            ",
+                &[&ip_id],
+            )
+            .await?
+            .iter()
+            .map(|row| HostDomain {
+                domain: row.get(0),
+                first_seen: millis(row.get(1)),
+                last_seen: millis(row.get(2)),
+                seen_count: row.get(3),
+                other_ips: row.get(4),
+            })
+            .collect();
+
+        let services_ports = ports(host.get(4));
+        let open_ports = ports(host.get(5));
+        let port_names = services_ports
+            .iter()
+            .chain(open_ports.iter())
+            .filter_map(|port| iana::service_name(*port).map(|name| (*port, name.to_string())))
+            .collect();
+
+        Ok(Some(HostSummary {
+            ip: ip.to_string(),
+            first_seen: millis(host.get(1)),
+            last_seen: millis(host.get(2)),
+            seen_count: host.get(3),
+            ports: services_ports,
+            open_ports,
+            port_names,
+            last_portscan: host.get::<_, Option<SystemTime>>(6).map(millis),
+            country: host.get(7),
+            asn: host.get(8),
+            as_name: host.get(9),
+            services,
+            domains,
+        }))
+    }
+
+    // Page of domains in alphabetical order, after the cursor (the last domain of the previous
+    // page) if given or else the offset, optionally matching a search
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_paginated_domains(
+        &self,
+        project_id: i64,
+        offset: i64,
+        rows: i64,
+        cursor: Option<&str>,
+        search: Option<&str>,
+    ) -> Result<PaginatedDomains, Error> {
+        let offset = if cursor.is_some() { 0 } else { offset };
+        let pattern = search.map(search_pattern);
+
+        let rows_vec = self
+            .client
+            .query(
+                "
+                SELECT domain.id, domain.domain, domain.first_seen, domain.last_seen,
+                    domain.seen_count,
+                    (SELECT COUNT(*) FROM ip_domain WHERE ip_domain.domain_id = domain.id),
+                    (
+                        SELECT COUNT(*) FROM service
+                        JOIN ip_ports ON service.ip_id = ip_ports.id
+                        WHERE service.domain = domain.domain AND ip_ports.project_id = $1
+                    ),
+                    domain.wildcard_ips, domain.zone_ns
+                FROM domain
+                WHERE domain.project_id = $1
+                    AND ($4::VARCHAR IS NULL OR domain.domain > $4)
+                    AND ($5::VARCHAR IS NULL OR domain.domain ILIKE $5)
+                ORDER BY domain.domain
+                LIMIT $2
+                OFFSET $3
+            ",
+                &[&project_id, &rows, &offset, &cursor, &pattern],
+            )
+            .await?;
+
+        // A full page may be followed by other rows
+        let next_cursor = match rows_vec.last() {
+            Some(last) if rows_vec.len() as i64 == rows => Some(last.get(1)),
+            _ => None,
+        };
+
+        let domains = rows_vec
+            .iter()
+            .map(|row| DomainRow {
+                id: row.get(0),
+                domain: row.get(1),
+                first_seen: millis(row.get(2)),
+                last_seen: millis(row.get(3)),
+                seen_count: row.get(4),
+                ips: row.get(5),
+                services: row.get(6),
+                wildcard_ips: row.get::<_, Option<Vec<String>>>(7).unwrap_or_default(),
+                zone_ns: row.get(8),
+            })
+            .collect();
+
         let rows_count = self
             .client
-            .query_one("SELECT COUNT(*) FROM service", &[])
+            .query_one(
+                "
+                SELECT COUNT(*) FROM domain
+                WHERE project_id = $1 AND ($2::VARCHAR IS NULL OR domain ILIKE $2)
+            ",
+                &[&project_id, &pattern],
+            )
             .await?
             .get(0);
 
-        Ok(PaginatedServices {
-            services: services_vec,
+        Ok(PaginatedDomains {
+            domains,
             rows_count,
+            next_cursor,
         })
     }
 
-    pub async fn delete_services(&self, ids: Vec<i64>) -> Result<(), Error> {
+    // The ips of a domain of the project (none if the domain is not in the project)
+    pub async fn get_domain_ips(
+        &self,
+        project_id: i64,
+        domain_id: i64,
+    ) -> Result<Option<Vec<DomainIp>>, Error> {
+        let domain: String = match self
+            .client
+            .query_opt(
+                "SELECT domain FROM domain WHERE id = $1 AND project_id = $2",
+                &[&domain_id, &project_id],
+            )
+            .await?
+        {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+
+        let ips = self
+            .client
+            .query(
+                "
+                SELECT ip_ports.ip, ip_domain.first_seen, ip_domain.last_seen,
+                    ip_domain.seen_count, ip_ports.country, ip_ports.asn, ip_ports.as_name,
+                    (
+                        SELECT COUNT(*) FROM service
+                        WHERE service.ip_id = ip_ports.id AND service.domain = $2
+                    )
+                FROM ip_domain
+                JOIN ip_ports ON ip_domain.ip_id = ip_ports.id
+                WHERE ip_domain.domain_id = $1
+                ORDER BY ip_ports.ip
+            ",
+                &[&domain_id, &domain],
+            )
+            .await?
+            .iter()
+            .map(|row| DomainIp {
+                ip: row.get(0),
+                first_seen: millis(row.get(1)),
+                last_seen: millis(row.get(2)),
+                seen_count: row.get(3),
+                country: row.get(4),
+                asn: row.get(5),
+                as_name: row.get(6),
+                services: row.get(7),
+            })
+            .collect();
+
+        Ok(Some(ips))
+    }
+
+    // Saves the ports checked for an ip and the open ones (replacing the previous port scan)
+    #[instrument(level = "debug", skip(self, ports_target, geo), fields(ip = %ports_target.ip))]
+    pub async fn update_or_insert_portscan(
+        &self,
+        project_id: i64,
+        ports_target: &PortsTarget,
+        geo: &GeoInfo,
+    ) -> Result<(), Error> {
+        let checked_ports: Vec<i32> = ports_target.ports.iter().map(|p| p.port as i32).collect();
+        let open_ports: Vec<i32> = ports_target
+            .open_ports()
+            .iter()
+            .map(|p| *p as i32)
+            .collect();
+
+        let stmt = self
+            .client
+            .prepare(
+                "
+                INSERT INTO ip_ports (ip, ports, checked_ports, open_ports, last_portscan, country, asn, as_name, project_id)
+                VALUES ($1, ARRAY[]::INTEGER[], $2, $3, current_timestamp, $4, $5, $6, $7)
+                ON CONFLICT (project_id, ip) DO UPDATE
+                SET checked_ports = excluded.checked_ports,
+                    open_ports = excluded.open_ports,
+                    last_portscan = excluded.last_portscan,
+                    country = COALESCE(excluded.country, ip_ports.country),
+                    asn = COALESCE(excluded.asn, ip_ports.asn),
+                    as_name = COALESCE(excluded.as_name, ip_ports.as_name)
+            ",
+            )
+            .await?;
+        self.client
+            .execute(
+                &stmt,
+                &[
+                    &ports_target.ip,
+                    &checked_ports,
+                    &open_ports,
+                    &geo.country,
+                    &geo.asn,
+                    &geo.as_name,
+                    &project_id,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    // Port scans more recent than max_age, by ip
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_recent_portscans(
+        &self,
+        project_id: i64,
+        max_age: Duration,
+    ) -> Result<HashMap<String, PortscanRow>, Error> {
+        let stmt = self
+            .client
+            .prepare(
+                "
+                SELECT ip, checked_ports, open_ports
+                FROM ip_ports
+                WHERE last_portscan > current_timestamp - make_interval(secs => $1)
+                    AND project_id = $2
+            ",
+            )
+            .await?;
+        let rows = self
+            .client
+            .query(&stmt, &[&max_age.as_secs_f64(), &project_id])
+            .await?;
+
+        let mut portscans = HashMap::new();
+        for row in rows {
+            let ports = |idx: usize| -> Vec<u16> {
+                row.get::<_, Option<Vec<i32>>>(idx)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|p| *p as u16)
+                    .collect()
+            };
+            portscans.insert(
+                row.get(0),
+                PortscanRow {
+                    checked_ports: ports(1),
+                    open_ports: ports(2),
+                },
+            );
+        }
+
+        Ok(portscans)
+    }
+
+    pub async fn insert_scan(&self, project_id: i64) -> Result<i64, Error> {
+        Ok(self
+            .client
+            .query_one(
+                "INSERT INTO scan (project_id) VALUES ($1) RETURNING id",
+                &[&project_id],
+            )
+            .await?
+            .get(0))
+    }
+
+    // Marks inactive the services of the hosts port scanned by the scan that it didn't see again
+    // (on a checked port, by a definition that ran), returning their number
+    pub async fn mark_stale_services(
+        &self,
+        scan_id: i64,
+        definitions: &[String],
+    ) -> Result<u64, Error> {
+        self.client
+            .execute(
+                "
+                UPDATE service SET active = false, inactive_since = current_timestamp
+                FROM ip_ports, scan
+                WHERE scan.id = $1
+                    AND service.ip_id = ip_ports.id
+                    AND ip_ports.project_id = scan.project_id
+                    AND ip_ports.last_portscan >= scan.started
+                    AND service.port = ANY(ip_ports.checked_ports)
+                    AND service.service = ANY($2)
+                    AND service.last_seen < scan.started
+                    AND service.active IS NOT FALSE
+            ",
+                &[&scan_id, &definitions],
+            )
+            .await
+    }
+
+    pub async fn finish_scan(&self, scan_id: i64) -> Result<(), Error> {
+        self.client
+            .execute(
+                "UPDATE scan SET finished = current_timestamp WHERE id = $1",
+                &[&scan_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, stats))]
+    pub async fn insert_scan_stats(&self, scan_id: i64, stats: &Value) -> Result<(), Error> {
+        // Sent as text, to be converted by postgres
+        self.client
+            .execute(
+                "INSERT INTO scan_stats (scan_id, stats) VALUES ($1, $2::TEXT::JSONB)",
+                &[&scan_id, &stats.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_scan_stats(
+        &self,
+        project_id: i64,
+        scan_id: i64,
+    ) -> Result<Option<ScanStats>, Error> {
+        let scan = match self
+            .client
+            .query_opt(
+                "SELECT started, finished FROM scan WHERE id = $1 AND project_id = $2",
+                &[&scan_id, &project_id],
+            )
+            .await?
+        {
+            Some(scan) => scan,
+            None => return Ok(None),
+        };
+
+        let snapshots = self
+            .client
+            .query(
+                "SELECT time, stats::TEXT FROM scan_stats WHERE scan_id = $1 ORDER BY time",
+                &[&scan_id],
+            )
+            .await?
+            .iter()
+            .map(|row| ScanStatsRow {
+                time: millis(row.get(0)),
+                stats: serde_json::from_str(row.get(1)).unwrap_or(Value::Null),
+            })
+            .collect();
+
+        Ok(Some(ScanStats {
+            scan_id,
+            started: millis(scan.get(0)),
+            finished: scan.get::<_, Option<SystemTime>>(1).map(millis),
+            snapshots,
+        }))
+    }
+
+    // Matches of a scan by country and by AS, from its last stats snapshot (empty without the
+    // enrichment databases), none if the scan doesn't exist
+    pub async fn get_scan_geo_matches(
+        &self,
+        project_id: i64,
+        scan_id: i64,
+    ) -> Result<Option<GeoAggregate>, Error> {
+        if self
+            .client
+            .query_opt(
+                "SELECT id FROM scan WHERE id = $1 AND project_id = $2",
+                &[&scan_id, &project_id],
+            )
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+
+        let geo = self
+            .client
+            .query_opt(
+                "
+                SELECT (stats->'geo_matches')::TEXT FROM scan_stats
+                WHERE scan_id = $1 AND stats ? 'geo_matches'
+                ORDER BY time DESC
+                LIMIT 1
+            ",
+                &[&scan_id],
+            )
+            .await?
+            .and_then(|row| serde_json::from_str(row.get(0)).ok())
+            .unwrap_or_default();
+        Ok(Some(geo))
+    }
+
+    // Last raw response of a service of the project, none if it wasn't saved
+    pub async fn get_service_response(
+        &self,
+        project_id: i64,
+        service_id: i64,
+    ) -> Result<Option<StoredResponse>, Error> {
+        let row = match self
+            .client
+            .query_opt(
+                "
+                SELECT service.protocol, service.response_time, service.response,
+                    service.truncated, service.headers->>'content-type'
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE service.id = $1 AND ip_ports.project_id = $2
+                    AND service.response IS NOT NULL
+            ",
+                &[&service_id, &project_id],
+            )
+            .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let protocol: String = row.get(0);
+        let response = String::from_utf8_lossy(row.get::<_, &[u8]>(2)).to_string();
+        let (head, body) = split_response(&protocol, &response);
+        Ok(Some(StoredResponse {
+            service_id,
+            protocol,
+            time: row
+                .get::<_, Option<SystemTime>>(1)
+                .map(millis)
+                .unwrap_or_default(),
+            head,
+            body,
+            content_type: row.get(4),
+            truncated: row.get::<_, Option<bool>>(3).unwrap_or(false),
+        }))
+    }
+
+    // Last request sent to a service of the project, none if it wasn't saved
+    pub async fn get_service_request(
+        &self,
+        project_id: i64,
+        service_id: i64,
+    ) -> Result<Option<SentRequest>, Error> {
+        Ok(self
+            .client
+            .query_opt(
+                "
+                SELECT service.protocol, service.request
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE service.id = $1 AND ip_ports.project_id = $2
+                    AND service.request IS NOT NULL
+            ",
+                &[&service_id, &project_id],
+            )
+            .await?
+            .map(|row| SentRequest {
+                service_id,
+                protocol: row.get(0),
+                request: row.get(1),
+            }))
+    }
+
+    pub async fn insert_har_entry(&self, scan_id: i64, entry: &Value) -> Result<(), Error> {
+        self.client
+            .execute(
+                "INSERT INTO har_entry (scan_id, entry) VALUES ($1, $2::TEXT::JSONB)",
+                &[&scan_id, &entry.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // HAR entries of a scan of the project, in order, none if the scan doesn't exist
+    pub async fn get_har_entries(
+        &self,
+        project_id: i64,
+        scan_id: i64,
+    ) -> Result<Option<Vec<Value>>, Error> {
+        if self
+            .client
+            .query_opt(
+                "SELECT id FROM scan WHERE id = $1 AND project_id = $2",
+                &[&scan_id, &project_id],
+            )
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            self.client
+                .query(
+                    "SELECT entry::TEXT FROM har_entry WHERE scan_id = $1 ORDER BY id",
+                    &[&scan_id],
+                )
+                .await?
+                .iter()
+                .map(|row| serde_json::from_str(row.get(0)).unwrap_or(Value::Null))
+                .collect(),
+        ))
+    }
+
+    pub async fn count_rows(&self) -> Result<Vec<(&'static str, i64)>, Error> {
+        let mut counts = Vec::new();
+        for table in &[
+            "project",
+            "domain",
+            "ip_ports",
+            "ip_domain",
+            "service",
+            "finding_attribute",
+            "scan",
+            "scan_stats",
+            "har_entry",
+        ] {
+            let count: i64 = self
+                .client
+                .query_one(format!("SELECT count(*) FROM {}", table).as_str(), &[])
+                .await?
+                .get(0);
+            counts.push((*table, count));
+        }
+        Ok(counts)
+    }
+
+    // Id of a project, created if missing (scans)
+    pub async fn get_or_insert_project(&self, name: &str) -> Result<i64, Error> {
+        Ok(self
+            .client
+            .query_one(
+                "
+                INSERT INTO project (name)
+                VALUES ($1)
+                ON CONFLICT (name) DO UPDATE
+                -- Workaround: do nothing but return the id
+                SET name = excluded.name
+                RETURNING id
+            ",
+                &[&name],
+            )
+            .await?
+            .get(0))
+    }
+
+    pub async fn get_project_id(&self, name: &str) -> Result<Option<i64>, Error> {
+        Ok(self
+            .client
+            .query_opt("SELECT id FROM project WHERE name = $1", &[&name])
+            .await?
+            .map(|row| row.get(0)))
+    }
+
+    pub async fn get_projects(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .client
+            .query("SELECT name FROM project ORDER BY name", &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    pub async fn update_or_insert_unknown_response(
+        &self,
+        project_id: i64,
+        target: &ReqTarget,
+        content: &ContentHash,
+    ) -> Result<(), Error> {
+        let banner: String = target.response.chars().take(UNKNOWN_BANNER_CHARS).collect();
+        self.client
+            .execute(
+                "
+                INSERT INTO unknown_response
+                    (project_id, ip, port, protocol, body_hash, simhash, banner)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (project_id, ip, port, protocol) DO UPDATE
+                SET body_hash = excluded.body_hash, simhash = excluded.simhash,
+                    banner = excluded.banner, last_seen = current_timestamp,
+                    seen_count = unknown_response.seen_count + 1
+            ",
+                &[
+                    &project_id,
+                    &target.ip,
+                    &(target.port as i32),
+                    &target.protocol,
+                    &content.body_hash,
+                    &content.simhash,
+                    &banner,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Clusters of the most recent unknown responses of the project, the biggest first
+    pub async fn get_unknown_clusters(
+        &self,
+        project_id: i64,
+        max_distance: u32,
+        limit: usize,
+    ) -> Result<Vec<UnknownCluster>, Error> {
+        let rows = self
+            .client
+            .query(
+                "
+                SELECT ip, port, protocol, simhash, banner FROM unknown_response
+                WHERE project_id = $1
+                ORDER BY last_seen DESC
+                LIMIT $2
+            ",
+                &[&project_id, &UNKNOWN_CLUSTERED_ROWS],
+            )
+            .await?;
+        let simhashes: Vec<i64> = rows.iter().map(|row| row.get(3)).collect();
+
+        let clusters = content::cluster(&simhashes, max_distance)
+            .into_iter()
+            .take(limit)
+            .map(|cluster| {
+                let mut hosts: Vec<&str> = cluster.iter().map(|i| rows[*i].get(0)).collect();
+                hosts.sort_unstable();
+                hosts.dedup();
+                let mut ports: Vec<u16> = cluster
+                    .iter()
+                    .map(|i| rows[*i].get::<_, i32>(1) as u16)
+                    .collect();
+                ports.sort_unstable();
+                ports.dedup();
+                let mut protocols: Vec<String> = cluster.iter().map(|i| rows[*i].get(2)).collect();
+                protocols.sort_unstable();
+                protocols.dedup();
+                UnknownCluster {
+                    responses: cluster.len(),
+                    hosts: hosts.len(),
+                    ports,
+                    protocols,
+                    samples: cluster
+                        .iter()
+                        .take(UNKNOWN_SAMPLES)
+                        .map(|i| UnknownSample {
+                            ip: rows[*i].get(0),
+                            port: rows[*i].get::<_, i32>(1) as u16,
+                            protocol: rows[*i].get(2),
+                            banner: rows[*i].get::<_, Option<String>>(4).unwrap_or_default(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(clusters)
+    }
+
+    // Active findings of the project by score (highest first), optionally in a state
+    pub async fn get_triage_queue(
+        &self,
+        project_id: i64,
+        state: Option<&str>,
+        offset: i64,
+        rows: i64,
+    ) -> Result<PaginatedTriage, Error> {
+        let findings = self
+            .client
+            .query(
+                "
+                SELECT service.id, ip_ports.ip, service.domain, service.port, service.service,
+                    service.version, service.description, service.severity, service.confidence,
+                    service.score, COALESCE(finding_triage.state, 'new'), service.last_seen
+                FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                LEFT JOIN finding_triage ON finding_triage.service_id = service.id
+                WHERE ip_ports.project_id = $1 AND service.active IS NOT FALSE
+                    AND ($2::VARCHAR IS NULL OR COALESCE(finding_triage.state, 'new') = $2)
+                ORDER BY service.score DESC, service.id DESC
+                LIMIT $3
+                OFFSET $4
+            ",
+                &[&project_id, &state, &rows, &offset],
+            )
+            .await?
+            .iter()
+            .map(|row| TriageFinding {
+                id: row.get(0),
+                ip: row.get(1),
+                domain: row.get(2),
+                port: row.get(3),
+                service: row.get(4),
+                version: row.get(5),
+                description: row.get(6),
+                severity: row.get(7),
+                confidence: row.get::<_, Option<f32>>(8).unwrap_or(1.0),
+                score: row.get::<_, Option<f32>>(9).unwrap_or(0.0),
+                state: row.get(10),
+                last_seen: millis(row.get(11)),
+            })
+            .collect();
+
+        let rows_count = self
+            .client
+            .query_one(
+                "
+                SELECT COUNT(*) FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                LEFT JOIN finding_triage ON finding_triage.service_id = service.id
+                WHERE ip_ports.project_id = $1 AND service.active IS NOT FALSE
+                    AND ($2::VARCHAR IS NULL OR COALESCE(finding_triage.state, 'new') = $2)
+            ",
+                &[&project_id, &state],
+            )
+            .await?
+            .get(0);
+
+        Ok(PaginatedTriage {
+            findings,
+            rows_count,
+        })
+    }
+
+    // Returns whether the finding exists in the project
+    pub async fn update_triage_state(
+        &self,
+        project_id: i64,
+        service_id: i64,
+        state: &str,
+    ) -> Result<bool, Error> {
+        let updated = self
+            .client
+            .execute(
+                "
+                INSERT INTO finding_triage (service_id, state)
+                SELECT service.id, $3 FROM service
+                JOIN ip_ports ON service.ip_id = ip_ports.id
+                WHERE service.id = $1 AND ip_ports.project_id = $2
+                ON CONFLICT (service_id) DO UPDATE
+                SET state = excluded.state, updated = current_timestamp
+            ",
+                &[&service_id, &project_id, &state],
+            )
+            .await?
+            > 0;
+        if !updated {
+            return Ok(false);
+        }
+
+        if state == "false_positive" {
+            let row = self
+                .client
+                .query_one(
+                    "SELECT service, response FROM service WHERE id = $1",
+                    &[&service_id],
+                )
+                .await?;
+            let definition: String = row.get(0);
+            let snippet = row
+                .get::<_, Option<Vec<u8>>>(1)
+                .map(|response| snippet(&response));
+            self.client
+                .execute(
+                    "
+                    INSERT INTO false_positive (service_id, definition, snippet)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (service_id) DO UPDATE
+                    SET definition = excluded.definition, snippet = excluded.snippet,
+                        created = current_timestamp
+                ",
+                    &[&service_id, &definition, &snippet],
+                )
+                .await?;
+        } else {
+            self.client
+                .execute(
+                    "DELETE FROM false_positive WHERE service_id = $1",
+                    &[&service_id],
+                )
+                .await?;
+        }
+        Ok(true)
+    }
+
+    // Snippets of the false positives and responses of the confirmed findings (accepted or
+    // resolved) by definition, of all the projects
+    pub async fn get_triage_feedback(&self) -> Result<BTreeMap<String, TriageFeedback>, Error> {
+        let mut feedback: BTreeMap<String, TriageFeedback> = BTreeMap::new();
+        for row in self
+            .client
+            .query("SELECT definition, snippet FROM false_positive", &[])
+            .await?
+        {
+            feedback
+                .entry(row.get(0))
+                .or_default()
+                .false_positives
+                .push(row.get(1));
+        }
+
+        for row in self
+            .client
+            .query(
+                "
+                SELECT service.service, service.response FROM service
+                JOIN finding_triage ON finding_triage.service_id = service.id
+                WHERE finding_triage.state IN ('accepted', 'resolved')
+                    AND service.response IS NOT NULL
+            ",
+                &[],
+            )
+            .await?
+        {
+            if let Some(definition) = feedback.get_mut(&row.get::<_, String>(0)) {
+                definition
+                    .confirmed
+                    .push(snippet(&row.get::<_, Vec<u8>>(1)));
+            }
+        }
+        Ok(feedback)
+    }
+
+    pub async fn get_saved_searches(&self, project_id: i64) -> Result<Vec<SavedSearch>, Error> {
+        Ok(self
+            .client
+            .query(
+                "
+                SELECT id, created, name, query FROM saved_search
+                WHERE project_id = $1
+                ORDER BY name
+            ",
+                &[&project_id],
+            )
+            .await?
+            .iter()
+            .map(|row| SavedSearch {
+                id: row.get(0),
+                created: millis(row.get(1)),
+                name: row.get(2),
+                query: row.get(3),
+            })
+            .collect())
+    }
+
+    // Saves a search, replacing the query of the one with the same name
+    pub async fn update_or_insert_saved_search(
+        &self,
+        project_id: i64,
+        name: &str,
+        query: &str,
+    ) -> Result<SavedSearch, Error> {
+        let row = self
+            .client
+            .query_one(
+                "
+                INSERT INTO saved_search (project_id, name, query)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (project_id, name) DO UPDATE
+                SET query = excluded.query
+                RETURNING id, created, name, query
+            ",
+                &[&project_id, &name, &query],
+            )
+            .await?;
+        Ok(SavedSearch {
+            id: row.get(0),
+            created: millis(row.get(1)),
+            name: row.get(2),
+            query: row.get(3),
+        })
+    }
+
+    // Returns whether the search existed in the project
+    pub async fn delete_saved_search(&self, project_id: i64, id: i64) -> Result<bool, Error> {
+        Ok(self
+            .client
+            .execute(
+                "DELETE FROM saved_search WHERE id = $1 AND project_id = $2",
+                &[&id, &project_id],
+            )
+            .await?
+            > 0)
+    }
+
+    pub async fn update_or_insert_netblock(&self, netblock: &Netblock) -> Result<u64, Error> {
+        self.client
+            .execute(
+                "
+                INSERT INTO netblock (network, range, name, handle, owner, country)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (network) DO UPDATE
+                SET range = excluded.range, name = excluded.name, handle = excluded.handle,
+                    owner = excluded.owner, country = excluded.country,
+                    last_lookup = current_timestamp
+            ",
+                &[
+                    &netblock.network,
+                    &netblock.range,
+                    &netblock.name,
+                    &netblock.handle,
+                    &netblock.owner,
+                    &netblock.country,
+                ],
+            )
+            .await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.client.is_closed()
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.client.simple_query("SELECT 1").await?;
+        Ok(())
+    }
+
+    pub async fn delete_services(&self, project_id: i64, ids: Vec<i64>) -> Result<(), Error> {
         for n in &ids {
             self.client
-                .query("DELETE FROM service WHERE id = $1", &[n])
+                .query(
+                    "
+                    DELETE FROM service
+                    WHERE id = $1
+                        AND ip_id IN (SELECT id FROM ip_ports WHERE project_id = $2)
+                ",
+                    &[n, &project_id],
+                )
                 .await?;
         }
+        self.rows_counts.lock().unwrap().clear();
         Ok(())
     }
 }
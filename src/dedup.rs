@@ -0,0 +1,134 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::IpAddr,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::worker::{self, DatasetRecord};
+
+// Duplicate ips of the dataset (--dedup-dataset): only the first name is kept, or the other ones
+// are tried as virtual hosts of the ip
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DedupMode {
+    First,
+    Vhosts,
+}
+
+impl DedupMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "first" => Some(DedupMode::First),
+            "vhosts" => Some(DedupMode::Vhosts),
+            _ => None,
+        }
+    }
+}
+
+// What the dedup pass read and skipped, reported when the scan starts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupReport {
+    // Sha256 of the dataset file (hex), to tell whether two scans read the same dataset
+    pub sha256: String,
+    // A records of the dataset, and the ones of an ip already seen
+    pub records: u64,
+    pub duplicates: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct DedupedDataset {
+    // (name, ip) of the first A record of every ip, in the order of the dataset
+    pub hosts: Vec<(String, String)>,
+    // Other names of the ips (vhosts mode only)
+    pub vhosts: HashMap<String, Vec<String>>,
+    // Tags of the ips, merged from all their records
+    pub tags: HashMap<String, Vec<String>>,
+    pub report: DedupReport,
+}
+
+// The ips are compared parsed (e.g. the IPv6 ones written differently), the other values as
+// they are
+fn ip_key(value: &str) -> String {
+    match value.trim().parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => value.trim().to_string(),
+    }
+}
+
+// Reads the whole dataset once, keeping a single target per ip. The malformed records and the
+// records other than A are skipped, like by the scans
+pub fn dedup(path: &str, mode: DedupMode) -> Result<DedupedDataset, String> {
+    let file = File::open(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    let mut deduped = DedupedDataset::default();
+    let mut hasher = Sha256::new();
+    let mut names: HashMap<String, usize> = HashMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+        let record = match worker::parse_dataset_record(&line) {
+            Ok(record) if record.record_type == "a" => record,
+            _ => continue,
+        };
+        deduped.report.records += 1;
+        let tags = worker::clean_tags(record.tags);
+
+        match names.entry(ip_key(&record.value)) {
+            Entry::Vacant(entry) => {
+                entry.insert(deduped.hosts.len());
+                if !tags.is_empty() {
+                    deduped.tags.insert(record.value.clone(), tags);
+                }
+                deduped.hosts.push((record.name, record.value));
+            }
+            Entry::Occupied(entry) => {
+                deduped.report.duplicates += 1;
+                let (name, ip) = &deduped.hosts[*entry.get()];
+                if !tags.is_empty() {
+                    let merged = deduped.tags.entry(ip.clone()).or_default();
+                    merged.extend(tags);
+                    *merged = worker::clean_tags(std::mem::take(merged));
+                }
+                if mode == DedupMode::Vhosts && *name != record.name {
+                    let vhosts = deduped.vhosts.entry(ip.clone()).or_default();
+                    if !vhosts.contains(&record.name) {
+                        vhosts.push(record.name);
+                    }
+                }
+            }
+        }
+    }
+    deduped.report.sha256 = hex::encode(hasher.finalize());
+
+    Ok(deduped)
+}
+
+// Writes the deduplicated dataset (same format), the kept names of every ip together. Returns
+// the number of written records
+pub fn write(path: &str, deduped: &DedupedDataset) -> Result<u64, String> {
+    let file = File::create(path).map_err(|e| format!("Unable to create {}: {}", path, e))?;
+    let mut output = BufWriter::new(file);
+    let mut count = 0;
+
+    for (name, ip) in &deduped.hosts {
+        let vhosts = deduped.vhosts.get(ip).into_iter().flatten();
+        let tags = deduped.tags.get(ip).cloned().unwrap_or_default();
+        for name in std::iter::once(name).chain(vhosts) {
+            let record = DatasetRecord {
+                name: name.clone(),
+                record_type: "a".to_string(),
+                value: ip.clone(),
+                tags: tags.clone(),
+            };
+            let line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+            writeln!(output, "{}", line).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+            count += 1;
+        }
+    }
+    output.flush().map_err(|e| e.to_string())?;
+
+    Ok(count)
+}
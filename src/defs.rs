@@ -0,0 +1,436 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use ed25519_dalek::PublicKey;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    conf::{self, Definition},
+    net,
+    plugins::Registry,
+    signing,
+};
+
+const INDEX_TIMEOUT: u64 = 30;
+// Index of the Git repositories, at their root
+const GIT_INDEX: &str = "index.json";
+// Public key of the definitions signatures compiled in (Ed25519, hex), trusted along with the
+// configured ones
+const PUBLIC_KEY: Option<&str> = option_env!("LACHESIS_DEFS_PUBLIC_KEY");
+// The signature of a definitions file is next to it (e.g. vnc.json.sig)
+pub const SIGNATURE_EXT: &str = ".sig";
+
+// Index of a definitions bundle, e.g.
+// {"definitions": [{"file": "jenkins.json", "version": "1.2.0", "sha256": "<hex>"}]}
+// The files are next to the index (same base url)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Index {
+    pub definitions: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexEntry {
+    pub file: String,
+    pub version: String,
+    pub sha256: String,
+}
+
+// Files installed from an index, saved next to the definitions directory (not in it, every
+// file there is loaded as definitions)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Installed {
+    index: String,
+    definitions: Vec<IndexEntry>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UpdateStatus {
+    New,
+    Updated,
+    Unchanged,
+}
+
+// A definitions file of the definitions directory
+#[derive(Debug)]
+pub struct InstalledFile {
+    pub file: String,
+    // None when not installed from an index (e.g. a local file)
+    pub version: Option<String>,
+    pub definitions: usize,
+    // Has a signature (not verified)
+    pub signed: bool,
+}
+
+fn installed_path(definitions_dir: &str) -> String {
+    format!("{}-index.json", definitions_dir.trim_end_matches('/'))
+}
+
+fn load_installed(definitions_dir: &str) -> Installed {
+    fs::read(installed_path(definitions_dir))
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+// Plain file names only, written in the definitions directory
+pub fn is_valid_file_name(file: &str) -> bool {
+    file.ends_with(".json")
+        && !file.starts_with('.')
+        && file
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Url of a file of the index, relative to it
+pub fn file_url(index_url: &str, file: &str) -> String {
+    let base = index_url.split(&['?', '#'][..]).next().unwrap_or_default();
+    match base.rfind('/') {
+        Some(idx) => format!("{}/{}", &base[..idx], file),
+        None => file.to_string(),
+    }
+}
+
+// Url of the signature of the index, next to it
+pub fn signature_url(index_url: &str) -> String {
+    let base = index_url.split(&['?', '#'][..]).next().unwrap_or_default();
+    format!("{}{}", base, SIGNATURE_EXT)
+}
+
+// The file must match the checksum of the index and contain valid definitions (validated as
+// when loaded, scripts included, with the built-in probes)
+pub fn verify_file(entry: &IndexEntry, content: &[u8]) -> Result<Vec<Definition>, String> {
+    let sha256 = hex::encode(Sha256::digest(content));
+    if !sha256.eq_ignore_ascii_case(&entry.sha256) {
+        return Err(format!("{}: checksum mismatch", entry.file));
+    }
+    conf::parse_definitions(&entry.file, content, &Registry::new())
+}
+
+// Git repositories urls (the other sources are https indexes)
+pub fn is_git_source(source: &str) -> bool {
+    ["git@", "git://", "ssh://", "file://"]
+        .iter()
+        .any(|prefix| source.starts_with(prefix))
+        || source.ends_with(".git")
+}
+
+// Source of the definitions: an https index with the files next to it, or a Git repository (a
+// shallow clone, removed when dropped) with the index at its root
+enum Bundle {
+    Index(String),
+    Git(PathBuf),
+}
+
+impl Bundle {
+    async fn open(source: &str) -> Result<Bundle, String> {
+        if !is_git_source(source) {
+            return Ok(Bundle::Index(source.to_string()));
+        }
+
+        let dir = std::env::temp_dir().join(format!("lachesis-defs-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (repo, clone_dir) = (source.to_string(), dir.clone());
+        let output = tokio::task::spawn_blocking(move || {
+            Command::new("git")
+                .args(["clone", "--quiet", "--depth", "1", "--", &repo])
+                .arg(&clone_dir)
+                .output()
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+        if !output.status.success() {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(format!(
+                "Unable to clone {}: {}",
+                source,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(Bundle::Git(dir))
+    }
+
+    // The index and its signature
+    async fn index(&self) -> Result<(Vec<u8>, String), String> {
+        let (index, signature) = match self {
+            Bundle::Index(url) => (
+                net::fetch(url, INDEX_TIMEOUT).await,
+                net::fetch(&signature_url(url), INDEX_TIMEOUT).await,
+            ),
+            Bundle::Git(dir) => (
+                fs::read(dir.join(GIT_INDEX)).map_err(|e| e.to_string()),
+                fs::read(dir.join(format!("{}{}", GIT_INDEX, SIGNATURE_EXT)))
+                    .map_err(|e| e.to_string()),
+            ),
+        };
+        let index = index.map_err(|e| format!("Unable to fetch the definitions index: {}", e))?;
+        let signature =
+            signature.map_err(|e| format!("Unable to fetch the index signature: {}", e))?;
+        Ok((index, String::from_utf8_lossy(&signature).to_string()))
+    }
+
+    async fn file(&self, file: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Bundle::Index(url) => net::fetch(&file_url(url, file), INDEX_TIMEOUT).await,
+            Bundle::Git(dir) => fs::read(dir.join(file)).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Unable to fetch {}: {}", file, e))
+    }
+}
+
+impl Drop for Bundle {
+    fn drop(&mut self) {
+        if let Bundle::Git(dir) = self {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+// The public key compiled in and the configured ones, at least one
+pub fn trusted_keys(configured: &[String]) -> Result<Vec<PublicKey>, String> {
+    let keys = PUBLIC_KEY
+        .iter()
+        .copied()
+        .chain(configured.iter().map(String::as_str))
+        .map(signing::parse_public_key)
+        .collect::<Result<Vec<PublicKey>, String>>()?;
+    if keys.is_empty() {
+        return Err(
+            "No definitions public key (--defs-public-key or defs_public_keys in the config file)"
+                .to_string(),
+        );
+    }
+    Ok(keys)
+}
+
+// Writes the signature of the file content, returns its path
+pub fn sign(path: &str, secret_key: &str) -> Result<String, String> {
+    let content = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+    let sig_path = format!("{}{}", path, SIGNATURE_EXT);
+    fs::write(&sig_path, signing::sign(secret_key, &content)?)
+        .map_err(|e| format!("Unable to write {}: {}", sig_path, e))?;
+    Ok(sig_path)
+}
+
+// Refuses the unsigned and the changed files (--require-signed-defs). The content verified is
+// the one then parsed, the file is not read again
+pub fn verify_signature(path: &str, content: &[u8], keys: &[PublicKey]) -> Result<(), String> {
+    let signature = fs::read_to_string(format!("{}{}", path, SIGNATURE_EXT))
+        .map_err(|_| format!("Definitions file {} is not signed", path))?;
+    if !signing::verify(keys, content, &signature) {
+        return Err(format!(
+            "Invalid signature of the definitions file {}",
+            path
+        ));
+    }
+    Ok(())
+}
+
+// Headers changing at every response or shared by most of the servers, never suggested
+const GENERIC_HEADERS: [&str; 16] = [
+    "date:",
+    "expires:",
+    "last-modified:",
+    "etag:",
+    "set-cookie:",
+    "content-length:",
+    "age:",
+    "connection:",
+    "content-type:",
+    "content-encoding:",
+    "transfer-encoding:",
+    "cache-control:",
+    "pragma:",
+    "vary:",
+    "accept-ranges:",
+    "keep-alive:",
+];
+// Lines of a suggested exclude_regex
+const MAX_EXCLUSION_LINES: usize = 3;
+
+// exclude_regex proposed for a definition, and the number of false positives it matches
+#[derive(Debug, PartialEq)]
+pub struct Exclusion {
+    pub exclude_regex: String,
+    pub matches: usize,
+}
+
+fn candidate_lines(response: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = response
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| {
+            let lowercase = line.to_lowercase();
+            line.len() >= 10
+                && line.len() <= 200
+                && line.chars().any(|c| c.is_alphabetic())
+                && !lowercase.starts_with("http/")
+                && !GENERIC_HEADERS.iter().any(|h| lowercase.starts_with(h))
+        })
+        .collect();
+    lines.sort_unstable();
+    lines.dedup();
+    lines
+}
+
+// Lines of the false positives responses (e.g. a Server header, a page title) found in at least
+// half of them and in none of the confirmed findings, the most common and longest first, until
+// all the false positives are matched
+pub fn suggest_exclusion(false_positives: &[String], confirmed: &[String]) -> Option<Exclusion> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for response in false_positives {
+        for line in candidate_lines(response) {
+            *counts.entry(line).or_default() += 1;
+        }
+    }
+    let mut candidates: Vec<(&str, usize)> = counts
+        .into_iter()
+        .filter(|(line, count)| {
+            count * 2 >= false_positives.len()
+                && !confirmed.iter().any(|response| response.contains(line))
+        })
+        .collect();
+    candidates.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then(b.0.len().cmp(&a.0.len()))
+            .then(a.0.cmp(b.0))
+    });
+
+    let mut lines: Vec<&str> = Vec::new();
+    let mut matched = vec![false; false_positives.len()];
+    for (line, _) in candidates {
+        if lines.len() == MAX_EXCLUSION_LINES || matched.iter().all(|m| *m) {
+            break;
+        }
+        let mut new_matches = false;
+        for (i, response) in false_positives.iter().enumerate() {
+            if !matched[i] && response.contains(line) {
+                matched[i] = true;
+                new_matches = true;
+            }
+        }
+        if new_matches {
+            lines.push(line);
+        }
+    }
+
+    if lines.is_empty() {
+        return None;
+    }
+    Some(Exclusion {
+        exclude_regex: lines
+            .iter()
+            .map(|line| regex::escape(line))
+            .collect::<Vec<String>>()
+            .join("|"),
+        matches: matched.iter().filter(|m| **m).count(),
+    })
+}
+
+// Installs the files of the index in the definitions directory, once all of them are downloaded
+// and verified. The index is trusted (its checksums) only when signed with one of the keys, and
+// every file must be signed too (<file>.sig next to it), its signature installed along with it
+pub async fn update(
+    source: &str,
+    trusted_keys: &[PublicKey],
+    definitions_dir: &str,
+) -> Result<Vec<(IndexEntry, UpdateStatus)>, String> {
+    let bundle = Bundle::open(source).await?;
+    let (index, signature) = bundle.index().await?;
+    if !signing::verify(trusted_keys, &index, &signature) {
+        return Err("Invalid signature of the definitions index".to_string());
+    }
+    let index: Index =
+        serde_json::from_slice(&index).map_err(|e| format!("Invalid definitions index: {}", e))?;
+
+    let mut files = Vec::new();
+    let mut definitions = Vec::new();
+    for entry in index.definitions {
+        if !is_valid_file_name(&entry.file) {
+            return Err(format!("Invalid file name in the index: {}", entry.file));
+        }
+        let content = bundle.file(&entry.file).await?;
+        definitions.extend(verify_file(&entry, &content)?);
+        // Installed with its signature, for --require-signed-defs
+        let signature = bundle
+            .file(&format!("{}{}", entry.file, SIGNATURE_EXT))
+            .await?;
+        if !signing::verify(trusted_keys, &content, &String::from_utf8_lossy(&signature)) {
+            return Err(format!("Invalid signature of {}", entry.file));
+        }
+        files.push((entry, content, signature));
+    }
+    conf::validate_follow_ups(&definitions)?;
+
+    let installed = load_installed(definitions_dir);
+    fs::create_dir_all(definitions_dir).map_err(|e| e.to_string())?;
+
+    let mut updated = Vec::new();
+    for (entry, content, signature) in files {
+        fs::write(Path::new(definitions_dir).join(&entry.file), content)
+            .map_err(|e| format!("Unable to write {}: {}", entry.file, e))?;
+        let sig_file = format!("{}{}", entry.file, SIGNATURE_EXT);
+        fs::write(Path::new(definitions_dir).join(&sig_file), signature)
+            .map_err(|e| format!("Unable to write {}: {}", sig_file, e))?;
+        let status = match installed.definitions.iter().find(|i| i.file == entry.file) {
+            Some(previous) if previous.sha256.eq_ignore_ascii_case(&entry.sha256) => {
+                UpdateStatus::Unchanged
+            }
+            Some(_) => UpdateStatus::Updated,
+            None => UpdateStatus::New,
+        };
+        updated.push((entry, status));
+    }
+
+    let installed = Installed {
+        index: source.to_string(),
+        definitions: updated.iter().map(|(entry, _)| entry.clone()).collect(),
+    };
+    fs::write(
+        installed_path(definitions_dir),
+        serde_json::to_string_pretty(&installed).unwrap(),
+    )
+    .map_err(|e| format!("Unable to save the installed definitions: {}", e))?;
+
+    Ok(updated)
+}
+
+// The files of the definitions directory, with the versions of the ones installed from an index
+pub fn list(definitions_dir: &str) -> Result<Vec<InstalledFile>, String> {
+    let versions: HashMap<String, String> = load_installed(definitions_dir)
+        .definitions
+        .into_iter()
+        .map(|entry| (entry.file, entry.version))
+        .collect();
+
+    let paths = fs::read_dir(definitions_dir)
+        .map_err(|_| "The definitions directory doesn't exist or is not readable".to_string())?;
+    let mut files = Vec::new();
+    for path in paths.flatten() {
+        let file = path.file_name().to_string_lossy().to_string();
+        if file.ends_with(SIGNATURE_EXT) {
+            continue;
+        }
+        // Not parsed as definitions, only counted
+        let definitions = fs::read(path.path())
+            .ok()
+            .and_then(|content| serde_json::from_slice::<Vec<serde_json::Value>>(&content).ok())
+            .map(|definitions| definitions.len())
+            .unwrap_or(0);
+        files.push(InstalledFile {
+            version: versions.get(&file).cloned(),
+            signed: Path::new(&format!("{}{}", path.path().display(), SIGNATURE_EXT)).exists(),
+            file,
+            definitions,
+        });
+    }
+    files.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(files)
+}
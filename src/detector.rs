@@ -1,8 +1,23 @@
+use std::borrow::Cow;
+
 use colored::Colorize;
 use regex::Regex;
-use semver::Version;
+use semver::{BuildMetadata, Comparator, Op, Prerelease, Version, VersionReq};
+use serde_json::Value;
+
+use crate::{
+    conf::{Definition, Extractor, Indicator, JsonCondition, RangeVersion},
+    content::ContentHash,
+    net,
+    plugins::raw_response,
+    script,
+    stats::format_host,
+    triage,
+    worker::ReqTarget,
+};
 
-use crate::{conf::Definition, stats::format_host, worker::ReqTarget};
+// Minimum confidence of the definitions declaring weighted indicators, if not specified
+const DEFAULT_MIN_CONFIDENCE: f32 = 0.5;
 
 #[derive(Clone, Debug)]
 pub struct DetectorResponse {
@@ -10,7 +25,15 @@ pub struct DetectorResponse {
     pub service: String,
     pub version: String,
     pub description: String,
+    pub confidence: f32,
+    // Severity of the definition
+    pub severity: String,
+    pub attributes: Vec<(String, String)>,
     pub error: Option<String>,
+    // Hashes of the response content, to group the identical pages
+    pub content: Option<ContentHash>,
+    // Request sent, with the secrets masked
+    pub request: Option<String>,
 }
 
 impl DetectorResponse {
@@ -20,41 +43,676 @@ impl DetectorResponse {
             service: String::new(),
             version: String::new(),
             description: String::new(),
+            confidence: 1.0,
+            severity: triage::DEFAULT_SEVERITY.to_string(),
+            attributes: Vec::new(),
             error: None,
+            content: None,
+            request: None,
         }
     }
 
-    fn new(target: ReqTarget) -> Self {
+    pub fn new(target: ReqTarget) -> Self {
         DetectorResponse {
+            request: target.sent_request.as_deref().map(net::mask_secrets),
             target,
             ..DetectorResponse::default()
         }
     }
 }
 
+// Select the part of the response a regex is matched against. Default: the whole raw response
+fn match_source<'a>(target: &'a ReqTarget, source: &Option<String>) -> Cow<'a, str> {
+    let source = match source {
+        Some(source) => source.as_str(),
+        None => return Cow::Borrowed(&target.response),
+    };
+
+    match source {
+        "body" => Cow::Borrowed(&target.body),
+        "headers" => Cow::Owned(
+            target
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect::<Vec<String>>()
+                .join("\r\n"),
+        ),
+        _ => match source.strip_prefix("header:") {
+            // Only the values of the headers with the specified name (case insensitive)
+            Some(header) => Cow::Owned(
+                target
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| name.eq_ignore_ascii_case(header))
+                    .map(|(_, value)| value.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\r\n"),
+            ),
+            None => Cow::Borrowed(&target.response),
+        },
+    }
+}
+
+// Lenient version parsing: optional "v" prefix, incomplete versions (e.g. 4.6 -> 4.6.0),
+// pre-release and build metadata (e.g. 2.0-beta -> 2.0.0-beta, 1.2.3-rc1+b5). The components after
+// the third one (e.g. the 4 of 1.2.3.4) are returned apart, as the numeric tail of the version
+pub fn parse_version(version: &str) -> Option<(Version, Vec<u64>)> {
+    let version = version.trim();
+    let version = version
+        .strip_prefix('v')
+        .or_else(|| version.strip_prefix('V'))
+        .unwrap_or(version);
+
+    let (version, build) = match version.find('+') {
+        Some(idx) => (&version[..idx], Some(&version[idx + 1..])),
+        None => (version, None),
+    };
+    let (core, pre) = match version.find('-') {
+        Some(idx) => (&version[..idx], Some(&version[idx + 1..])),
+        None => (version, None),
+    };
+
+    let components: Vec<&str> = core.split('.').collect();
+    if components
+        .iter()
+        .any(|c| c.is_empty() || !c.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    let mut normalized = String::new();
+    for i in 0..3 {
+        let n = match components.get(i) {
+            Some(c) => c.parse::<u64>().ok()?,
+            None => 0,
+        };
+        if i > 0 {
+            normalized.push('.');
+        }
+        normalized += &n.to_string();
+    }
+
+    if let Some(pre) = pre {
+        normalized = format!("{}-{}", normalized, pre);
+    }
+    if let Some(build) = build {
+        normalized = format!("{}+{}", normalized, build);
+    }
+
+    let mut tail = Vec::new();
+    for c in components.iter().skip(3) {
+        tail.push(c.parse::<u64>().ok()?);
+    }
+
+    Version::parse(&normalized)
+        .ok()
+        .map(|version| (version, tail))
+}
+
+// A version is in range if it matches the requirement (e.g. ">=4.3, <4.7", "4.x"), or if it's
+// between from and to (both inclusive, each one optional). The versions are compared by precedence,
+// their numeric tail included (1.2.3.4 > 1.2.3)
+pub fn version_in_range((version, tail): &(Version, Vec<u64>), range: &RangeVersion) -> bool {
+    let version = precedence(version, tail);
+
+    if let Some(req) = &range.range {
+        let req = match VersionReq::parse(req) {
+            Ok(req) => req,
+            Err(_) => return false,
+        };
+        return req
+            .comparators
+            .iter()
+            .all(|cmp| comparator_matches(cmp, &version));
+    }
+
+    if let Some(from) = &range.from {
+        match parse_version(from) {
+            Some((from, from_tail)) if version >= precedence(&from, &from_tail) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(to) = &range.to {
+        match parse_version(to) {
+            Some((to, to_tail)) if version <= precedence(&to, &to_tail) => (),
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+// Numeric components of a version (the trailing zeros ignored: 1.2 = 1.2.0.0), then its
+// pre-release (lower than the release: 2.0.0-beta < 2.0.0). The build metadata is ignored
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Precedence(Vec<u64>, Prerelease);
+
+fn precedence(version: &Version, tail: &[u64]) -> Precedence {
+    let mut numbers = vec![version.major, version.minor, version.patch];
+    numbers.extend_from_slice(tail);
+    while numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    Precedence(numbers, version.pre.clone())
+}
+
+// Unlike VersionReq::matches, the pre-releases are not excluded from the comparators without one:
+// 2.0.0-beta matches <2.0.0, and not >=2.0.0
+fn comparator_matches(cmp: &Comparator, version: &Precedence) -> bool {
+    let lower = precedence(
+        &Version {
+            major: cmp.major,
+            minor: cmp.minor.unwrap_or(0),
+            patch: cmp.patch.unwrap_or(0),
+            pre: cmp.pre.clone(),
+            build: BuildMetadata::EMPTY,
+        },
+        &[],
+    );
+    // Exclusive upper bounds, below the pre-releases of the bound too
+    let bound = |major, minor, patch| {
+        precedence(
+            &Version {
+                major,
+                minor,
+                patch,
+                pre: Prerelease::new("0").unwrap(),
+                build: BuildMetadata::EMPTY,
+            },
+            &[],
+        )
+    };
+    // Past the last version matching the specified components (e.g. 1.3.0-0 for 1.2)
+    let next = match (cmp.minor, cmp.patch) {
+        (None, _) => bound(cmp.major + 1, 0, 0),
+        (Some(minor), None) => bound(cmp.major, minor + 1, 0),
+        (Some(minor), Some(patch)) => bound(cmp.major, minor, patch + 1),
+    };
+    let partial = cmp.minor.is_none() || cmp.patch.is_none();
+
+    match cmp.op {
+        Op::Exact | Op::Wildcard if partial => *version >= lower && *version < next,
+        Op::Exact | Op::Wildcard => *version == lower,
+        Op::Greater if partial => *version >= next,
+        Op::Greater => *version > lower,
+        Op::GreaterEq => *version >= lower,
+        Op::Less => *version < lower,
+        Op::LessEq if partial => *version < next,
+        Op::LessEq => *version <= lower,
+        Op::Tilde => {
+            let upper = match cmp.minor {
+                Some(minor) => bound(cmp.major, minor + 1, 0),
+                None => bound(cmp.major + 1, 0, 0),
+            };
+            *version >= lower && *version < upper
+        }
+        Op::Caret => {
+            let upper = match (cmp.major, cmp.minor, cmp.patch) {
+                (0, Some(0), Some(patch)) => bound(0, 0, patch + 1),
+                (0, Some(minor), _) => bound(0, minor + 1, 0),
+                (major, _, _) => bound(major + 1, 0, 0),
+            };
+            *version >= lower && *version < upper
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonPathSegment {
+    Key(String),
+    Index(usize),
+}
+
+// Minimal JSONPath subset: $ followed by .key, ['key'] or [index] segments
+pub fn parse_json_path(path: &str) -> Option<Vec<JsonPathSegment>> {
+    let mut rest = path.strip_prefix('$')?;
+    let mut segments = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(&['.', '['][..]).unwrap_or(r.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(JsonPathSegment::Key(r[..end].to_string()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix("['") {
+            let end = r.find("']")?;
+            segments.push(JsonPathSegment::Key(r[..end].to_string()));
+            rest = &r[end + 2..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            segments.push(JsonPathSegment::Index(r[..end].parse().ok()?));
+            rest = &r[end + 1..];
+        } else {
+            return None;
+        }
+    }
+
+    Some(segments)
+}
+
+fn json_lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut current = value;
+    for segment in parse_json_path(path)? {
+        current = match segment {
+            JsonPathSegment::Key(key) => current.get(key)?,
+            JsonPathSegment::Index(idx) => current.get(idx)?,
+        };
+    }
+    Some(current)
+}
+
+// Strings are used as they are, any other JSON value in its serialized form
+fn json_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+fn json_condition_matches(body: &Value, cond: &JsonCondition) -> bool {
+    let found = match json_lookup(body, &cond.path) {
+        Some(found) => found,
+        None => return false,
+    };
+
+    let value = match &cond.value {
+        Some(value) => value,
+        None => return cond.op == "exists",
+    };
+
+    match cond.op.as_str() {
+        "eq" => found == value,
+        "ne" => found != value,
+        "gt" | "lt" => match (found.as_f64(), value.as_f64()) {
+            (Some(f), Some(v)) if cond.op == "gt" => f > v,
+            (Some(f), Some(v)) => f < v,
+            _ => false,
+        },
+        "regex" => match value.as_str().map(Regex::new) {
+            Some(Ok(re)) => re.is_match(&json_to_string(found)),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Named values extracted from the response. The value is the capture group named "value" if
+// present, else the first capture group, else the whole match
+fn extract_attributes(target: &ReqTarget, extractors: &[Extractor]) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+
+    for extractor in extractors {
+        let re = Regex::new(extractor.regex.as_str()).unwrap();
+        let source = match_source(target, &extractor.source);
+        let caps = match re.captures(&source) {
+            Some(caps) => caps,
+            None => continue,
+        };
+
+        let value = caps
+            .name("value")
+            .or_else(|| caps.get(1))
+            .or_else(|| caps.get(0))
+            .map(|m| m.as_str().trim().to_string())
+            .unwrap_or_default();
+
+        if !value.is_empty() {
+            attributes.push((extractor.name.clone(), value));
+        }
+    }
+
+    attributes
+}
+
+fn indicator_matches(target: &ReqTarget, indicator: &Indicator) -> bool {
+    if let Some(ports) = &indicator.ports {
+        if !ports.contains(&target.port) {
+            return false;
+        }
+    }
+
+    if let Some(status) = &indicator.status {
+        match target.status {
+            Some(s) if status.contains(&s) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(regex) = &indicator.regex {
+        let re = Regex::new(regex.as_str()).unwrap();
+        if !re.is_match(&match_source(target, &indicator.source)) {
+            return false;
+        }
+    }
+
+    if let Some(hashes) = &indicator.favicon_hash {
+        match target.favicon_hash {
+            Some(hash) if hashes.contains(&hash) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(tls_cn) = &indicator.tls_cn {
+        let re = Regex::new(tls_cn.as_str()).unwrap();
+        match &target.tls_cn {
+            Some(name) if re.is_match(name) => (),
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+// Whether the definition has indicators on the favicon of the web server
+pub fn needs_favicon(def: &Definition) -> bool {
+    def.service
+        .indicators
+        .iter()
+        .flatten()
+        .any(|indicator| indicator.favicon_hash.is_some())
+}
+
+// Whether the definition has indicators on the TLS certificate of the port
+pub fn needs_tls_cn(def: &Definition) -> bool {
+    def.service
+        .indicators
+        .iter()
+        .flatten()
+        .any(|indicator| indicator.tls_cn.is_some())
+}
+
+// Weighted share of the matching indicators (0.0 - 1.0). Without indicators, the service regex
+// alone gives full confidence
+fn confidence(target: &ReqTarget, indicators: &Option<Vec<Indicator>>) -> f32 {
+    let indicators = match indicators {
+        Some(indicators) if !indicators.is_empty() => indicators,
+        _ => return 1.0,
+    };
+
+    let mut total = 0.0;
+    let mut matched = 0.0;
+    for indicator in indicators {
+        total += indicator.weight;
+        if indicator_matches(target, indicator) {
+            matched += indicator.weight;
+        }
+    }
+
+    if total > 0.0 {
+        matched / total
+    } else {
+        0.0
+    }
+}
+
+// Custom match logic can be plugged in as an additional detector (see plugins::Registry)
+pub trait Detector: Send + Sync {
+    fn detect(&self, target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorResponse>;
+}
+
+// Built-in detector, matching the responses against the definitions
+pub struct DefinitionsDetector;
+
+impl Detector for DefinitionsDetector {
+    fn detect(&self, target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorResponse> {
+        detect(target, definitions)
+    }
+}
+
+// Protocols of the web definitions: both the schemes (http/s), or only one of them
+pub fn is_http(protocol: &str) -> bool {
+    matches!(protocol, "http/s" | "http" | "https")
+}
+
+// Protocols of the definitions sending raw payloads, in clear or over TLS
+pub fn is_custom(protocol: &str) -> bool {
+    matches!(protocol, "tcp/custom" | "tls/custom")
+}
+
+// Schemes requested for the definitions of a web protocol
+pub fn http_schemes(protocol: &str) -> &'static [&'static str] {
+    match protocol {
+        "http" => &["http"],
+        "https" => &["https"],
+        _ => &["https", "http"],
+    }
+}
+
+// Protocols of the definitions probing websocket endpoints
+pub fn is_websocket(protocol: &str) -> bool {
+    matches!(protocol, "ws/s" | "ws" | "wss")
+}
+
+// Schemes requested for the definitions of a websocket protocol
+pub fn websocket_schemes(protocol: &str) -> &'static [&'static str] {
+    match protocol {
+        "ws" => &["ws"],
+        "wss" => &["wss"],
+        _ => &["wss", "ws"],
+    }
+}
+
+// http and https responses are matched by the http/s definitions and by the ones with the same
+// scheme (or with the scheme requested before a fallback), ws and wss responses likewise by the
+// ws/s ones, any other response by the definitions with the same protocol
+fn protocol_matches(target: &ReqTarget, def: &Definition) -> bool {
+    // The responses of the default credentials requests are matched by the definitions with
+    // credentials only, and the other responses by the other definitions
+    if target.credentials.is_some() != def.options.credentials.is_some() {
+        return false;
+    }
+    // Same for the tcp/custom probes sent behind a PROXY protocol header
+    if target.proxy_protocol != def.options.proxy_protocol {
+        return false;
+    }
+    match target.protocol.as_str() {
+        "http" | "https" => {
+            def.protocol == "http/s"
+                || def.protocol == target.protocol
+                || target.scheme_fallback.as_deref() == Some(def.protocol.as_str())
+        }
+        "ws" | "wss" => def.protocol == "ws/s" || def.protocol == target.protocol,
+        protocol => def.protocol == protocol,
+    }
+}
+
+// The definitions matching the status and the headers of the http/s responses only: their
+// requests don't need the body
+pub fn matches_headers_only(def: &Definition) -> bool {
+    let head = |source: &Option<String>| match source.as_deref() {
+        Some(source) => source == "headers" || source.starts_with("header:"),
+        None => false,
+    };
+    let versions = match &def.versions {
+        Some(versions) => {
+            versions
+                .semver
+                .iter()
+                .all(|semver| semver.json_path.is_none() && head(&semver.source))
+                && versions.regex.iter().flatten().all(|ver| head(&ver.source))
+        }
+        None => true,
+    };
+
+    is_http(&def.protocol)
+        && head(&def.service.source)
+        && def.service.json.is_none()
+        && def.service.min_length.is_none()
+        && def.service.max_length.is_none()
+        && def
+            .service
+            .indicators
+            .iter()
+            .flatten()
+            .all(|indicator| indicator.regex.is_none() || head(&indicator.source))
+        && versions
+        && def.extractors.iter().flatten().all(|ext| head(&ext.source))
+        && def.script.is_none()
+}
+
+// Whether the HEAD response headers match one of the regexes. Without an answer, or when HEAD is
+// not supported, the GET requests are sent anyway
+pub fn head_matches(head: &Option<(u16, Vec<(String, String)>)>, regexes: &[&Regex]) -> bool {
+    let (status, headers) = match head {
+        Some((status, _)) if *status == 405 || *status == 501 => return true,
+        Some(head) => head,
+        None => return true,
+    };
+    let headers = format!("HTTP {}\r\n{}", status, raw_response(headers));
+    regexes.iter().any(|regex| regex.is_match(&headers))
+}
+
+// Parsed only once per response, and only if the body looks like JSON
+fn parse_json_body(target: &ReqTarget) -> Option<Value> {
+    let trimmed_body = target.body.trim_start();
+    if trimmed_body.starts_with('{') || trimmed_body.starts_with('[') {
+        serde_json::from_str(trimmed_body).ok()
+    } else {
+        None
+    }
+}
+
+// Service conditions of a definition, returns the confidence if the response matches
+fn service_matches(target: &ReqTarget, def: &Definition, json_body: &Option<Value>) -> Option<f32> {
+    let service_source = match_source(target, &def.service.source);
+    let service_re = Regex::new(def.service.regex.as_str()).unwrap();
+    service_re.find(&service_source)?;
+
+    // Exclusions (e.g. lookalikes, honeypots) are evaluated before anything else is emitted
+    if let Some(exclude_regex) = &def.service.exclude_regex {
+        let exclude_re = Regex::new(exclude_regex.as_str()).unwrap();
+        if exclude_re.is_match(&service_source) {
+            return None;
+        }
+    }
+
+    if let (Some(exclude_status), Some(s)) = (&def.service.exclude_status, target.status) {
+        if exclude_status.contains(&s) {
+            return None;
+        }
+    }
+
+    if let Some(conditions) = &def.service.json {
+        match json_body {
+            Some(body) if conditions.iter().all(|c| json_condition_matches(body, c)) => (),
+            _ => return None,
+        }
+    }
+
+    // Status code and length (body only for http/s) conditions
+    if let Some(status) = &def.service.status {
+        match target.status {
+            Some(s) if status.contains(&s) => (),
+            _ => return None,
+        }
+    }
+
+    let length = target.body.len();
+    if matches!(def.service.min_length, Some(min) if length < min)
+        || matches!(def.service.max_length, Some(max) if length > max)
+    {
+        return None;
+    }
+
+    let confidence = confidence(target, &def.service.indicators);
+    if confidence < def.service.min_confidence.unwrap_or(DEFAULT_MIN_CONFIDENCE) {
+        return None;
+    }
+
+    Some(confidence)
+}
+
+// Whether the response matches the service of at least one of the definitions (used by the
+// probes to stop early, e.g. on multiple paths)
+pub fn matches_any(target: &ReqTarget, definitions: &[&Definition]) -> bool {
+    let json_body = parse_json_body(target);
+    definitions.iter().any(|def| {
+        protocol_matches(target, def) && service_matches(target, def, &json_body).is_some()
+    })
+}
+
 pub fn detect(target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorResponse> {
     let mut matching = Vec::new();
 
+    let json_body = parse_json_body(target);
+
     for def in definitions {
-        if ((target.protocol == "http" || target.protocol == "https") && def.protocol != "http/s")
-            || (target.protocol == "tcp/custom" && def.protocol != "tcp/custom")
-        {
+        if !protocol_matches(target, def) {
             continue;
         }
 
         let mut response = DetectorResponse::new(target.clone());
 
-        let service_re = Regex::new(def.service.regex.as_str()).unwrap();
-        match service_re.find(&target.response) {
-            Some(m) => m,
+        response.confidence = match service_matches(target, def, &json_body) {
+            Some(confidence) => confidence,
             None => continue,
         };
 
         response.service = def.name.clone();
+        if let Some(severity) = &def.service.severity {
+            response.severity = severity.clone();
+        }
+        if let Some(extractors) = &def.extractors {
+            response.attributes = extract_attributes(target, extractors);
+        }
+        if let Some(credentials) = &target.credentials {
+            response
+                .attributes
+                .push(("default_credentials".to_string(), credentials.clone()));
+        }
+        // The service answered behind the PROXY header (v1/v2), or just echoed it back (it
+        // doesn't speak the PROXY protocol)
+        if let Some(version) = &target.proxy_protocol {
+            let value = match net::is_proxy_header(target.response.as_bytes()) {
+                true => "echo",
+                false => version,
+            };
+            response
+                .attributes
+                .push(("proxy_protocol".to_string(), value.to_string()));
+        }
+        // Answered only with the other scheme, e.g. https -> http
+        if let Some(scheme) = &target.scheme_fallback {
+            response.attributes.push((
+                "scheme_fallback".to_string(),
+                format!("{} -> {}", scheme, target.protocol),
+            ));
+        }
         if def.service.log {
             matching.push(response.clone());
         }
 
+        // A script takes over the version detection
+        if let Some(ast) = &def.compiled_script {
+            match script::run(ast, target) {
+                Ok(Some(result)) => {
+                    if let Some(service) = result.service {
+                        response.service = service;
+                    }
+                    response.version = result.version;
+                    response.description = result.description;
+                    matching.push(response.clone());
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    response.error = Some(format!(
+                        "[{}:{}] - {} ({})",
+                        format_host(&response.target).cyan(),
+                        target.port.to_string().cyan(),
+                        err,
+                        def.name
+                    ));
+                    matching.push(response.clone());
+                }
+            }
+            continue;
+        }
+
         let versions = match def.versions.clone() {
             Some(ver) => ver,
             None => continue,
@@ -62,27 +720,24 @@ pub fn detect(target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorRes
 
         if let Some(semver) = versions.semver {
             let version_re = Regex::new(semver.regex.as_str()).unwrap();
-            let version_mat = match version_re.captures(&target.response) {
+            // With a JSON path, the version regex is matched against the selected JSON value
+            let source = match &semver.json_path {
+                Some(path) => match json_body.as_ref().and_then(|b| json_lookup(b, path)) {
+                    Some(value) => Cow::Owned(json_to_string(value)),
+                    None => continue,
+                },
+                None => match_source(target, &semver.source),
+            };
+            let version_mat = match version_re.captures(&source) {
                 Some(m) => m,
                 None => continue,
             };
 
             response.version = version_mat["version"].to_string();
 
-            // Incomplete semver fix (e.g. 4.6 -> 4.6.0)
-            let mut dots = 0;
-            for c in response.version.bytes() {
-                if c == b'.' {
-                    dots += 1;
-                }
-            }
-            if dots < 2 {
-                response.version += ".0";
-            }
-
-            let version = match Version::parse(response.version.as_str()) {
-                Ok(ver) => ver,
-                Err(_err) => {
+            let version = match parse_version(&response.version) {
+                Some(ver) => ver,
+                None => {
                     response.error = Some(format!(
                         "[{}:{}] - Unknown or invalid semver: {}",
                         format_host(&response.target).cyan(),
@@ -95,9 +750,7 @@ pub fn detect(target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorRes
             };
 
             for ver in semver.ranges {
-                if version >= Version::parse(ver.from.as_str()).unwrap()
-                    && version <= Version::parse(ver.to.as_str()).unwrap()
-                {
+                if version_in_range(&version, &ver) {
                     response.description = ver.description;
                     matching.push(response.clone());
                 }
@@ -108,7 +761,7 @@ pub fn detect(target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorRes
             for ver in regex {
                 let re = Regex::new(ver.regex.as_str()).unwrap();
 
-                if let Some(_mat) = re.find(&target.response) {
+                if let Some(_mat) = re.find(&match_source(target, &ver.source)) {
                     response.version = ver.version;
                     response.description = ver.description;
                     matching.push(response.clone());
@@ -0,0 +1,129 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use tokio::{runtime::Builder, sync::Semaphore};
+
+use crate::{
+    net,
+    resolver::Resolver,
+    scope::Scope,
+    zone::{self, DomainInfo},
+};
+
+// Certificates of the domains (and their subdomains) logged in the certificate transparency logs
+const CRT_SH_URL: &str = "https://crt.sh/";
+// crt.sh is slow with the large domains
+const CRT_SH_TIMEOUT: u64 = 120;
+const MAX_CONCURRENT_LOOKUPS: usize = 50;
+
+// Targets as (name, ip)
+pub type Hosts = Vec<(String, String)>;
+
+// Names of a crt.sh response belonging to the domain (wildcards as the base name, e.g.
+// *.example.com as example.com)
+pub fn parse_ct_names(domain: &str, response: &[u8]) -> Result<BTreeSet<String>, String> {
+    let certificates: Vec<serde_json::Value> =
+        serde_json::from_slice(response).map_err(|e| format!("Invalid response: {}", e))?;
+    let suffix = format!(".{}", domain);
+
+    let mut names = BTreeSet::new();
+    for certificate in &certificates {
+        // One or more names, newline separated
+        let name_value = match certificate["name_value"].as_str() {
+            Some(name_value) => name_value,
+            None => continue,
+        };
+        for name in name_value.lines() {
+            let name = name.trim().to_lowercase();
+            let name = name.trim_start_matches("*.");
+            if name == domain || name.ends_with(&suffix) {
+                names.insert(name.to_string());
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+async fn ct_names(domain: &str) -> Result<BTreeSet<String>, String> {
+    let url = format!("{}?q=%25.{}&output=json", CRT_SH_URL, domain);
+    let body = net::fetch(&url, CRT_SH_TIMEOUT).await?;
+    parse_ct_names(domain, &body)
+}
+
+// IPv4 addresses of the names (the ones not resolving are skipped)
+async fn resolve_names(names: BTreeSet<String>, resolver: Arc<Resolver>) -> Vec<(String, String)> {
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_LOOKUPS));
+
+    let mut lookups = Vec::new();
+    for name in names {
+        let (semaphore, resolver) = (semaphore.clone(), resolver.clone());
+        lookups.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await;
+            resolver
+                .lookup_ipv4(&name)
+                .await
+                .into_iter()
+                .map(|ip| (name.clone(), ip))
+                .collect::<Vec<_>>()
+        }));
+    }
+
+    let mut targets = Vec::new();
+    for lookup in lookups {
+        if let Ok(resolved) = lookup.await {
+            targets.extend(resolved);
+        }
+    }
+    targets
+}
+
+// Expands the domains to their subdomains (certificate transparency logs, and the zone when
+// transferred) and resolves them. Targets as (name, ip), the names are kept for the Host headers
+pub fn expand(
+    domains: &[String],
+    transfer: bool,
+    resolver: Arc<Resolver>,
+    scope: Option<&Scope>,
+) -> Result<(Hosts, Vec<DomainInfo>), String> {
+    // Targets are resolved while loading the conf, before the scan runtime is started
+    let rt = Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut names = BTreeSet::new();
+    let mut infos = Vec::new();
+    for domain in domains {
+        let domain = domain.trim().trim_end_matches('.').to_lowercase();
+        names.insert(domain.clone());
+        let subdomains = rt
+            .block_on(ct_names(&domain))
+            .map_err(|e| format!("Unable to query the CT logs for {}: {}", domain, e))?;
+        names.extend(subdomains);
+
+        let info = rt.block_on(zone::inspect(&domain, transfer, &resolver, scope));
+        if !info.wildcard_ips.is_empty() {
+            eprintln!(
+                "Wildcard DNS records on {} ({})",
+                domain,
+                info.wildcard_ips.join(", ")
+            );
+        }
+        if let Some(ns) = &info.zone_ns {
+            eprintln!(
+                "Zone transfer of {} allowed by {} ({} records)",
+                domain,
+                ns,
+                info.zone.len()
+            );
+        }
+        names.extend(zone::zone_hosts(&info));
+        infos.push(info);
+    }
+
+    let targets = rt.block_on(resolve_names(names, resolver));
+    if targets.is_empty() {
+        return Err("None of the domains (and subdomains) resolves to an IPv4 address".to_string());
+    }
+    Ok((targets, infos))
+}
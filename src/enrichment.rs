@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+
+use maxminddb::{geoip2, MaxMindDBError, Mmap, Reader};
+
+// Geo-IP and ASN lookups of the hosts, from MaxMind DB files (e.g. GeoLite2-Country and
+// GeoLite2-ASN, or ASN databases in the same format built from MRT dumps). The files are memory
+// mapped, so the lookups don't load the whole databases in memory
+#[derive(Debug)]
+pub struct Enrichment {
+    country: Option<Reader<Mmap>>,
+    asn: Option<Reader<Mmap>>,
+}
+
+// Enrichment of an ip, every field is missing when the ip is not in the databases (or the
+// database is not configured)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+    pub as_name: Option<String>,
+}
+
+fn open(path: Option<&str>) -> Result<Option<Reader<Mmap>>, String> {
+    match path {
+        Some(path) => match Reader::open_mmap(path) {
+            Ok(reader) => Ok(Some(reader)),
+            Err(err) => Err(format!("Unable to open the database {}: {}", path, err)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Enrichment {
+    pub fn open(country_db: Option<&str>, asn_db: Option<&str>) -> Result<Self, String> {
+        Ok(Enrichment {
+            country: open(country_db)?,
+            asn: open(asn_db)?,
+        })
+    }
+
+    pub fn lookup(&self, ip: &str) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return info,
+        };
+
+        // Not found addresses (e.g. private networks) are not errors
+        if let Some(reader) = &self.country {
+            let country: Result<geoip2::Country, MaxMindDBError> = reader.lookup(ip);
+            if let Ok(country) = country {
+                info.country = country
+                    .country
+                    .or(country.registered_country)
+                    .and_then(|c| c.iso_code)
+                    .map(|code| code.to_string());
+            }
+        }
+        if let Some(reader) = &self.asn {
+            let asn: Result<geoip2::Asn, MaxMindDBError> = reader.lookup(ip);
+            if let Ok(asn) = asn {
+                info.asn = asn.autonomous_system_number.map(i64::from);
+                info.as_name = asn
+                    .autonomous_system_organization
+                    .map(|name| name.to_string());
+            }
+        }
+
+        info
+    }
+}
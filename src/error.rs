@@ -0,0 +1,146 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    io::{self, ErrorKind},
+};
+
+use thiserror::Error;
+use tokio::sync::mpsc::error::SendError;
+
+// Errors of a single target or request. They are reported (e.g. as WorkerMessage::Fail) instead
+// of taking down the whole scan
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Invalid dataset record: {0}")]
+    InvalidDatasetRecord(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] hyper::http::Error),
+    #[error(transparent)]
+    Db(#[from] tokio_postgres::Error),
+    #[error("The receiver channel is closed")]
+    ChannelClosed,
+}
+
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Self {
+        Error::ChannelClosed
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Class of a failed request, to tell the network problems from the targets behavior (e.g. when
+// tuning the scans)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FailClass {
+    Dns,
+    ConnectRefused,
+    // Other connection errors (e.g. unreachable, reset)
+    Connection,
+    Tls,
+    Protocol,
+    BodyRead,
+    // Per-host budget exceeded, the remaining probes of the host are skipped
+    Budget,
+    Other,
+}
+
+impl FailClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailClass::Dns => "dns",
+            FailClass::ConnectRefused => "connect_refused",
+            FailClass::Connection => "connection",
+            FailClass::Tls => "tls",
+            FailClass::Protocol => "protocol",
+            FailClass::BodyRead => "body_read",
+            FailClass::Budget => "budget",
+            FailClass::Other => "other",
+        }
+    }
+
+    pub fn from_io(err: &io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => FailClass::ConnectRefused,
+            _ => FailClass::Connection,
+        }
+    }
+
+    // The connector errors are told apart by their causes: the dns resolution (hosts given as
+    // names), the tcp connection and the tls handshake
+    pub fn from_hyper(err: &hyper::Error) -> Self {
+        if err.is_parse() || err.is_incomplete_message() {
+            return FailClass::Protocol;
+        }
+
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if cause.is::<native_tls::Error>() {
+                return FailClass::Tls;
+            }
+            if cause.to_string().starts_with("dns error") {
+                return FailClass::Dns;
+            }
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                return FailClass::from_io(io_err);
+            }
+            source = cause.source();
+        }
+
+        if err.is_connect() || err.is_closed() {
+            FailClass::Connection
+        } else {
+            FailClass::Other
+        }
+    }
+}
+
+impl fmt::Display for FailClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Phase of a request that timed out. The connector errors of the http/s requests are timeout
+// phases too, told apart from the other connection errors
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TimeoutPhase {
+    #[error("connect timeout")]
+    Connect,
+    #[error("tls handshake timeout")]
+    Tls,
+    // No byte of the response in time (connection included)
+    #[error("first byte timeout")]
+    FirstByte,
+    // Deadline of the whole request
+    #[error("total timeout")]
+    Total,
+}
+
+impl TimeoutPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::Tls => "tls",
+            TimeoutPhase::FirstByte => "first_byte",
+            TimeoutPhase::Total => "total",
+        }
+    }
+
+    pub fn from_hyper(err: &hyper::Error) -> Option<Self> {
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if let Some(phase) = cause.downcast_ref::<TimeoutPhase>() {
+                return Some(*phase);
+            }
+            source = cause.source();
+        }
+        None
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::worker::ReqTarget;
+
+// Request of an http/s probe as sent, kept to save the matches as HAR entries (--har)
+#[derive(Debug, Clone, PartialEq)]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub started: SystemTime,
+    // Until the end of the response body
+    pub elapsed: Duration,
+}
+
+impl HarRequest {
+    // Request line (absolute URL), headers and payload as sent
+    pub fn raw(&self) -> String {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", self.method, self.url);
+        for (name, value) in &self.headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        raw.push_str(&self.body);
+        raw
+    }
+}
+
+// UTC date and time as ISO 8601 (e.g. 2021-06-01T12:00:00.000Z), from the days since the epoch
+// to the civil date (howardhinnant.github.io/date_algorithms.html)
+pub fn iso_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn name_values(pairs: &[(String, String)]) -> Vec<Value> {
+    pairs
+        .iter()
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// HAR 1.2 entry of the exchange of a matching http/s response, with the matching definitions
+// (custom field "_services")
+pub fn entry(target: &ReqTarget, services: &[String]) -> Option<Value> {
+    let request = target.http_request.as_ref()?;
+    let millis = request.elapsed.as_secs_f64() * 1000.0;
+
+    // The phases of the connection when it was opened for the request (-1 otherwise, the connect
+    // time includes the tls one as in the HAR spec), the whole request time as wait when unknown
+    let timings = match target.timings {
+        Some(timings) => {
+            let phase = |ms: Option<u64>| ms.map(|ms| ms as f64).unwrap_or(-1.0);
+            let connect = match (timings.connect, timings.tls) {
+                (Some(connect), tls) => (connect + tls.unwrap_or(0)) as f64,
+                (None, _) => -1.0,
+            };
+            let ttfb = timings.ttfb.map(|ms| ms as f64).unwrap_or(millis);
+            json!({
+                "dns": phase(timings.dns),
+                "connect": connect,
+                "ssl": phase(timings.tls),
+                "send": 0,
+                "wait": (ttfb - phase(timings.dns).max(0.0) - connect.max(0.0)).max(0.0),
+                "receive": (millis - ttfb).max(0.0),
+            })
+        }
+        None => json!({"send": 0, "wait": millis, "receive": 0}),
+    };
+
+    // Status line of the raw response, e.g. "HTTP/1.1 200 OK"
+    let status_line = target.response.lines().next().unwrap_or("");
+    let mut status_parts = status_line.splitn(3, ' ');
+    let http_version = status_parts.next().unwrap_or("HTTP/1.1");
+    let status_text = status_parts.nth(1).unwrap_or("");
+
+    let query_string: Vec<Value> = match request.url.split_once('?') {
+        Some((_, query)) => query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                json!({"name": name, "value": value})
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let mut har_request = json!({
+        "method": request.method,
+        "url": request.url,
+        "httpVersion": http_version,
+        "cookies": [],
+        "headers": name_values(&request.headers),
+        "queryString": query_string,
+        "headersSize": -1,
+        "bodySize": request.body.len(),
+    });
+    if !request.body.is_empty() {
+        har_request["postData"] = json!({
+            "mimeType": header_value(&request.headers, "content-type").unwrap_or(""),
+            "text": request.body,
+        });
+    }
+
+    Some(json!({
+        "startedDateTime": iso_time(request.started),
+        "time": millis,
+        "request": har_request,
+        "response": {
+            "status": target.status.unwrap_or(0),
+            "statusText": status_text,
+            "httpVersion": http_version,
+            "cookies": [],
+            "headers": name_values(&target.headers),
+            "content": {
+                "size": target.body.len(),
+                "mimeType": header_value(&target.headers, "content-type").unwrap_or(""),
+                "text": target.body,
+            },
+            "redirectURL": header_value(&target.headers, "location").unwrap_or(""),
+            "headersSize": -1,
+            "bodySize": target.body.len(),
+        },
+        "cache": {},
+        "timings": timings,
+        "serverIPAddress": target.ip,
+        "_services": services,
+        "_truncated": target.truncated,
+    }))
+}
+
+// HAR file of the entries of a scan
+pub fn log(entries: Vec<Value>) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {"name": "lachesis", "version": env!("CARGO_PKG_VERSION")},
+            "pages": [],
+            "entries": entries,
+        }
+    })
+}
@@ -0,0 +1,199 @@
+// Conventional service names of the ports (IANA Service Name and Transport Protocol Port Number
+// Registry, tcp), to annotate the open ports without a matching definition. A subset: the
+// well-known ports and the registered ones commonly exposed. Sorted by port
+const SERVICE_NAMES: &[(u16, &str)] = &[
+    (7, "echo"),
+    (9, "discard"),
+    (13, "daytime"),
+    (19, "chargen"),
+    (20, "ftp-data"),
+    (21, "ftp"),
+    (22, "ssh"),
+    (23, "telnet"),
+    (25, "smtp"),
+    (37, "time"),
+    (43, "whois"),
+    (49, "tacacs"),
+    (53, "domain"),
+    (69, "tftp"),
+    (70, "gopher"),
+    (79, "finger"),
+    (80, "http"),
+    (81, "hosts2-ns"),
+    (82, "xfer"),
+    (88, "kerberos"),
+    (102, "iso-tsap"),
+    (110, "pop3"),
+    (111, "sunrpc"),
+    (113, "auth"),
+    (119, "nntp"),
+    (123, "ntp"),
+    (135, "epmap"),
+    (137, "netbios-ns"),
+    (138, "netbios-dgm"),
+    (139, "netbios-ssn"),
+    (143, "imap"),
+    (161, "snmp"),
+    (162, "snmptrap"),
+    (177, "xdmcp"),
+    (179, "bgp"),
+    (194, "irc"),
+    (389, "ldap"),
+    (427, "svrloc"),
+    (443, "https"),
+    (444, "snpp"),
+    (445, "microsoft-ds"),
+    (464, "kpasswd"),
+    (465, "submissions"),
+    (500, "isakmp"),
+    (502, "mbap"),
+    (512, "exec"),
+    (513, "login"),
+    (514, "shell"),
+    (515, "printer"),
+    (520, "router"),
+    (523, "ibm-db2"),
+    (540, "uucp"),
+    (543, "klogin"),
+    (544, "kshell"),
+    (548, "afpovertcp"),
+    (554, "rtsp"),
+    (563, "nntps"),
+    (587, "submission"),
+    (593, "http-rpc-epmap"),
+    (623, "asf-rmcp"),
+    (631, "ipp"),
+    (636, "ldaps"),
+    (646, "ldp"),
+    (873, "rsync"),
+    (902, "ideafarm-door"),
+    (989, "ftps-data"),
+    (990, "ftps"),
+    (992, "telnets"),
+    (993, "imaps"),
+    (995, "pop3s"),
+    (1080, "socks"),
+    (1194, "openvpn"),
+    (1311, "rxmon"),
+    (1433, "ms-sql-s"),
+    (1434, "ms-sql-m"),
+    (1521, "ncube-lm"),
+    (1583, "simbaexpress"),
+    (1701, "l2f"),
+    (1723, "pptp"),
+    (1812, "radius"),
+    (1813, "radius-acct"),
+    (1883, "mqtt"),
+    (1900, "ssdp"),
+    (1911, "mtp"),
+    (2000, "cisco-sccp"),
+    (2049, "nfs"),
+    (2082, "infowave"),
+    (2083, "radsec"),
+    (2086, "gnunet"),
+    (2087, "eli"),
+    (2121, "scientia-ssdb"),
+    (2181, "eforward"),
+    (2222, "EtherNet-IP-1"),
+    (2375, "docker"),
+    (2376, "docker-s"),
+    (2379, "etcd-client"),
+    (2380, "etcd-server"),
+    (2404, "iec-104"),
+    (2483, "ttc"),
+    (2484, "ttc-ssl"),
+    (3000, "hbci"),
+    (3128, "ndl-aas"),
+    (3260, "iscsi-target"),
+    (3268, "msft-gc"),
+    (3269, "msft-gc-ssl"),
+    (3306, "mysql"),
+    (3389, "ms-wbt-server"),
+    (3478, "stun"),
+    (3690, "svn"),
+    (4369, "epmd"),
+    (4443, "pharos"),
+    (4444, "krb524"),
+    (4500, "ipsec-nat-t"),
+    (4567, "tram"),
+    (4786, "smart-install"),
+    (4840, "opcua-tcp"),
+    (4848, "appserv-http"),
+    (5000, "commplex-main"),
+    (5006, "wsm-server"),
+    (5007, "wsm-server-ssl"),
+    (5060, "sip"),
+    (5061, "sips"),
+    (5222, "xmpp-client"),
+    (5269, "xmpp-server"),
+    (5353, "mdns"),
+    (5432, "postgresql"),
+    (5555, "personal-agent"),
+    (5601, "esmagent"),
+    (5671, "amqps"),
+    (5672, "amqp"),
+    (5683, "coap"),
+    (5684, "coaps"),
+    (5900, "rfb"),
+    (5984, "couchdb"),
+    (5985, "wsman"),
+    (5986, "wsmans"),
+    (6000, "x11"),
+    (6379, "redis"),
+    (6443, "sun-sr-https"),
+    (6514, "syslog-tls"),
+    (6660, "ircu"),
+    (6667, "ircu"),
+    (6697, "ircs-u"),
+    (7001, "afs3-callback"),
+    (7474, "neo4j"),
+    (7547, "cwmp"),
+    (8000, "irdmi"),
+    (8008, "http-alt"),
+    (8080, "http-alt"),
+    (8081, "sunproxyadmin"),
+    (8086, "d-s-n"),
+    (8088, "radan-http"),
+    (8090, "opsmessaging"),
+    (8118, "privoxy"),
+    (8123, "polipo"),
+    (8181, "intermapper"),
+    (8333, "bitcoin"),
+    (8443, "pcsync-https"),
+    (8500, "fmtp"),
+    (8834, "nessus-xmlrpc"),
+    (8883, "secure-mqtt"),
+    (8888, "ddi-tcp-1"),
+    (9000, "cslistener"),
+    (9090, "websm"),
+    (9092, "XmlIpcRegSvc"),
+    (9100, "pdl-datastream"),
+    (9200, "wap-wsp"),
+    (9300, "vrace"),
+    (9418, "git"),
+    (9443, "tungsten-https"),
+    (9999, "distinct"),
+    (10000, "ndmp"),
+    (11211, "memcache"),
+    (20000, "dnp"),
+    (27017, "mongodb"),
+    (44818, "EtherNet-IP-2"),
+    (47808, "bacnet"),
+];
+
+// Conventional service name of a port, if any
+pub fn service_name(port: u16) -> Option<&'static str> {
+    SERVICE_NAMES
+        .binary_search_by_key(&port, |(port, _)| *port)
+        .ok()
+        .map(|i| SERVICE_NAMES[i].1)
+}
+
+// A port with its conventional service name, if any (e.g. "3306 (mysql?)"): only a guess, the
+// service listening on it wasn't fingerprinted
+pub fn annotate(port: u16) -> String {
+    match service_name(port) {
+        Some(name) => format!("{} ({}?)", port, name),
+        None => port.to_string(),
+    }
+}
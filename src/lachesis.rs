@@ -1,129 +1,612 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
 use colored::Colorize;
 use tokio::{
     runtime::Builder,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Semaphore,
+    },
+    task,
 };
+use tracing::instrument;
 
 use crate::{
-    conf::{self, Conf},
+    breaker::Breaker,
+    cdn::{self, CdnRanges},
+    cli::{Cli, Command, DbCommand, DefsArgs, DefsCommand, ScopeArgs, ScopeCommand},
+    conf::{self, Conf, Definition},
+    content, convert,
     db::DbMan,
-    detector,
+    defs::{self, UpdateStatus},
+    detector::DetectorResponse,
+    error::FailClass,
+    har,
+    monitor::{self, ScanSummary},
+    oshint,
+    page::{self, RobotsFetcher},
+    pcap,
+    persistence::Persister,
+    plan,
+    plugins::Registry,
+    rdap::{self, RdapFetcher},
+    scope, selftest, shard, signing,
+    sink::{self, OutputSink},
     stats::Stats,
+    trace, update,
     web::{self, UIMessage},
-    worker::{self, PortsTarget, ReqTarget, WorkerMessage},
+    worker::{self, FollowUp, PortStatus, PortsTarget, ReqTarget, WorkerMessage},
 };
 
-async fn handle_response_msg(conf: &Conf, stats: &mut Stats, dbm: &DbMan, target: ReqTarget) {
-    stats.update_req_avg_time(target.time, &target.protocol);
+// Interval of the stats snapshots saved in the db during the scan
+const STATS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+// Detection tasks running at once, the receiver loop waits for a slot (and so do the workers)
+const MAX_PENDING_DETECTIONS: usize = 1_000;
 
-    stats.log_response(&target);
+#[derive(Debug)]
+struct Detection {
+    target: ReqTarget,
+    responses: Vec<DetectorResponse>,
+    errors: Vec<String>,
+}
 
-    let det_responses = detector::detect(&target, &conf.definitions);
+// Shared by the detection tasks
+struct DetectionCtx {
+    registry: Arc<Registry>,
+    definitions: Vec<Definition>,
+    persister: Arc<Persister>,
+    // Sinks of the matching services, the db (persister) first
+    sinks: Vec<Arc<dyn OutputSink>>,
+    // Directory of the pcap files (--pcap-matches)
+    pcap_dir: Option<String>,
+    cdn: Arc<CdnRanges>,
+    // robots.txt and sitemap.xml of the matching web servers (--fetch-robots)
+    robots: Option<RobotsFetcher>,
+    // Owners of the networks of the findings (--rdap)
+    rdap: Option<RdapFetcher>,
+    // Scan of the HAR entries of the matches (--har)
+    har_scan: Option<i64>,
+    // Responses matching no definition (--collect-unknown)
+    collect_unknown: bool,
+    // Timings of the http/s matches as attributes (--timing-attributes)
+    timing_attributes: bool,
+    // Slots of the detection tasks
+    permits: Arc<Semaphore>,
+}
 
-    let mut matching = false;
-    if !det_responses.is_empty() {
-        for res in det_responses {
-            if let Some(error) = res.error {
-                stats.log_int_err(error);
-                continue;
+// Regex matching is CPU bound, so it runs on the blocking pool. The matching services are then
+// saved in the db (and their exchanges as pcap files, if enabled) from the same task, without
+// stalling the receiver loop
+#[instrument(
+    level = "debug",
+    skip(ctx, target),
+    fields(protocol = %target.protocol, ip = %target.ip, port = target.port)
+)]
+async fn detect_and_persist(ctx: Arc<DetectionCtx>, mut target: ReqTarget) -> Detection {
+    let capture = target.capture.take();
+    let det_target = target.clone();
+    let det_ctx = ctx.clone();
+    let detection = task::spawn_blocking(move || {
+        let responses = det_ctx.registry.detect(&det_target, &det_ctx.definitions);
+        // Title and meta generator of the matching web pages, the connect time, OS hint and
+        // request timings (if enabled) of the matching services, and the hashes of their content (or of the unknown ones)
+        let (page, content) = if responses.iter().any(|res| res.error.is_none()) {
+            let mut attributes = page::attributes(&det_target);
+            attributes.extend(oshint::attributes(&det_target));
+            if let (true, Some(timings)) = (det_ctx.timing_attributes, &det_target.timings) {
+                attributes.extend(timings.attributes());
             }
+            (attributes, content::hash(&det_target))
+        } else if det_ctx.collect_unknown {
+            (Vec::new(), content::hash(&det_target))
+        } else {
+            (Vec::new(), None)
+        };
+        (responses, page, content)
+    })
+    .await;
+    let (mut responses, mut page, content) = match detection {
+        Ok(detection) => detection,
+        Err(err) => {
+            return Detection {
+                target,
+                responses: Vec::new(),
+                errors: vec![format!("The detection task has panicked: {:?}", err)],
+            }
+        }
+    };
 
-            matching = true;
+    // Services behind a CDN/WAF (by the ip or the response headers) are tagged, their version
+    // and attributes may be the ones of the edge
+    let cdn = ctx
+        .cdn
+        .provider_by_ip(&target.ip)
+        .or_else(|| cdn::provider_by_headers(&target.headers))
+        .map(|provider| provider.to_string());
 
-            stats.log_match(&res);
+    if let Some(robots) = &ctx.robots {
+        if responses.iter().any(|res| res.error.is_none()) {
+            page.extend(robots.attributes(&target).await);
+        }
+    }
 
-            if let Err(err) = dbm.insert_service(&res).await {
-                stats.log_int_err(format!(
-                    "Error while saving a matching service in the db: {}",
+    let mut errors = Vec::new();
+    if let Some(rdap) = &ctx.rdap {
+        if responses.iter().any(|res| res.error.is_none()) {
+            if let Some(netblock) = rdap.netblock(&target.ip).await {
+                page.extend(rdap::attributes(&netblock));
+                if let Err(err) = ctx.persister.save_netblock(&netblock).await {
+                    errors.push(format!(
+                        "Error while saving the netblock in the db: {}",
+                        err
+                    ));
+                }
+            }
+        }
+    }
+
+    for res in &mut responses {
+        if res.error.is_some() {
+            continue;
+        }
+
+        if let Some(provider) = &cdn {
+            res.attributes.push(("cdn".to_string(), provider.clone()));
+        }
+        res.content = content.clone();
+        // The attributes extracted by the definition take precedence
+        for (name, value) in &page {
+            if !res.attributes.iter().any(|(n, _)| n == name) {
+                res.attributes.push((name.clone(), value.clone()));
+            }
+        }
+
+        for sink in &ctx.sinks {
+            if let Err(err) = sink.write(res).await {
+                errors.push(format!(
+                    "Error while writing a matching service to the {} sink: {}",
+                    sink.name(),
                     err
                 ));
-                continue;
-            };
+            }
+        }
 
-            // headless_chrome is unmaintained
-            // browser::maybe_take_screenshot(&target, id);
+        if let (Some(dir), Some(capture)) = (&ctx.pcap_dir, &capture) {
+            if let Err(err) = pcap::save(dir, capture, &res.service) {
+                errors.push(format!("Error while saving the pcap file: {}", err));
+            }
         }
+
+        // headless_chrome is unmaintained
+        // browser::maybe_take_screenshot(&target, id);
     }
 
-    stats.increment_successful(&target.protocol, matching);
+    // Clustered by similarity to find the most common unidentified services
+    if ctx.collect_unknown && !responses.iter().any(|res| res.error.is_none()) {
+        if let Some(content) = &content {
+            if let Err(err) = ctx.persister.save_unknown_response(&target, content).await {
+                errors.push(format!(
+                    "Error while saving the unknown response in the db: {}",
+                    err
+                ));
+            }
+        }
+    }
+
+    // One HAR entry per exchange, with all the definitions it matched
+    if let Some(scan_id) = ctx.har_scan {
+        let services: Vec<String> = responses
+            .iter()
+            .filter(|res| res.error.is_none())
+            .map(|res| res.service.clone())
+            .collect();
+        if !services.is_empty() {
+            if let Some(entry) = har::entry(&target, &services) {
+                if let Err(err) = ctx.persister.save_har_entry(scan_id, &entry).await {
+                    errors.push(format!(
+                        "Error while saving the HAR entry in the db: {}",
+                        err
+                    ));
+                }
+            }
+        }
+    }
+
+    Detection {
+        target,
+        responses,
+        errors,
+    }
 }
 
-async fn handle_portstarget_msg(stats: &mut Stats, ports_target: PortsTarget) {
+async fn handle_response_msg(
+    stats: &mut Stats,
+    det_tx: &Sender<Result<Detection, String>>,
+    ctx: &Arc<DetectionCtx>,
+    target: ReqTarget,
+) {
+    stats.update_req_avg_time(target.time, &target.protocol, target.port);
+    if let Some(timings) = &target.timings {
+        stats.update_request_timings(timings);
+    }
+
+    stats.log_response(&target);
+
+    let permit = ctx.permits.clone().acquire_owned().await.unwrap();
+    let det_tx = det_tx.clone();
+    let task = tokio::spawn(detect_and_persist(ctx.clone(), target));
+    // A panicked detection is sent too, the receiver loop waits for all of them
+    tokio::spawn(async move {
+        let detection = task.await.map_err(|e| e.to_string());
+        drop(permit);
+        let _ = det_tx.send(detection).await;
+    });
+}
+
+// Follow-up definitions (on_match) of the definitions matched by a detection, once per ip, port
+// and definition
+fn follow_up_of(
+    definitions: &[Definition],
+    detection: &Detection,
+    followed: &mut HashSet<(String, u16, String)>,
+) -> Option<FollowUp> {
+    let mut follow_ups = Vec::new();
+    for res in detection.responses.iter().filter(|res| res.error.is_none()) {
+        let def = match definitions.iter().find(|def| def.name == res.service) {
+            Some(def) => def,
+            None => continue,
+        };
+        let key = (
+            detection.target.ip.clone(),
+            detection.target.port,
+            def.name.clone(),
+        );
+        if def.on_match.is_some() && followed.insert(key) {
+            follow_ups.extend(def.on_match.iter().flatten().cloned());
+        }
+    }
+    if follow_ups.is_empty() {
+        return None;
+    }
+
+    let mut target = ReqTarget::new(detection.target.domain.clone(), detection.target.ip.clone());
+    target.port = detection.target.port;
+    target.tags = detection.target.tags.clone();
+    Some(FollowUp {
+        target,
+        definitions: follow_ups,
+    })
+}
+
+fn handle_detection_msg(stats: &mut Stats, summary: &mut ScanSummary, detection: Detection) {
+    let mut matching = false;
+    for res in detection.responses {
+        if let Some(error) = res.error {
+            stats.log_int_err(error);
+            continue;
+        }
+
+        matching = true;
+
+        stats.increment_definition_match(&res.service);
+        stats.increment_geo_match(&res.target.ip);
+        stats.log_match(&res);
+        summary.add_service(&res);
+    }
+
+    for error in detection.errors {
+        stats.log_int_err(error);
+    }
+
+    stats.increment_successful(&detection.target.protocol, matching);
+}
+
+// Outcome of a request or of a port check, for the circuit breaker (--breaker-threshold)
+fn record_outcome(stats: &mut Stats, breaker: &Option<Arc<Breaker>>, failed: bool) {
+    if let Some(event) = breaker.as_ref().and_then(|breaker| breaker.record(failed)) {
+        stats.log_breaker(event);
+    }
+}
+
+// The sinks get the open ports of a port inventory only (--ports-only)
+async fn handle_portstarget_msg(
+    stats: &mut Stats,
+    persister: &Arc<Persister>,
+    sinks: &[Arc<dyn OutputSink>],
+    ports_target: PortsTarget,
+) {
     stats.update_ports_stats(&ports_target);
 
     let open_ports = ports_target.open_ports();
     if !open_ports.is_empty() {
         stats.log_open_ports(&ports_target.ip, &open_ports);
+        for sink in sinks {
+            if let Err(err) = sink.write_ports(&ports_target.ip, &open_ports).await {
+                stats.log_int_err(format!(
+                    "Error while writing the open ports to the {} sink: {}",
+                    sink.name(),
+                    err
+                ));
+            }
+        }
+    }
+
+    // Saved for the next scans (--reuse-portscan)
+    if let Err(err) = persister.save_portscan(&ports_target).await {
+        stats.log_int_err(format!(
+            "Error while saving the port scan in the db: {}",
+            err
+        ));
+    }
+}
+
+async fn save_stats_snapshot(stats: &mut Stats, persister: &Arc<Persister>, scan_id: Option<i64>) {
+    if let Some(scan_id) = scan_id {
+        if let Err(err) = persister
+            .save_scan_stats(scan_id, &stats.json_stats())
+            .await
+        {
+            stats.log_int_err(format!(
+                "Error while saving the scan stats in the db: {}",
+                err
+            ));
+        }
     }
 }
 
-pub async fn run_worker(conf: &Conf) -> Result<(), ()> {
-    let mut stats = Stats::new(conf.max_targets);
+pub async fn run_worker(conf: &Conf, registry: Arc<Registry>) -> Result<ScanSummary, ()> {
+    let mut stats = Stats::new(conf);
+    let mut summary = ScanSummary::default();
 
-    let dbm = match DbMan::init(&conf.db_conf).await {
-        Ok(dbm) => dbm,
+    let persister = match Persister::init(
+        &conf.db_conf,
+        &conf.project,
+        conf.enrichment.clone(),
+        conf.store_responses,
+    )
+    .await
+    {
+        Ok(persister) => persister,
         Err(err) => {
             stats.log_int_err(format!("Db initialization error: {}", err));
             return Err(());
         }
     };
 
-    let (tx, mut rx): (Sender<WorkerMessage>, Receiver<WorkerMessage>) = mpsc::channel(100_000);
+    // Wildcard records and zones of the domains targets
+    for info in &conf.domain_infos {
+        if let Err(err) = persister.save_domain_dns(info).await {
+            stats.log_int_err(format!(
+                "Error while saving the DNS records of {} in the db: {}",
+                info.domain, err
+            ));
+        }
+    }
 
-    let jhandle = tokio::spawn(worker::run(tx, conf.clone()));
+    // Recent port scans (if reused), by ip
+    let portscans = match conf.reuse_portscan {
+        Some(max_age) => match persister.recent_portscans(max_age).await {
+            Ok(portscans) => portscans,
+            Err(err) => {
+                stats.log_int_err(format!("Error while loading the port scans: {}", err));
+                return Err(());
+            }
+        },
+        None => HashMap::new(),
+    };
 
-    loop {
-        let msg = match rx.recv().await {
-            Some(msg) => msg,
-            None => continue,
-        };
+    if let Some(dedup) = &conf.dedup {
+        stats.log_dataset_dedup(dedup);
+    }
 
-        stats.update_avg_reqs_per_sec();
+    // Scan session, its stats are saved periodically and at the end of the scan
+    let scan_id = match persister.start_scan().await {
+        Ok(scan_id) => {
+            stats.log_scan(scan_id);
+            Some(scan_id)
+        }
+        Err(err) => {
+            stats.log_int_err(format!("Error while saving the scan in the db: {}", err));
+            None
+        }
+    };
+    let mut last_stats_snapshot = Instant::now();
 
-        match msg {
-            WorkerMessage::PortsTarget(ports_target) => {
-                handle_portstarget_msg(&mut stats, ports_target).await;
-                continue;
+    let persister = Arc::new(persister);
+    let mut sinks: Vec<Arc<dyn OutputSink>> = vec![persister.clone()];
+    for sink_conf in &conf.output_sinks {
+        match sink::open(sink_conf) {
+            Ok(sink) => sinks.push(sink),
+            Err(err) => {
+                stats.log_int_err(format!("Output sink error: {}", err));
+                return Err(());
             }
-            WorkerMessage::Fail(target, error_context, error) => {
-                if conf.debug {
-                    stats.log_fail(&target, error_context, error);
+        }
+    }
+    let det_ctx = Arc::new(DetectionCtx {
+        registry: registry.clone(),
+        definitions: conf.definitions.clone(),
+        persister: persister.clone(),
+        sinks,
+        pcap_dir: conf.pcap_matches.clone(),
+        cdn: conf.cdn.clone(),
+        robots: if conf.fetch_robots {
+            Some(RobotsFetcher::new(conf))
+        } else {
+            None
+        },
+        rdap: if conf.rdap {
+            Some(RdapFetcher::default())
+        } else {
+            None
+        },
+        har_scan: if conf.har { scan_id } else { None },
+        collect_unknown: conf.collect_unknown,
+        timing_attributes: conf.timing_attributes,
+        permits: Arc::new(Semaphore::new(MAX_PENDING_DETECTIONS)),
+    });
+
+    let (tx, mut rx): (Sender<WorkerMessage>, Receiver<WorkerMessage>) = mpsc::channel(100_000);
+    let (det_tx, mut det_rx) = mpsc::channel(100_000);
+    // Follow-up probes of the matches, sent back to the worker. The sender is dropped once the
+    // targets are done and their detections completed
+    let (follow_up_tx, follow_up_rx) = mpsc::channel(100_000);
+    let mut follow_up_tx = Some(follow_up_tx);
+    let mut followed = HashSet::new();
+    let mut targets_done = false;
+    let breaker = conf.breaker.map(|breaker| Arc::new(Breaker::new(breaker)));
+
+    let jhandle = tokio::spawn(worker::run(
+        tx,
+        conf.clone(),
+        registry.clone(),
+        Arc::new(portscans),
+        follow_up_rx,
+        breaker.clone(),
+    ));
+
+    // After the shutdown message, keep looping until all the pending detections are completed
+    let mut shutdown = false;
+    let mut pending_detections: u64 = 0;
+    while !shutdown || pending_detections > 0 {
+        tokio::select! {
+            Some(msg) = rx.recv(), if !shutdown => {
+                stats.update_avg_reqs_per_sec();
+
+                if last_stats_snapshot.elapsed() >= STATS_SNAPSHOT_INTERVAL {
+                    last_stats_snapshot = Instant::now();
+                    save_stats_snapshot(&mut stats, &persister, scan_id).await;
                 }
-                stats.increment_failed(&target.protocol);
-                continue;
+
+                match msg {
+                    WorkerMessage::PortsTarget(ports_target) => {
+                        summary.add_ports(&ports_target);
+                        for port in &ports_target.ports {
+                            let failed = port.status == PortStatus::Timedout;
+                            record_outcome(&mut stats, &breaker, failed);
+                        }
+                        let sinks = if conf.ports_only { &det_ctx.sinks[..] } else { &[] };
+                        handle_portstarget_msg(&mut stats, &persister, sinks, ports_target).await;
+                    }
+                    WorkerMessage::Fail(target, class, error_context, error) => {
+                        if conf.debug {
+                            stats.log_fail(&target, class, error_context, error);
+                        }
+                        stats.increment_failed(&target.protocol);
+                        stats.increment_fail_class(class);
+                        // A refused connection is an answer, a skipped probe no outcome
+                        match class {
+                            FailClass::Budget => (),
+                            FailClass::ConnectRefused => record_outcome(&mut stats, &breaker, false),
+                            _ => record_outcome(&mut stats, &breaker, true),
+                        }
+                    }
+                    WorkerMessage::Timeout(target, phase) => {
+                        if conf.debug {
+                            stats.log_timeout(&target, phase);
+                        }
+                        stats.increment_timedout(&target.protocol);
+                        stats.increment_timeout_phase(phase);
+                        record_outcome(&mut stats, &breaker, true);
+                    }
+                    WorkerMessage::Response(target) => {
+                        record_outcome(&mut stats, &breaker, false);
+                        pending_detections += 1;
+                        handle_response_msg(&mut stats, &det_tx, &det_ctx, target).await;
+                    }
+                    WorkerMessage::Vhost(target) => {
+                        stats.log_vhost(&target);
+                        if let Err(err) = persister.save_vhost(&target).await {
+                            stats.log_int_err(format!(
+                                "Error while saving the virtual host in the db: {}",
+                                err
+                            ));
+                        }
+                    }
+                    WorkerMessage::OutOfScope(target) => {
+                        stats.log_out_of_scope(&target);
+                        if let Some(scope) = &conf.scope {
+                            if let Err(err) = scope.audit_refused(&target) {
+                                stats.log_int_err(format!("Scope audit error: {}", err));
+                            }
+                        }
+                    }
+                    WorkerMessage::Breaker(event) => stats.log_breaker(event),
+                    WorkerMessage::NextTarget => {
+                        stats.increment_targets();
+                    }
+                    WorkerMessage::TargetsDone => {
+                        targets_done = true;
+                        if pending_detections == 0 {
+                            follow_up_tx = None;
+                        }
+                    }
+                    WorkerMessage::Shutdown => shutdown = true,
+                };
             }
-            WorkerMessage::Timeout(target) => {
-                if conf.debug {
-                    stats.log_timeout(&target);
+            Some(detection) = det_rx.recv() => {
+                pending_detections -= 1;
+                match detection {
+                    Ok(detection) => {
+                        let follow_up = follow_up_of(&conf.definitions, &detection, &mut followed);
+                        handle_detection_msg(&mut stats, &mut summary, detection);
+                        if let (Some(follow_up), Some(follow_up_tx)) = (follow_up, &follow_up_tx) {
+                            let _ = follow_up_tx.send(follow_up).await;
+                        }
+                    }
+                    Err(e) => stats.log_int_err(format!("The detection task has panicked: {}", e)),
+                }
+                // The follow-up detections can't trigger other follow-ups
+                if targets_done && pending_detections == 0 {
+                    follow_up_tx = None;
                 }
-                stats.increment_timedout(&target.protocol);
-                continue;
-            }
-            WorkerMessage::Response(target) => {
-                handle_response_msg(conf, &mut stats, &dbm, target).await;
-                continue;
-            }
-            WorkerMessage::NextTarget => {
-                stats.increment_targets();
-                continue;
             }
-            WorkerMessage::Shutdown => break,
-        };
+            else => break,
+        }
     }
 
     if let Err(e) = jhandle.await {
         stats.log_int_err(format!("The task being joined has panicked: {:?}", e));
     };
 
+    // Services of the port scanned hosts that the definitions of this scan didn't see again
+    if let Some(scan_id) = scan_id {
+        let definitions: Vec<String> = conf
+            .definitions
+            .iter()
+            .map(|def| def.name.clone())
+            .collect();
+        match persister.mark_stale_services(scan_id, &definitions).await {
+            Ok(Some(count)) => stats.log_stale_services(count),
+            Ok(None) => (),
+            Err(err) => stats.log_int_err(format!(
+                "Error while marking the services not seen as inactive: {}",
+                err
+            )),
+        }
+    }
+
     stats.finish();
 
-    Ok(())
+    save_stats_snapshot(&mut stats, &persister, scan_id).await;
+    if let Some(scan_id) = scan_id {
+        if let Err(err) = persister.finish_scan(scan_id).await {
+            stats.log_int_err(format!("Error while saving the scan in the db: {}", err));
+        }
+    }
+
+    Ok(summary)
 }
 
-async fn run_ui() -> Result<(), ()> {
+async fn run_ui(conf: &Conf) -> Result<(), ()> {
     let (tx, mut rx): (Sender<UIMessage>, Receiver<UIMessage>) = mpsc::channel(100);
 
-    tokio::spawn(web::run(tx));
+    tokio::spawn(web::run(tx, conf.db_conf.clone(), conf.users.clone()));
 
     loop {
         match rx.recv().await {
@@ -133,19 +616,238 @@ async fn run_ui() -> Result<(), ()> {
     }
 }
 
+async fn run_db(conf: &Conf, command: DbCommand) -> Result<(), ()> {
+    // The schema is created (or upgraded) when connecting
+    let dbm = match DbMan::init(&conf.db_conf).await {
+        Ok(dbm) => dbm,
+        Err(err) => {
+            eprintln!("[{}] Db initialization error: {}", "ERROR".red(), err);
+            return Err(());
+        }
+    };
+
+    match command {
+        DbCommand::Init => println!("The db schema is up to date"),
+        DbCommand::Stats => match dbm.count_rows().await {
+            Ok(counts) => {
+                for (table, count) in counts {
+                    println!("{:<20}{}", table, count);
+                }
+            }
+            Err(err) => {
+                eprintln!("[{}] Db query error: {}", "ERROR".red(), err);
+                return Err(());
+            }
+        },
+    }
+
+    Ok(())
+}
+
+async fn suggest_exclusions(conf: &Conf) -> Result<(), ()> {
+    let feedback = match DbMan::init(&conf.db_conf).await {
+        Ok(dbm) => dbm.get_triage_feedback().await,
+        Err(err) => {
+            eprintln!("[{}] Db initialization error: {}", "ERROR".red(), err);
+            return Err(());
+        }
+    };
+    let feedback = match feedback {
+        Ok(feedback) => feedback,
+        Err(err) => {
+            eprintln!("[{}] Db query error: {}", "ERROR".red(), err);
+            return Err(());
+        }
+    };
+    if feedback.is_empty() {
+        println!("No findings marked as false positives");
+    }
+
+    for (definition, feedback) in feedback {
+        let responses: Vec<String> = feedback.false_positives.iter().flatten().cloned().collect();
+        println!(
+            "{}: {} false positives ({} with a stored response)",
+            definition.cyan(),
+            feedback.false_positives.len(),
+            responses.len()
+        );
+        match defs::suggest_exclusion(&responses, &feedback.confirmed) {
+            Some(exclusion) => println!(
+                "    \"exclude_regex\": {} (matches {}/{}, none of the {} confirmed)",
+                serde_json::to_string(&exclusion.exclude_regex).unwrap(),
+                exclusion.matches,
+                responses.len(),
+                feedback.confirmed.len()
+            ),
+            None => println!("    no content shared by the false positives"),
+        }
+    }
+    Ok(())
+}
+
+async fn self_update(check_only: bool) -> Result<(), String> {
+    let release = match update::check().await? {
+        Some(release) => release,
+        None => {
+            println!("Lachesis v{} is up to date", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    };
+    println!(
+        "Lachesis v{} is available (current: v{})",
+        release.version,
+        env!("CARGO_PKG_VERSION")
+    );
+    if check_only {
+        return Ok(());
+    }
+    update::install(&release).await?;
+    println!("Updated to v{}", release.version);
+    Ok(())
+}
+
+// The errors are printed, the binary only sets its exit code
+#[allow(clippy::result_unit_err)]
 pub fn run() -> Result<(), ()> {
-    let conf = match conf::load() {
-        Ok(conf) => conf,
+    let cli = Cli::parse();
+    let config = cli.config.clone();
+    let config = config.as_deref();
+
+    let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+    let registry = Arc::new(Registry::new());
+    let command = match cli.into_command() {
+        Ok(command) => command,
         Err(err) => {
             eprintln!("[{}] {}", "ERROR".red(), err);
             return Err(());
         }
     };
 
-    let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-    if conf.web_ui {
-        rt.block_on(run_ui())
-    } else {
-        rt.block_on(run_worker(&conf))
-    }
+    let err = match command {
+        Command::Scan(args) => match conf::load_with_registry(args.clone(), config, &registry) {
+            Ok(conf) if conf.trace.is_some() && trace::init(&conf).is_err() => {
+                "Unable to open the trace file".to_string()
+            }
+            Ok(conf) if conf.web_ui => return rt.block_on(run_ui(&conf)),
+            Ok(conf) if conf.dry_run => match plan::print(&conf) {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            },
+            Ok(conf) if conf.monitor.is_some() => {
+                return monitor::run(&rt, conf, args, config, registry)
+            }
+            Ok(conf) => return rt.block_on(run_worker(&conf, registry)).map(|_| ()),
+            Err(err) => err.to_string(),
+        },
+        Command::Ui => match conf::load_ui(config) {
+            Ok(conf) => return rt.block_on(run_ui(&conf)),
+            Err(err) => err.to_string(),
+        },
+        Command::Db(args) => match conf::load_ui(config) {
+            Ok(conf) => return rt.block_on(run_db(&conf, args.command)),
+            Err(err) => err.to_string(),
+        },
+        Command::Scope(ScopeArgs {
+            command: ScopeCommand::Sign { file },
+        }) => match scope::key().and_then(|key| scope::sign(&file, &key)) {
+            Ok(signature) => {
+                println!("signature = \"{}\"", signature);
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+        Command::Defs(DefsArgs {
+            command: DefsCommand::Update { index, public_key },
+        }) => match conf::load_definitions_index(index, config)
+            .and_then(|index| Ok((index, conf::load_defs_public_keys(public_key, config)?)))
+        {
+            Ok((index, keys)) => match defs::trusted_keys(&keys)
+                .and_then(|keys| rt.block_on(defs::update(&index, &keys, &conf::definitions_dir())))
+            {
+                Ok(updated) => {
+                    for (entry, status) in updated {
+                        let status = match status {
+                            UpdateStatus::New => "new",
+                            UpdateStatus::Updated => "updated",
+                            UpdateStatus::Unchanged => "unchanged",
+                        };
+                        println!("{:<40}{:<12}{}", entry.file, entry.version, status);
+                    }
+                    return Ok(());
+                }
+                Err(err) => err,
+            },
+            Err(err) => err.to_string(),
+        },
+        Command::Defs(DefsArgs {
+            command: DefsCommand::List,
+        }) => match defs::list(&conf::definitions_dir()) {
+            Ok(files) => {
+                for file in files {
+                    println!(
+                        "{:<40}{:<12}{:<16}{}",
+                        file.file,
+                        file.version.as_deref().unwrap_or("-"),
+                        format!("{} definitions", file.definitions),
+                        if file.signed { "signed" } else { "" }
+                    );
+                }
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+        Command::Defs(DefsArgs {
+            command: DefsCommand::SuggestExclusions,
+        }) => match conf::load_ui(config) {
+            Ok(conf) => return rt.block_on(suggest_exclusions(&conf)),
+            Err(err) => err.to_string(),
+        },
+        Command::Defs(DefsArgs {
+            command: DefsCommand::Sign { file, secret_key },
+        }) => match defs::sign(&file, &secret_key) {
+            Ok(sig_path) => {
+                println!("Signature written to {}", sig_path);
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+        Command::Defs(DefsArgs {
+            command: DefsCommand::Keygen { secret_key },
+        }) => match signing::keygen(&secret_key) {
+            Ok(public_key) => {
+                println!("public_key = \"{}\"", public_key);
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+        Command::SelfUpdate(args) => match rt.block_on(self_update(args.check_only)) {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        },
+        Command::Selftest(args) => match rt.block_on(selftest::run(&args)) {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        },
+        // The targets go to stdout, so the count goes to stderr
+        Command::Split(args) => match shard::run(&args) {
+            Ok(count) => {
+                eprintln!(
+                    "{} targets written (shard {}/{})",
+                    count, args.shard, args.shards
+                );
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+        Command::Convert(args) => match convert::run(&args) {
+            Ok(count) => {
+                println!("{} records written to {}", count, args.output);
+                return Ok(());
+            }
+            Err(err) => err,
+        },
+    };
+
+    eprintln!("[{}] {}", "ERROR".red(), err);
+    Err(())
 }
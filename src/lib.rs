@@ -0,0 +1,53 @@
+// The modules are exposed to the benchmarks (benches/), the binary is src/main.rs
+#[macro_use]
+extern crate validator_derive;
+#[macro_use]
+extern crate rocket;
+
+pub mod asn;
+pub mod breaker;
+pub mod cdn;
+pub mod cli;
+pub mod conf;
+pub mod content;
+pub mod control;
+pub mod convert;
+pub mod db;
+pub mod dedup;
+pub mod defs;
+pub mod detector;
+pub mod domains;
+pub mod enrichment;
+pub mod error;
+pub mod har;
+pub mod iana;
+pub mod lachesis;
+mod monitor;
+pub mod net;
+pub mod oshint;
+pub mod page;
+pub mod pcap;
+pub mod permutation;
+pub mod persistence;
+pub mod plan;
+pub mod plugins;
+pub mod rdap;
+pub mod resolver;
+pub mod scope;
+pub mod script;
+pub mod selftest;
+pub mod shard;
+pub mod signing;
+pub mod sink;
+pub mod stats;
+pub mod stream;
+pub mod template;
+#[cfg(test)]
+mod test;
+pub mod trace;
+pub mod triage;
+pub mod update;
+pub mod validators;
+pub mod web;
+pub mod worker;
+pub mod zone;
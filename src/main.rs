@@ -1,26 +1,9 @@
-#[macro_use]
-extern crate clap;
-#[macro_use]
-extern crate validator_derive;
-#[macro_use]
-extern crate rocket;
-
-mod conf;
-mod db;
-mod detector;
-mod lachesis;
-mod net;
-mod stats;
-#[cfg(test)]
-mod test;
-mod validators;
-mod web;
-mod worker;
-
+use lachesis::lachesis;
 use unindent::unindent;
 
 fn main() {
-    println!(
+    // On stderr, stdout is kept for the results (e.g. --stdout-ndjson)
+    eprintln!(
         "{}",
         unindent(
             "
@@ -0,0 +1,248 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    thread,
+};
+
+use colored::Colorize;
+use serde_derive::Serialize;
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+use crate::{
+    cli::ScanArgs,
+    conf::{self, Conf},
+    detector::DetectorResponse,
+    lachesis, net,
+    plugins::Registry,
+    worker::PortsTarget,
+};
+
+const WEBHOOK_TIMEOUT: u64 = 30;
+
+// What a scan found, compared with the previous scan in monitoring mode
+#[derive(Debug, Default, Clone)]
+pub struct ScanSummary {
+    // Open ports of the scanned hosts (also the ones without open ports)
+    pub hosts: BTreeMap<String, BTreeSet<u16>>,
+    // Versions of the matching services, by (ip, port, service)
+    pub services: BTreeMap<(String, u16, String), String>,
+}
+
+impl ScanSummary {
+    pub fn add_ports(&mut self, ports_target: &PortsTarget) {
+        self.hosts
+            .entry(ports_target.ip.clone())
+            .or_default()
+            .extend(ports_target.open_ports());
+    }
+
+    pub fn add_service(&mut self, res: &DetectorResponse) {
+        self.services.insert(
+            (res.target.ip.clone(), res.target.port, res.service.clone()),
+            res.version.clone(),
+        );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    NewHost {
+        ip: String,
+        ports: Vec<u16>,
+    },
+    NewPort {
+        ip: String,
+        port: u16,
+    },
+    ClosedPort {
+        ip: String,
+        port: u16,
+    },
+    // A host with open ports in the previous scan and none in this one
+    DisappearedHost {
+        ip: String,
+    },
+    NewService {
+        ip: String,
+        port: u16,
+        service: String,
+        version: String,
+    },
+    ChangedVersion {
+        ip: String,
+        port: u16,
+        service: String,
+        from: String,
+        to: String,
+    },
+}
+
+impl Change {
+    fn describe(&self) -> String {
+        match self {
+            Change::NewHost { ip, ports } => format!("{} new host, open ports: {:?}", ip, ports),
+            Change::NewPort { ip, port } => format!("{}:{} new open port", ip, port),
+            Change::ClosedPort { ip, port } => format!("{}:{} port closed", ip, port),
+            Change::DisappearedHost { ip } => format!("{} host disappeared", ip),
+            Change::NewService {
+                ip,
+                port,
+                service,
+                version,
+            } => format!("{}:{} new service {} {}", ip, port, service, version),
+            Change::ChangedVersion {
+                ip,
+                port,
+                service,
+                from,
+                to,
+            } => format!(
+                "{}:{} {} version changed from {} to {}",
+                ip, port, service, from, to
+            ),
+        }
+    }
+}
+
+// Changes between two scans. Only the hosts scanned by both are compared (e.g. a dataset scanned
+// up to max-targets picks different hosts every time), the other ones are new hosts
+pub fn diff(previous: &ScanSummary, current: &ScanSummary) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    for (ip, ports) in &current.hosts {
+        match previous.hosts.get(ip) {
+            None if !ports.is_empty() => changes.push(Change::NewHost {
+                ip: ip.clone(),
+                ports: ports.iter().cloned().collect(),
+            }),
+            None => (),
+            Some(previous_ports) if !previous_ports.is_empty() && ports.is_empty() => {
+                changes.push(Change::DisappearedHost { ip: ip.clone() })
+            }
+            Some(previous_ports) => {
+                for port in ports.difference(previous_ports) {
+                    changes.push(Change::NewPort {
+                        ip: ip.clone(),
+                        port: *port,
+                    });
+                }
+                for port in previous_ports.difference(ports) {
+                    changes.push(Change::ClosedPort {
+                        ip: ip.clone(),
+                        port: *port,
+                    });
+                }
+            }
+        }
+    }
+
+    for ((ip, port, service), version) in &current.services {
+        match previous.services.get(&(ip.clone(), *port, service.clone())) {
+            None => changes.push(Change::NewService {
+                ip: ip.clone(),
+                port: *port,
+                service: service.clone(),
+                version: version.clone(),
+            }),
+            Some(previous_version) if previous_version != version => {
+                changes.push(Change::ChangedVersion {
+                    ip: ip.clone(),
+                    port: *port,
+                    service: service.clone(),
+                    from: previous_version.clone(),
+                    to: version.clone(),
+                })
+            }
+            Some(_) => (),
+        }
+    }
+
+    changes
+}
+
+// Status messages, on stderr when stdout is kept for the findings (--stdout-ndjson)
+fn log(conf: &Conf, message: String) {
+    if conf.stdout_ndjson {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+// Logs the changes and sends them to the webhook (if any)
+async fn alert(conf: &Conf, changes: &[Change]) {
+    for change in changes {
+        if conf.json_logs || conf.stdout_ndjson {
+            let mut line = json!(change);
+            line["type"] = json!("change");
+            println!("{}", line);
+        } else {
+            println!("[{}] {}", "CHANGE".yellow(), change.describe());
+        }
+    }
+
+    if let Some(webhook) = &conf.webhook {
+        if changes.is_empty() {
+            return;
+        }
+        let body = json!({ "changes": changes });
+        if let Err(err) = net::post_json(webhook, &body, WEBHOOK_TIMEOUT).await {
+            eprintln!("[{}] Webhook error: {}", "ERROR".red(), err);
+        }
+    }
+}
+
+// Scans the targets every interval (the conf is loaded again before every scan, e.g. to resolve
+// the domains and asns again), and reports the changes since the previous scan
+pub fn run(
+    rt: &Runtime,
+    conf: Conf,
+    args: ScanArgs,
+    config: Option<&str>,
+    registry: Arc<Registry>,
+) -> Result<(), ()> {
+    let interval = match conf.monitor {
+        Some(interval) => interval,
+        None => return Err(()),
+    };
+
+    let mut previous: Option<ScanSummary> = None;
+    let mut next_conf = Some(conf);
+    loop {
+        let conf = match next_conf.take() {
+            Some(conf) => conf,
+            None => match conf::load_with_registry(args.clone(), config, &registry) {
+                Ok(conf) => conf,
+                Err(err) => {
+                    eprintln!("[{}] {}", "ERROR".red(), err);
+                    thread::sleep(interval);
+                    continue;
+                }
+            },
+        };
+
+        // A failed scan (e.g. db unreachable) is not compared
+        if let Ok(summary) = rt.block_on(lachesis::run_worker(&conf, registry.clone())) {
+            match &previous {
+                Some(previous) => rt.block_on(alert(&conf, &diff(previous, &summary))),
+                None => log(
+                    &conf,
+                    format!("[{}] First scan completed (baseline)", "MONITOR".blue()),
+                ),
+            }
+            previous = Some(summary);
+        }
+
+        log(
+            &conf,
+            format!(
+                "[{}] Next scan in {}s",
+                "MONITOR".blue(),
+                interval.as_secs()
+            ),
+        );
+        thread::sleep(interval);
+    }
+}
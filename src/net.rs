@@ -1,25 +1,230 @@
 use std::{
-    net::SocketAddr,
-    time::{Duration, Instant},
+    error::Error as StdError,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
 
 use hyper::{
-    client::{Client, HttpConnector},
-    Body, Method, Request, Uri,
+    body::HttpBody,
+    client::{
+        connect::{Connect, Connected, Connection},
+        Client, HttpConnector,
+    },
+    service::Service,
+    Body, Method, Request, Response, Uri,
 };
-use hyper_tls::HttpsConnector;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use regex::Regex;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::mpsc::Sender,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{lookup_host, TcpSocket, TcpStream, UdpSocket},
+    sync::mpsc::{self, Sender},
     time,
 };
 use tokio_native_tls::TlsConnector;
+use tracing::instrument;
 
-use super::worker::{PortStatus, PortTarget, ReqTarget, WorkerMessage};
+use crate::{
+    content,
+    error::{Error, FailClass, Result, TimeoutPhase},
+    har::HarRequest,
+    pcap::Capture,
+    plugins::BoxFuture,
+    worker::{PortStatus, PortTarget, ReqTarget, WorkerMessage},
+};
+
+// Connects from the configured source address, if any
+pub async fn connect(addr: &SocketAddr, source_ip: Option<IpAddr>) -> io::Result<TcpStream> {
+    match source_ip {
+        Some(ip) => {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.bind(SocketAddr::new(ip, 0))?;
+            socket.connect(*addr).await
+        }
+        None => TcpStream::connect(addr).await,
+    }
+}
+
+// Delay before the next address of a name is tried while the previous attempt is still pending
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+// Resolved addresses of a name in connection order (RFC 8305): the families alternate, starting
+// with the one of the first address. With a source address only its family is reachable
+pub fn happy_eyeballs_order(addrs: Vec<SocketAddr>, source_ip: Option<IpAddr>) -> Vec<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| source_ip.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()))
+        .collect();
+    let first_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_ipv6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut ordered = Vec::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// Connects to the first address answering (happy eyeballs): each attempt starts when the previous
+// one fails or after HAPPY_EYEBALLS_DELAY, the pending ones are dropped with the first connected
+pub async fn connect_any(
+    addrs: Vec<SocketAddr>,
+    source_ip: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let mut addrs = happy_eyeballs_order(addrs, source_ip).into_iter();
+    let (tx, mut rx) = mpsc::channel(addrs.len().max(1));
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    let (mut next, mut pending) = (addrs.next(), 0);
+    loop {
+        if let Some(addr) = next.take() {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _ = tx.send(connect(&addr, source_ip).await).await;
+            });
+            pending += 1;
+        }
+        if pending == 0 {
+            return Err(last_error);
+        }
+        let more = addrs.len() > 0;
+        tokio::select! {
+            Some(result) = rx.recv() => {
+                pending -= 1;
+                match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_error = e,
+                }
+                next = addrs.next();
+            }
+            _ = time::sleep(HAPPY_EYEBALLS_DELAY), if more => next = addrs.next(),
+        }
+    }
+}
+
+// Binds a UDP socket to the configured source address, if any
+pub async fn bind_udp(source_ip: Option<IpAddr>) -> io::Result<UdpSocket> {
+    let ip = source_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    UdpSocket::bind(SocketAddr::new(ip, 0)).await
+}
+
+// Minimal TLS 1.2 ClientHello (with the SNI extension for the domains), enough for a TLS server
+// to answer with a ServerHello or an alert
+fn client_hello(domain: &str) -> Vec<u8> {
+    let mut extensions: Vec<u8> = Vec::new();
+    if !domain.is_empty() && domain.len() < 256 {
+        let name = domain.as_bytes();
+        let len = name.len() as u16;
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(len + 5).to_be_bytes());
+        extensions.extend_from_slice(&(len + 3).to_be_bytes());
+        extensions.push(0x00);
+        extensions.extend_from_slice(&len.to_be_bytes());
+        extensions.extend_from_slice(name);
+    }
+    // Supported groups (x25519, secp256r1, secp384r1) and uncompressed point format
+    extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x08, 0x00, 0x06, 0x00, 0x1d, 0x00, 0x17]);
+    extensions.extend_from_slice(&[0x00, 0x18, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+    // Signature algorithms (rsa/ecdsa/rsa-pss with sha256/384/512, rsa/ecdsa with sha1)
+    extensions.extend_from_slice(&[0x00, 0x0d, 0x00, 0x18, 0x00, 0x16]);
+    extensions.extend_from_slice(&[0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x04, 0x03, 0x05, 0x03]);
+    extensions.extend_from_slice(&[0x06, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x02, 0x01]);
+    extensions.extend_from_slice(&[0x02, 0x03]);
+
+    let ciphers: &[u8] = &[
+        0xc0, 0x2f, 0xc0, 0x30, 0xc0, 0x2b, 0xc0, 0x2c, 0xcc, 0xa8, 0xcc, 0xa9, 0xc0, 0x13, 0xc0,
+        0x14, 0x00, 0x9c, 0x00, 0x9d, 0x00, 0x2f, 0x00, 0x35, 0x00, 0x0a,
+    ];
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend((0..32).map(|_| rand::random::<u8>()));
+    hello.push(0x00);
+    hello.extend_from_slice(&(ciphers.len() as u16).to_be_bytes());
+    hello.extend_from_slice(ciphers);
+    hello.extend_from_slice(&[0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
 
-pub async fn test_port(ip: String, port: u16, timeout_millis: u64) -> PortTarget {
-    let addr = format!("{}:{}", ip, port).parse::<SocketAddr>().unwrap();
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+// Whether the port speaks TLS, by the first bytes of the answer to a ClientHello: a handshake or
+// alert record is TLS, anything else (e.g. an HTTP 400 error) is plain text. None when there's no
+// answer (connection error, closed or timed out), the port could speak both
+#[instrument(level = "debug", skip(domain, timeout, source_ip))]
+pub async fn sniff_tls(
+    ip: &str,
+    port: u16,
+    domain: &str,
+    timeout: u64,
+    source_ip: Option<IpAddr>,
+) -> Option<bool> {
+    let addr = socket_addr(ip, port).ok()?;
+    let sniff = async {
+        let mut stream = connect(&addr, source_ip).await.ok()?;
+        stream.write_all(&client_hello(domain)).await.ok()?;
+        let mut answer = [0; 3];
+        let n = stream.read(&mut answer).await.ok()?;
+        match &answer[..n] {
+            [] => None,
+            [0x16, 0x03, ..] | [0x15, 0x03, ..] => Some(true),
+            [0x16] | [0x15] => Some(true),
+            _ => Some(false),
+        }
+    };
+    time::timeout(Duration::from_secs(timeout), sniff)
+        .await
+        .ok()
+        .flatten()
+}
+
+// Seconds an idle keep-alive connection is kept in the client pool
+const POOL_IDLE_TIMEOUT: u64 = 5;
+
+// Redirects followed by the requests to the public APIs
+const MAX_REDIRECTS: usize = 5;
+// Body of the responses of the public APIs (the largest ones are the release binaries and the
+// crt.sh responses of the large domains)
+const MAX_FETCH_BYTES: usize = 128 * 1024 * 1024;
+
+pub fn socket_addr(ip: &str, port: u16) -> Result<SocketAddr> {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => Err(Error::InvalidAddress(ip.to_string())),
+    }
+}
+
+// The connection of an open port is returned too, to be reused by the first tcp probe
+#[instrument(level = "debug", skip(source_ip))]
+pub async fn test_port(
+    ip: String,
+    port: u16,
+    timeout_millis: u64,
+    source_ip: Option<IpAddr>,
+) -> Result<(PortTarget, Option<TcpStream>)> {
+    let addr = socket_addr(&ip, port)?;
     let mut port_target = PortTarget {
         port,
         status: PortStatus::Closed,
@@ -28,42 +233,379 @@ pub async fn test_port(ip: String, port: u16, timeout_millis: u64) -> PortTarget
 
     match time::timeout(
         Duration::from_millis(timeout_millis),
-        TcpStream::connect(&addr),
+        connect(&addr, source_ip),
     )
     .await
     {
         Ok(s) => match s {
-            Ok(_) => {
+            Ok(stream) => {
                 port_target.status = PortStatus::Open;
-                port_target
+                Ok((port_target, Some(stream)))
             }
-            Err(_) => port_target,
+            Err(_) => Ok((port_target, None)),
         },
         Err(_) => {
             port_target.status = PortStatus::Timedout;
-            port_target
+            Ok((port_target, None))
+        }
+    }
+}
+
+// Deadlines of the phases of a request: the tcp connection, the tls handshake, the first byte of
+// the response (connection included) and the whole request. The phases without their own
+// deadline are bound by the total one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub tls: Duration,
+    pub first_byte: Duration,
+    pub total: Duration,
+}
+
+impl Timeouts {
+    pub fn new(total: Duration) -> Self {
+        Timeouts {
+            connect: total,
+            tls: total,
+            first_byte: total,
+            total,
+        }
+    }
+
+    // The longest deadline of each phase (e.g. of the definitions sharing a request)
+    pub fn longest(self, other: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.max(other.connect),
+            tls: self.tls.max(other.tls),
+            first_byte: self.first_byte.max(other.first_byte),
+            total: self.total.max(other.total),
         }
     }
 }
 
-pub fn build_https_client() -> Client<HttpsConnector<HttpConnector>> {
+// Timing breakdown of an http/s request (ms): the dns lookup, the tcp connection and the tls
+// handshake of its connection (none when a keep-alive connection was reused, or for the ips, not
+// resolved), and the first byte of the response since the request was sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestTimings {
+    pub dns: Option<u64>,
+    pub connect: Option<u64>,
+    pub tls: Option<u64>,
+    pub ttfb: Option<u64>,
+}
+
+impl RequestTimings {
+    pub fn phases(&self) -> [(&'static str, Option<u64>); 4] {
+        [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("tls", self.tls),
+            ("ttfb", self.ttfb),
+        ]
+    }
+
+    // Finding attributes (--timing-attributes), e.g. ttfb_ms
+    pub fn attributes(&self) -> Vec<(String, String)> {
+        self.phases()
+            .iter()
+            .filter_map(|(phase, ms)| ms.map(|ms| (format!("{}_ms", phase), ms.to_string())))
+            .collect()
+    }
+}
+
+fn millis(since: Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+// Timings of a connection of the TimedConnector, in the extensions of all its responses. Only the
+// first one reports them, the next ones reused the connection
+#[derive(Debug, Clone)]
+struct ConnTimings {
+    timings: RequestTimings,
+    reported: Arc<AtomicBool>,
+}
+
+// Client of the http/s probes, to try other client stacks. Implemented by the hyper client with
+// any connector (e.g. a mocked one in the tests), only the TimedConnector reports the timings of
+// its connections
+pub trait HttpClient: Clone + Send + Sync + 'static {
+    fn send(&self, request: Request<Body>) -> BoxFuture<'static, hyper::Result<Response<Body>>>;
+
+    // The dns, connect and tls timings of the connection of the response, if opened for it
+    fn connection_timings(&self, response: &Response<Body>) -> RequestTimings;
+}
+
+impl<C> HttpClient for Client<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    fn send(&self, request: Request<Body>) -> BoxFuture<'static, hyper::Result<Response<Body>>> {
+        Box::pin(self.request(request))
+    }
+
+    fn connection_timings(&self, response: &Response<Body>) -> RequestTimings {
+        match response.extensions().get::<ConnTimings>() {
+            Some(conn) if !conn.reported.swap(true, Ordering::SeqCst) => conn.timings,
+            _ => RequestTimings::default(),
+        }
+    }
+}
+
+// Connection of the TimedConnector, with its timings
+pub struct TimedStream {
+    stream: MaybeHttpsStream<TcpStream>,
+    timings: ConnTimings,
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl Connection for TimedStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected().extra(self.timings.clone())
+    }
+}
+
+// Connector of the http/s probes: the dns lookup (names only), the tcp connection and the tls
+// handshake with their own deadlines, failing with the phase that timed out (see
+// TimeoutPhase::from_hyper). The duration of each phase is kept with the connection
+#[derive(Clone)]
+pub struct TimedConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    source_ip: Option<IpAddr>,
+    connect_timeout: Duration,
+    tls_timeout: Duration,
+}
+
+impl Service<Uri> for TimedConnector {
+    type Response = TimedStream;
+    type Error = Box<dyn StdError + Send + Sync>;
+    type Future = BoxFuture<'static, std::result::Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let https = uri.scheme_str() == Some("https");
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let mut http = self.http.clone();
+        let tls = self.tls.clone();
+        let source_ip = self.source_ip;
+        let (connect_timeout, tls_timeout) = (self.connect_timeout, self.tls_timeout);
+        Box::pin(async move {
+            let mut timings = RequestTimings::default();
+
+            // The names are resolved here to time the lookup and connect to all their addresses,
+            // the tls handshake still gets them
+            let addrs = if host.parse::<IpAddr>().is_err() {
+                let started = Instant::now();
+                let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+                let addrs: Vec<SocketAddr> =
+                    time::timeout(connect_timeout, lookup_host((host.as_str(), port)))
+                        .await
+                        .map_err(|_| TimeoutPhase::Connect)?
+                        .map_err(|e| io::Error::new(e.kind(), format!("dns error: {}", e)))?
+                        .collect();
+                if addrs.is_empty() {
+                    return Err(
+                        io::Error::new(io::ErrorKind::NotFound, "dns error: no addresses").into(),
+                    );
+                }
+                timings.dns = Some(millis(started));
+                Some(addrs)
+            } else {
+                None
+            };
+
+            let started = Instant::now();
+            let tcp = match addrs {
+                Some(addrs) => time::timeout(connect_timeout, connect_any(addrs, source_ip))
+                    .await
+                    .map_err(|_| TimeoutPhase::Connect)??,
+                None => time::timeout(connect_timeout, http.call(uri))
+                    .await
+                    .map_err(|_| TimeoutPhase::Connect)??,
+            };
+            timings.connect = Some(millis(started));
+            let stream = if https {
+                let started = Instant::now();
+                let tls = time::timeout(tls_timeout, tls.connect(&host, tcp))
+                    .await
+                    .map_err(|_| TimeoutPhase::Tls)??;
+                timings.tls = Some(millis(started));
+                MaybeHttpsStream::Https(tls)
+            } else {
+                MaybeHttpsStream::Http(tcp)
+            };
+            Ok(TimedStream {
+                stream,
+                timings: ConnTimings {
+                    timings,
+                    reported: Arc::new(AtomicBool::new(false)),
+                },
+            })
+        })
+    }
+}
+
+pub type HttpsClient = Client<TimedConnector>;
+
+pub fn build_https_client(source_ip: Option<IpAddr>, timeouts: &Timeouts) -> HttpsClient {
     // TODOs:
     // - Tweak connectors and client configuration
     // - Try using rustls instead of native_tls as TLS connector
     let mut http = HttpConnector::new();
-    //http.set_connect_timeout(Some(Duration::from_millis(1000)));
     http.enforce_http(false);
+    http.set_local_address(source_ip);
     let tls_connector = native_tls::TlsConnector::builder()
         .danger_accept_invalid_certs(true)
         .build()
         .unwrap();
-    let tls_connector = TlsConnector::from(tls_connector);
-    let https = HttpsConnector::from((http, tls_connector));
+    let connector = TimedConnector {
+        http,
+        tls: TlsConnector::from(tls_connector),
+        source_ip,
+        connect_timeout: timeouts.connect,
+        tls_timeout: timeouts.tls,
+    };
+    // A single keep-alive connection per host and port, reused by all the requests of a target
+    // and closed shortly after the target is completed
     Client::builder()
-        //.pool_idle_timeout(Duration::from_millis(1250))
+        .pool_max_idle_per_host(1)
+        .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT))
         //.http2_keep_alive_timeout(Duration::from_millis(1000))
         //.retry_canceled_requests(false)
-        .build(https)
+        .build(connector)
+}
+
+// Location of a redirect, absolute or relative to the uri of the request (e.g. /path, page?q=1)
+pub fn redirect_uri(uri: &Uri, location: &str) -> std::result::Result<Uri, String> {
+    let scheme = uri.scheme_str().unwrap_or("https");
+    let resolved = match location.parse::<Uri>() {
+        Ok(absolute) if absolute.scheme().is_some() => return Ok(absolute),
+        _ if location.starts_with("//") => format!("{}:{}", scheme, location),
+        _ => {
+            let authority = uri.authority().map(|a| a.as_str()).unwrap_or_default();
+            if location.starts_with('/') {
+                format!("{}://{}{}", scheme, authority, location)
+            } else {
+                // Next to the current path
+                let path = uri.path();
+                let dir = &path[..path.rfind('/').map_or(0, |idx| idx + 1)];
+                let dir = if dir.is_empty() { "/" } else { dir };
+                format!("{}://{}{}{}", scheme, authority, dir, location)
+            }
+        }
+    };
+    resolved
+        .parse()
+        .map_err(|e| format!("Invalid redirect url: {}", e))
+}
+
+// Body of a GET request to a public API (e.g. the targets sources), verifying the certificates.
+// The redirects are followed (e.g. the RDAP bootstrap service). Some APIs (e.g. GitHub) refuse the
+// requests without a user agent. The timeout is of the whole exchange, the body included
+pub async fn fetch(url: &str, timeout: u64) -> std::result::Result<Vec<u8>, String> {
+    let mut uri: Uri = url.parse().map_err(|e| format!("Invalid url: {}", e))?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+
+    let exchange = async {
+        let mut redirects = 0;
+        let res = loop {
+            let req = Request::get(uri.clone())
+                .header(
+                    hyper::header::USER_AGENT,
+                    concat!("lachesis/", env!("CARGO_PKG_VERSION")),
+                )
+                .body(Body::empty())
+                .map_err(|e| format!("Invalid request: {}", e))?;
+            let res = client
+                .request(req)
+                .await
+                .map_err(|e| format!("Request error: {}", e))?;
+            let location = res
+                .headers()
+                .get(hyper::header::LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if res.status().is_redirection() && redirects < MAX_REDIRECTS => {
+                    uri = redirect_uri(&uri, location)?;
+                    redirects += 1;
+                }
+                _ => break res,
+            }
+        };
+        if !res.status().is_success() {
+            return Err(format!("Unexpected response status {}", res.status()));
+        }
+
+        let mut body = res.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| format!("Response read error: {}", e))?;
+            if bytes.len() + chunk.len() > MAX_FETCH_BYTES {
+                return Err(format!("Response over {} bytes", MAX_FETCH_BYTES));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok::<_, String>(bytes)
+    };
+
+    match time::timeout(Duration::from_secs(timeout), exchange).await {
+        Ok(result) => result,
+        Err(_) => Err("Request timed out".to_string()),
+    }
+}
+
+// POST of a JSON body (e.g. the webhooks), verifying the certificates
+pub async fn post_json(
+    url: &str,
+    body: &serde_json::Value,
+    timeout: u64,
+) -> std::result::Result<(), String> {
+    let request = Request::post(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|e| format!("Invalid url: {}", e))?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+
+    match time::timeout(Duration::from_secs(timeout), client.request(request)).await {
+        Ok(Ok(res)) if res.status().is_success() => Ok(()),
+        Ok(Ok(res)) => Err(format!("Unexpected response status {}", res.status())),
+        Ok(Err(err)) => Err(format!("Request error: {}", err)),
+        Err(_) => Err("Request timed out".to_string()),
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -74,173 +616,999 @@ pub struct HttpsOptions {
     pub payload: String,
 }
 
-pub async fn http_s(
-    tx: Sender<WorkerMessage>,
-    client: Client<HttpsConnector<HttpConnector>>,
-    mut target: ReqTarget,
-    options: HttpsOptions,
-    user_agent: String,
-    timeout: u64,
-) {
-    let uri: Uri = format!(
-        "{}://{}:{}{}",
-        target.protocol, target.ip, target.port, options.path
+// User agents of the requests: --user-agent, or the --user-agent-file ones in turn (one per
+// request), so that a large scan doesn't send a single fingerprintable value
+pub struct UserAgents {
+    agents: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl UserAgents {
+    pub fn new(user_agent: &str, rotation: &[String]) -> Self {
+        let agents = if rotation.is_empty() {
+            vec![user_agent.to_string()]
+        } else {
+            rotation.to_vec()
+        };
+        UserAgents {
+            agents,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn next(&self) -> &str {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.agents[i % self.agents.len()]
+    }
+}
+
+// Standard base64 (with padding)
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Authorization header value of HTTP Basic authentication
+pub fn basic_auth(user: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64(format!("{}:{}", user, password).as_bytes())
     )
-    .parse()
-    .unwrap();
+}
+
+// Names of the headers, parameters and JSON keys holding secrets
+const SECRET_NAME: &str =
+    r"[\w.-]*(?:password|passwd|pwd|secret|token|api[_-]?key|access[_-]?key|session)[\w.-]*";
+const SECRET_MASK: &str = "********";
+
+// Headers, parameters and JSON values regexes of the secrets, compiled once (every saved request
+// is masked)
+static SECRET_RES: OnceLock<[Regex; 3]> = OnceLock::new();
+
+// A request as saved with the findings: the values of the authentication headers (the scheme
+// kept), of the cookies and of the headers, parameters and JSON keys named like a secret are masked
+pub fn mask_secrets(request: &str) -> String {
+    let [headers_re, params_re, json_re] = SECRET_RES.get_or_init(|| {
+        [
+            Regex::new(&format!(
+                r"(?im)^((?:proxy-)?authorization:[ \t]*(?:[\w-]+[ \t]+)?|(?:cookie|{}):[ \t]*)[^\r\n]+",
+                SECRET_NAME
+            ))
+            .unwrap(),
+            Regex::new(&format!(r"(?i)({}=)[^&\s]*", SECRET_NAME)).unwrap(),
+            Regex::new(&format!(
+                r#"(?i)("{}"\s*:\s*")(?:[^"\\]|\\.)*""#,
+                SECRET_NAME
+            ))
+            .unwrap(),
+        ]
+    });
+
+    let masked = headers_re.replace_all(request, format!("${{1}}{}", SECRET_MASK).as_str());
+    let masked = params_re.replace_all(&masked, format!("${{1}}{}", SECRET_MASK).as_str());
+    json_re
+        .replace_all(&masked, format!("${{1}}{}\"", SECRET_MASK).as_str())
+        .to_string()
+}
+
+// One user agent per line, the empty lines and the comments (#) are skipped
+pub fn parse_user_agents(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// The User-Agent header of the options (e.g. the user_agent of a definition) replaces the given
+// one
+pub fn build_request(
+    target: &ReqTarget,
+    options: HttpsOptions,
+    user_agent: &str,
+) -> Result<Request<Body>> {
+    let addr = socket_addr(&target.ip, target.port)?;
+    let uri: Uri = format!("{}://{}{}", target.protocol, addr, options.path)
+        .parse()
+        .map_err(|_| Error::InvalidRequest(format!("invalid path {}", options.path)))?;
+    let method = Method::from_bytes(options.method.as_bytes())
+        .map_err(|_| Error::InvalidRequest(format!("invalid method {}", options.method)))?;
 
     let mut request = Request::builder()
         .uri(uri)
-        .method(Method::from_bytes(options.method.as_bytes()).unwrap())
+        .method(method)
         .header("Host", target.domain.clone())
-        .header("User-Agent", user_agent.clone())
         .header("Accept", "*/*");
+    if !options
+        .headers
+        .iter()
+        .any(|(header, _)| header.eq_ignore_ascii_case("user-agent"))
+    {
+        request = request.header("User-Agent", user_agent);
+    }
 
     for (header, value) in options.headers {
         request = request.header(&header, &value);
     }
 
-    let request = request.body(Body::from(options.payload)).unwrap();
+    Ok(request.body(Body::from(options.payload))?)
+}
+
+// GET of another path of a target (e.g. robots.txt) outside of the probes, nothing is sent to the
+// receiver loop. The body is returned for the 200 responses only
+pub async fn http_get<C: HttpClient>(
+    client: &C,
+    target: &ReqTarget,
+    path: &str,
+    user_agent: &str,
+    timeout: u64,
+    max_bytes: usize,
+) -> Option<String> {
+    let options = HttpsOptions {
+        method: "GET".to_string(),
+        path: path.to_string(),
+        headers: Vec::new(),
+        payload: String::new(),
+    };
+    let request = build_request(target, options, user_agent).ok()?;
 
-    let time = Duration::from_secs(timeout);
     let request = async {
-        let (parts, body) = match client.request(request).await {
-            Ok(r) => r.into_parts(),
-            Err(e) => {
-                tx.send(WorkerMessage::Fail(
-                    target.clone(),
-                    "Request error".to_string(),
-                    Some(e.to_string()),
-                ))
-                .await
-                .unwrap();
-                return;
+        let (parts, mut body) = client.send(request).await.ok()?.into_parts();
+        if parts.status.as_u16() != 200 {
+            return None;
+        }
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.ok()?;
+            let remaining = max_bytes - bytes.len();
+            if chunk.len() > remaining {
+                bytes.extend_from_slice(&chunk[..remaining]);
+                break;
             }
-        };
+            bytes.extend_from_slice(&chunk);
+        }
+        Some(String::from_utf8_lossy(&bytes).to_string())
+    };
 
-        match hyper::body::to_bytes(body).await {
-            Ok(b) => {
-                // Merge response's headers and body (UTF-8)
-                let mut raw_content = format!("{:?} {}\r\n", parts.version, parts.status);
-                for (name, value) in &parts.headers {
-                    raw_content = format!(
-                        "{}{}: {}\r\n",
-                        raw_content,
-                        name,
-                        value.to_str().unwrap_or("")
-                    );
-                }
-                raw_content = format!("{}\r\n{}", raw_content, String::from_utf8_lossy(&b));
+    time::timeout(Duration::from_secs(timeout), request)
+        .await
+        .ok()
+        .flatten()
+}
 
-                target.response = raw_content;
+// HEAD / of a target (--head-first), nothing is sent to the receiver loop either. The status and
+// the headers, none on errors and timeouts
+pub async fn http_head<C>(
+    client: &C,
+    target: &ReqTarget,
+    user_agent: &str,
+    timeout: u64,
+) -> Option<(u16, Vec<(String, String)>)>
+where
+    C: HttpClient,
+{
+    let options = HttpsOptions {
+        method: "HEAD".to_string(),
+        path: "/".to_string(),
+        headers: Vec::new(),
+        payload: String::new(),
+    };
+    let request = build_request(target, options, user_agent).ok()?;
 
-                tx.send(WorkerMessage::Response(target.clone()))
-                    .await
-                    .unwrap();
+    let res = time::timeout(Duration::from_secs(timeout), client.send(request))
+        .await
+        .ok()?
+        .ok()?;
+    let headers = res
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
+        .collect();
+    Some((res.status().as_u16(), headers))
+}
+
+// Max simhash distance (bits) of two pages of the same virtual host
+pub const VHOST_DISTANCE: u32 = 6;
+
+// Response to a Host header of the vhost discovery (--vhost-wordlist). The host name is removed
+// from the redirect and the page first, the default virtual host often echoes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct VhostResponse {
+    pub status: u16,
+    pub location: Option<String>,
+    pub simhash: Option<i64>,
+}
+
+impl VhostResponse {
+    // Another status, redirect or page than the baseline (the answer to a random host name)
+    pub fn differs_from(&self, baseline: &VhostResponse) -> bool {
+        if self.status != baseline.status || self.location != baseline.location {
+            return true;
+        }
+        match (self.simhash, baseline.simhash) {
+            (Some(simhash), Some(baseline)) => {
+                content::distance(simhash, baseline) > VHOST_DISTANCE
             }
-            Err(e) => {
-                tx.send(WorkerMessage::Fail(
-                    target.clone(),
-                    "Response error".to_string(),
-                    Some(e.to_string()),
-                ))
-                .await
-                .unwrap();
+            (simhash, baseline) => simhash != baseline,
+        }
+    }
+}
+
+// GET / of a target with its domain as Host header (vhost discovery), nothing is sent to the
+// receiver loop. None on errors and timeouts
+pub async fn vhost_get<C>(
+    client: &C,
+    target: &ReqTarget,
+    user_agent: &str,
+    timeout: u64,
+    max_bytes: usize,
+) -> Option<VhostResponse>
+where
+    C: HttpClient,
+{
+    let options = HttpsOptions {
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        headers: Vec::new(),
+        payload: String::new(),
+    };
+    let request = build_request(target, options, user_agent).ok()?;
+
+    let request = async {
+        let (parts, mut body) = client.send(request).await.ok()?.into_parts();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.ok()?;
+            let remaining = max_bytes - bytes.len();
+            if chunk.len() > remaining {
+                bytes.extend_from_slice(&chunk[..remaining]);
+                break;
             }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        let host = target.domain.to_lowercase();
+        let page = ReqTarget {
+            body: String::from_utf8_lossy(&bytes)
+                .to_lowercase()
+                .replace(&host, ""),
+            ..ReqTarget::default()
         };
+        Some(VhostResponse {
+            status: parts.status.as_u16(),
+            location: parts.headers.get("location").map(|value| {
+                value
+                    .to_str()
+                    .unwrap_or("")
+                    .to_lowercase()
+                    .replace(&host, "")
+            }),
+            simhash: content::hash(&page).map(|hash| hash.simhash),
+        })
     };
 
-    if time::timeout(time, request).await.is_err() {
-        tx.send(WorkerMessage::Timeout(target.clone()))
-            .await
-            .unwrap();
+    time::timeout(Duration::from_secs(timeout), request)
+        .await
+        .ok()
+        .flatten()
+}
+
+// GET /favicon.ico of a web server, the hash of the icon (see favicon_hash). None on errors,
+// timeouts and missing icons. An icon over the max size isn't hashed (the hash would differ)
+pub async fn favicon_get<C>(
+    client: &C,
+    target: &ReqTarget,
+    user_agent: &str,
+    timeout: u64,
+    max_bytes: usize,
+) -> Option<i32>
+where
+    C: HttpClient,
+{
+    let options = HttpsOptions {
+        method: "GET".to_string(),
+        path: "/favicon.ico".to_string(),
+        headers: Vec::new(),
+        payload: String::new(),
+    };
+    let request = build_request(target, options, user_agent).ok()?;
+
+    let request = async {
+        let (parts, mut body) = client.send(request).await.ok()?.into_parts();
+        if !parts.status.is_success() {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.ok()?;
+            if bytes.len() + chunk.len() > max_bytes {
+                return None;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(favicon_hash(&bytes))
+        }
+    };
+
+    time::timeout(Duration::from_secs(timeout), request)
+        .await
+        .ok()
+        .flatten()
+}
+
+// Hash of a favicon as computed by Shodan: MurmurHash3 of its base64 encoding, with a newline
+// every 76 characters and at the end (Python's base64.encodebytes)
+pub fn favicon_hash(favicon: &[u8]) -> i32 {
+    let mut encoded = Vec::new();
+    for line in base64(favicon).as_bytes().chunks(76) {
+        encoded.extend_from_slice(line);
+        encoded.push(b'\n');
     }
+    murmur3_32(&encoded, 0) as i32
 }
 
-pub async fn tcp_custom(
-    tx: Sender<WorkerMessage>,
-    mut target: ReqTarget,
-    payload: String,
+// MurmurHash3 (x86, 32 bits)
+pub(crate) fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        hash ^= mix(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0, |k, (i, byte)| k | (*byte as u32) << (8 * i));
+        hash ^= mix(k);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+// Common name of the TLS certificate of a port (any certificate is accepted). None on errors and
+// timeouts
+pub async fn tls_common_name(
+    ip: &str,
+    port: u16,
+    domain: &str,
     timeout: u64,
-) {
-    let addr = match format!("{}:{}", target.ip, target.port).parse::<SocketAddr>() {
-        Ok(addr) => addr,
-        Err(_e) => {
-            tx.send(WorkerMessage::Fail(
-                target,
-                "Invalid address".to_string(),
-                None,
-            ))
+    source_ip: Option<IpAddr>,
+) -> Option<String> {
+    let addr = socket_addr(ip, port).ok()?;
+    let handshake = async {
+        let stream = connect(&addr, source_ip).await.ok()?;
+        let server_name = if domain.is_empty() { ip } else { domain };
+        let tls = build_tls_connector(&[])
+            .connect(server_name, stream)
             .await
-            .unwrap();
-            return;
+            .ok()?;
+        let certificate = tls.get_ref().peer_certificate().ok()??;
+        subject_common_name(&certificate.to_der().ok()?)
+    };
+    time::timeout(Duration::from_secs(timeout), handshake)
+        .await
+        .ok()
+        .flatten()
+}
+
+// Tag, content and rest of a DER element (definite lengths only)
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
         }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize);
+        (len, 2 + n)
     };
+    let end = header.checked_add(len)?;
+    Some((tag, data.get(header..end)?, &data[end..]))
+}
 
-    let to = Duration::from_secs(timeout);
-    let cb = async {
-        let mut stream = match TcpStream::connect(&addr).await {
-            Ok(s) => s,
-            Err(e) => {
-                tx.send(WorkerMessage::Fail(
-                    target.clone(),
-                    "TCP stream connection error".to_string(),
+// Common name of the subject of a certificate (DER), the last one if many
+pub(crate) fn subject_common_name(der: &[u8]) -> Option<String> {
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const OID: u8 = 0x06;
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (tag, certificate, _) = der_element(der)?;
+    let (tbs_tag, mut fields, _) = der_element(certificate)?;
+    if tag != SEQUENCE || tbs_tag != SEQUENCE {
+        return None;
+    }
+    // Version (optional), serial number, signature, issuer and validity, then the subject
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.2;
+    }
+    for _ in 0..4 {
+        fields = der_element(fields)?.2;
+    }
+    let (tag, mut subject, _) = der_element(fields)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    let mut common_name = None;
+    while !subject.is_empty() {
+        let (tag, mut names, rest) = der_element(subject)?;
+        if tag != SET {
+            return None;
+        }
+        subject = rest;
+        while !names.is_empty() {
+            let (_, name, rest) = der_element(names)?;
+            names = rest;
+            let (tag, oid, value) = der_element(name)?;
+            if tag == OID && oid == COMMON_NAME {
+                let (_, value, _) = der_element(value)?;
+                common_name = Some(String::from_utf8_lossy(value).to_string());
+            }
+        }
+    }
+    common_name
+}
+
+// Sends the response (or the failure) to the receiver loop, and also returns the response target.
+// The connect and tls deadlines are the ones of the connector of the client
+#[instrument(
+    level = "debug",
+    skip(tx, client, target, options, user_agent, timeouts, max_bytes),
+    fields(protocol = %target.protocol, ip = %target.ip, port = target.port, path = %options.path)
+)]
+// Any client (e.g. with a mocked connector in the tests), the probes use the https client of the
+// worker
+pub async fn http_s<C>(
+    tx: Sender<WorkerMessage>,
+    client: C,
+    mut target: ReqTarget,
+    options: HttpsOptions,
+    user_agent: String,
+    timeouts: Timeouts,
+    max_bytes: usize,
+) -> Option<ReqTarget>
+where
+    C: HttpClient,
+{
+    let payload = options.payload.clone();
+    let request = match build_request(&target, options, &user_agent) {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid request".to_string(),
                     Some(e.to_string()),
                 ))
-                .await
-                .unwrap();
-                return;
+                .await;
+            return None;
+        }
+    };
+
+    // The request as sent, for the HAR entries
+    let started = Instant::now();
+    let mut http_request = HarRequest {
+        method: request.method().to_string(),
+        url: request.uri().to_string(),
+        headers: request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
+            .collect(),
+        body: payload,
+        started: SystemTime::now(),
+        elapsed: Duration::default(),
+    };
+    target.sent_request = Some(http_request.raw());
+
+    let request = async {
+        let (parts, mut body) = match time::timeout(timeouts.first_byte, client.send(request)).await
+        {
+            Ok(Ok(r)) => {
+                let mut timings = client.connection_timings(&r);
+                timings.ttfb = Some(millis(started));
+                target.timings = Some(timings);
+                r.into_parts()
+            }
+            Err(_) => {
+                let _ = tx
+                    .send(WorkerMessage::Timeout(
+                        target.clone(),
+                        TimeoutPhase::FirstByte,
+                    ))
+                    .await;
+                return None;
+            }
+            Ok(Err(e)) => {
+                if let Some(phase) = TimeoutPhase::from_hyper(&e) {
+                    let _ = tx.send(WorkerMessage::Timeout(target.clone(), phase)).await;
+                    return None;
+                }
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        target.clone(),
+                        FailClass::from_hyper(&e),
+                        "Request error".to_string(),
+                        Some(e.to_string()),
+                    ))
+                    .await;
+                return None;
             }
         };
 
-        stream.writable().await.unwrap();
-        if let Err(e) = stream.write_all(payload.as_bytes()).await {
-            tx.send(WorkerMessage::Fail(
-                target.clone(),
-                "TCP stream write error".to_string(),
-                Some(e.to_string()),
-            ))
-            .await
-            .unwrap();
-            return;
+        // The body is read in chunks up to the max size, the rest is never downloaded. Not at all
+        // for the headers only requests: dropped unread, closing the connection
+        let mut bytes = Vec::new();
+        let mut read = Ok(());
+        if target.headers_only {
+            drop(body);
+        } else {
+            while let Some(chunk) = body.data().await {
+                match chunk {
+                    Ok(chunk) => {
+                        let remaining = max_bytes - bytes.len();
+                        if chunk.len() > remaining {
+                            bytes.extend_from_slice(&chunk[..remaining]);
+                            target.truncated = true;
+                            break;
+                        }
+                        bytes.extend_from_slice(&chunk);
+                    }
+                    Err(e) => {
+                        read = Err(e);
+                        break;
+                    }
+                }
+            }
         }
 
-        // TODO - configurable max response size
-        let mut response = vec![0; 10240];
-        let mut response_lenght = 0;
-        loop {
-            stream.readable().await.unwrap();
+        match read {
+            Ok(()) => {
+                target.status = Some(parts.status.as_u16());
+
+                // Keep headers and body (UTF-8) separated for the definitions matching only one
+                // of them, and also merge them in the raw response
+                for (name, value) in &parts.headers {
+                    target
+                        .headers
+                        .push((name.to_string(), value.to_str().unwrap_or("").to_string()));
+                }
+                target.body = String::from_utf8_lossy(&bytes).to_string();
 
-            match stream.read(&mut response).await {
-                Ok(n) if n == 0 => break,
-                Ok(n) => {
-                    response_lenght += n;
+                let mut raw_content = format!("{:?} {}\r\n", parts.version, parts.status);
+                for (name, value) in &target.headers {
+                    raw_content = format!("{}{}: {}\r\n", raw_content, name, value);
                 }
-                Err(e) => {
-                    tx.send(WorkerMessage::Fail(
+                raw_content = format!("{}\r\n{}", raw_content, target.body);
+
+                target.response = raw_content;
+                http_request.elapsed = started.elapsed();
+                target.http_request = Some(http_request);
+
+                let _ = tx.send(WorkerMessage::Response(target.clone())).await;
+                Some(target.clone())
+            }
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
                         target.clone(),
-                        "TCP stream read error".to_string(),
+                        FailClass::BodyRead,
+                        "Response error".to_string(),
                         Some(e.to_string()),
                     ))
-                    .await
-                    .unwrap();
-                    return;
-                }
-            };
+                    .await;
+                None
+            }
+        }
+    };
+
+    match time::timeout(timeouts.total, request).await {
+        Ok(response) => response,
+        Err(_) => {
+            let _ = tx
+                .send(WorkerMessage::Timeout(target.clone(), TimeoutPhase::Total))
+                .await;
+            None
         }
+    }
+}
 
-        if response_lenght > 0 {
-            response.truncate(response_lenght);
-            target.response = String::from_utf8_lossy(&response).to_string();
-            tx.send(WorkerMessage::Response(target.clone()))
+// Byte stream of a probe connection: a TCP stream, or an in-memory one in the tests
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn local_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Stream for TcpStream {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl Stream for tokio_native_tls::TlsStream<Box<dyn Stream>> {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().get_ref().get_ref().local_addr()
+    }
+}
+
+// Opens the connections of the tcp/custom and tls/custom probes
+pub trait Transport: Send + Sync {
+    fn connect<'a>(&'a self, addr: &'a SocketAddr) -> BoxFuture<'a, io::Result<Box<dyn Stream>>>;
+
+    // Runs on every connection before the payload (new or cached), e.g. the TLS handshake
+    fn handshake<'a>(
+        &'a self,
+        stream: Box<dyn Stream>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move { Ok(stream) })
+    }
+}
+
+// TCP connections from the configured source address, if any
+pub struct TcpTransport {
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Transport for TcpTransport {
+    fn connect<'a>(&'a self, addr: &'a SocketAddr) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let stream = connect(addr, self.source_ip).await?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+}
+
+// TCP connections wrapped in TLS (tls/custom). The certificates are not verified, the server name
+// is sent as SNI (unless it's an ip) and the ALPN protocols are offered, if any
+pub struct TlsTransport {
+    pub source_ip: Option<IpAddr>,
+    pub connector: TlsConnector,
+    pub server_name: String,
+}
+
+pub fn build_tls_connector(alpn: &[String]) -> TlsConnector {
+    let alpn: Vec<&str> = alpn.iter().map(String::as_str).collect();
+    let tls_connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .request_alpns(&alpn)
+        .build()
+        .unwrap();
+    TlsConnector::from(tls_connector)
+}
+
+impl Transport for TlsTransport {
+    fn connect<'a>(&'a self, addr: &'a SocketAddr) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let stream = connect(addr, self.source_ip).await?;
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        })
+    }
+
+    fn handshake<'a>(
+        &'a self,
+        stream: Box<dyn Stream>,
+    ) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        Box::pin(async move {
+            let tls = self
+                .connector
+                .connect(&self.server_name, stream)
                 .await
-                .unwrap();
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Box::new(tls) as Box<dyn Stream>)
+        })
+    }
+}
+
+// Port knocking: a connection attempt to every port of the sequence in order, `delay` apart (the
+// SYN is the knock, the ports are usually filtered or closed), the last one `delay` before the
+// probe. Every knock waits for the end of its window, so they are evenly spaced however fast the
+// connections fail
+pub async fn knock(
+    transport: &dyn Transport,
+    ip: &str,
+    ports: &[u16],
+    delay: Duration,
+) -> Result<()> {
+    for port in ports {
+        let addr = socket_addr(ip, *port)?;
+        let window = Instant::now() + delay;
+        let _ = time::timeout(delay, transport.connect(&addr)).await;
+        time::sleep_until(window.into()).await;
+    }
+    Ok(())
+}
+
+// Outcome of a single tcp/custom (or tls/custom) payload: the response or the failure (class, context, error)
+enum TcpOutcome {
+    // Response, whether it was truncated and the local address of the connection
+    Response(Vec<u8>, bool, Option<SocketAddr>),
+    Fail(FailClass, String, Option<String>),
+    Timeout(TimeoutPhase),
+}
+
+// Signature of the PROXY protocol v2 headers
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// PROXY protocol header of a connection from local to remote, "v1" (text) or "v2" (binary). The
+// real endpoints are declared, the peer sees the scanner itself as the client. Without the local
+// address (or with mixed families) the header declares an unknown/local connection
+pub fn proxy_header(version: &str, local: Option<SocketAddr>, remote: &SocketAddr) -> Vec<u8> {
+    let endpoints = local.filter(|local| local.is_ipv4() == remote.is_ipv4());
+    if version == "v1" {
+        return match endpoints {
+            Some(local) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if local.is_ipv4() { "TCP4" } else { "TCP6" },
+                local.ip(),
+                remote.ip(),
+                local.port(),
+                remote.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        };
+    }
+
+    // Version 2 and PROXY command (TCP over IPv4 or IPv6), or LOCAL command (unspecified family)
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    let mut addresses = Vec::new();
+    match (endpoints.map(|local| local.ip()), remote.ip()) {
+        (Some(IpAddr::V4(src)), IpAddr::V4(dst)) => {
+            header.extend_from_slice(&[0x21, 0x11]);
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
+        }
+        (Some(IpAddr::V6(src)), IpAddr::V6(dst)) => {
+            header.extend_from_slice(&[0x21, 0x21]);
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
         }
+        _ => header.extend_from_slice(&[0x20, 0x00]),
+    }
+    if let Some(local) = endpoints {
+        addresses.extend_from_slice(&local.port().to_be_bytes());
+        addresses.extend_from_slice(&remote.port().to_be_bytes());
+    }
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+// Whether the data starts with a PROXY protocol header (v1 or v2)
+pub fn is_proxy_header(data: &[u8]) -> bool {
+    data.starts_with(b"PROXY TCP4 ")
+        || data.starts_with(b"PROXY TCP6 ")
+        || data.starts_with(b"PROXY UNKNOWN")
+        || data.starts_with(PROXY_V2_SIGNATURE)
+}
+
+async fn tcp_exchange(
+    transport: &dyn Transport,
+    addr: &SocketAddr,
+    payload: &str,
+    cached: Option<Box<dyn Stream>>,
+    max_bytes: usize,
+    proxy_protocol: Option<&str>,
+    timeouts: &Timeouts,
+) -> TcpOutcome {
+    let first_byte_deadline = time::Instant::now() + timeouts.first_byte;
+    let stream = match cached {
+        Some(s) => s,
+        None => match time::timeout(timeouts.connect, transport.connect(addr)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                return TcpOutcome::Fail(
+                    FailClass::from_io(&e),
+                    "TCP stream connection error".to_string(),
+                    Some(e.to_string()),
+                )
+            }
+            Err(_) => return TcpOutcome::Timeout(TimeoutPhase::Connect),
+        },
+    };
+    let mut stream = match time::timeout(timeouts.tls, transport.handshake(stream)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            return TcpOutcome::Fail(
+                FailClass::Tls,
+                "TLS handshake error".to_string(),
+                Some(e.to_string()),
+            )
+        }
+        Err(_) => return TcpOutcome::Timeout(TimeoutPhase::Tls),
     };
 
-    if time::timeout(to, cb).await.is_err() {
-        tx.send(WorkerMessage::Timeout(target.clone()))
-            .await
-            .unwrap();
+    let local = stream.local_addr();
+
+    // The PROXY header and the payload in a single write, some load balancers expect the whole
+    // header in the first segment
+    let mut request = match proxy_protocol {
+        Some(version) => proxy_header(version, local, addr),
+        None => Vec::new(),
+    };
+    request.extend_from_slice(payload.as_bytes());
+    if let Err(e) = stream.write_all(&request).await {
+        return TcpOutcome::Fail(
+            FailClass::from_io(&e),
+            "TCP stream write error".to_string(),
+            Some(e.to_string()),
+        );
+    }
+
+    let mut response = Vec::new();
+    let mut chunk = vec![0; 4096];
+    loop {
+        // Only the first read has its own deadline, the next ones are bound by the total one
+        let read = if response.is_empty() {
+            match time::timeout_at(first_byte_deadline, stream.read(&mut chunk)).await {
+                Ok(read) => read,
+                Err(_) => return TcpOutcome::Timeout(TimeoutPhase::FirstByte),
+            }
+        } else {
+            stream.read(&mut chunk).await
+        };
+        match read {
+            Ok(n) if n == 0 => break,
+            Ok(n) => {
+                let remaining = max_bytes - response.len();
+                if n > remaining {
+                    response.extend_from_slice(&chunk[..remaining]);
+                    return TcpOutcome::Response(response, true, local);
+                }
+                response.extend_from_slice(&chunk[..n]);
+            }
+            Err(e) => {
+                return TcpOutcome::Fail(
+                    FailClass::BodyRead,
+                    "TCP stream read error".to_string(),
+                    Some(e.to_string()),
+                )
+            }
+        };
+    }
+
+    TcpOutcome::Response(response, false, local)
+}
+
+// Sends the payloads in order, stopping at the first one that gets a response. When none of them
+// gets a response, the outcome of the last one is reported. The first payload is sent over the
+// cached connection, if any (and sent again over a new one if that fails, e.g. closed by the peer,
+// when the budget of the host allows another request).
+// Every connection starts with the PROXY header of the target, if any, or with the TLS handshake of
+// the transport (tls/custom). The exchanged bytes (decrypted) are kept with the response when
+// captured (--pcap-matches)
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    level = "debug",
+    skip(tx, target, payloads, timeouts, transport, cached, retry_budget, max_bytes, capture),
+    fields(ip = %target.ip, port = target.port)
+)]
+pub async fn tcp_custom(
+    tx: Sender<WorkerMessage>,
+    mut target: ReqTarget,
+    payloads: Vec<String>,
+    timeouts: Timeouts,
+    transport: &dyn Transport,
+    mut cached: Option<Box<dyn Stream>>,
+    retry_budget: impl Future<Output = bool>,
+    max_bytes: usize,
+    capture: bool,
+) {
+    let addr = match socket_addr(&target.ip, target.port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid address".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return;
+        }
     };
+
+    let mut outcome = TcpOutcome::Response(Vec::new(), false, None);
+    let mut request = "";
+    let mut started = SystemTime::now();
+    let mut retry_budget = Some(retry_budget);
+    'payloads: for payload in &payloads {
+        let attempts = if cached.is_some() { 2 } else { 1 };
+        for attempt in 0..attempts {
+            if attempt > 0 && !retry_budget.take().unwrap().await {
+                return;
+            }
+            request = payload;
+            started = SystemTime::now();
+            let exchange = tcp_exchange(
+                transport,
+                &addr,
+                payload,
+                cached.take(),
+                max_bytes,
+                target.proxy_protocol.as_deref(),
+                &timeouts,
+            );
+            outcome = match time::timeout(timeouts.total, exchange).await {
+                Ok(outcome) => outcome,
+                Err(_) => TcpOutcome::Timeout(TimeoutPhase::Total),
+            };
+
+            if matches!(&outcome, TcpOutcome::Response(response, ..) if !response.is_empty()) {
+                break 'payloads;
+            }
+        }
+    }
+
+    match outcome {
+        TcpOutcome::Response(response, truncated, local) => {
+            if !response.is_empty() {
+                if let (true, Some(local)) = (capture, local) {
+                    target.capture = Some(Capture {
+                        local,
+                        remote: addr,
+                        request: request.as_bytes().to_vec(),
+                        response: response.clone(),
+                        time: started,
+                    });
+                }
+                target.response = String::from_utf8_lossy(&response).to_string();
+                target.truncated = truncated;
+                target.body = target.response.clone();
+                target.sent_request = Some(request.to_string());
+                let _ = tx.send(WorkerMessage::Response(target)).await;
+            }
+        }
+        TcpOutcome::Fail(class, context, error) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(target, class, context, error))
+                .await;
+        }
+        TcpOutcome::Timeout(phase) => {
+            let _ = tx.send(WorkerMessage::Timeout(target, phase)).await;
+        }
+    }
 }
@@ -0,0 +1,81 @@
+use crate::worker::ReqTarget;
+
+// Banner and header fragments (lowercase) telling the operating system or the kind of device,
+// checked in order
+const HINTS: &[(&str, &str)] = &[
+    ("microsoft-iis", "windows"),
+    ("microsoft-httpapi", "windows"),
+    ("(win32)", "windows"),
+    ("(win64)", "windows"),
+    ("openssh_for_windows", "windows"),
+    ("asp.net", "windows"),
+    ("x-aspnet-version", "windows"),
+    ("windows", "windows"),
+    ("freebsd", "bsd"),
+    ("openbsd", "bsd"),
+    ("netbsd", "bsd"),
+    ("ubuntu", "linux"),
+    ("debian", "linux"),
+    ("centos", "linux"),
+    ("red hat", "linux"),
+    ("fedora", "linux"),
+    ("alpine", "linux"),
+    ("linux", "linux"),
+    ("goahead", "embedded"),
+    ("boa/", "embedded"),
+    ("mini_httpd", "embedded"),
+    ("micro_httpd", "embedded"),
+    ("uhttpd", "embedded"),
+    ("thttpd", "embedded"),
+    ("rompager", "embedded"),
+    ("allegro", "embedded"),
+    ("virata-emweb", "embedded"),
+    ("mikrotik", "embedded"),
+    ("routeros", "embedded"),
+    ("dropbear", "embedded"),
+    ("hikvision", "embedded"),
+    ("dnvrs-webs", "embedded"),
+    ("zyxel", "embedded"),
+];
+
+// Headers with the software of the server
+const HINT_HEADERS: &[&str] = &[
+    "server",
+    "x-powered-by",
+    "x-aspnet-version",
+    "www-authenticate",
+];
+
+// Coarse guess of the operating system (windows, linux, bsd) or device kind (embedded) from the
+// server headers, or from the first line of the other protocols (e.g. the SSH banner)
+pub fn guess(target: &ReqTarget) -> Option<&'static str> {
+    let text = if target.protocol == "http" || target.protocol == "https" {
+        target
+            .headers
+            .iter()
+            .filter(|(name, _)| HINT_HEADERS.contains(&name.to_lowercase().as_str()))
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else {
+        target.response.lines().next().unwrap_or("").to_string()
+    };
+    let text = text.to_lowercase();
+
+    HINTS
+        .iter()
+        .find(|(fragment, _)| text.contains(fragment))
+        .map(|(_, os)| *os)
+}
+
+// Finding attributes: the TCP connect time of the port and the operating system guess
+pub fn attributes(target: &ReqTarget) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    if let Some(rtt) = target.connect_rtt {
+        attributes.push(("connect_rtt_ms".to_string(), rtt.to_string()));
+    }
+    if let Some(os) = guess(target) {
+        attributes.push(("os_hint".to_string(), os.to_string()));
+    }
+    attributes
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use regex::Regex;
+
+use crate::{conf::Conf, net, worker::ReqTarget};
+
+// Max length of the saved values
+const MAX_VALUE_LEN: usize = 1000;
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+// Collapses the whitespaces (e.g. titles split on more lines) and truncates the value
+fn clean(text: &str) -> String {
+    let text = decode_entities(&text.split_whitespace().collect::<Vec<&str>>().join(" "));
+    text.chars().take(MAX_VALUE_LEN).collect()
+}
+
+// Splits a header value at the commas outside of the quoted strings
+fn split_unquoted(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+// Schemes and realms of the WWW-Authenticate challenges (e.g. Basic realm="Router", Digest
+// realm="Router", nonce="..."), in order
+pub fn auth_challenges(headers: &[(String, String)]) -> Vec<(String, Option<String>)> {
+    let mut challenges: Vec<(String, Option<String>)> = Vec::new();
+    let values = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("www-authenticate"))
+        .map(|(_, value)| value);
+    for value in values {
+        for part in split_unquoted(value) {
+            let part = part.trim();
+            // A challenge starts with the scheme, followed by its first parameter (if any)
+            let (first, rest) = part.split_once(' ').unwrap_or((part, ""));
+            let param = if !first.is_empty() && !first.contains('=') {
+                challenges.push((first.to_string(), None));
+                rest.trim()
+            } else {
+                part
+            };
+            if let (Some((name, value)), Some(challenge)) =
+                (param.split_once('='), challenges.last_mut())
+            {
+                if name.trim().eq_ignore_ascii_case("realm") && challenge.1.is_none() {
+                    challenge.1 = Some(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    challenges
+}
+
+// Title and meta generator of an html page and the authentication schemes, as finding attributes
+pub fn attributes(target: &ReqTarget) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    if target.protocol != "http" && target.protocol != "https" {
+        return attributes;
+    }
+
+    let title_re = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    if let Some(title) = title_re.captures(&target.body).map(|caps| clean(&caps[1])) {
+        if !title.is_empty() {
+            attributes.push(("title".to_string(), title));
+        }
+    }
+
+    let meta_re = Regex::new(r"(?is)<meta\s[^>]*>").unwrap();
+    let generator_re = Regex::new(r#"(?i)name\s*=\s*["']?generator["'\s/>]"#).unwrap();
+    let content_re = Regex::new(r#"(?is)content\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    let generator = meta_re
+        .find_iter(&target.body)
+        .map(|meta| meta.as_str())
+        .filter(|meta| generator_re.is_match(meta))
+        .find_map(|meta| content_re.captures(meta))
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|content| clean(content.as_str()));
+    if let Some(generator) = generator {
+        if !generator.is_empty() {
+            attributes.push(("generator".to_string(), generator));
+        }
+    }
+
+    let challenges = auth_challenges(&target.headers);
+    if !challenges.is_empty() {
+        let mut schemes: Vec<&str> = Vec::new();
+        for (scheme, _) in &challenges {
+            if !schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) {
+                schemes.push(scheme);
+            }
+        }
+        attributes.push(("auth_schemes".to_string(), schemes.join(", ")));
+    }
+    if let Some(realm) = challenges.into_iter().find_map(|(_, realm)| realm) {
+        attributes.push(("auth_realm".to_string(), clean(&realm)));
+    }
+
+    attributes
+}
+
+// Disallowed paths of a robots.txt (not found pages and other content are ignored)
+pub fn robots_attributes(body: &str) -> Vec<(String, String)> {
+    let mut disallowed = BTreeSet::new();
+    let mut is_robots = false;
+    for line in body.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (field, value) = match line.find(':') {
+            Some(idx) => (line[..idx].trim().to_lowercase(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        match field.as_str() {
+            "user-agent" => is_robots = true,
+            "disallow" if !value.is_empty() => {
+                disallowed.insert(value.to_string());
+            }
+            _ => (),
+        }
+    }
+
+    if !is_robots || disallowed.is_empty() {
+        return Vec::new();
+    }
+    let disallowed: Vec<String> = disallowed.into_iter().collect();
+    vec![("robots_disallow".to_string(), clean(&disallowed.join(" ")))]
+}
+
+// Number of urls of a sitemap.xml
+pub fn sitemap_attributes(body: &str) -> Vec<(String, String)> {
+    if !body.contains("<urlset") && !body.contains("<sitemapindex") {
+        return Vec::new();
+    }
+    let urls = body.matches("<loc>").count();
+    vec![("sitemap_urls".to_string(), urls.to_string())]
+}
+
+// Attributes of the web servers by protocol, ip and port
+type ServerAttributes = HashMap<(String, String, u16), Vec<(String, String)>>;
+
+// Fetches robots.txt and sitemap.xml of the web servers with matching services (--fetch-robots),
+// once per server
+pub struct RobotsFetcher {
+    client: net::HttpsClient,
+    user_agents: net::UserAgents,
+    timeout: u64,
+    max_bytes: usize,
+    fetched: Mutex<ServerAttributes>,
+}
+
+impl RobotsFetcher {
+    pub fn new(conf: &Conf) -> Self {
+        RobotsFetcher {
+            client: net::build_https_client(conf.source_ip, &conf.timeouts(None)),
+            user_agents: net::UserAgents::new(&conf.user_agent, &conf.user_agents),
+            timeout: conf.req_timeout,
+            max_bytes: conf.max_response_bytes,
+            fetched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn attributes(&self, target: &ReqTarget) -> Vec<(String, String)> {
+        if target.protocol != "http" && target.protocol != "https" {
+            return Vec::new();
+        }
+
+        let key = (target.protocol.clone(), target.ip.clone(), target.port);
+        if let Some(attributes) = self.fetched.lock().unwrap().get(&key) {
+            return attributes.clone();
+        }
+
+        let get = |path| {
+            net::http_get(
+                &self.client,
+                target,
+                path,
+                self.user_agents.next(),
+                self.timeout,
+                self.max_bytes,
+            )
+        };
+        let mut attributes = Vec::new();
+        if let Some(body) = get("/robots.txt").await {
+            attributes.extend(robots_attributes(&body));
+        }
+        if let Some(body) = get("/sitemap.xml").await {
+            attributes.extend(sitemap_attributes(&body));
+        }
+
+        self.fetched.lock().unwrap().insert(key, attributes.clone());
+        attributes
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Raw IP packets (IPv4 and IPv6), no link layer
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+// Payload bytes per segment
+const MSS: usize = 1460;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+// Bytes exchanged with a target (tcp/custom), recorded when the matches are captured
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+    pub time: SystemTime,
+}
+
+struct Packet {
+    from_local: bool,
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    payload: Vec<u8>,
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [a, b] => u16::from_be_bytes([*a, *b]),
+            [a] => u16::from_be_bytes([*a, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn tcp_segment(src: &SocketAddr, dst: &SocketAddr, packet: &Packet) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + packet.payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&packet.seq.to_be_bytes());
+    tcp.extend_from_slice(&packet.ack.to_be_bytes());
+    tcp.push(5 << 4); // Header length (20 bytes)
+    tcp.push(packet.flags);
+    tcp.extend_from_slice(&65535u16.to_be_bytes()); // Window
+    tcp.extend_from_slice(&[0, 0, 0, 0]); // Checksum and urgent pointer
+    tcp.extend_from_slice(&packet.payload);
+
+    // Pseudo header
+    let mut pseudo = Vec::new();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            pseudo.extend_from_slice(&ipv6_octets(src));
+            pseudo.extend_from_slice(&ipv6_octets(dst));
+            pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+        }
+    }
+    pseudo.extend_from_slice(&tcp);
+    let sum = checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    tcp
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn ip_packet(src: &SocketAddr, dst: &SocketAddr, packet: &Packet) -> Vec<u8> {
+    let tcp = tcp_segment(src, dst, packet);
+
+    let mut ip = Vec::with_capacity(40 + tcp.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            ip.push(0x45); // Version and header length (20 bytes)
+            ip.push(0);
+            ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0]); // Id, don't fragment
+            ip.push(64); // TTL
+            ip.push(6); // TCP
+            ip.extend_from_slice(&[0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+        }
+        (src, dst) => {
+            ip.extend_from_slice(&[0x60, 0, 0, 0]);
+            ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            ip.push(6); // TCP
+            ip.push(64); // Hop limit
+            ip.extend_from_slice(&ipv6_octets(src));
+            ip.extend_from_slice(&ipv6_octets(dst));
+        }
+    }
+    ip.extend_from_slice(&tcp);
+
+    ip
+}
+
+// The tcp conversation of the exchange: handshake, request, response and close
+fn packets(capture: &Capture) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    // Arbitrary initial sequence numbers
+    let (mut local_seq, mut remote_seq) = (1_000u32, 2_000u32);
+
+    packets.push(Packet {
+        from_local: true,
+        flags: SYN,
+        seq: local_seq,
+        ack: 0,
+        payload: Vec::new(),
+    });
+    packets.push(Packet {
+        from_local: false,
+        flags: SYN | ACK,
+        seq: remote_seq,
+        ack: local_seq + 1,
+        payload: Vec::new(),
+    });
+    local_seq += 1;
+    remote_seq += 1;
+    packets.push(Packet {
+        from_local: true,
+        flags: ACK,
+        seq: local_seq,
+        ack: remote_seq,
+        payload: Vec::new(),
+    });
+
+    for (from_local, data) in &[(true, &capture.request), (false, &capture.response)] {
+        for segment in data.chunks(MSS) {
+            let (seq, ack) = if *from_local {
+                (&mut local_seq, remote_seq)
+            } else {
+                (&mut remote_seq, local_seq)
+            };
+            packets.push(Packet {
+                from_local: *from_local,
+                flags: PSH | ACK,
+                seq: *seq,
+                ack,
+                payload: segment.to_vec(),
+            });
+            *seq = seq.wrapping_add(segment.len() as u32);
+        }
+    }
+
+    packets.push(Packet {
+        from_local: false,
+        flags: FIN | ACK,
+        seq: remote_seq,
+        ack: local_seq,
+        payload: Vec::new(),
+    });
+    packets.push(Packet {
+        from_local: true,
+        flags: FIN | ACK,
+        seq: local_seq,
+        ack: remote_seq + 1,
+        payload: Vec::new(),
+    });
+
+    packets
+}
+
+// Pcap file of the exchange. The bytes are the ones sent and received, the tcp/ip headers are
+// rebuilt (e.g. sequence numbers, windows and segmentation are not the original ones)
+pub fn to_bytes(capture: &Capture) -> Vec<u8> {
+    let mut pcap = Vec::new();
+    pcap.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    pcap.extend_from_slice(&2u16.to_le_bytes());
+    pcap.extend_from_slice(&4u16.to_le_bytes());
+    pcap.extend_from_slice(&[0; 8]); // Timezone and accuracy
+    pcap.extend_from_slice(&SNAPLEN.to_le_bytes());
+    pcap.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+
+    let start = capture
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    for (n, packet) in packets(capture).iter().enumerate() {
+        let data = if packet.from_local {
+            ip_packet(&capture.local, &capture.remote, packet)
+        } else {
+            ip_packet(&capture.remote, &capture.local, packet)
+        };
+
+        // One microsecond between the packets, to keep them ordered
+        let time = start + n as u64;
+        pcap.extend_from_slice(&((time / 1_000_000) as u32).to_le_bytes());
+        pcap.extend_from_slice(&((time % 1_000_000) as u32).to_le_bytes());
+        pcap.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        pcap.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        pcap.extend_from_slice(&data);
+    }
+
+    pcap
+}
+
+// Saves the capture of a match as <dir>/<ip>_<port>_<service>_<time>.pcap
+pub fn save(dir: &str, capture: &Capture, service: &str) -> Result<String, String> {
+    let service: String = service
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let name = format!(
+        "{}_{}_{}_{}.pcap",
+        capture.remote.ip().to_string().replace(':', "-"),
+        capture.remote.port(),
+        service,
+        capture
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    );
+    let path = Path::new(dir).join(name);
+
+    fs::write(&path, to_bytes(capture)).map_err(|e| e.to_string())?;
+    Ok(path.to_string_lossy().to_string())
+}
@@ -0,0 +1,82 @@
+use std::net::Ipv4Addr;
+
+use ipnet::Ipv4Net;
+use rand::Rng;
+
+// Visits every host of the subnets exactly once in a pseudo-random order (like masscan), so that
+// the probes are spread over the whole range instead of hitting one network at a time.
+// A full period LCG over the next power of two of the hosts count, skipping the values out of
+// range (cycle walking)
+#[derive(Debug)]
+pub struct SubnetPermutation {
+    nets: Vec<(Ipv4Net, u64)>,
+    total: u64,
+    modulus: u64,
+    multiplier: u64,
+    increment: u64,
+    state: u64,
+    generated: u64,
+}
+
+// Same hosts of Ipv4Net::hosts (network and broadcast addresses excluded, except for /31 and /32)
+fn hosts(net: &Ipv4Net) -> (u32, u64) {
+    let size = 1u64 << (32 - net.prefix_len());
+    let network = u32::from(net.network());
+    if net.prefix_len() >= 31 {
+        (network, size)
+    } else {
+        (network + 1, size - 2)
+    }
+}
+
+pub fn hosts_count(nets: &[Ipv4Net]) -> u64 {
+    nets.iter().map(|net| hosts(net).1).sum()
+}
+
+impl SubnetPermutation {
+    pub fn new(nets: &[Ipv4Net]) -> Self {
+        let nets: Vec<(Ipv4Net, u64)> = nets.iter().map(|net| (*net, hosts(net).1)).collect();
+        let total = nets.iter().map(|(_, count)| count).sum::<u64>();
+        let modulus = total.max(1).next_power_of_two();
+
+        // Hull-Dobell: with a power of two modulus, the period is full if the increment is odd
+        // and the multiplier is 1 mod 4. Never 1 (a plain sequential walk)
+        let mut rng = rand::thread_rng();
+        Self {
+            nets,
+            total,
+            modulus,
+            multiplier: 4 * rng.gen_range(1..(modulus / 4).max(2)) + 1,
+            increment: rng.gen_range(0..modulus) | 1,
+            state: rng.gen_range(0..modulus),
+            generated: 0,
+        }
+    }
+
+    fn host(&self, mut idx: u64) -> Ipv4Addr {
+        for (net, count) in &self.nets {
+            if idx < *count {
+                return Ipv4Addr::from(hosts(net).0 + idx as u32);
+            }
+            idx -= count;
+        }
+        unreachable!()
+    }
+}
+
+impl Iterator for SubnetPermutation {
+    type Item = Ipv4Addr;
+
+    fn next(&mut self) -> Option<Ipv4Addr> {
+        while self.generated < self.modulus {
+            // Overlapping subnets can total more than 2^32 hosts, the product overflows a u64
+            self.state = ((self.multiplier as u128 * self.state as u128 + self.increment as u128)
+                % self.modulus as u128) as u64;
+            self.generated += 1;
+            if self.state < self.total {
+                return Some(self.host(self.state));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::{sleep, Duration},
+};
+use tokio_postgres::Error;
+use tracing::instrument;
+
+use crate::{
+    conf::{self, DbConf},
+    content::ContentHash,
+    db::{self, DbMan, PortscanRow},
+    detector::DetectorResponse,
+    enrichment::{Enrichment, GeoInfo},
+    rdap::Netblock,
+    worker::{PortsTarget, ReqTarget},
+    zone::DomainInfo,
+};
+
+// Local journal of the services that couldn't be saved while the db was unreachable, in the logs
+// directory
+const SPOOL_FILE: &str = "db-spool.jsonl";
+// Extensions of the spool being replayed, and of its lines that couldn't be parsed
+const REPLAY_EXT: &str = "replaying";
+const BAD_EXT: &str = "bad";
+const MAX_ATTEMPTS: u32 = 4;
+const BACKOFF_MILLIS: u64 = 250;
+
+// A matching service as written in the spool
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SpooledService {
+    service: String,
+    version: String,
+    description: String,
+    protocol: String,
+    ip: String,
+    domain: String,
+    port: u16,
+    confidence: f32,
+    attributes: Vec<(String, String)>,
+    #[serde(default)]
+    truncated: bool,
+    // Missing in the spools written before the projects (saved in the project of the scan)
+    #[serde(default)]
+    project_id: Option<i64>,
+    #[serde(default)]
+    content: Option<ContentHash>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    // Raw response (--store-responses)
+    #[serde(default)]
+    response: Option<String>,
+    // Triage score inputs
+    #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    severity: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    // Request sent (secrets masked)
+    #[serde(default)]
+    request: Option<String>,
+}
+
+impl SpooledService {
+    fn from_response(res: &DetectorResponse, project_id: i64, store_response: bool) -> Self {
+        SpooledService {
+            service: res.service.clone(),
+            version: res.version.clone(),
+            description: res.description.clone(),
+            protocol: res.target.protocol.clone(),
+            ip: res.target.ip.clone(),
+            domain: res.target.domain.clone(),
+            port: res.target.port,
+            confidence: res.confidence,
+            attributes: res.attributes.clone(),
+            truncated: res.target.truncated,
+            project_id: Some(project_id),
+            content: res.content.clone(),
+            headers: db::saved_headers(&res.target.headers),
+            response: if store_response {
+                Some(res.target.response.clone())
+            } else {
+                None
+            },
+            status: res.target.status,
+            severity: Some(res.severity.clone()),
+            tags: res.target.tags.clone(),
+            request: res.request.clone(),
+        }
+    }
+
+    fn into_response(self) -> DetectorResponse {
+        let target = ReqTarget {
+            protocol: self.protocol,
+            ip: self.ip,
+            domain: self.domain,
+            port: self.port,
+            truncated: self.truncated,
+            headers: self.headers,
+            response: self.response.unwrap_or_default(),
+            status: self.status,
+            tags: self.tags,
+            ..Default::default()
+        };
+
+        let mut res = DetectorResponse::new(target);
+        res.service = self.service;
+        res.version = self.version;
+        res.description = self.description;
+        res.confidence = self.confidence;
+        res.attributes = self.attributes;
+        res.content = self.content;
+        res.request = self.request;
+        if let Some(severity) = self.severity {
+            res.severity = severity;
+        }
+        res
+    }
+}
+
+// Saves the matching services, retrying with backoff (and reconnecting) when the db is
+// unreachable. What can't be saved is spooled to a local journal, replayed as soon as the db is
+// reachable again
+pub struct Persister {
+    db_conf: DbConf,
+    dbm: RwLock<Arc<DbMan>>,
+    spool_path: PathBuf,
+    spool_lock: Mutex<()>,
+    spooled: AtomicBool,
+    enrichment: Option<Arc<Enrichment>>,
+    // Project of the scan results
+    project_id: i64,
+    // Saves the raw responses of the services (--store-responses)
+    store_responses: bool,
+}
+
+impl Persister {
+    pub async fn init(
+        db_conf: &DbConf,
+        project: &str,
+        enrichment: Option<Arc<Enrichment>>,
+        store_responses: bool,
+    ) -> Result<Self, Error> {
+        let dbm = DbMan::init(db_conf).await?;
+        let project_id = dbm.get_or_insert_project(project).await?;
+
+        Ok(Persister::new(
+            db_conf,
+            dbm,
+            &Path::new(&conf::logs_dir()).join(SPOOL_FILE),
+            enrichment,
+            project_id,
+            store_responses,
+        ))
+    }
+
+    pub(crate) fn new(
+        db_conf: &DbConf,
+        dbm: DbMan,
+        spool_path: &Path,
+        enrichment: Option<Arc<Enrichment>>,
+        project_id: i64,
+        store_responses: bool,
+    ) -> Self {
+        let replay_path = spool_path.with_extension(REPLAY_EXT);
+        Persister {
+            db_conf: db_conf.clone(),
+            dbm: RwLock::new(Arc::new(dbm)),
+            spool_path: spool_path.to_path_buf(),
+            spool_lock: Mutex::new(()),
+            // Leftovers of a previous run (or of an interrupted replay) are replayed with the
+            // first saved service
+            spooled: AtomicBool::new(spool_path.exists() || replay_path.exists()),
+            enrichment,
+            project_id,
+            store_responses,
+        }
+    }
+
+    // Geo-IP/ASN values of an ip, looked up at insert time (also for the replayed services)
+    fn geo(&self, ip: &str) -> GeoInfo {
+        match &self.enrichment {
+            Some(enrichment) => enrichment.lookup(ip),
+            None => GeoInfo::default(),
+        }
+    }
+
+    async fn reconnect(&self) {
+        if let Ok(dbm) = DbMan::init(&self.db_conf).await {
+            *self.dbm.write().await = Arc::new(dbm);
+        }
+    }
+
+    async fn try_insert(&self, service: &DetectorResponse) -> Result<(), String> {
+        let mut last_err = String::new();
+        let geo = self.geo(&service.target.ip);
+
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                sleep(Duration::from_millis(BACKOFF_MILLIS << (attempt - 1))).await;
+            }
+
+            let dbm = self.dbm.read().await.clone();
+            if dbm.is_closed() {
+                self.reconnect().await;
+                continue;
+            }
+
+            match dbm
+                .insert_service(self.project_id, service, &geo, self.store_responses)
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(err) => last_err = err.to_string(),
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // The caller holds the spool lock
+    fn spool(&self, spooled: &SpooledService) -> Result<(), String> {
+        let line = serde_json::to_string(spooled).map_err(|e| e.to_string())?;
+        if let Some(dir) = self.spool_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.spool_path)
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+
+        self.spooled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Saves the services of the spool, the ones failing again are spooled again. The spool is
+    // moved aside while replayed, and removed only once all its lines are saved or spooled again
+    // (the lines that can't be parsed are kept in a .bad file)
+    pub(crate) async fn replay(&self) -> Result<(), String> {
+        let _guard = self.spool_lock.lock().await;
+
+        // The spool of an interrupted replay is replayed first, the current one at the next call
+        let replay_path = self.spool_path.with_extension(REPLAY_EXT);
+        if !replay_path.exists() {
+            match fs::rename(&self.spool_path, &replay_path) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.spooled.store(false, Ordering::SeqCst);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let content = fs::read_to_string(&replay_path).map_err(|e| e.to_string())?;
+
+        let dbm = self.dbm.read().await.clone();
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let spooled: SpooledService = match serde_json::from_str(line) {
+                Ok(spooled) => spooled,
+                Err(_) => {
+                    let mut file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(self.spool_path.with_extension(BAD_EXT))
+                        .map_err(|e| e.to_string())?;
+                    writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+                    continue;
+                }
+            };
+            let project_id = spooled.project_id.unwrap_or(self.project_id);
+            let store_response = spooled.response.is_some();
+            let service = spooled.clone().into_response();
+            let geo = self.geo(&service.target.ip);
+            // Spooled again as it was (project and response of the scan that spooled it)
+            if dbm
+                .insert_service(project_id, &service, &geo, store_response)
+                .await
+                .is_err()
+            {
+                self.spool(&spooled)?;
+            }
+        }
+
+        fs::remove_file(&replay_path).map_err(|e| e.to_string())?;
+        self.spooled
+            .store(self.spool_path.exists(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Port scans are not critical (only used to skip the next ones), so they are saved once and
+    // never spooled
+    pub async fn save_portscan(&self, ports_target: &PortsTarget) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.update_or_insert_portscan(self.project_id, ports_target, &self.geo(&ports_target.ip))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn save_domain_dns(&self, info: &DomainInfo) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        dbm.update_domain_dns(self.project_id, info)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Vhosts are discovered again at the next scan, not spooled either
+    pub async fn save_vhost(&self, target: &ReqTarget) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.insert_vhost(self.project_id, target, &self.geo(&target.ip))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Netblocks are looked up again at the next scan, so they are not spooled either
+    pub async fn save_netblock(&self, netblock: &Netblock) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.update_or_insert_netblock(netblock)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // Scan sessions and their stats snapshots are not spooled either (the next snapshot replaces
+    // a lost one)
+    pub async fn start_scan(&self) -> Result<i64, String> {
+        let dbm = self.dbm.read().await.clone();
+        dbm.insert_scan(self.project_id)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn save_scan_stats(&self, scan_id: i64, stats: &Value) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.insert_scan_stats(scan_id, stats)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Not spooled: the HAR entries are a debugging aid, the findings themselves are
+    pub async fn save_har_entry(&self, scan_id: i64, entry: &Value) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.insert_har_entry(scan_id, entry)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Not spooled, as the HAR entries
+    pub async fn save_unknown_response(
+        &self,
+        target: &ReqTarget,
+        content: &ContentHash,
+    ) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        if dbm.is_closed() {
+            return Err("Db unreachable".to_string());
+        }
+        dbm.update_or_insert_unknown_response(self.project_id, target, content)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Not while services are spooled: they were seen, but are not saved yet. Only the services of
+    // the definitions of the scan can be stale, the other ones were not looked for
+    pub async fn mark_stale_services(
+        &self,
+        scan_id: i64,
+        definitions: &[String],
+    ) -> Result<Option<u64>, String> {
+        if self.spooled.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        let dbm = self.dbm.read().await.clone();
+        dbm.mark_stale_services(scan_id, definitions)
+            .await
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn finish_scan(&self, scan_id: i64) -> Result<(), String> {
+        let dbm = self.dbm.read().await.clone();
+        dbm.finish_scan(scan_id).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn recent_portscans(
+        &self,
+        max_age: Duration,
+    ) -> Result<HashMap<String, PortscanRow>, String> {
+        let dbm = self.dbm.read().await.clone();
+        dbm.get_recent_portscans(self.project_id, max_age)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Includes the retries and the spooling
+    #[instrument(
+        level = "debug",
+        skip(self, service),
+        fields(ip = %service.target.ip, port = service.target.port)
+    )]
+    pub async fn insert_service(&self, service: &DetectorResponse) -> Result<(), String> {
+        match self.try_insert(service).await {
+            Ok(_) => {
+                if self.spooled.load(Ordering::SeqCst) {
+                    self.replay()
+                        .await
+                        .map_err(|e| format!("Error while replaying the db spool: {}", e))?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                let _guard = self.spool_lock.lock().await;
+                let spooled =
+                    SpooledService::from_response(service, self.project_id, self.store_responses);
+                match self.spool(&spooled) {
+                    Ok(_) => Err(format!(
+                        "Db unreachable, the matching service has been spooled to {}: {}",
+                        self.spool_path.display(),
+                        err
+                    )),
+                    Err(spool_err) => Err(format!(
+                        "Db unreachable and spool error, the matching service is lost: {} ({})",
+                        err, spool_err
+                    )),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{conf::Conf, detector, permutation, worker};
+
+// Requests sent to every target: one check per port, and the probes of the open ports (all the
+// ports open is the worst case)
+#[derive(Debug, Default, PartialEq)]
+pub struct TargetRequests {
+    pub port_checks: u64,
+    pub max_probes: u64,
+    // Probes requests by protocol
+    pub protocols: BTreeMap<String, u64>,
+}
+
+pub fn target_requests(conf: &Conf) -> TargetRequests {
+    let mut requests = TargetRequests::default();
+
+    // The ports behind the knock sequences, and the ones of the follow-up definitions (probed
+    // on the port of a match), are not checked
+    let ports: HashSet<u16> = conf
+        .definitions
+        .iter()
+        .filter(|def| !def.is_knock() && !def.is_follow_up())
+        .flat_map(|def| def.options.ports.iter().cloned())
+        .collect();
+    requests.port_checks = ports.len() as u64;
+    if conf.ports_only {
+        return requests;
+    }
+
+    // Same deduplication of the probes (http/s requests with the same options, and protocols other
+    // than tcp/custom probing each port once)
+    let mut http_requests = HashSet::new();
+    let mut probed_ports = HashSet::new();
+    // Schemes of the web definitions on each port, the TLS sniffing is needed for both
+    let mut port_schemes: BTreeMap<u16, BTreeSet<&str>> = BTreeMap::new();
+    for def in conf
+        .definitions
+        .iter()
+        .filter(|d| detector::is_http(&d.protocol))
+    {
+        for port in &def.options.ports {
+            port_schemes
+                .entry(*port)
+                .or_default()
+                .extend(detector::http_schemes(&def.protocol));
+        }
+    }
+    for def in &conf.definitions {
+        let count = requests.protocols.entry(def.protocol.clone()).or_insert(0);
+        for port in &def.options.ports {
+            match def.protocol.as_str() {
+                "http/s" | "http" | "https" => {
+                    let paths = match &def.options.paths {
+                        Some(paths) => paths.clone(),
+                        None => vec![def.options.path.clone().unwrap_or_else(|| "/".to_string())],
+                    };
+                    let key = (
+                        *port,
+                        def.options.method.clone(),
+                        def.options.headers.clone(),
+                        def.options.payload.clone(),
+                        paths.clone(),
+                    );
+                    // One TLS sniffing per port (when both the schemes are requested), then the
+                    // paths with the matching scheme
+                    if port_schemes[port].len() > 1
+                        && probed_ports.insert(("tls".to_string(), *port))
+                    {
+                        *count += 1;
+                    }
+                    // HEAD request of the port before the GET ones (--head-first)
+                    if conf.head_first
+                        && def.options.head_regex.is_some()
+                        && probed_ports.insert(("head".to_string(), *port))
+                    {
+                        *count += 1;
+                    }
+                    // Every pair of default credentials
+                    if let Some(credentials) = &def.options.credentials {
+                        *count += credentials.len() as u64;
+                    } else if http_requests.insert(key) {
+                        *count += paths.len() as u64;
+                    }
+                }
+                // The TLS sniffing of the port (when both the schemes are requested), then the
+                // handshake with the matching scheme, or with both without a sniffing answer
+                "ws/s" | "ws" | "wss" => {
+                    if def.protocol == "ws/s" && probed_ports.insert(("tls".to_string(), *port)) {
+                        *count += 1;
+                    }
+                    *count += detector::websocket_schemes(&def.protocol).len() as u64;
+                }
+                "tcp/custom" | "tls/custom" => {
+                    *count += def.options.payloads.as_ref().map(|p| p.len()).unwrap_or(1) as u64;
+                    // The knock sequence before the probe of every port
+                    *count += def.options.knock.as_ref().map(|k| k.len()).unwrap_or(0) as u64;
+                }
+                protocol => {
+                    if probed_ports.insert((protocol.to_string(), *port)) {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+    }
+    // Vhost discovery of the ip targets (--vhost-wordlist): a TLS sniffing, a random host name
+    // and the host names of the wordlist on every web port
+    if !conf.vhost_words.is_empty() && !port_schemes.is_empty() {
+        requests.protocols.insert(
+            "vhost".to_string(),
+            port_schemes.len() as u64 * (conf.vhost_words.len() as u64 + 2),
+        );
+    }
+    requests.max_probes = requests.protocols.values().sum();
+
+    requests
+}
+
+// Number of targets (A records of the dataset, or hosts of the subnets), up to max-targets
+pub fn targets_count(conf: &Conf) -> Result<u64, String> {
+    let count = if !conf.dataset.is_empty() && conf.dedup.is_none() {
+        let file = File::open(&conf.dataset).map_err(|e| e.to_string())?;
+        let mut count = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if let Ok(record) = worker::parse_dataset_record(&line) {
+                if record.record_type == "a" {
+                    count += 1;
+                }
+            }
+        }
+        count
+    } else {
+        permutation::hosts_count(&conf.nets) + conf.hosts.len() as u64
+    };
+
+    if conf.max_targets != 0 {
+        Ok(count.min(conf.max_targets))
+    } else {
+        Ok(count)
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    format!("{}h {}m {}s", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+pub fn print(conf: &Conf) -> Result<(), String> {
+    println!("Scan plan (dry run, no request is sent)\n");
+
+    if let Some(profile) = &conf.profile {
+        println!("Profile: {}\n", profile);
+    }
+    if conf.ports_only {
+        println!("Ports only: the ports are checked, not probed\n");
+    }
+
+    println!("Definitions: {}", conf.definitions.len());
+    for def in &conf.definitions {
+        println!(
+            "  {} ({}) ports: {:?}",
+            def.name, def.protocol, def.options.ports
+        );
+    }
+
+    let ports: BTreeSet<u16> = conf
+        .definitions
+        .iter()
+        .flat_map(|def| def.options.ports.iter().cloned())
+        .collect();
+    println!("\nPorts: {:?}", ports);
+
+    let requests = target_requests(conf);
+    println!("\nRequests per target:");
+    println!("  port checks: {}", requests.port_checks);
+    for (protocol, count) in &requests.protocols {
+        println!("  {} probes: up to {}", protocol, count);
+    }
+
+    let targets = targets_count(conf)?;
+    if conf.target_stream {
+        println!("\nTargets: read from stdin (target stream)");
+    } else if !conf.dataset.is_empty() {
+        match &conf.dedup {
+            Some(dedup) => println!(
+                "\nTargets: {} (dataset {}, {} duplicate ips skipped)",
+                targets, conf.dataset, dedup.duplicates
+            ),
+            None => println!("\nTargets: {} (dataset {})", targets, conf.dataset),
+        }
+    } else {
+        let mut sources = Vec::new();
+        if !conf.nets.is_empty() {
+            let nets: Vec<String> = conf.nets.iter().map(|net| net.to_string()).collect();
+            sources.push(format!("subnets {}", nets.join(", ")));
+        }
+        if !conf.hosts.is_empty() {
+            let names: BTreeSet<&String> = conf.hosts.iter().map(|(name, _)| name).collect();
+            sources.push(format!("{} resolved domains", names.len()));
+        }
+        println!("\nTargets: {} ({})", targets, sources.join(", "));
+    }
+
+    // The probes of a host are spaced by the per-host delay, the hosts are probed meanwhile
+    if conf.host_delay != 0 || conf.host_jitter != 0 {
+        let per_host = requests.max_probes.saturating_sub(1) * (conf.host_delay + conf.host_jitter);
+        println!(
+            "Host delay: {}ms + up to {}ms jitter between the probes of a host (up to {} per host)",
+            conf.host_delay,
+            conf.host_jitter,
+            format_duration(per_host / 1000)
+        );
+    }
+
+    if let Some(scope) = &conf.scope {
+        let nets: Vec<String> = scope.authorized.iter().map(|net| net.to_string()).collect();
+        println!(
+            "Scope: {} (the targets outside {} are refused)",
+            scope.engagement,
+            nets.join(", ")
+        );
+    }
+
+    // The targets of a stream are not known in advance
+    if conf.target_stream {
+        return Ok(());
+    }
+
+    let min_requests = targets * requests.port_checks;
+    let max_requests = targets * (requests.port_checks + requests.max_probes);
+    println!(
+        "Estimated requests: {} (all the ports closed) to {} (all the ports open)",
+        min_requests, max_requests
+    );
+
+    let rate_secs = min_requests.checked_div(conf.max_rate);
+    let timeout_secs =
+        (max_requests * conf.req_timeout).checked_div(conf.max_concurrent_requests as u64);
+    if let Some(secs) = rate_secs {
+        println!(
+            "Estimated duration: at least {} (rate limit of {} requests/s)",
+            format_duration(secs),
+            conf.max_rate
+        );
+    }
+    if let Some(secs) = timeout_secs {
+        println!(
+            "Estimated duration: at most {} (every request timing out, {} concurrent requests)",
+            format_duration(secs),
+            conf.max_concurrent_requests
+        );
+    }
+    if rate_secs.is_none() && timeout_secs.is_none() {
+        println!("Estimated duration: unknown (no rate limit or max concurrent requests)");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    conf::Definition,
+    net,
+    plugins::{field, io_err, run_tcp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+// Max size of a single frame/packet
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+/*
+ * MQTT: CONNECT with clean session and no credentials, for each protocol level (5, 3.1.1, 3.1).
+ * A connection accepted means anonymous access is allowed
+ */
+
+const MQTT_VERSIONS: [(&str, &str, u8); 3] = [
+    ("5.0", "MQTT", 5),
+    ("3.1.1", "MQTT", 4),
+    ("3.1", "MQIsdp", 3),
+];
+
+pub struct MqttProbe;
+
+impl Probe for MqttProbe {
+    fn protocol(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "mqtt", mqtt))
+    }
+}
+
+fn mqtt_connect(name: &str, level: u8) -> Vec<u8> {
+    let client_id = b"lachesis";
+
+    let mut variable = Vec::new();
+    variable.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    variable.extend_from_slice(name.as_bytes());
+    // Protocol level, flags (clean session), keep alive (60s)
+    variable.extend_from_slice(&[level, 0x02, 0x00, 0x3C]);
+    // Empty properties (MQTT 5 only)
+    if level == 5 {
+        variable.push(0x00);
+    }
+    variable.extend_from_slice(&(client_id.len() as u16).to_be_bytes());
+    variable.extend_from_slice(client_id);
+
+    // The remaining length always fits in a single byte here
+    let mut packet = vec![0x10, variable.len() as u8];
+    packet.extend_from_slice(&variable);
+    packet
+}
+
+// Return code of the CONNACK packet (connect acknowledge flags, return/reason code, ...)
+async fn mqtt_connack(stream: &mut TcpStream) -> Result<u8, String> {
+    let mut header = [0; 2];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("CONNACK read error", e))?;
+    if header[0] >> 4 != 2 || header[1] < 2 || header[1] & 0x80 != 0 {
+        return Err("Invalid CONNACK packet".to_string());
+    }
+
+    let mut body = vec![0; header[1] as usize];
+    stream
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| io_err("CONNACK read error", e))?;
+
+    Ok(body[1])
+}
+
+fn mqtt_return_code(code: u8) -> &'static str {
+    match code {
+        0x00 => "accepted",
+        0x01 | 0x84 => "unsupported protocol version",
+        0x02 | 0x85 => "client identifier rejected",
+        0x03 | 0x88 => "server unavailable",
+        0x04 | 0x86 => "bad username or password",
+        0x05 | 0x87 => "not authorized",
+        _ => "unknown",
+    }
+}
+
+pub(crate) async fn mqtt(stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let addr = stream
+        .peer_addr()
+        .map_err(|e| io_err("Peer address error", e))?;
+    // New connections egress from the same local address of the probe one
+    let source_ip = stream
+        .local_addr()
+        .map_err(|e| io_err("Local address error", e))?
+        .ip();
+
+    let mut stream = Some(stream);
+    let mut versions = Vec::new();
+    let mut anonymous = false;
+    let mut last_code = None;
+
+    for (version, name, level) in MQTT_VERSIONS.iter() {
+        // The first attempt reuses the probe connection, the following ones need a new one
+        let mut s = match stream.take() {
+            Some(s) => s,
+            None => match net::connect(&addr, Some(source_ip)).await {
+                Ok(s) => s,
+                Err(_e) => break,
+            },
+        };
+
+        if let Err(e) = s.write_all(&mqtt_connect(name, *level)).await {
+            return Err(io_err("CONNECT write error", e));
+        }
+
+        // Brokers usually close the connection on unsupported protocol levels
+        let code = match mqtt_connack(&mut s).await {
+            Ok(code) => code,
+            Err(_e) => continue,
+        };
+        last_code = Some(code);
+
+        if code == 0x01 || code == 0x84 {
+            continue;
+        }
+        versions.push(*version);
+
+        if code == 0x00 {
+            anonymous = true;
+            // DISCONNECT
+            let _ = s.write_all(&[0xE0, 0x00]).await;
+        }
+    }
+
+    let code = last_code.ok_or("No CONNACK received")?;
+    let mut fields = vec![
+        field("versions", versions.join(",")),
+        field("anonymous", anonymous),
+    ];
+    if !anonymous {
+        fields.push(field("return_code", mqtt_return_code(code)));
+    }
+
+    Ok(fields)
+}
+
+/*
+ * AMQP 0-9-1: protocol header, the broker replies with Connection.Start (server properties and
+ * authentication mechanisms) or with the protocol header of the version it supports
+ */
+
+const AMQP_HEADER: [u8; 8] = [b'A', b'M', b'Q', b'P', 0, 0, 9, 1];
+
+pub struct AmqpProbe;
+
+impl Probe for AmqpProbe {
+    fn protocol(&self) -> &'static str {
+        "amqp"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "amqp", amqp))
+    }
+}
+
+fn read_u32_be(buf: &[u8], offset: usize) -> Option<usize> {
+    let b = buf.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+// String values of a field table (the other types are skipped)
+pub(crate) fn amqp_table(table: &[u8]) -> Vec<(String, String)> {
+    let mut values = Vec::new();
+    let mut offset = 0;
+
+    while offset < table.len() {
+        let key_len = table[offset] as usize;
+        let key = match table.get(offset + 1..offset + 1 + key_len) {
+            Some(key) => String::from_utf8_lossy(key).to_string(),
+            None => break,
+        };
+        offset += 1 + key_len;
+
+        let value_type = match table.get(offset) {
+            Some(t) => *t,
+            None => break,
+        };
+        offset += 1;
+
+        let size = match value_type {
+            b'S' | b'F' | b'A' | b'x' => match read_u32_be(table, offset) {
+                Some(len) => {
+                    if value_type == b'S' {
+                        if let Some(value) = table.get(offset + 4..offset + 4 + len) {
+                            values.push((key, String::from_utf8_lossy(value).to_string()));
+                        }
+                    }
+                    4 + len
+                }
+                None => break,
+            },
+            b't' | b'b' | b'B' => 1,
+            b's' | b'u' => 2,
+            b'I' | b'i' | b'f' => 4,
+            b'l' | b'L' | b'd' | b'T' => 8,
+            b'D' => 5,
+            b'V' => 0,
+            _ => break,
+        };
+        offset += size;
+    }
+
+    values
+}
+
+pub(crate) async fn amqp(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    stream
+        .write_all(&AMQP_HEADER)
+        .await
+        .map_err(|e| io_err("Protocol header write error", e))?;
+
+    // Frame header: type (1), channel (2), size (4)
+    let mut header = [0; 7];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Frame read error", e))?;
+
+    // Protocol version mismatch, the broker replies with its own header
+    if header[..4] == AMQP_HEADER[..4] {
+        return Ok(vec![field(
+            "versions",
+            format!("{}-{}-{}", header[5], header[6], header[4]),
+        )]);
+    }
+
+    let size = read_u32_be(&header, 3).unwrap_or(0);
+    if header[0] != 1 || !(4..=MAX_MESSAGE_SIZE).contains(&size) {
+        return Err("Invalid Connection.Start frame".to_string());
+    }
+
+    let mut payload = vec![0; size];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| io_err("Frame read error", e))?;
+
+    // Class 10 (connection), method 10 (start), version major/minor, server properties,
+    // mechanisms, locales
+    if payload[..4] != [0, 10, 0, 10] {
+        return Err("Unexpected AMQP method".to_string());
+    }
+
+    let version = format!(
+        "{}-{}",
+        payload.get(4).unwrap_or(&0),
+        payload.get(5).unwrap_or(&0)
+    );
+    let mut fields = vec![field("versions", version)];
+
+    let table_len = read_u32_be(&payload, 6).ok_or("Invalid server properties")?;
+    let table = payload
+        .get(10..10 + table_len)
+        .ok_or("Invalid server properties")?;
+    for (name, value) in amqp_table(table) {
+        match name.as_str() {
+            "product" | "version" | "platform" | "cluster_name" => fields.push(field(&name, value)),
+            _ => (),
+        }
+    }
+
+    let mechanisms_len = read_u32_be(&payload, 10 + table_len).unwrap_or(0);
+    let mechanisms = payload
+        .get(14 + table_len..14 + table_len + mechanisms_len)
+        .map(|m| String::from_utf8_lossy(m).to_string())
+        .unwrap_or_default();
+    fields.push(field(
+        "anonymous",
+        mechanisms.split(' ').any(|m| m == "ANONYMOUS"),
+    ));
+    fields.push(field("mechanisms", mechanisms));
+
+    Ok(fields)
+}
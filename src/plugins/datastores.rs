@@ -0,0 +1,500 @@
+use std::{convert::TryFrom, str};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    conf::Definition,
+    plugins::{field, io_err, run_tcp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+// Max size of a single handshake/reply message
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+fn read_cstring(buf: &[u8], offset: usize) -> Option<(String, usize)> {
+    let len = buf.get(offset..)?.iter().position(|b| *b == 0)?;
+    let s = String::from_utf8_lossy(&buf[offset..offset + len]).to_string();
+    Some((s, offset + len + 1))
+}
+
+fn read_i32_le(buf: &[u8], offset: usize) -> Option<i32> {
+    let b = buf.get(offset..offset + 4)?;
+    Some(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// Length field of a BSON value, the negative ones are invalid
+fn read_len(buf: &[u8], offset: usize) -> Option<usize> {
+    usize::try_from(read_i32_le(buf, offset)?).ok()
+}
+
+fn read_i32_be(buf: &[u8], offset: usize) -> Option<i32> {
+    let b = buf.get(offset..offset + 4)?;
+    Some(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/*
+ * MySQL: the server sends its handshake (or an error) right after the connection
+ */
+
+pub struct MysqlProbe;
+
+impl Probe for MysqlProbe {
+    fn protocol(&self) -> &'static str {
+        "mysql"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "mysql", mysql))
+    }
+}
+
+async fn mysql(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    // Packet header: 3 bytes payload length (little endian), 1 byte sequence id
+    let mut header = [0; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Handshake read error", e))?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    if len == 0 || len > MAX_MESSAGE_SIZE {
+        return Err(format!("Invalid packet length: {}", len));
+    }
+
+    let mut payload = vec![0; len];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| io_err("Handshake read error", e))?;
+
+    // Error packet (e.g. host not allowed to connect)
+    if payload[0] == 0xFF {
+        let code = payload.get(1..3).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let message = String::from_utf8_lossy(payload.get(3..).unwrap_or_default()).to_string();
+        return Ok(vec![
+            field("error_code", code.unwrap_or(0)),
+            field("error", message.trim_start_matches('#')),
+        ]);
+    }
+
+    let protocol_version = payload[0];
+    let (version, offset) = read_cstring(&payload, 1).ok_or("Invalid handshake packet")?;
+    let mut fields = vec![
+        field("protocol_version", protocol_version),
+        field("version", version),
+    ];
+
+    // connection id (4), auth data part 1 (8), filler (1), capabilities (2), charset (1),
+    // status (2), capabilities upper (2), auth data length (1), reserved (10), auth data part 2
+    // (max(13, auth data length - 8)), auth plugin name
+    let auth_data_len = *payload
+        .get(offset + 4 + 8 + 1 + 2 + 1 + 2 + 2)
+        .unwrap_or(&0) as usize;
+    let plugin_offset = offset
+        + 4
+        + 8
+        + 1
+        + 2
+        + 1
+        + 2
+        + 2
+        + 1
+        + 10
+        + std::cmp::max(13, auth_data_len.saturating_sub(8));
+    if let Some((auth_plugin, _)) = read_cstring(&payload, plugin_offset) {
+        fields.push(field("auth_plugin", auth_plugin));
+    }
+
+    Ok(fields)
+}
+
+/*
+ * PostgreSQL: startup message as user postgres, the reply says which authentication is required
+ * (the server version is only reported when no authentication is required)
+ */
+
+pub struct PostgresqlProbe;
+
+impl Probe for PostgresqlProbe {
+    fn protocol(&self) -> &'static str {
+        "postgresql"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "postgresql", postgresql))
+    }
+}
+
+fn postgresql_auth(auth_type: i32) -> &'static str {
+    match auth_type {
+        0 => "none",
+        3 => "password",
+        5 => "md5",
+        7 => "gss",
+        9 => "sspi",
+        10 => "sasl",
+        _ => "unknown",
+    }
+}
+
+async fn read_pg_message(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0; 5];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Message read error", e))?;
+    let len = read_i32_be(&header, 1).unwrap_or(0) as usize;
+    if !(4..=MAX_MESSAGE_SIZE).contains(&len) {
+        return Err(format!("Invalid message length: {}", len));
+    }
+
+    let mut body = vec![0; len - 4];
+    stream
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| io_err("Message read error", e))?;
+
+    Ok((header[0], body))
+}
+
+async fn postgresql(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    // Protocol 3.0, parameters user and database
+    let mut params = Vec::new();
+    params.extend_from_slice(&196_608i32.to_be_bytes());
+    for p in ["user", "postgres", "database", "postgres"].iter() {
+        params.extend_from_slice(p.as_bytes());
+        params.push(0);
+    }
+    params.push(0);
+    let mut startup = ((params.len() + 4) as i32).to_be_bytes().to_vec();
+    startup.extend_from_slice(&params);
+
+    stream
+        .write_all(&startup)
+        .await
+        .map_err(|e| io_err("Startup write error", e))?;
+
+    let mut fields = Vec::new();
+    loop {
+        let (tag, body) = read_pg_message(&mut stream).await?;
+        match tag {
+            b'R' => {
+                let auth_type = read_i32_be(&body, 0).unwrap_or(-1);
+                fields.push(field("auth", postgresql_auth(auth_type)));
+                if auth_type != 0 {
+                    break;
+                }
+            }
+            // Parameter status (e.g. server_version), after a successful authentication
+            b'S' => {
+                if let Some((name, offset)) = read_cstring(&body, 0) {
+                    if let Some((value, _)) = read_cstring(&body, offset) {
+                        if name == "server_version" {
+                            fields.push(field("version", value));
+                        }
+                    }
+                }
+            }
+            // Error fields: type byte + string, the message has type M
+            b'E' => {
+                let mut offset = 0;
+                while let Some(field_type) = body.get(offset) {
+                    if *field_type == 0 {
+                        break;
+                    }
+                    let (value, next) = match read_cstring(&body, offset + 1) {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    if *field_type == b'M' {
+                        fields.push(field("error", value));
+                    }
+                    offset = next;
+                }
+                break;
+            }
+            // Ready for query
+            b'Z' => break,
+            _ => (),
+        }
+    }
+
+    Ok(fields)
+}
+
+/*
+ * Redis: INFO server, answered only if no authentication is required
+ */
+
+pub struct RedisProbe;
+
+impl Probe for RedisProbe {
+    fn protocol(&self) -> &'static str {
+        "redis"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "redis", redis))
+    }
+}
+
+// End of the header and length of a bulk string reply, None when it's not one (or incomplete)
+fn bulk_header(response: &[u8]) -> Option<(usize, usize)> {
+    if response.first() != Some(&b'$') {
+        return None;
+    }
+    let header_end = response.windows(2).position(|w| w == b"\r\n")?;
+    let len = str::from_utf8(response.get(1..header_end)?)
+        .ok()?
+        .parse()
+        .ok()?;
+    Some((header_end, len))
+}
+
+pub(crate) async fn redis(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    stream
+        .write_all(b"INFO server\r\n")
+        .await
+        .map_err(|e| io_err("INFO write error", e))?;
+
+    let mut response = Vec::new();
+    let mut buf = vec![0; 4096];
+    loop {
+        let n = stream
+            .read(&mut buf)
+            .await
+            .map_err(|e| io_err("INFO read error", e))?;
+        response.extend_from_slice(&buf[..n]);
+
+        // Error reply, or complete bulk string ($<length>\r\n<data>\r\n). Any other complete
+        // header is not waited for
+        if n == 0 || response.len() > MAX_MESSAGE_SIZE || response.first() == Some(&b'-') {
+            break;
+        }
+        match bulk_header(&response) {
+            Some((header_end, len)) if response.len() >= (header_end + 2).saturating_add(len) => {
+                break
+            }
+            None if response.windows(2).any(|w| w == b"\r\n") => break,
+            _ => (),
+        }
+    }
+
+    let text = String::from_utf8_lossy(&response).to_string();
+    if let Some(error) = text.strip_prefix('-') {
+        return Ok(vec![
+            field("auth", "required"),
+            field("error", error.trim_end()),
+        ]);
+    }
+
+    if !text.starts_with('$') {
+        return Err("Unexpected INFO reply".to_string());
+    }
+
+    let mut fields = vec![field("auth", "none")];
+    for line in text.lines() {
+        let (name, value) = match line.find(':') {
+            Some(idx) => (&line[..idx], &line[idx + 1..]),
+            None => continue,
+        };
+        match name {
+            "redis_version" => fields.push(field("version", value)),
+            "redis_mode" | "os" | "arch_bits" => fields.push(field(name, value)),
+            _ => (),
+        }
+    }
+
+    Ok(fields)
+}
+
+/*
+ * MongoDB: buildInfo (version, allowed without authentication) and listDatabases (fails if
+ * authentication is required), as legacy OP_QUERY commands
+ */
+
+pub struct MongodbProbe;
+
+impl Probe for MongodbProbe {
+    fn protocol(&self) -> &'static str {
+        "mongodb"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "mongodb", mongodb))
+    }
+}
+
+const OP_REPLY: i32 = 1;
+const OP_QUERY: i32 = 2004;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum BsonValue {
+    Double(f64),
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Other,
+}
+
+// Document with a single int32 element (e.g. {buildInfo: 1})
+fn bson_command(command: &str) -> Vec<u8> {
+    let mut elements = vec![0x10];
+    elements.extend_from_slice(command.as_bytes());
+    elements.push(0);
+    elements.extend_from_slice(&1i32.to_le_bytes());
+    elements.push(0);
+
+    let mut doc = ((elements.len() + 4) as i32).to_le_bytes().to_vec();
+    doc.extend_from_slice(&elements);
+    doc
+}
+
+// Top level elements only, the nested documents are skipped
+pub(crate) fn parse_bson(doc: &[u8]) -> Option<Vec<(String, BsonValue)>> {
+    let len = read_len(doc, 0)?;
+    let doc = doc.get(..len)?;
+
+    let mut elements = Vec::new();
+    let mut offset = 4;
+    while let Some(element_type) = doc.get(offset) {
+        if *element_type == 0 {
+            break;
+        }
+        let (name, value_offset) = read_cstring(doc, offset + 1)?;
+        let (value, size) = match element_type {
+            0x01 => {
+                let b = doc.get(value_offset..value_offset.checked_add(8)?)?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(b);
+                (BsonValue::Double(f64::from_le_bytes(bytes)), 8)
+            }
+            0x02 => {
+                let len = read_len(doc, value_offset)?;
+                let start = value_offset.checked_add(4)?;
+                let s = doc.get(start..start.checked_add(len.saturating_sub(1))?)?;
+                (
+                    BsonValue::String(String::from_utf8_lossy(s).to_string()),
+                    len.checked_add(4)?,
+                )
+            }
+            0x03 | 0x04 => (BsonValue::Other, read_len(doc, value_offset)?),
+            0x05 => (
+                BsonValue::Other,
+                read_len(doc, value_offset)?.checked_add(5)?,
+            ),
+            0x07 => (BsonValue::Other, 12),
+            0x08 => (BsonValue::Bool(*doc.get(value_offset)? == 1), 1),
+            0x09 | 0x11 => (BsonValue::Other, 8),
+            0x0A => (BsonValue::Other, 0),
+            0x10 => (BsonValue::Int(read_i32_le(doc, value_offset)? as i64), 4),
+            0x12 => {
+                let b = doc.get(value_offset..value_offset.checked_add(8)?)?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(b);
+                (BsonValue::Int(i64::from_le_bytes(bytes)), 8)
+            }
+            0x13 => (BsonValue::Other, 16),
+            _ => return Some(elements),
+        };
+        elements.push((name, value));
+        offset = value_offset.checked_add(size)?;
+    }
+
+    Some(elements)
+}
+
+async fn mongodb_command(
+    stream: &mut TcpStream,
+    request_id: i32,
+    command: &str,
+) -> Result<Vec<(String, BsonValue)>, String> {
+    // flags, full collection name, number to skip, number to return, query
+    let mut body = 0i32.to_le_bytes().to_vec();
+    body.extend_from_slice(b"admin.$cmd\0");
+    body.extend_from_slice(&0i32.to_le_bytes());
+    body.extend_from_slice(&(-1i32).to_le_bytes());
+    body.extend_from_slice(&bson_command(command));
+
+    // Header: message length, request id, response to, op code
+    let mut message = ((body.len() + 16) as i32).to_le_bytes().to_vec();
+    message.extend_from_slice(&request_id.to_le_bytes());
+    message.extend_from_slice(&0i32.to_le_bytes());
+    message.extend_from_slice(&OP_QUERY.to_le_bytes());
+    message.extend_from_slice(&body);
+
+    stream
+        .write_all(&message)
+        .await
+        .map_err(|e| io_err("Command write error", e))?;
+
+    let mut header = [0; 16];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Reply read error", e))?;
+    let len = read_i32_le(&header, 0).unwrap_or(0) as usize;
+    if !(16..=MAX_MESSAGE_SIZE).contains(&len) || read_i32_le(&header, 12) != Some(OP_REPLY) {
+        return Err("Invalid OP_REPLY header".to_string());
+    }
+
+    let mut reply = vec![0; len - 16];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(|e| io_err("Reply read error", e))?;
+
+    // response flags (4), cursor id (8), starting from (4), number returned (4), documents
+    reply
+        .get(20..)
+        .and_then(parse_bson)
+        .ok_or_else(|| "Invalid OP_REPLY document".to_string())
+}
+
+async fn mongodb(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let mut fields = Vec::new();
+
+    for (name, value) in mongodb_command(&mut stream, 1, "buildInfo").await? {
+        match (name.as_str(), value) {
+            ("version", BsonValue::String(version)) => fields.push(field("version", version)),
+            ("maxWireVersion", BsonValue::Int(wire)) => {
+                fields.push(field("max_wire_version", wire))
+            }
+            _ => (),
+        }
+    }
+
+    let list = mongodb_command(&mut stream, 2, "listDatabases").await?;
+    let ok = list.iter().any(|(name, value)| {
+        name == "ok"
+            && match value {
+                BsonValue::Double(ok) => *ok == 1.0,
+                BsonValue::Int(ok) => *ok == 1,
+                BsonValue::Bool(ok) => *ok,
+                _ => false,
+            }
+    });
+    fields.push(field("auth", if ok { "none" } else { "required" }));
+
+    if let Some((_, BsonValue::String(error))) = list.iter().find(|(name, _)| name == "errmsg") {
+        fields.push(field("error", error));
+    }
+
+    Ok(fields)
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc::Sender,
+    time,
+};
+
+use crate::{
+    conf::Definition,
+    error::{FailClass, TimeoutPhase},
+    net,
+    plugins::{raw_response, BoxFuture, Probe, ProbeContext},
+    worker::{ReqTarget, WorkerMessage},
+};
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+const TYPE_A: u16 = 1;
+const TYPE_TXT: u16 = 16;
+pub(crate) const CLASS_IN: u16 = 1;
+const CLASS_CH: u16 = 3;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+// Name resolved to check if the server is an open resolver
+const RECURSION_TEST_NAME: &str = "example.com";
+
+// Asks for version.bind (CH TXT) and checks for open recursion, over UDP and (if the port is
+// open) TCP
+pub struct DnsProbe;
+
+impl Probe for DnsProbe {
+    fn protocol(&self) -> &'static str {
+        "dns"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut ports = HashSet::new();
+            for def in defs {
+                for port in &def.options.ports {
+                    ports.insert(*port);
+                }
+            }
+
+            for port in ports {
+                let mut transports = vec!["udp"];
+                if ctx.open_ports.contains(&port) {
+                    transports.push("tcp");
+                }
+
+                for transport in transports {
+                    if !ctx.spend_budget("dns", port, false).await {
+                        return;
+                    }
+                    ctx.ws.maybe_wait_for_permit(port).await;
+
+                    let mut target = ctx.target.clone();
+                    target.domain = String::new();
+                    target.protocol = "dns".to_string();
+                    target.port = port;
+                    target.time = Instant::now();
+                    target.connect_rtt = ctx.connect_rtts.get(&port).cloned();
+
+                    dns(
+                        ctx.tx.clone(),
+                        target,
+                        transport,
+                        ctx.ws.conf.req_timeout,
+                        ctx.ws.conf.source_ip,
+                    )
+                    .await;
+
+                    ctx.ws.maybe_release_permit(port).await;
+                }
+            }
+        })
+    }
+}
+
+pub(crate) fn build_query(
+    id: u16,
+    name: &str,
+    qtype: u16,
+    qclass: u16,
+    recursion: bool,
+) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&(if recursion { FLAG_RD } else { 0 }).to_be_bytes());
+    // qdcount, ancount, nscount, arcount
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&qclass.to_be_bytes());
+    query
+}
+
+pub(crate) fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Skips a (possibly compressed) domain name, returning the offset after it
+fn skip_name(buf: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(offset)?;
+        if len & 0xC0 == 0xC0 {
+            return Some(offset + 2);
+        }
+        offset += 1;
+        if len == 0 {
+            return Some(offset);
+        }
+        offset += len as usize;
+    }
+}
+
+struct DnsAnswer {
+    flags: u16,
+    answers: u16,
+    txt: Option<String>,
+}
+
+fn parse_response(id: u16, buf: &[u8]) -> Option<DnsAnswer> {
+    if read_u16(buf, 0)? != id {
+        return None;
+    }
+
+    let flags = read_u16(buf, 2)?;
+    let questions = read_u16(buf, 4)?;
+    let answers = read_u16(buf, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(buf, offset)? + 4;
+    }
+
+    // First TXT record among the answers (if any)
+    let mut txt = None;
+    for _ in 0..answers {
+        offset = skip_name(buf, offset)?;
+        let rtype = read_u16(buf, offset)?;
+        let rdlength = read_u16(buf, offset + 8)? as usize;
+        offset += 10;
+        let rdata = buf.get(offset..offset + rdlength)?;
+        offset += rdlength;
+
+        if rtype == TYPE_TXT && txt.is_none() {
+            let len = *rdata.first()? as usize;
+            txt = Some(String::from_utf8_lossy(rdata.get(1..1 + len)?).to_string());
+        }
+    }
+
+    Some(DnsAnswer {
+        flags,
+        answers,
+        txt,
+    })
+}
+
+pub(crate) async fn exchange(
+    addr: &SocketAddr,
+    source_ip: Option<IpAddr>,
+    transport: &str,
+    query: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut response = vec![0; MAX_MESSAGE_SIZE];
+
+    if transport == "udp" {
+        let socket = net::bind_udp(source_ip)
+            .await
+            .map_err(|e| format!("UDP socket error: {}", e))?;
+        socket
+            .connect(addr)
+            .await
+            .map_err(|e| format!("UDP connect error: {}", e))?;
+        socket
+            .send(query)
+            .await
+            .map_err(|e| format!("UDP send error: {}", e))?;
+        let n = socket
+            .recv(&mut response)
+            .await
+            .map_err(|e| format!("UDP receive error: {}", e))?;
+        response.truncate(n);
+    } else {
+        // Over TCP, messages are prefixed by their length
+        let mut stream = net::connect(addr, source_ip)
+            .await
+            .map_err(|e| format!("TCP stream connection error: {}", e))?;
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream
+            .write_all(&message)
+            .await
+            .map_err(|e| format!("TCP stream write error: {}", e))?;
+        let mut len = [0; 2];
+        stream
+            .read_exact(&mut len)
+            .await
+            .map_err(|e| format!("TCP stream read error: {}", e))?;
+        response.resize(u16::from_be_bytes(len) as usize, 0);
+        stream
+            .read_exact(&mut response)
+            .await
+            .map_err(|e| format!("TCP stream read error: {}", e))?;
+    }
+
+    Ok(response)
+}
+
+async fn query(
+    addr: &SocketAddr,
+    source_ip: Option<IpAddr>,
+    transport: &str,
+    name: &str,
+    qtype: u16,
+    qclass: u16,
+    recursion: bool,
+) -> Result<DnsAnswer, String> {
+    let id: u16 = rand::thread_rng().gen();
+    let response = exchange(
+        addr,
+        source_ip,
+        transport,
+        &build_query(id, name, qtype, qclass, recursion),
+    )
+    .await?;
+
+    match parse_response(id, &response) {
+        Some(answer) => Ok(answer),
+        None => Err("Invalid DNS response".to_string()),
+    }
+}
+
+pub(crate) async fn dns(
+    tx: Sender<WorkerMessage>,
+    mut target: ReqTarget,
+    transport: &str,
+    timeout: u64,
+    source_ip: Option<IpAddr>,
+) {
+    let addr = match net::socket_addr(&target.ip, target.port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid address".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return;
+        }
+    };
+
+    let to = Duration::from_secs(timeout);
+    let cb = async {
+        // Both queries are sent, many servers refuse the CHAOS one
+        let version = query(
+            &addr,
+            source_ip,
+            transport,
+            "version.bind",
+            TYPE_TXT,
+            CLASS_CH,
+            false,
+        )
+        .await;
+
+        // Open resolver: recursion available and an answer for a name it's not authoritative for
+        let recursion = query(
+            &addr,
+            source_ip,
+            transport,
+            RECURSION_TEST_NAME,
+            TYPE_A,
+            CLASS_IN,
+            true,
+        )
+        .await;
+
+        let (version, recursion) = match (version, recursion) {
+            (Err(e), Err(_)) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        target.clone(),
+                        FailClass::Protocol,
+                        "DNS query error".to_string(),
+                        Some(e),
+                    ))
+                    .await;
+                return;
+            }
+            (version, Ok(answer)) if answer.flags & FLAG_RA != 0 && answer.answers > 0 => {
+                (version.ok().and_then(|answer| answer.txt), "open")
+            }
+            (version, _) => (version.ok().and_then(|answer| answer.txt), "closed"),
+        };
+
+        target
+            .headers
+            .push(("transport".to_string(), transport.to_string()));
+        target
+            .headers
+            .push(("version".to_string(), version.clone().unwrap_or_default()));
+        target
+            .headers
+            .push(("recursion".to_string(), recursion.to_string()));
+        target.body = version.unwrap_or_default();
+        target.response = raw_response(&target.headers);
+
+        let _ = tx.send(WorkerMessage::Response(target.clone())).await;
+    };
+
+    if time::timeout(to, cb).await.is_err() {
+        let _ = tx
+            .send(WorkerMessage::Timeout(target.clone(), TimeoutPhase::Total))
+            .await;
+    };
+}
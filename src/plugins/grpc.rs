@@ -0,0 +1,300 @@
+use hyper::{body::HttpBody, client::conn, Body, HeaderMap, Request};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    conf::Definition,
+    plugins::{field, io_err, run_tcp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+// Max size of a single frame/message
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+/*
+ * h2c: HTTP/2 with prior knowledge, the connection preface and an empty SETTINGS frame in clear.
+ * An HTTP/2 server answers with its own SETTINGS frame, an HTTP/1.1 one with a 400 (or closes)
+ */
+
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const H2_FRAME_SETTINGS: u8 = 0x4;
+const H2_FRAME_GOAWAY: u8 = 0x7;
+
+pub struct H2cProbe;
+
+impl Probe for H2cProbe {
+    fn protocol(&self) -> &'static str {
+        "h2c"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "h2c", h2c))
+    }
+}
+
+fn h2_setting_name(id: u16) -> String {
+    match id {
+        0x1 => "header_table_size".to_string(),
+        0x2 => "enable_push".to_string(),
+        0x3 => "max_concurrent_streams".to_string(),
+        0x4 => "initial_window_size".to_string(),
+        0x5 => "max_frame_size".to_string(),
+        0x6 => "max_header_list_size".to_string(),
+        id => format!("setting_{}", id),
+    }
+}
+
+async fn h2c(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let mut request = H2_PREFACE.to_vec();
+    request.extend_from_slice(&[0, 0, 0, H2_FRAME_SETTINGS, 0, 0, 0, 0, 0]);
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|e| io_err("Preface write error", e))?;
+
+    // Length (24 bits), type, flags and stream id
+    let mut header = [0; 9];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Frame read error", e))?;
+    if header.starts_with(b"HTTP/") {
+        return Err("Not an HTTP/2 server (HTTP/1.x response)".to_string());
+    }
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err("Invalid HTTP/2 frame".to_string());
+    }
+    let mut payload = vec![0; len];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| io_err("Frame read error", e))?;
+
+    let mut fields = vec![field("h2c", "prior knowledge")];
+    match header[3] {
+        H2_FRAME_SETTINGS => {
+            for setting in payload.chunks_exact(6) {
+                fields.push(field(
+                    &h2_setting_name(u16::from_be_bytes([setting[0], setting[1]])),
+                    u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]),
+                ));
+            }
+            // SETTINGS acknowledgement, then a GOAWAY (no error) closing the connection
+            let mut frames = vec![0, 0, 0, H2_FRAME_SETTINGS, 0x1, 0, 0, 0, 0];
+            frames.extend_from_slice(&[0, 0, 8, H2_FRAME_GOAWAY]);
+            frames.extend_from_slice(&[0; 13]);
+            let _ = stream.write_all(&frames).await;
+        }
+        // Servers refusing the connection right away (e.g. HTTP/2 over TLS only)
+        H2_FRAME_GOAWAY if len >= 8 => {
+            let code = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+            fields.push(field("goaway", code));
+        }
+        _ => return Err("Invalid HTTP/2 frame".to_string()),
+    }
+
+    Ok(fields)
+}
+
+/*
+ * gRPC: ServerReflectionInfo (v1, then v1alpha) over h2c, listing the services exposed. The
+ * servers without reflection still answer with a grpc-status (12, unimplemented)
+ */
+
+const REFLECTION_SERVICES: [(&str, &str); 2] = [
+    ("v1", "grpc.reflection.v1.ServerReflection"),
+    ("v1alpha", "grpc.reflection.v1alpha.ServerReflection"),
+];
+
+const GRPC_UNIMPLEMENTED: &str = "12";
+
+// ServerReflectionRequest with an empty list_services (field 7)
+const LIST_SERVICES: [u8; 2] = [0x3a, 0x00];
+
+pub struct GrpcProbe;
+
+impl Probe for GrpcProbe {
+    fn protocol(&self) -> &'static str {
+        "grpc"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "grpc", grpc))
+    }
+}
+
+// Length-prefixed message (uncompressed) of the gRPC body
+fn grpc_message(message: &[u8]) -> Vec<u8> {
+    let mut data = vec![0];
+    data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    data.extend_from_slice(message);
+    data
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Length-delimited fields (number and value: strings, bytes, messages) of a protobuf message,
+// the other ones are skipped. None when it's malformed
+fn proto_fields(data: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        match key & 0x7 {
+            0 => {
+                read_varint(data, &mut pos)?;
+            }
+            1 => pos += 8,
+            2 => {
+                let len = read_varint(data, &mut pos)? as usize;
+                let value = data.get(pos..pos.checked_add(len)?)?;
+                pos += len;
+                fields.push((key >> 3, value));
+            }
+            5 => pos += 4,
+            _ => return None,
+        }
+    }
+    if pos > data.len() {
+        return None;
+    }
+    Some(fields)
+}
+
+// Service names of a ServerReflectionResponse (list_services_response, field 6, of
+// ServiceResponse messages with the name in field 1), or the error message (error_response,
+// field 7)
+fn reflection_services(message: &[u8]) -> Result<Vec<String>, String> {
+    let invalid = || "Invalid reflection response".to_string();
+    for (number, value) in proto_fields(message).ok_or_else(invalid)? {
+        match number {
+            6 => {
+                let mut services = Vec::new();
+                for (number, service) in proto_fields(value).ok_or_else(invalid)? {
+                    if number != 1 {
+                        continue;
+                    }
+                    for (number, name) in proto_fields(service).ok_or_else(invalid)? {
+                        if number == 1 {
+                            services.push(String::from_utf8_lossy(name).to_string());
+                        }
+                    }
+                }
+                return Ok(services);
+            }
+            7 => {
+                let message = proto_fields(value)
+                    .ok_or_else(invalid)?
+                    .into_iter()
+                    .find(|(number, _)| *number == 2)
+                    .map(|(_, message)| String::from_utf8_lossy(message).to_string())
+                    .unwrap_or_default();
+                return Err(format!("Reflection error: {}", message));
+            }
+            _ => (),
+        }
+    }
+    Err(invalid())
+}
+
+// grpc-status and grpc-message of the trailers, or of the headers (trailers-only responses)
+fn grpc_status(headers: &HeaderMap, trailers: Option<&HeaderMap>) -> Vec<(String, String)> {
+    ["grpc-status", "grpc-message"]
+        .iter()
+        .filter_map(|name| {
+            trailers
+                .and_then(|trailers| trailers.get(*name))
+                .or_else(|| headers.get(*name))
+                .and_then(|value| value.to_str().ok())
+                .map(|value| field(name, value))
+        })
+        .collect()
+}
+
+async fn grpc(stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let addr = stream
+        .peer_addr()
+        .map_err(|e| io_err("Peer address error", e))?;
+    let (mut sender, connection) = conn::Builder::new()
+        .http2_only(true)
+        .handshake::<_, Body>(stream)
+        .await
+        .map_err(|e| format!("HTTP/2 handshake error: {}", e))?;
+    tokio::spawn(connection);
+
+    let mut fields = Vec::new();
+    for (version, service) in REFLECTION_SERVICES.iter() {
+        let request = Request::post(format!("http://{}/{}/ServerReflectionInfo", addr, service))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(grpc_message(&LIST_SERVICES)))
+            .map_err(|e| e.to_string())?;
+        let mut response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| format!("HTTP/2 request error: {}", e))?;
+
+        let mut data = Vec::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            let chunk = chunk.map_err(|e| format!("HTTP/2 body read error: {}", e))?;
+            data.extend_from_slice(&chunk);
+            if data.len() > MAX_MESSAGE_SIZE {
+                return Err("gRPC response too large".to_string());
+            }
+        }
+        let trailers = response.body_mut().trailers().await.ok().flatten();
+
+        fields = vec![field("http_status", response.status().as_u16())];
+        for name in ["content-type", "server"].iter() {
+            if let Some(value) = response.headers().get(*name) {
+                fields.push(field(name, value.to_str().unwrap_or_default()));
+            }
+        }
+        let status = grpc_status(response.headers(), trailers.as_ref());
+        let unimplemented = status
+            .iter()
+            .any(|(name, value)| name == "grpc-status" && value == GRPC_UNIMPLEMENTED);
+        fields.extend(status);
+        if unimplemented {
+            continue;
+        }
+
+        // The first message of the body (compression flag, length and the message)
+        if data.len() >= 5 && data[0] == 0 {
+            let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+            if let Some(message) = data.get(5..5 + len) {
+                match reflection_services(message) {
+                    Ok(services) => {
+                        fields.push(field("reflection", version));
+                        fields.push(field("services", services.join(",")));
+                    }
+                    Err(e) => fields.push(field("reflection_error", e)),
+                }
+            }
+        }
+        break;
+    }
+
+    Ok(fields)
+}
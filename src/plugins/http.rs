@@ -0,0 +1,498 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::{
+    conf::Definition,
+    detector,
+    net::{self, HttpsOptions},
+    plugins::{BoxFuture, Probe, ProbeContext},
+    template,
+};
+
+pub struct HttpProbe;
+
+impl Probe for HttpProbe {
+    fn protocol(&self) -> &'static str {
+        "http/s"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["http", "https"]
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            // Avoid duplicate requests (same port, method, path, headers and payload), a request
+            // for each one of the paths of the definitions. In the order of the paths
+            let mut http_s_unique_opts: Vec<((u16, HttpsOptions), Vec<&Definition>)> = Vec::new();
+            let mut unique_opts_idx: HashMap<_, usize> = HashMap::new();
+            // The default credentials are tried separately, one pair at a time
+            for def in defs.iter().filter(|def| def.options.credentials.is_none()) {
+                let paths = match &def.options.paths {
+                    Some(paths) => paths.clone(),
+                    None => vec![def.options.path.clone().unwrap_or_else(|| "/".to_string())],
+                };
+
+                // The user agent of the definition is sent as a header, the definitions with
+                // different ones don't share the requests
+                let mut headers = def.options.headers.clone().unwrap_or_default();
+                if let Some(user_agent) = &def.options.user_agent {
+                    headers.push(("User-Agent".to_string(), user_agent.clone()));
+                }
+
+                for port in def
+                    .options
+                    .ports
+                    .iter()
+                    .filter(|p| ctx.open_ports.contains(p))
+                {
+                    for path in &paths {
+                        let options = HttpsOptions {
+                            method: def
+                                .options
+                                .method
+                                .clone()
+                                .unwrap_or_else(|| "GET".to_string()),
+                            path: path.clone(),
+                            headers: headers.clone(),
+                            payload: def
+                                .options
+                                .payload
+                                .clone()
+                                .unwrap_or_else(|| "".to_string()),
+                        };
+                        match unique_opts_idx.entry((*port, options)) {
+                            Entry::Occupied(entry) => http_s_unique_opts[*entry.get()].1.push(*def),
+                            Entry::Vacant(entry) => {
+                                http_s_unique_opts.push((entry.key().clone(), vec![*def]));
+                                entry.insert(http_s_unique_opts.len() - 1);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Schemes requested on each port, by the definitions restricted to one of them or not
+            let mut port_schemes: HashMap<u16, HashSet<&str>> = HashMap::new();
+            for def in defs {
+                for port in def.options.ports.iter() {
+                    if ctx.open_ports.contains(port) {
+                        port_schemes
+                            .entry(*port)
+                            .or_default()
+                            .extend(detector::http_schemes(&def.protocol));
+                    }
+                }
+            }
+
+            // Whether each port speaks TLS, only the matching scheme is requested (both when the
+            // sniffing is inconclusive). Not needed when the port gets a single scheme anyway
+            let mut tls = HashMap::new();
+            for (port, _) in port_schemes.iter().filter(|(_, s)| s.len() > 1) {
+                let port = *port;
+                if !ctx.spend_budget("tls", port, false).await {
+                    return;
+                }
+                ctx.ws.maybe_wait_for_permit(port).await;
+                let sniffed = net::sniff_tls(
+                    &ctx.target.ip,
+                    port,
+                    &ctx.target.domain,
+                    ctx.ws.conf.req_timeout,
+                    ctx.ws.conf.source_ip,
+                )
+                .await;
+                ctx.ws.maybe_release_permit(port).await;
+                tls.insert(port, sniffed);
+            }
+
+            // Protocol and port pairs whose requests failed (e.g. https on a plain http port),
+            // skipped for the remaining definitions instead of opening a new connection each time
+            let mut failed = HashSet::new();
+            // Headers of the HEAD requests by protocol and port (--head-first)
+            let mut heads = HashMap::new();
+            // Scheme fallbacks of the ports not sniffed, by protocol and port
+            let mut fallbacks = HashMap::new();
+            // Requests already sent with the other scheme after a fallback, by scheme, not sent
+            // again by the pass of that scheme
+            let mut fell_back = HashSet::new();
+            // Definitions already matched by protocol and port, their next paths are not requested
+            let mut matched = HashSet::new();
+            // Favicon hashes by scheme and port, certificate common names by port (indicators)
+            let mut favicons = HashMap::new();
+            let mut tls_cns = HashMap::new();
+
+            for protocol in ["https", "http"].iter() {
+                for (key, opts_defs) in &http_s_unique_opts {
+                    let (port, opts) = key;
+                    let opts_defs: Vec<&Definition> = opts_defs
+                        .iter()
+                        .filter(|def| !matched.contains(&(*protocol, *port, def.name.as_str())))
+                        .cloned()
+                        .collect();
+                    let skip_scheme = match tls.get(port).cloned().flatten() {
+                        Some(tls) => tls != (*protocol == "https"),
+                        None => false,
+                    };
+                    // Only the schemes of the definitions sharing the request
+                    let wanted = opts_defs
+                        .iter()
+                        .any(|def| detector::http_schemes(&def.protocol).contains(protocol));
+                    if !wanted
+                        || skip_scheme
+                        || failed.contains(&(*protocol, *port))
+                        || fell_back.contains(&(*protocol, key))
+                    {
+                        continue;
+                    }
+
+                    // Sequential requests to the same host reuse the client's pooled
+                    // (keep-alive) connection, stopping at the first matching path
+                    // The largest max size of the definitions sharing the request
+                    let max_bytes = opts_defs
+                        .iter()
+                        .map(|def| {
+                            def.options
+                                .max_response_bytes
+                                .unwrap_or(ctx.ws.conf.max_response_bytes)
+                        })
+                        .max()
+                        .unwrap_or(ctx.ws.conf.max_response_bytes);
+                    // And the longest timeouts
+                    let timeouts = opts_defs
+                        .iter()
+                        .map(|def| ctx.ws.conf.timeouts(Some(def)))
+                        .reduce(net::Timeouts::longest)
+                        .unwrap_or_else(|| ctx.ws.conf.timeouts(None));
+
+                    // The GET requests are skipped when the headers of the port don't look
+                    // promising for any of the definitions (all of them with a head_regex)
+                    let head_regexes: Option<Vec<&Regex>> = opts_defs
+                        .iter()
+                        .map(|def| def.options.compiled_head_regex.as_ref())
+                        .collect();
+                    if let (true, Some(head_regexes)) = (ctx.ws.conf.head_first, head_regexes) {
+                        if let Entry::Vacant(entry) = heads.entry((*protocol, *port)) {
+                            if !ctx.spend_budget(protocol, *port, false).await {
+                                return;
+                            }
+                            ctx.ws.maybe_wait_for_permit(*port).await;
+                            let mut target = ctx.target.clone();
+                            target.protocol = protocol.to_string();
+                            target.port = *port;
+                            let head = net::http_head(
+                                &ctx.ws.https_client,
+                                &target,
+                                ctx.ws.user_agents.next(),
+                                ctx.ws.conf.req_timeout,
+                            )
+                            .await;
+                            ctx.ws.maybe_release_permit(*port).await;
+                            entry.insert(head);
+                        }
+                        if !detector::head_matches(&heads[&(*protocol, *port)], &head_regexes) {
+                            continue;
+                        }
+                    }
+
+                    let auth = opts_defs.iter().any(|def| def.is_auth());
+                    let favicon = opts_defs.iter().any(|def| detector::needs_favicon(def));
+                    let tls_cn = opts_defs.iter().any(|def| detector::needs_tls_cn(def));
+                    // The body is not downloaded when no definition of the request needs it (and
+                    // the unknown responses are not collected)
+                    let headers_only = !ctx.ws.conf.collect_unknown
+                        && opts_defs
+                            .iter()
+                            .all(|def| detector::matches_headers_only(def));
+                    // The other scheme once the port fell back to it
+                    let mut scheme = fallbacks
+                        .get(&(*protocol, *port))
+                        .cloned()
+                        .flatten()
+                        .unwrap_or(*protocol);
+                    // Failure messages of the request retried with the other scheme, only
+                    // sent if the retry fails too
+                    let mut held = Vec::new();
+                    let response = loop {
+                        // The favicon and the certificate of the port, once
+                        if favicon && !favicons.contains_key(&(scheme, *port)) {
+                            match fetch_favicon(ctx, scheme, *port).await {
+                                Some(hash) => favicons.insert((scheme, *port), hash),
+                                None => return,
+                            };
+                        }
+                        if tls_cn && scheme == "https" && !tls_cns.contains_key(port) {
+                            match ctx.tls_common_name(*port).await {
+                                Some(common_name) => tls_cns.insert(*port, common_name),
+                                None => return,
+                            };
+                        }
+
+                        if !ctx.spend_budget(scheme, *port, auth).await {
+                            return;
+                        }
+                        ctx.ws.maybe_wait_for_permit(*port).await;
+
+                        let mut target = ctx.target.clone();
+                        target.protocol = scheme.to_string();
+                        target.port = *port;
+                        target.time = Instant::now();
+                        target.connect_rtt = ctx.connect_rtts.get(port).cloned();
+                        target.headers_only = headers_only;
+                        target.favicon_hash = favicons.get(&(scheme, *port)).cloned().flatten();
+                        if scheme == "https" {
+                            target.tls_cn = tls_cns.get(port).cloned().flatten();
+                        }
+                        if scheme != *protocol {
+                            target.scheme_fallback = Some(protocol.to_string());
+                        }
+
+                        // Placeholders of the path and payload expanded for the target
+                        let mut opts = opts.clone();
+                        opts.path = template::expand(&opts.path, &target);
+                        opts.payload = template::expand(&opts.payload, &target);
+
+                        // The messages of a request that may be retried are held until known
+                        let may_fall_back = scheme == *protocol
+                            && !tls.contains_key(port)
+                            && fallbacks.get(&(*protocol, *port)) != Some(&None);
+                        let (tx, mut attempt_rx) = if may_fall_back {
+                            let (tx, rx) = mpsc::channel(2);
+                            (tx, Some(rx))
+                        } else {
+                            (ctx.tx.clone(), None)
+                        };
+
+                        let response = net::http_s(
+                            tx,
+                            ctx.ws.https_client_for(&timeouts).clone(),
+                            target,
+                            opts,
+                            ctx.ws.user_agents.next().to_string(),
+                            timeouts,
+                            max_bytes,
+                        )
+                        .await;
+
+                        ctx.ws.maybe_release_permit(*port).await;
+
+                        let mut attempt = Vec::new();
+                        if let Some(attempt_rx) = &mut attempt_rx {
+                            while let Some(msg) = attempt_rx.recv().await {
+                                attempt.push(msg);
+                            }
+                        }
+
+                        // A failed request is retried with the other scheme when the port
+                        // speaks it (the ports sniffed already got the right one)
+                        if response.is_none() && may_fall_back {
+                            if let Some(fallback) =
+                                fallback_scheme(ctx, &mut fallbacks, protocol, *port).await
+                            {
+                                scheme = fallback;
+                                held = attempt;
+                                continue;
+                            }
+                        }
+                        if response.is_some() {
+                            held.clear();
+                        }
+                        for msg in held.drain(..).chain(attempt) {
+                            let _ = ctx.tx.send(msg).await;
+                        }
+                        break response;
+                    };
+                    if scheme != *protocol {
+                        fell_back.insert((scheme, key));
+                    }
+
+                    match response {
+                        Some(response) => {
+                            for def in &opts_defs {
+                                if detector::matches_any(&response, &[*def]) {
+                                    matched.insert((*protocol, *port, def.name.as_str()));
+                                    matched.insert((scheme, *port, def.name.as_str()));
+                                }
+                            }
+                        }
+                        None => {
+                            failed.insert((*protocol, *port));
+                        }
+                    }
+                }
+            }
+
+            // Default credentials (--try-default-creds): a single attempt at a time, at least
+            // --auth-delay apart, stopping at the first pair matching the definition
+            let auth_delay = Duration::from_millis(ctx.ws.conf.auth_delay);
+            let mut last_attempt: Option<Instant> = None;
+            for def in defs.iter() {
+                let credentials = match &def.options.credentials {
+                    Some(credentials) => credentials,
+                    None => continue,
+                };
+                let ports = def
+                    .options
+                    .ports
+                    .iter()
+                    .filter(|p| ctx.open_ports.contains(p));
+                for port in ports {
+                    // Once a scheme fell back to the other one, the latter is not tried again
+                    let mut schemes = HashSet::new();
+                    'schemes: for protocol in detector::http_schemes(&def.protocol) {
+                        let skip_scheme = match tls.get(port).cloned().flatten() {
+                            Some(tls) => tls != (*protocol == "https"),
+                            None => false,
+                        };
+                        if skip_scheme || failed.contains(&(*protocol, *port)) {
+                            continue;
+                        }
+                        let scheme = fallbacks
+                            .get(&(*protocol, *port))
+                            .cloned()
+                            .flatten()
+                            .unwrap_or(*protocol);
+                        if !schemes.insert(scheme) {
+                            continue;
+                        }
+
+                        for (user, password) in credentials {
+                            if let Some(last) = last_attempt {
+                                sleep(auth_delay.saturating_sub(last.elapsed())).await;
+                            }
+                            if !ctx.spend_budget(scheme, *port, true).await {
+                                return;
+                            }
+                            ctx.ws.maybe_wait_for_permit(*port).await;
+
+                            let mut target = ctx.target.clone();
+                            target.protocol = scheme.to_string();
+                            if scheme != *protocol {
+                                target.scheme_fallback = Some(protocol.to_string());
+                            }
+                            target.port = *port;
+                            target.time = Instant::now();
+                            target.connect_rtt = ctx.connect_rtts.get(port).cloned();
+                            target.credentials = Some(format!("{}:{}", user, password));
+                            target.headers_only =
+                                !ctx.ws.conf.collect_unknown && detector::matches_headers_only(def);
+
+                            let mut headers = def.options.headers.clone().unwrap_or_default();
+                            if let Some(user_agent) = &def.options.user_agent {
+                                headers.push(("User-Agent".to_string(), user_agent.clone()));
+                            }
+                            headers.push((
+                                "Authorization".to_string(),
+                                net::basic_auth(user, password),
+                            ));
+                            let path = def.options.path.as_deref().unwrap_or("/");
+                            let payload = def.options.payload.as_deref().unwrap_or("");
+                            let opts = HttpsOptions {
+                                method: def
+                                    .options
+                                    .method
+                                    .clone()
+                                    .unwrap_or_else(|| "GET".to_string()),
+                                path: template::expand(path, &target),
+                                headers,
+                                payload: template::expand(payload, &target),
+                            };
+
+                            let timeouts = ctx.ws.conf.timeouts(Some(def));
+                            let response = net::http_s(
+                                ctx.tx.clone(),
+                                ctx.ws.https_client_for(&timeouts).clone(),
+                                target,
+                                opts,
+                                ctx.ws.user_agents.next().to_string(),
+                                timeouts,
+                                def.options
+                                    .max_response_bytes
+                                    .unwrap_or(ctx.ws.conf.max_response_bytes),
+                            )
+                            .await;
+                            last_attempt = Some(Instant::now());
+
+                            ctx.ws.maybe_release_permit(*port).await;
+
+                            match response {
+                                Some(response) if !detector::matches_any(&response, &[*def]) => (),
+                                Some(_) => break 'schemes,
+                                None => {
+                                    failed.insert((*protocol, *port));
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+// Hash of the favicon of the web server (for the indicators of the definitions), None when the
+// budget of the host is exceeded
+async fn fetch_favicon(ctx: &ProbeContext<'_>, scheme: &str, port: u16) -> Option<Option<i32>> {
+    if !ctx.spend_budget(scheme, port, false).await {
+        return None;
+    }
+    ctx.ws.maybe_wait_for_permit(port).await;
+    let mut target = ctx.target.clone();
+    target.protocol = scheme.to_string();
+    target.port = port;
+    let hash = net::favicon_get(
+        &ctx.ws.https_client,
+        &target,
+        ctx.ws.user_agents.next(),
+        ctx.ws.conf.req_timeout,
+        ctx.ws.conf.max_response_bytes,
+    )
+    .await;
+    ctx.ws.maybe_release_permit(port).await;
+    Some(hash)
+}
+
+// The other scheme of a request failed on a port not sniffed, when the port speaks it: http after
+// an https request to a plain port (e.g. a TLS handshake error), https after an http request to a
+// TLS one (a binary reply). The port is sniffed once per scheme
+async fn fallback_scheme(
+    ctx: &ProbeContext<'_>,
+    fallbacks: &mut HashMap<(&'static str, u16), Option<&'static str>>,
+    protocol: &'static str,
+    port: u16,
+) -> Option<&'static str> {
+    if let Some(fallback) = fallbacks.get(&(protocol, port)) {
+        return *fallback;
+    }
+    if !ctx.spend_budget("tls", port, false).await {
+        return None;
+    }
+    ctx.ws.maybe_wait_for_permit(port).await;
+    let sniffed = net::sniff_tls(
+        &ctx.target.ip,
+        port,
+        &ctx.target.domain,
+        ctx.ws.conf.req_timeout,
+        ctx.ws.conf.source_ip,
+    )
+    .await;
+    ctx.ws.maybe_release_permit(port).await;
+
+    let fallback = match (protocol, sniffed) {
+        ("https", Some(false)) => Some("http"),
+        ("http", Some(true)) => Some("https"),
+        _ => None,
+    };
+    fallbacks.insert((protocol, port), fallback);
+    fallback
+}
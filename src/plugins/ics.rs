@@ -0,0 +1,321 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::{
+    conf::Definition,
+    plugins::{field, io_err, run_tcp_probe, run_udp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+fn read_cstring(buf: &[u8], offset: usize) -> Option<String> {
+    let bytes = buf.get(offset..)?;
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let s = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/*
+ * Modbus: read device identification (function 0x2B, MEI type 0x0E), basic objects
+ */
+
+// MBAP header (transaction id, protocol id, length, unit id), read device id code 1 (basic),
+// starting from object 0
+const MODBUS_READ_DEVICE_ID: [u8; 11] = [
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x2B, 0x0E, 0x01, 0x00,
+];
+
+pub struct ModbusProbe;
+
+impl Probe for ModbusProbe {
+    fn protocol(&self) -> &'static str {
+        "modbus"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "modbus", modbus))
+    }
+}
+
+fn modbus_object(id: u8) -> String {
+    match id {
+        0 => "vendor".to_string(),
+        1 => "product_code".to_string(),
+        2 => "revision".to_string(),
+        3 => "vendor_url".to_string(),
+        4 => "product_name".to_string(),
+        5 => "model".to_string(),
+        6 => "application".to_string(),
+        _ => format!("object_{}", id),
+    }
+}
+
+pub(crate) async fn modbus(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    stream
+        .write_all(&MODBUS_READ_DEVICE_ID)
+        .await
+        .map_err(|e| io_err("Request write error", e))?;
+
+    let mut mbap = [0; 7];
+    stream
+        .read_exact(&mut mbap)
+        .await
+        .map_err(|e| io_err("Response read error", e))?;
+    let len = u16::from_be_bytes([mbap[4], mbap[5]]) as usize;
+    if !(2..=MAX_MESSAGE_SIZE).contains(&len) {
+        return Err(format!("Invalid MBAP length: {}", len));
+    }
+
+    let mut pdu = vec![0; len - 1];
+    stream
+        .read_exact(&mut pdu)
+        .await
+        .map_err(|e| io_err("Response read error", e))?;
+
+    // Exception response (function code | 0x80, exception code)
+    if pdu[0] == 0xAB {
+        return Ok(vec![field("exception", pdu.get(1).copied().unwrap_or(0))]);
+    }
+    if pdu[0] != 0x2B || pdu.get(1) != Some(&0x0E) {
+        return Err("Unexpected Modbus function in response".to_string());
+    }
+
+    // MEI type, read device id code, conformity level, more follows, next object id, objects
+    // count, then the objects (id, length, value)
+    let count = *pdu.get(6).ok_or("Invalid device identification response")?;
+    let mut fields = vec![field("unit_id", mbap[6])];
+    let mut offset = 7;
+    for _ in 0..count {
+        let id = *pdu.get(offset).ok_or("Truncated object")?;
+        let len = *pdu.get(offset + 1).ok_or("Truncated object")? as usize;
+        let value = pdu
+            .get(offset + 2..offset + 2 + len)
+            .ok_or("Truncated object")?;
+        fields.push(field(
+            &modbus_object(id),
+            String::from_utf8_lossy(value).trim(),
+        ));
+        offset += 2 + len;
+    }
+
+    Ok(fields)
+}
+
+/*
+ * S7comm: COTP connection (rack 0, slot 2), setup communication and SZL reads of the module
+ * identification (0x0011) and component identification (0x001C)
+ */
+
+const S7_COTP_CR: [u8; 22] = [
+    0x03, 0x00, 0x00, 0x16, 0x11, 0xE0, 0x00, 0x00, 0x00, 0x14, 0x00, 0xC1, 0x02, 0x01, 0x00, 0xC2,
+    0x02, 0x01, 0x02, 0xC0, 0x01, 0x0A,
+];
+const S7_SETUP_COMM: [u8; 25] = [
+    0x03, 0x00, 0x00, 0x19, 0x02, 0xF0, 0x80, 0x32, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00,
+    0x00, 0xF0, 0x00, 0x00, 0x01, 0x00, 0x01, 0x01, 0xE0,
+];
+const S7_SZL_MODULE_ID: [u8; 33] = [
+    0x03, 0x00, 0x00, 0x21, 0x02, 0xF0, 0x80, 0x32, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00,
+    0x08, 0x00, 0x01, 0x12, 0x04, 0x11, 0x44, 0x01, 0x00, 0xFF, 0x09, 0x00, 0x04, 0x00, 0x11, 0x00,
+    0x01,
+];
+const S7_SZL_COMPONENT_ID: [u8; 33] = [
+    0x03, 0x00, 0x00, 0x21, 0x02, 0xF0, 0x80, 0x32, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00,
+    0x08, 0x00, 0x01, 0x12, 0x04, 0x11, 0x44, 0x01, 0x00, 0xFF, 0x09, 0x00, 0x04, 0x00, 0x1C, 0x00,
+    0x01,
+];
+
+pub struct S7Probe;
+
+impl Probe for S7Probe {
+    fn protocol(&self) -> &'static str {
+        "s7"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "s7", s7))
+    }
+}
+
+// Sends a TPKT packet and reads the whole TPKT response
+async fn tpkt_request(stream: &mut TcpStream, request: &[u8]) -> Result<Vec<u8>, String> {
+    stream
+        .write_all(request)
+        .await
+        .map_err(|e| io_err("Request write error", e))?;
+
+    let mut header = [0; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| io_err("Response read error", e))?;
+    if header[0] != 0x03 {
+        return Err("Invalid TPKT header".to_string());
+    }
+
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut response = header.to_vec();
+    response.resize(len.max(4), 0);
+    stream
+        .read_exact(&mut response[4..])
+        .await
+        .map_err(|e| io_err("Response read error", e))?;
+
+    Ok(response)
+}
+
+pub(crate) async fn s7(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let cc = tpkt_request(&mut stream, &S7_COTP_CR).await?;
+    // COTP connection confirm
+    if cc.get(5) != Some(&0xD0) {
+        return Err("COTP connection refused".to_string());
+    }
+
+    let setup = tpkt_request(&mut stream, &S7_SETUP_COMM).await?;
+    // S7 protocol id
+    if setup.get(7) != Some(&0x32) {
+        return Err("Invalid S7 setup communication response".to_string());
+    }
+
+    let mut fields = Vec::new();
+
+    let module = tpkt_request(&mut stream, &S7_SZL_MODULE_ID).await?;
+    if let Some(order_number) = read_cstring(&module, 43) {
+        fields.push(field("module", order_number));
+    }
+    if let Some(hardware) = read_cstring(&module, 71) {
+        fields.push(field("hardware", hardware));
+    }
+    if let Some(v) = module.get(122..125) {
+        fields.push(field("firmware", format!("{}.{}.{}", v[0], v[1], v[2])));
+    }
+
+    let component = tpkt_request(&mut stream, &S7_SZL_COMPONENT_ID).await?;
+    for (name, offset) in [
+        ("system_name", 39),
+        ("module_type", 73),
+        ("plant_id", 107),
+        ("copyright", 141),
+        ("serial", 175),
+    ]
+    .iter()
+    {
+        if let Some(value) = read_cstring(&component, *offset) {
+            fields.push(field(name, value));
+        }
+    }
+
+    Ok(fields)
+}
+
+/*
+ * BACnet/IP: ReadProperty requests to the device object (wildcard instance), over UDP
+ */
+
+const BACNET_PROPERTIES: [(&str, u8); 5] = [
+    ("vendor", 121),
+    ("model", 70),
+    ("firmware", 44),
+    ("application_software", 12),
+    ("object_name", 77),
+];
+
+pub struct BacnetProbe;
+
+impl Probe for BacnetProbe {
+    fn protocol(&self) -> &'static str {
+        "bacnet"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_udp_probe(ctx, defs, "bacnet", bacnet))
+    }
+}
+
+// BVLC (original unicast), NPDU (expecting reply), confirmed ReadProperty of the device object
+// 4194303 (any device)
+fn bacnet_read_property(invoke_id: u8, property: u8) -> Vec<u8> {
+    vec![
+        0x81, 0x0A, 0x00, 0x11, 0x01, 0x04, 0x00, 0x05, invoke_id, 0x0C, 0x0C, 0x02, 0x3F, 0xFF,
+        0xFF, 0x19, property,
+    ]
+}
+
+// Value between the opening (0x3E) and closing (0x3F) tags of a ReadProperty ComplexACK
+pub(crate) fn bacnet_value(response: &[u8], property: u8) -> Option<String> {
+    // APDU type 3 (ComplexACK)
+    let apdu = response.iter().position(|b| *b & 0xF0 == 0x30)?;
+    let offset = response
+        .get(apdu..)?
+        .windows(3)
+        .position(|w| w == [0x19, property, 0x3E])?
+        + apdu
+        + 3;
+
+    let tag = *response.get(offset)?;
+    let (mut len, mut value_offset) = ((tag & 0x07) as usize, offset + 1);
+    if len == 5 {
+        len = *response.get(value_offset)? as usize;
+        value_offset += 1;
+    }
+    let value = response.get(value_offset..value_offset + len)?;
+
+    match tag >> 4 {
+        // Unsigned integer
+        2 => Some(
+            value
+                .iter()
+                .fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                .to_string(),
+        ),
+        // Character string, the first byte is the encoding
+        7 => Some(String::from_utf8_lossy(value.get(1..)?).to_string()),
+        _ => None,
+    }
+}
+
+async fn bacnet(socket: UdpSocket) -> Result<Vec<(String, String)>, String> {
+    let mut fields = Vec::new();
+
+    for (idx, (name, property)) in BACNET_PROPERTIES.iter().enumerate() {
+        socket
+            .send(&bacnet_read_property(idx as u8, *property))
+            .await
+            .map_err(|e| io_err("Request send error", e))?;
+
+        let mut response = vec![0; MAX_MESSAGE_SIZE];
+        let n = socket
+            .recv(&mut response)
+            .await
+            .map_err(|e| io_err("Response receive error", e))?;
+        response.truncate(n);
+
+        if response.first() != Some(&0x81) {
+            return Err("Invalid BVLC header".to_string());
+        }
+
+        if let Some(value) = bacnet_value(&response, *property) {
+            fields.push(field(name, value));
+        }
+    }
+
+    Ok(fields)
+}
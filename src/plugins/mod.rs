@@ -0,0 +1,547 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::{mpsc::Sender, Mutex},
+    time,
+};
+
+use crate::{
+    conf::Definition,
+    detector::{DefinitionsDetector, Detector, DetectorResponse},
+    error::{FailClass, TimeoutPhase},
+    net,
+    worker::{ReqTarget, WorkerMessage, WorkerState},
+};
+
+pub(crate) mod brokers;
+pub(crate) mod datastores;
+pub(crate) mod dns;
+mod grpc;
+mod http;
+#[cfg(feature = "ics")]
+pub(crate) mod ics;
+pub(crate) mod remote_access;
+pub(crate) mod ssh;
+mod tcp_custom;
+mod websocket;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub struct ProbeContext<'a> {
+    pub ws: &'a WorkerState,
+    pub tx: &'a Sender<WorkerMessage>,
+    pub target: &'a ReqTarget,
+    pub open_ports: &'a HashSet<u16>,
+    // Connections opened while checking the ports, each one reused by the first tcp probe
+    pub streams: Mutex<HashMap<u16, TcpStream>>,
+    // TCP connect times (ms) of the open ports
+    pub connect_rtts: HashMap<u16, u64>,
+    // Budget of the host, shared by all the targets of its ip
+    pub budget: Arc<HostBudget>,
+}
+
+// Requests sent to the host (ip) and authentication attempts, against the per-host budget
+// (--host-max-requests, --host-max-auth), and the instant of the next request allowed by the
+// per-host delay (--host-delay)
+#[derive(Default)]
+pub struct HostBudget {
+    requests: AtomicU64,
+    auth_attempts: AtomicU64,
+    exceeded: AtomicBool,
+    next_request: std::sync::Mutex<Option<Instant>>,
+}
+
+impl<'a> ProbeContext<'a> {
+    pub async fn take_stream(&self, port: u16) -> Option<TcpStream> {
+        self.streams.lock().await.remove(&port)
+    }
+
+    // Takes a request from the budget of the host, false when it's exceeded. The first request
+    // over the budget is reported as a failure, the following ones are skipped silently. The
+    // request is then delayed by the per-host delay, if any
+    pub async fn spend_budget(&self, protocol: &str, port: u16, auth: bool) -> bool {
+        if self.budget_exceeded() {
+            return false;
+        }
+
+        let conf = &self.ws.conf;
+        let requests = self.budget.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        let auth_attempts = if auth {
+            self.budget.auth_attempts.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.budget.auth_attempts.load(Ordering::SeqCst)
+        };
+
+        let exceeded = if conf.host_max_requests != 0 && requests > conf.host_max_requests {
+            format!("max {} requests", conf.host_max_requests)
+        } else if conf.host_max_auth != 0 && auth_attempts > conf.host_max_auth {
+            format!("max {} authentication attempts", conf.host_max_auth)
+        } else {
+            self.pace().await;
+            return true;
+        };
+
+        if !self.budget.exceeded.swap(true, Ordering::SeqCst) {
+            let _ = self
+                .tx
+                .send(WorkerMessage::Fail(
+                    probe_target(self, protocol, port),
+                    FailClass::Budget,
+                    "Per-host budget exceeded, the remaining probes are skipped".to_string(),
+                    Some(exceeded),
+                ))
+                .await;
+        }
+        false
+    }
+
+    // Spaces the requests to the host by --host-delay plus a random jitter (--host-jitter). The
+    // wait comes before the concurrency and rate limits permits, so the requests to the other
+    // hosts are sent meanwhile (interleaved) instead of a burst to a single host
+    async fn pace(&self) {
+        let conf = &self.ws.conf;
+        if conf.host_delay == 0 && conf.host_jitter == 0 {
+            return;
+        }
+        let jitter = match conf.host_jitter {
+            0 => 0,
+            jitter => rand::thread_rng().gen_range(0..=jitter),
+        };
+        let slot = {
+            let mut next_request = self.budget.next_request.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_request.map_or(now, |next| next.max(now));
+            *next_request = Some(slot + Duration::from_millis(conf.host_delay + jitter));
+            slot
+        };
+        time::sleep_until(slot.into()).await;
+    }
+
+    pub fn budget_exceeded(&self) -> bool {
+        self.budget.exceeded.load(Ordering::SeqCst)
+    }
+
+    // Common name of the TLS certificate of the port (for the indicators of the definitions), None
+    // when the budget of the host is exceeded
+    pub async fn tls_common_name(&self, port: u16) -> Option<Option<String>> {
+        if !self.spend_budget("tls", port, false).await {
+            return None;
+        }
+        self.ws.maybe_wait_for_permit(port).await;
+        let common_name = net::tls_common_name(
+            &self.target.ip,
+            port,
+            &self.target.domain,
+            self.ws.conf.req_timeout,
+            self.ws.conf.source_ip,
+        )
+        .await;
+        self.ws.maybe_release_permit(port).await;
+        Some(common_name)
+    }
+}
+
+// A probe handles all the definitions with its protocol. The responses (or failures, timeouts)
+// are sent to the receiver loop as worker messages, then matched by the detectors
+pub trait Probe: Send + Sync {
+    fn protocol(&self) -> &'static str;
+
+    // Other protocols of the definitions handled by the probe (e.g. the single scheme http ones)
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn handles(&self, protocol: &str) -> bool {
+        protocol == self.protocol() || self.aliases().contains(&protocol)
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()>;
+}
+
+// Raw response of the probes exposing structured fields as "headers" (one "name: value" per line)
+pub fn raw_response(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+pub fn field(name: &str, value: impl ToString) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+pub fn io_err(context: &str, e: std::io::Error) -> String {
+    format!("{}: {}", context, e)
+}
+
+// Unique open ports of the definitions (each port is probed only once)
+pub fn open_ports(ctx: &ProbeContext, defs: &[&Definition]) -> Vec<u16> {
+    let mut ports = Vec::new();
+    for def in defs {
+        for port in &def.options.ports {
+            if ctx.open_ports.contains(port) && !ports.contains(port) {
+                ports.push(*port);
+            }
+        }
+    }
+    ports
+}
+
+pub fn probe_target(ctx: &ProbeContext, protocol: &str, port: u16) -> ReqTarget {
+    let mut target = ctx.target.clone();
+    target.domain = String::new();
+    target.protocol = protocol.to_string();
+    target.port = port;
+    target.time = Instant::now();
+    target.connect_rtt = ctx.connect_rtts.get(&port).cloned();
+    target
+}
+
+// Connects to the target (or reuses the cached connection) and runs the protocol exchange. The
+// resulting structured fields are exposed to the definitions as headers, and all together as raw
+// response and body
+pub async fn tcp_exchange<F, Fut, R>(
+    tx: Sender<WorkerMessage>,
+    mut target: ReqTarget,
+    timeouts: net::Timeouts,
+    source_ip: Option<IpAddr>,
+    cached: Option<TcpStream>,
+    retry_budget: R,
+    exchange: F,
+) where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = Result<Vec<(String, String)>, String>>,
+    R: Future<Output = bool>,
+{
+    let addr = match net::socket_addr(&target.ip, target.port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid address".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return;
+        }
+    };
+
+    let cb = async {
+        // An exchange failed over the cached connection (e.g. closed by the peer in the meantime)
+        // is retried over a new one, another request against the budget of the host
+        let cached_result = match cached {
+            Some(stream) => match exchange(stream).await {
+                Ok(fields) => Some(fields),
+                Err(_) if !retry_budget.await => return,
+                Err(_) => None,
+            },
+            None => None,
+        };
+
+        let result = match cached_result {
+            Some(fields) => Ok(fields),
+            None => match time::timeout(timeouts.connect, net::connect(&addr, source_ip)).await {
+                Ok(Ok(stream)) => exchange(stream).await,
+                Err(_) => {
+                    let _ = tx
+                        .send(WorkerMessage::Timeout(
+                            target.clone(),
+                            TimeoutPhase::Connect,
+                        ))
+                        .await;
+                    return;
+                }
+                Ok(Err(e)) => {
+                    let _ = tx
+                        .send(WorkerMessage::Fail(
+                            target.clone(),
+                            FailClass::from_io(&e),
+                            "TCP stream connection error".to_string(),
+                            Some(e.to_string()),
+                        ))
+                        .await;
+                    return;
+                }
+            },
+        };
+
+        let fields = match result {
+            Ok(fields) => fields,
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        target.clone(),
+                        FailClass::Protocol,
+                        format!("{} exchange error", target.protocol.to_uppercase()),
+                        Some(e),
+                    ))
+                    .await;
+                return;
+            }
+        };
+
+        target.headers.extend(fields);
+        target.response = raw_response(&target.headers);
+        target.body = target.response.clone();
+
+        let _ = tx.send(WorkerMessage::Response(target.clone())).await;
+    };
+
+    if time::timeout(timeouts.total, cb).await.is_err() {
+        let _ = tx
+            .send(WorkerMessage::Timeout(target.clone(), TimeoutPhase::Total))
+            .await;
+    };
+}
+
+// Runs the exchange against every open port of the definitions
+pub async fn run_tcp_probe<F, Fut>(
+    ctx: &ProbeContext<'_>,
+    defs: &[&Definition],
+    protocol: &str,
+    exchange: F,
+) where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = Result<Vec<(String, String)>, String>>,
+{
+    let auth = defs.iter().any(|def| def.is_auth());
+    for port in open_ports(ctx, defs) {
+        if !ctx.spend_budget(protocol, port, auth).await {
+            return;
+        }
+        ctx.ws.maybe_wait_for_permit(port).await;
+
+        tcp_exchange(
+            ctx.tx.clone(),
+            probe_target(ctx, protocol, port),
+            ctx.ws.conf.timeouts(None),
+            ctx.ws.conf.source_ip,
+            ctx.take_stream(port).await,
+            ctx.spend_budget(protocol, port, auth),
+            &exchange,
+        )
+        .await;
+
+        ctx.ws.maybe_release_permit(port).await;
+    }
+}
+
+// Same as tcp_exchange, over a UDP socket connected to the target
+pub async fn udp_exchange<F, Fut>(
+    tx: Sender<WorkerMessage>,
+    mut target: ReqTarget,
+    timeout: u64,
+    source_ip: Option<IpAddr>,
+    exchange: F,
+) where
+    F: FnOnce(UdpSocket) -> Fut,
+    Fut: Future<Output = Result<Vec<(String, String)>, String>>,
+{
+    let addr = match net::socket_addr(&target.ip, target.port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid address".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return;
+        }
+    };
+
+    let to = Duration::from_secs(timeout);
+    let cb = async {
+        let socket = match net::bind_udp(source_ip).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        target.clone(),
+                        FailClass::Other,
+                        "UDP socket error".to_string(),
+                        Some(e.to_string()),
+                    ))
+                    .await;
+                return;
+            }
+        };
+
+        if let Err(e) = socket.connect(&addr).await {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    target.clone(),
+                    FailClass::from_io(&e),
+                    "UDP socket connection error".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return;
+        }
+
+        let fields = match exchange(socket).await {
+            Ok(fields) => fields,
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        target.clone(),
+                        FailClass::Protocol,
+                        format!("{} exchange error", target.protocol.to_uppercase()),
+                        Some(e),
+                    ))
+                    .await;
+                return;
+            }
+        };
+
+        target.headers.extend(fields);
+        target.response = raw_response(&target.headers);
+        target.body = target.response.clone();
+
+        let _ = tx.send(WorkerMessage::Response(target.clone())).await;
+    };
+
+    if time::timeout(to, cb).await.is_err() {
+        let _ = tx
+            .send(WorkerMessage::Timeout(target.clone(), TimeoutPhase::Total))
+            .await;
+    };
+}
+
+// Runs the exchange against every port of the definitions (UDP ports are not checked in advance)
+pub async fn run_udp_probe<F, Fut>(
+    ctx: &ProbeContext<'_>,
+    defs: &[&Definition],
+    protocol: &str,
+    exchange: F,
+) where
+    F: Fn(UdpSocket) -> Fut,
+    Fut: Future<Output = Result<Vec<(String, String)>, String>>,
+{
+    let mut ports = Vec::new();
+    for def in defs {
+        for port in &def.options.ports {
+            if !ports.contains(port) {
+                ports.push(*port);
+            }
+        }
+    }
+
+    let auth = defs.iter().any(|def| def.is_auth());
+    for port in ports {
+        if !ctx.spend_budget(protocol, port, auth).await {
+            return;
+        }
+        ctx.ws.maybe_wait_for_permit(port).await;
+
+        udp_exchange(
+            ctx.tx.clone(),
+            probe_target(ctx, protocol, port),
+            ctx.ws.conf.req_timeout,
+            ctx.ws.conf.source_ip,
+            &exchange,
+        )
+        .await;
+
+        ctx.ws.maybe_release_permit(port).await;
+    }
+}
+
+pub struct Registry {
+    probes: Vec<Box<dyn Probe>>,
+    detectors: Vec<Box<dyn Detector>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    // Built-in probes and detectors (some behind a feature flag). Additional ones can be added
+    // with register_probe and register_detector: the same registry is passed to
+    // conf::load_with_registry, validating the definitions protocol against its probes, and to
+    // lachesis::run_worker
+    pub fn new() -> Self {
+        let mut registry = Registry {
+            probes: Vec::new(),
+            detectors: Vec::new(),
+        };
+
+        registry.register_probe(Box::new(tcp_custom::TcpCustomProbe));
+        registry.register_probe(Box::new(tcp_custom::TlsCustomProbe));
+        registry.register_probe(Box::new(http::HttpProbe));
+        registry.register_probe(Box::new(ssh::SshProbe));
+        registry.register_probe(Box::new(dns::DnsProbe));
+        registry.register_probe(Box::new(datastores::MysqlProbe));
+        registry.register_probe(Box::new(datastores::PostgresqlProbe));
+        registry.register_probe(Box::new(datastores::RedisProbe));
+        registry.register_probe(Box::new(datastores::MongodbProbe));
+        registry.register_probe(Box::new(remote_access::RdpProbe));
+        registry.register_probe(Box::new(remote_access::VncProbe));
+        registry.register_probe(Box::new(brokers::MqttProbe));
+        registry.register_probe(Box::new(brokers::AmqpProbe));
+        registry.register_probe(Box::new(websocket::WebsocketProbe));
+        registry.register_probe(Box::new(grpc::H2cProbe));
+        registry.register_probe(Box::new(grpc::GrpcProbe));
+        #[cfg(feature = "ics")]
+        {
+            registry.register_probe(Box::new(ics::ModbusProbe));
+            registry.register_probe(Box::new(ics::S7Probe));
+            registry.register_probe(Box::new(ics::BacnetProbe));
+        }
+
+        registry.register_detector(Box::new(DefinitionsDetector));
+
+        registry
+    }
+
+    pub fn register_probe(&mut self, probe: Box<dyn Probe>) {
+        self.probes.push(probe);
+    }
+
+    pub fn register_detector(&mut self, detector: Box<dyn Detector>) {
+        self.detectors.push(detector);
+    }
+
+    pub fn probes(&self) -> &[Box<dyn Probe>] {
+        &self.probes
+    }
+
+    pub fn protocols(&self) -> Vec<&'static str> {
+        self.probes
+            .iter()
+            .flat_map(|p| std::iter::once(p.protocol()).chain(p.aliases().iter().cloned()))
+            .collect()
+    }
+
+    pub fn detect(&self, target: &ReqTarget, definitions: &[Definition]) -> Vec<DetectorResponse> {
+        let mut responses = Vec::new();
+        for detector in &self.detectors {
+            responses.extend(detector.detect(target, definitions));
+        }
+        responses
+    }
+}
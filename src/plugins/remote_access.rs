@@ -0,0 +1,230 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    conf::Definition,
+    plugins::{field, io_err, run_tcp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+/*
+ * RDP: X.224 connection request with an RDP negotiation request, the server selects one of the
+ * requested security protocols or replies with a negotiation failure
+ */
+
+// TPKT header, X.224 connection request, RDP negotiation request for TLS and CredSSP (NLA)
+const RDP_NEG_REQUEST: [u8; 19] = [
+    0x03, 0x00, 0x00, 0x13, 0x0E, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00, 0x03,
+    0x00, 0x00, 0x00,
+];
+const RDP_NEG_RSP: u8 = 0x02;
+const RDP_NEG_FAILURE: u8 = 0x03;
+
+pub struct RdpProbe;
+
+impl Probe for RdpProbe {
+    fn protocol(&self) -> &'static str {
+        "rdp"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "rdp", rdp))
+    }
+}
+
+fn rdp_protocol(protocol: u32) -> &'static str {
+    match protocol {
+        0 => "rdp",
+        1 => "tls",
+        2 => "credssp",
+        4 => "rdstls",
+        8 => "credssp_early_user_auth",
+        _ => "unknown",
+    }
+}
+
+fn rdp_failure(code: u32) -> &'static str {
+    match code {
+        1 => "ssl_required_by_server",
+        2 => "ssl_not_allowed_by_server",
+        3 => "ssl_cert_not_on_server",
+        4 => "inconsistent_flags",
+        5 => "hybrid_required_by_server",
+        6 => "ssl_with_user_auth_required_by_server",
+        _ => "unknown",
+    }
+}
+
+pub(crate) async fn rdp(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    stream
+        .write_all(&RDP_NEG_REQUEST)
+        .await
+        .map_err(|e| io_err("Negotiation request write error", e))?;
+
+    let mut tpkt = [0; 4];
+    stream
+        .read_exact(&mut tpkt)
+        .await
+        .map_err(|e| io_err("Negotiation response read error", e))?;
+    if tpkt[0] != 0x03 {
+        return Err("Invalid TPKT header".to_string());
+    }
+
+    let len = u16::from_be_bytes([tpkt[2], tpkt[3]]) as usize;
+    let mut x224 = vec![0; len.saturating_sub(4)];
+    stream
+        .read_exact(&mut x224)
+        .await
+        .map_err(|e| io_err("Negotiation response read error", e))?;
+
+    // X.224 connection confirm (0xD0)
+    if x224.get(1).map(|b| b & 0xF0) != Some(0xD0) {
+        return Err("Invalid X.224 connection confirm".to_string());
+    }
+
+    // Old servers (standard RDP security only) don't send a negotiation response, else it follows
+    // the 7 bytes of the X.224 header
+    let neg = match x224.get(7..15) {
+        Some(neg) => neg,
+        None => return Ok(vec![field("security", "rdp"), field("nla", "no")]),
+    };
+    let value = u32::from_le_bytes([neg[4], neg[5], neg[6], neg[7]]);
+
+    match neg[0] {
+        RDP_NEG_RSP => Ok(vec![
+            field("security", rdp_protocol(value)),
+            field(
+                "nla",
+                if value == 2 || value == 8 {
+                    "yes"
+                } else {
+                    "no"
+                },
+            ),
+        ]),
+        RDP_NEG_FAILURE => Ok(vec![
+            field("failure", rdp_failure(value)),
+            field("nla", if value == 5 { "yes" } else { "no" }),
+        ]),
+        _ => Err("Invalid RDP negotiation response".to_string()),
+    }
+}
+
+/*
+ * VNC: RFB version handshake and security types offered by the server
+ */
+
+pub struct VncProbe;
+
+impl Probe for VncProbe {
+    fn protocol(&self) -> &'static str {
+        "vnc"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "vnc", vnc))
+    }
+}
+
+fn vnc_security_type(security_type: u32) -> String {
+    match security_type {
+        1 => "none".to_string(),
+        2 => "vnc_auth".to_string(),
+        5 => "ra2".to_string(),
+        6 => "ra2ne".to_string(),
+        16 => "tight".to_string(),
+        17 => "ultra".to_string(),
+        18 => "tls".to_string(),
+        19 => "vencrypt".to_string(),
+        30 => "apple_dh".to_string(),
+        _ => security_type.to_string(),
+    }
+}
+
+async fn read_reason(stream: &mut TcpStream) -> Option<String> {
+    let len = stream.read_u32().await.ok()? as usize;
+    let mut reason = vec![0; len.min(4096)];
+    stream.read_exact(&mut reason).await.ok()?;
+    Some(String::from_utf8_lossy(&reason).to_string())
+}
+
+pub(crate) async fn vnc(mut stream: TcpStream) -> Result<Vec<(String, String)>, String> {
+    let mut version = [0; 12];
+    stream
+        .read_exact(&mut version)
+        .await
+        .map_err(|e| io_err("Version read error", e))?;
+    let version = String::from_utf8_lossy(&version).trim_end().to_string();
+    if !version.starts_with("RFB ") {
+        return Err("Invalid RFB version".to_string());
+    }
+
+    let mut fields = vec![field("version", version[4..].to_string())];
+
+    // Before 3.7, the server picks the security type (3.3 handshake)
+    let legacy = version.as_str() < "RFB 003.007";
+    let reply: &[u8] = if legacy {
+        b"RFB 003.003\n"
+    } else {
+        b"RFB 003.008\n"
+    };
+    stream
+        .write_all(reply)
+        .await
+        .map_err(|e| io_err("Version write error", e))?;
+
+    let security_types = if legacy {
+        let security_type = stream
+            .read_u32()
+            .await
+            .map_err(|e| io_err("Security type read error", e))?;
+        vec![security_type]
+    } else {
+        let count = stream
+            .read_u8()
+            .await
+            .map_err(|e| io_err("Security types read error", e))?;
+        let mut types = vec![0; count as usize];
+        stream
+            .read_exact(&mut types)
+            .await
+            .map_err(|e| io_err("Security types read error", e))?;
+        types.into_iter().map(u32::from).collect()
+    };
+
+    // No security types: connection failed, followed by the reason
+    if security_types.is_empty() || security_types == [0] {
+        if let Some(reason) = read_reason(&mut stream).await {
+            fields.push(field("error", reason));
+        }
+        return Ok(fields);
+    }
+
+    fields.push(field(
+        "security_types",
+        security_types
+            .iter()
+            .map(|t| vnc_security_type(*t))
+            .collect::<Vec<String>>()
+            .join(","),
+    ));
+    fields.push(field(
+        "auth",
+        if security_types.contains(&1) {
+            "none"
+        } else {
+            "required"
+        },
+    ));
+
+    Ok(fields)
+}
@@ -0,0 +1,334 @@
+use std::net::SocketAddr;
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+use crate::{
+    conf::Definition,
+    net,
+    plugins::{run_tcp_probe, BoxFuture, Probe, ProbeContext},
+};
+
+const CLIENT_BANNER: &str = "SSH-2.0-lachesis\r\n";
+// Max banner section size (the server can send other lines before the version line)
+const MAX_BANNER_SIZE: usize = 8192;
+const MAX_PACKET_SIZE: usize = 35000;
+const SSH_MSG_DISCONNECT: u8 = 1;
+const SSH_MSG_IGNORE: u8 = 2;
+const SSH_MSG_DEBUG: u8 = 4;
+const SSH_MSG_KEXINIT: u8 = 20;
+// Same codes for the ECDH messages (SSH_MSG_KEX_ECDH_INIT, SSH_MSG_KEX_ECDH_REPLY)
+const SSH_MSG_KEXDH_INIT: u8 = 30;
+const SSH_MSG_KEXDH_REPLY: u8 = 31;
+const KEXINIT_NAME_LISTS: [&str; 10] = [
+    "kex_algorithms",
+    "server_host_key_algorithms",
+    "encryption_algorithms_client_to_server",
+    "encryption_algorithms_server_to_client",
+    "mac_algorithms_client_to_server",
+    "mac_algorithms_server_to_client",
+    "compression_algorithms_client_to_server",
+    "compression_algorithms_server_to_client",
+    "languages_client_to_server",
+    "languages_server_to_client",
+];
+// Key exchanges of the host keys, in order of preference. Only the host key of the reply is
+// needed, the client key is any valid one: random bytes (curve25519), the generator (nistp256)
+const KEX_ALGORITHMS: [&str; 3] = [
+    "curve25519-sha256",
+    "curve25519-sha256@libssh.org",
+    "ecdh-sha2-nistp256",
+];
+const P256_GENERATOR: [u8; 65] = [
+    0x04, 0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40,
+    0xf2, 0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2,
+    0x96, 0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e,
+    0x16, 0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51,
+    0xf5,
+];
+// Host keys collected from a server, a connection each
+const MAX_HOST_KEYS: usize = 4;
+
+// Reads the version banner and the server's KEXINIT packet (offered algorithms), then the host
+// keys of the server: a key exchange (up to the server reply) for each host key algorithm. The
+// session keys are never computed
+pub struct SshProbe;
+
+impl Probe for SshProbe {
+    fn protocol(&self) -> &'static str {
+        "ssh"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(run_tcp_probe(ctx, defs, "ssh", move |stream| {
+            handshake(ctx, stream)
+        }))
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// SSH string: uint32 length + bytes
+fn read_string(buf: &[u8], offset: usize) -> Option<&[u8]> {
+    let len = read_u32(buf, offset)? as usize;
+    buf.get(offset + 4..(offset + 4).checked_add(len)?)
+}
+
+fn put_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// KEXINIT payload: byte msg code, byte[16] cookie, then the name-lists (uint32 length + string)
+pub(crate) fn parse_kexinit(payload: &[u8]) -> Option<Vec<(String, String)>> {
+    if payload.first() != Some(&SSH_MSG_KEXINIT) {
+        return None;
+    }
+
+    let mut offset = 17;
+    let mut name_lists = Vec::new();
+    for name in KEXINIT_NAME_LISTS.iter() {
+        let list = read_string(payload, offset)?;
+        offset += 4 + list.len();
+        name_lists.push((name.to_string(), String::from_utf8_lossy(list).to_string()));
+    }
+
+    Some(name_lists)
+}
+
+// Host key (K_S) of the key exchange reply: byte msg code, string K_S, then the server key and
+// the signature
+pub(crate) fn parse_kex_reply(payload: &[u8]) -> Option<&[u8]> {
+    if payload.first() != Some(&SSH_MSG_KEXDH_REPLY) {
+        return None;
+    }
+    read_string(payload, 1).filter(|host_key| !host_key.is_empty())
+}
+
+// Type and fingerprint of a host key, as OpenSSH (e.g. "ssh-ed25519 SHA256:<unpadded base64>")
+pub(crate) fn fingerprint(host_key: &[u8]) -> Option<String> {
+    let key_type = std::str::from_utf8(read_string(host_key, 0)?).ok()?;
+    Some(format!(
+        "{} SHA256:{}",
+        key_type,
+        net::base64(&Sha256::digest(host_key)).trim_end_matches('=')
+    ))
+}
+
+// The offered host key algorithms to collect: one per key (the rsa-sha2 ones sign with the
+// ssh-rsa key), no certificates
+pub(crate) fn host_key_algorithms(offered: &str) -> Vec<&str> {
+    let mut key_types = Vec::new();
+    let mut algorithms = Vec::new();
+    for algorithm in offered
+        .split(',')
+        .filter(|algorithm| !algorithm.is_empty() && !algorithm.contains("-cert-"))
+    {
+        let key_type = match algorithm {
+            "rsa-sha2-256" | "rsa-sha2-512" => "ssh-rsa",
+            algorithm => algorithm,
+        };
+        if !key_types.contains(&key_type) {
+            key_types.push(key_type);
+            algorithms.push(algorithm);
+        }
+    }
+    algorithms.truncate(MAX_HOST_KEYS);
+    algorithms
+}
+
+fn name_list<'a>(name_lists: &'a [(String, String)], name: &str) -> &'a str {
+    name_lists
+        .iter()
+        .find(|(list, _)| list == name)
+        .map_or("", |(_, algorithms)| algorithms.as_str())
+}
+
+// Binary packet (before the key exchange there is no MAC): uint32 packet length, byte padding
+// length, byte[n] payload, byte[padding length] padding, a multiple of 8 bytes overall
+fn packet(payload: &[u8]) -> Vec<u8> {
+    let mut padding = 8 - (5 + payload.len()) % 8;
+    if padding < 4 {
+        padding += 8;
+    }
+    let mut packet = ((1 + payload.len() + padding) as u32)
+        .to_be_bytes()
+        .to_vec();
+    packet.push(padding as u8);
+    packet.extend_from_slice(payload);
+    packet.extend((0..padding).map(|_| rand::random::<u8>()));
+    packet
+}
+
+async fn read_packet(reader: &mut BufReader<TcpStream>) -> Result<Vec<u8>, String> {
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut len)
+        .await
+        .map_err(|e| format!("Packet read error: {}", e))?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET_SIZE {
+        return Err(format!("Invalid packet length: {}", len));
+    }
+
+    let mut packet = vec![0; len];
+    reader
+        .read_exact(&mut packet)
+        .await
+        .map_err(|e| format!("Packet read error: {}", e))?;
+
+    let padding = packet[0] as usize;
+    match packet.get(1..len.saturating_sub(padding)) {
+        Some(payload) => Ok(payload.to_vec()),
+        None => Err("Invalid packet padding".to_string()),
+    }
+}
+
+// Banner exchange, then the server KEXINIT: the banner and the offered algorithms
+async fn open(
+    stream: TcpStream,
+) -> Result<(BufReader<TcpStream>, String, Vec<(String, String)>), String> {
+    let mut reader = BufReader::new(stream);
+
+    let mut banner = String::new();
+    let mut read = 0;
+    while !banner.starts_with("SSH-") {
+        let mut line = Vec::new();
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(|e| format!("Banner read error: {}", e))?;
+        read += n;
+        if n == 0 || read > MAX_BANNER_SIZE {
+            return Err("SSH version banner not found".to_string());
+        }
+        banner = String::from_utf8_lossy(&line).trim_end().to_string();
+    }
+
+    reader
+        .get_mut()
+        .write_all(CLIENT_BANNER.as_bytes())
+        .await
+        .map_err(|e| format!("Banner write error: {}", e))?;
+
+    let payload = read_packet(&mut reader).await?;
+    match parse_kexinit(&payload) {
+        Some(name_lists) => Ok((reader, banner, name_lists)),
+        None => Err("Invalid KEXINIT packet".to_string()),
+    }
+}
+
+// Key exchange with a single host key algorithm, up to the server reply: the host key
+async fn host_key(
+    reader: &mut BufReader<TcpStream>,
+    name_lists: &[(String, String)],
+    kex: &str,
+    algorithm: &str,
+) -> Result<Vec<u8>, String> {
+    // The other algorithms as offered by the server, so that the negotiation succeeds
+    let mut kexinit = vec![SSH_MSG_KEXINIT];
+    kexinit.extend_from_slice(&rand::random::<[u8; 16]>());
+    for (name, algorithms) in name_lists {
+        let algorithms = match name.as_str() {
+            "kex_algorithms" => kex,
+            "server_host_key_algorithms" => algorithm,
+            _ => algorithms,
+        };
+        put_string(&mut kexinit, algorithms.as_bytes());
+    }
+    // first_kex_packet_follows, reserved
+    kexinit.extend_from_slice(&[0; 5]);
+
+    let mut kex_init = vec![SSH_MSG_KEXDH_INIT];
+    if kex.starts_with("curve25519") {
+        put_string(&mut kex_init, &rand::random::<[u8; 32]>());
+    } else {
+        put_string(&mut kex_init, &P256_GENERATOR);
+    }
+
+    let mut request = packet(&kexinit);
+    request.extend(packet(&kex_init));
+    reader
+        .get_mut()
+        .write_all(&request)
+        .await
+        .map_err(|e| format!("KEX write error: {}", e))?;
+
+    loop {
+        let payload = read_packet(reader).await?;
+        match payload.first() {
+            Some(&SSH_MSG_IGNORE) | Some(&SSH_MSG_DEBUG) => continue,
+            Some(&SSH_MSG_DISCONNECT) => return Err("Disconnected by the server".to_string()),
+            _ => {
+                return parse_kex_reply(&payload)
+                    .map(|host_key| host_key.to_vec())
+                    .ok_or_else(|| "Invalid KEX reply".to_string())
+            }
+        }
+    }
+}
+
+// Banner, algorithms and host keys (one "host_key" field each) are available to the definitions
+// as headers (e.g. source "header:kex_algorithms"). The host keys after the first one are
+// collected over new connections, each one a request against the budget of the host
+async fn handshake(
+    ctx: &ProbeContext<'_>,
+    stream: TcpStream,
+) -> Result<Vec<(String, String)>, String> {
+    let addr: Option<SocketAddr> = stream.peer_addr().ok();
+    let (mut reader, banner, name_lists) = open(stream).await?;
+
+    let mut fields = vec![("banner".to_string(), banner)];
+    fields.extend(name_lists.iter().cloned());
+
+    let kex = KEX_ALGORITHMS.iter().find(|kex| {
+        name_list(&name_lists, "kex_algorithms")
+            .split(',')
+            .any(|offered| offered == **kex)
+    });
+    let (kex, addr) = match (kex, addr) {
+        (Some(kex), Some(addr)) => (kex, addr),
+        _ => return Ok(fields),
+    };
+
+    let mut server_lists = name_lists.clone();
+    let algorithms = host_key_algorithms(name_list(&name_lists, "server_host_key_algorithms"));
+    for (i, algorithm) in algorithms.iter().enumerate() {
+        if i > 0 {
+            if !ctx.spend_budget("ssh", addr.port(), false).await {
+                break;
+            }
+            let connect = net::connect(&addr, ctx.ws.conf.source_ip);
+            let opened = match time::timeout(ctx.ws.conf.timeouts(None).connect, connect).await {
+                Ok(Ok(stream)) => open(stream).await,
+                _ => break,
+            };
+            match opened {
+                Ok((new_reader, _, new_lists)) => {
+                    reader = new_reader;
+                    server_lists = new_lists;
+                }
+                Err(_) => break,
+            }
+        }
+
+        if let Ok(host_key) = host_key(&mut reader, &server_lists, kex, algorithm).await {
+            if let Some(fingerprint) = fingerprint(&host_key) {
+                fields.push(("host_key".to_string(), fingerprint));
+            }
+        }
+    }
+
+    Ok(fields)
+}
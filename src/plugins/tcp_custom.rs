@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    conf::Definition,
+    detector, net,
+    plugins::{BoxFuture, Probe, ProbeContext},
+    template,
+};
+
+// Delay between the knocks of a sequence, and before the probe (ms)
+const DEFAULT_KNOCK_DELAY: u64 = 200;
+
+pub struct TcpCustomProbe;
+
+impl Probe for TcpCustomProbe {
+    fn protocol(&self) -> &'static str {
+        "tcp/custom"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            for def in defs {
+                let transport = net::TcpTransport {
+                    source_ip: ctx.ws.conf.source_ip,
+                };
+
+                for port in &def.options.ports {
+                    // The ports behind a knock sequence are knocked before every probe (the
+                    // sequence opens the port for a while, usually for the next connection only)
+                    if let Some(knock) = &def.options.knock {
+                        for knock_port in knock {
+                            if !ctx.spend_budget("tcp/custom", *knock_port, false).await {
+                                return;
+                            }
+                        }
+                        ctx.ws.maybe_wait_for_permit(*port).await;
+                        let delay = def.options.knock_delay.unwrap_or(DEFAULT_KNOCK_DELAY);
+                        let knocked = net::knock(
+                            &transport,
+                            &ctx.target.ip,
+                            knock,
+                            Duration::from_millis(delay),
+                        )
+                        .await;
+                        ctx.ws.maybe_release_permit(*port).await;
+                        if knocked.is_err() {
+                            continue;
+                        }
+                    } else if !ctx.open_ports.contains(port) {
+                        continue;
+                    }
+
+                    if !probe_port(ctx, def, *port, "tcp/custom", &transport).await {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
+
+// The same exchange over TLS, for the services speaking TLS from the first byte (e.g. LDAPS,
+// SMTP submission over TLS, DNS over TLS)
+pub struct TlsCustomProbe;
+
+impl Probe for TlsCustomProbe {
+    fn protocol(&self) -> &'static str {
+        "tls/custom"
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            for def in defs {
+                let connector =
+                    net::build_tls_connector(def.options.alpn.as_deref().unwrap_or_default());
+
+                for port in &def.options.ports {
+                    if !ctx.open_ports.contains(port) {
+                        continue;
+                    }
+
+                    // The sni option (placeholders expanded, empty for none) or the domain of
+                    // the target. Without a name the ip is passed, never sent as SNI
+                    let mut target = ctx.target.clone();
+                    target.port = *port;
+                    let server_name = match &def.options.sni {
+                        Some(sni) => template::expand(sni, &target),
+                        None => target.domain.clone(),
+                    };
+                    let transport = net::TlsTransport {
+                        source_ip: ctx.ws.conf.source_ip,
+                        connector: connector.clone(),
+                        server_name: if server_name.is_empty() {
+                            target.ip.clone()
+                        } else {
+                            server_name
+                        },
+                    };
+
+                    if !probe_port(ctx, def, *port, "tls/custom", &transport).await {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
+
+// Sends the payloads of the definition to a port, false when the budget of the host is exceeded
+async fn probe_port(
+    ctx: &ProbeContext<'_>,
+    def: &Definition,
+    port: u16,
+    protocol: &str,
+    transport: &dyn net::Transport,
+) -> bool {
+    // The certificate of the port first, for the indicators of the definition
+    let tls_cn = if protocol == "tls/custom" && detector::needs_tls_cn(def) {
+        match ctx.tls_common_name(port).await {
+            Some(tls_cn) => tls_cn,
+            None => return false,
+        }
+    } else {
+        None
+    };
+
+    if !ctx.spend_budget(protocol, port, def.is_auth()).await {
+        return false;
+    }
+    ctx.ws.maybe_wait_for_permit(port).await;
+
+    let payloads = match &def.options.payloads {
+        Some(payloads) => payloads.clone(),
+        None => vec![def.options.payload.clone().unwrap()],
+    };
+    let max_bytes = def
+        .options
+        .max_response_bytes
+        .unwrap_or(ctx.ws.conf.max_response_bytes);
+
+    let mut target = ctx.target.clone();
+    target.port = port;
+    // Placeholders expanded before the domain is cleared (e.g. {host})
+    let target_payloads = payloads
+        .iter()
+        .map(|payload| template::expand(payload, &target))
+        .collect();
+    target.domain = String::new();
+    target.protocol = protocol.to_string();
+    target.time = Instant::now();
+    target.connect_rtt = ctx.connect_rtts.get(&port).cloned();
+    target.proxy_protocol = def.options.proxy_protocol.clone();
+    target.tls_cn = tls_cn;
+
+    net::tcp_custom(
+        ctx.tx.clone(),
+        target,
+        target_payloads,
+        ctx.ws.conf.timeouts(Some(def)),
+        transport,
+        ctx.take_stream(port)
+            .await
+            .map(|stream| Box::new(stream) as Box<dyn net::Stream>),
+        ctx.spend_budget(protocol, port, def.is_auth()),
+        max_bytes,
+        ctx.ws.conf.pcap_matches.is_some(),
+    )
+    .await;
+
+    ctx.ws.maybe_release_permit(port).await;
+    true
+}
@@ -0,0 +1,487 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    conf::Definition,
+    detector,
+    error::{FailClass, TimeoutPhase},
+    net::{self, Stream, Transport},
+    plugins::{BoxFuture, Probe, ProbeContext},
+    template,
+    worker::WorkerMessage,
+};
+
+// Frames read after the upgrade, and the longest wait for them (bound by the first byte deadline)
+const MAX_FRAMES: usize = 3;
+const FRAMES_WAIT: Duration = Duration::from_secs(2);
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// Websocket endpoints: the HTTP upgrade handshake on the path of the definition, then the payload
+// (if any) as a text frame. The headers of the handshake response are exposed as headers, the
+// first frames of the server (one per line) as body
+pub struct WebsocketProbe;
+
+impl Probe for WebsocketProbe {
+    fn protocol(&self) -> &'static str {
+        "ws/s"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["ws", "wss"]
+    }
+
+    fn run<'a>(
+        &'a self,
+        ctx: &'a ProbeContext<'a>,
+        defs: &'a [&'a Definition],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            // Whether the port speaks TLS, sniffed once per port for the ws/s definitions
+            let mut tls: HashMap<u16, Option<bool>> = HashMap::new();
+
+            for def in defs {
+                for port in &def.options.ports {
+                    let port = *port;
+                    if !ctx.open_ports.contains(&port) {
+                        continue;
+                    }
+
+                    let mut schemes = detector::websocket_schemes(&def.protocol).to_vec();
+                    if schemes.len() > 1 {
+                        let sniffed = match tls.get(&port) {
+                            Some(sniffed) => *sniffed,
+                            None => {
+                                if !ctx.spend_budget("tls", port, false).await {
+                                    return;
+                                }
+                                ctx.ws.maybe_wait_for_permit(port).await;
+                                let sniffed = net::sniff_tls(
+                                    &ctx.target.ip,
+                                    port,
+                                    &ctx.target.domain,
+                                    ctx.ws.conf.req_timeout,
+                                    ctx.ws.conf.source_ip,
+                                )
+                                .await;
+                                ctx.ws.maybe_release_permit(port).await;
+                                tls.insert(port, sniffed);
+                                sniffed
+                            }
+                        };
+                        if let Some(tls) = sniffed {
+                            schemes.retain(|scheme| (*scheme == "wss") == tls);
+                        }
+                    }
+
+                    // Without a sniffing answer both the schemes are tried, until one gets a
+                    // handshake response
+                    for scheme in schemes {
+                        if !ctx.spend_budget(scheme, port, def.is_auth()).await {
+                            return;
+                        }
+                        ctx.ws.maybe_wait_for_permit(port).await;
+                        let answered = probe_port(ctx, def, port, scheme).await;
+                        ctx.ws.maybe_release_permit(port).await;
+                        if answered {
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+// Handshake response and the first frames of the server
+struct Upgrade {
+    head: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    frames: Vec<String>,
+    truncated: bool,
+}
+
+enum WsOutcome {
+    Response(Upgrade),
+    Fail(FailClass, String, Option<String>),
+    Timeout(TimeoutPhase),
+}
+
+// Probes the endpoint of the definition with a scheme, true when the server answered the handshake
+async fn probe_port(ctx: &ProbeContext<'_>, def: &Definition, port: u16, scheme: &str) -> bool {
+    let mut target = ctx.target.clone();
+    target.port = port;
+    target.protocol = scheme.to_string();
+    target.time = Instant::now();
+    target.connect_rtt = ctx.connect_rtts.get(&port).cloned();
+
+    let addr = match net::socket_addr(&target.ip, port) {
+        Ok(addr) => addr,
+        Err(e) => {
+            let _ = ctx
+                .tx
+                .send(WorkerMessage::Fail(
+                    target,
+                    FailClass::Other,
+                    "Invalid address".to_string(),
+                    Some(e.to_string()),
+                ))
+                .await;
+            return false;
+        }
+    };
+
+    let host = if target.domain.is_empty() {
+        target.ip.clone()
+    } else {
+        target.domain.clone()
+    };
+    let transport: Box<dyn Transport> = if scheme == "wss" {
+        Box::new(net::TlsTransport {
+            source_ip: ctx.ws.conf.source_ip,
+            connector: net::build_tls_connector(&["http/1.1".to_string()]),
+            server_name: host.clone(),
+        })
+    } else {
+        Box::new(net::TcpTransport {
+            source_ip: ctx.ws.conf.source_ip,
+        })
+    };
+
+    let path = template::expand(def.options.path.as_deref().unwrap_or("/"), &target);
+    let headers: Vec<(String, String)> = def
+        .options
+        .headers
+        .iter()
+        .flatten()
+        .map(|(name, value)| (name.clone(), template::expand(value, &target)))
+        .collect();
+    let host_header = match (scheme, port) {
+        ("ws", 80) | ("wss", 443) => host,
+        _ => format!("{}:{}", host, port),
+    };
+    let request = handshake_request(&path, &host_header, ctx.ws.user_agents.next(), &headers);
+    let frame = def
+        .options
+        .payload
+        .as_ref()
+        .map(|payload| template::expand(payload, &target));
+    let max_bytes = def
+        .options
+        .max_response_bytes
+        .unwrap_or(ctx.ws.conf.max_response_bytes);
+    let timeouts = ctx.ws.conf.timeouts(Some(def));
+
+    let exchange = upgrade(
+        transport.as_ref(),
+        &addr,
+        &request,
+        frame.as_deref(),
+        max_bytes,
+        &timeouts,
+    );
+    let outcome = match time::timeout(timeouts.total, exchange).await {
+        Ok(outcome) => outcome,
+        Err(_) => WsOutcome::Timeout(TimeoutPhase::Total),
+    };
+
+    match outcome {
+        WsOutcome::Response(upgrade) => {
+            target.status = Some(upgrade.status);
+            target.headers = upgrade.headers;
+            target.body = upgrade.frames.join("\n");
+            target.response = format!("{}{}", upgrade.head, target.body);
+            target.truncated = upgrade.truncated;
+            target.sent_request = Some(format!(
+                "{}{}",
+                String::from_utf8_lossy(&request),
+                frame.as_deref().unwrap_or("")
+            ));
+            let _ = ctx.tx.send(WorkerMessage::Response(target)).await;
+            true
+        }
+        WsOutcome::Fail(class, context, error) => {
+            let _ = ctx
+                .tx
+                .send(WorkerMessage::Fail(target, class, context, error))
+                .await;
+            false
+        }
+        WsOutcome::Timeout(phase) => {
+            let _ = ctx.tx.send(WorkerMessage::Timeout(target, phase)).await;
+            false
+        }
+    }
+}
+
+// Upgrade request with a random key, the headers of the definition are appended (e.g. Origin,
+// Sec-WebSocket-Protocol)
+fn handshake_request(
+    path: &str,
+    host: &str,
+    user_agent: &str,
+    headers: &[(String, String)],
+) -> Vec<u8> {
+    let key: [u8; 16] = rand::thread_rng().gen();
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nUser-Agent: {}\r\n",
+        path,
+        host,
+        net::base64(&key),
+        user_agent
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+async fn upgrade(
+    transport: &dyn Transport,
+    addr: &SocketAddr,
+    request: &[u8],
+    frame: Option<&str>,
+    max_bytes: usize,
+    timeouts: &net::Timeouts,
+) -> WsOutcome {
+    let first_byte_deadline = time::Instant::now() + timeouts.first_byte;
+    let stream = match time::timeout(timeouts.connect, transport.connect(addr)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            return WsOutcome::Fail(
+                FailClass::from_io(&e),
+                "TCP stream connection error".to_string(),
+                Some(e.to_string()),
+            )
+        }
+        Err(_) => return WsOutcome::Timeout(TimeoutPhase::Connect),
+    };
+    let mut stream = match time::timeout(timeouts.tls, transport.handshake(stream)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            return WsOutcome::Fail(
+                FailClass::Tls,
+                "TLS handshake error".to_string(),
+                Some(e.to_string()),
+            )
+        }
+        Err(_) => return WsOutcome::Timeout(TimeoutPhase::Tls),
+    };
+
+    if let Err(e) = stream.write_all(request).await {
+        return WsOutcome::Fail(
+            FailClass::from_io(&e),
+            "Websocket handshake write error".to_string(),
+            Some(e.to_string()),
+        );
+    }
+
+    // The response head, up to the empty line (the frames sent right after it may be read with it)
+    let mut data = Vec::new();
+    let mut chunk = vec![0; 4096];
+    let head_end = loop {
+        if let Some(end) = find(&data, b"\r\n\r\n") {
+            break end + 4;
+        }
+        if data.len() >= max_bytes {
+            return WsOutcome::Fail(
+                FailClass::Protocol,
+                "Websocket handshake error".to_string(),
+                Some("response head too long".to_string()),
+            );
+        }
+        match time::timeout_at(first_byte_deadline, stream.read(&mut chunk)).await {
+            Ok(Ok(0)) if data.is_empty() => {
+                return WsOutcome::Fail(
+                    FailClass::Protocol,
+                    "Websocket handshake error".to_string(),
+                    Some("connection closed".to_string()),
+                )
+            }
+            Ok(Ok(0)) => break data.len(),
+            Ok(Ok(n)) => data.extend_from_slice(&chunk[..n]),
+            Ok(Err(e)) => {
+                return WsOutcome::Fail(
+                    FailClass::BodyRead,
+                    "Websocket handshake read error".to_string(),
+                    Some(e.to_string()),
+                )
+            }
+            Err(_) => return WsOutcome::Timeout(TimeoutPhase::FirstByte),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+    let (status, headers) = match parse_head(&head) {
+        Some(parsed) => parsed,
+        None => {
+            return WsOutcome::Fail(
+                FailClass::Protocol,
+                "Websocket handshake error".to_string(),
+                head.lines().next().map(|line| line.to_string()),
+            )
+        }
+    };
+
+    // Any other status (e.g. 400, 426 or a plain web page) is reported as it is, without frames
+    let mut frames = Vec::new();
+    let mut truncated = false;
+    if status == 101 {
+        if let Some(frame) = frame {
+            if let Err(e) = stream
+                .write_all(&encode_frame(OPCODE_TEXT, frame.as_bytes()))
+                .await
+            {
+                return WsOutcome::Fail(
+                    FailClass::from_io(&e),
+                    "Websocket frame write error".to_string(),
+                    Some(e.to_string()),
+                );
+            }
+        }
+        let deadline = time::Instant::now() + FRAMES_WAIT.min(timeouts.first_byte);
+        let buffer = data[head_end..].to_vec();
+        let budget = max_bytes.saturating_sub(head_end);
+        let (read, over) = read_frames(&mut stream, buffer, budget, deadline).await;
+        frames = read;
+        truncated = over;
+        // Closing handshake, its answer isn't awaited
+        let _ = stream.write_all(&encode_frame(OPCODE_CLOSE, &[])).await;
+    }
+
+    WsOutcome::Response(Upgrade {
+        head,
+        status,
+        headers,
+        frames,
+        truncated,
+    })
+}
+
+// Reads the data frames (text and binary, decoded lossily) until MAX_FRAMES, a close frame, the
+// deadline or max bytes (true when it was reached). The control frames are skipped
+async fn read_frames(
+    stream: &mut Box<dyn Stream>,
+    mut buffer: Vec<u8>,
+    max_bytes: usize,
+    deadline: time::Instant,
+) -> (Vec<String>, bool) {
+    let mut frames = Vec::new();
+    let mut chunk = vec![0; 4096];
+    loop {
+        while let Some((opcode, payload, len)) = decode_frame(&buffer) {
+            buffer.drain(..len);
+            match opcode {
+                OPCODE_CLOSE => return (frames, false),
+                OPCODE_PING | OPCODE_PONG => (),
+                _ => {
+                    frames.push(String::from_utf8_lossy(&payload).to_string());
+                    if frames.len() >= MAX_FRAMES {
+                        return (frames, false);
+                    }
+                }
+            }
+        }
+        if buffer.len() >= max_bytes {
+            return (frames, true);
+        }
+        match time::timeout_at(deadline, stream.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => buffer.extend_from_slice(&chunk[..n]),
+            _ => return (frames, false),
+        }
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Status and headers of the handshake response
+fn parse_head(head: &str) -> Option<(u16, Vec<(String, String)>)> {
+    let mut lines = head.lines();
+    let status_line = lines.next()?;
+    if !status_line.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status_line.split_whitespace().nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    Some((status, headers))
+}
+
+// Opcode, (unmasked) payload and length of the first frame of the data, None when incomplete
+pub fn decode_frame(data: &[u8]) -> Option<(u8, Vec<u8>, usize)> {
+    if data.len() < 2 {
+        return None;
+    }
+    let opcode = data[0] & 0x0f;
+    let masked = data[1] & 0x80 != 0;
+    let (len, mut offset) = match data[1] & 0x7f {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+        127 if data.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&data[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        }
+        126 | 127 => return None,
+        len => (len as usize, 2),
+    };
+    let mask = if masked {
+        let mask = data.get(offset..offset + 4)?.to_vec();
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    let mut payload = data.get(offset..offset.checked_add(len)?)?.to_vec();
+    if let Some(mask) = mask {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte ^= mask[i % 4]);
+    }
+    Some((opcode, payload, offset + len))
+}
+
+// Single (final) frame, masked with a random key as every frame sent by a client
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = rand::thread_rng().gen();
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
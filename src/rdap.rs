@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::{net, worker};
+
+// Bootstrap service redirecting to the RDAP server of the registry (RIR) of the address
+const RDAP_URL: &str = "https://rdap.org/ip/";
+const RDAP_TIMEOUT: u64 = 10;
+// The registries rate limit the lookups, so they are sent one at a time with this interval
+const RDAP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Owner of the network of a finding, by the registry data
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Netblock {
+    // The /24 (or /48) that was looked up
+    pub network: String,
+    // First and last address of the registered block
+    pub range: String,
+    pub name: String,
+    pub handle: String,
+    pub owner: Option<String>,
+    pub country: Option<String>,
+}
+
+// Full name (vCard "fn") of an RDAP entity
+fn entity_name(entity: &Value) -> Option<String> {
+    entity["vcardArray"][1]
+        .as_array()?
+        .iter()
+        .find(|field| field[0] == "fn")
+        .and_then(|field| field[3].as_str())
+        .map(|name| name.to_string())
+}
+
+// RDAP ip network response: the owner is the registrant entity, or the first entity with a name
+pub fn parse_rdap(network: &str, response: &[u8]) -> Result<Netblock, String> {
+    let response: Value =
+        serde_json::from_slice(response).map_err(|e| format!("Invalid response: {}", e))?;
+    if response["objectClassName"] != "ip network" {
+        return Err("Invalid response: not an ip network".to_string());
+    }
+
+    let text = |field: &str| response[field].as_str().unwrap_or_default().to_string();
+    let entities = response["entities"].as_array().cloned().unwrap_or_default();
+    let registrant = entities.iter().find(|entity| {
+        entity["roles"]
+            .as_array()
+            .map(|roles| roles.iter().any(|role| role == "registrant"))
+            .unwrap_or(false)
+    });
+    let owner = registrant
+        .and_then(entity_name)
+        .or_else(|| entities.iter().find_map(entity_name));
+
+    Ok(Netblock {
+        network: network.to_string(),
+        range: format!("{} - {}", text("startAddress"), text("endAddress")),
+        name: text("name"),
+        handle: text("handle"),
+        owner,
+        country: response["country"].as_str().map(|c| c.to_string()),
+    })
+}
+
+// Attributes of the findings
+pub fn attributes(netblock: &Netblock) -> Vec<(String, String)> {
+    let mut attributes = vec![("netblock".to_string(), netblock.name.clone())];
+    if let Some(owner) = &netblock.owner {
+        attributes.push(("netblock_owner".to_string(), owner.clone()));
+    }
+    attributes
+}
+
+#[derive(Default)]
+struct Lookups {
+    // Netblocks by network, none when the lookup failed (not tried again)
+    cache: HashMap<String, Option<Netblock>>,
+    last: Option<Instant>,
+}
+
+// RDAP lookups of the networks of the findings (--rdap), once per network
+#[derive(Default)]
+pub struct RdapFetcher {
+    lookups: Mutex<Lookups>,
+}
+
+impl RdapFetcher {
+    pub async fn netblock(&self, ip: &str) -> Option<Netblock> {
+        let network = worker::network_of(ip);
+
+        // Held during the lookup, so that the same network is looked up once
+        let mut lookups = self.lookups.lock().await;
+        if let Some(netblock) = lookups.cache.get(&network) {
+            return netblock.clone();
+        }
+
+        if let Some(last) = lookups.last {
+            if last.elapsed() < RDAP_INTERVAL {
+                sleep(RDAP_INTERVAL - last.elapsed()).await;
+            }
+        }
+        lookups.last = Some(Instant::now());
+
+        let netblock = match net::fetch(&format!("{}{}", RDAP_URL, ip), RDAP_TIMEOUT).await {
+            Ok(body) => parse_rdap(&network, &body).ok(),
+            Err(_) => None,
+        };
+        lookups.cache.insert(network, netblock.clone());
+        netblock
+    }
+}
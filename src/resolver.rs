@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    sync::Mutex,
+    time::{self, Instant},
+};
+use tokio_native_tls::TlsConnector;
+
+use crate::{
+    plugins::dns::{build_query, exchange, read_u16, CLASS_IN},
+    zone::{self, ZoneRecord},
+};
+
+pub const TYPE_A: u16 = 1;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLVER_TIMEOUT: u64 = 5;
+const DNS_PORT: u16 = 53;
+const DOT_PORT: u16 = 853;
+// Truncated UDP response, asked again over TCP
+const FLAG_TC: u16 = 0x0200;
+const RCODE_NXDOMAIN: u16 = 3;
+
+// Server of the lookups: the system resolver, a DNS server (UDP, TCP when truncated), a DNS over
+// TLS server (with the name of its certificate) or a DNS over HTTPS url
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    System,
+    Udp(SocketAddr),
+    Tls(SocketAddr, String),
+    Https(String),
+}
+
+fn parse_addr(value: &str, default_port: u16) -> Option<SocketAddr> {
+    value.parse::<SocketAddr>().ok().or_else(|| {
+        value
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
+
+// --resolver values: "1.1.1.1", "1.1.1.1:5353" or "tls://1.1.1.1[:853][#cloudflare-dns.com]" (the
+// certificate name, the ip if not given). The --doh values are https urls
+pub fn parse_upstream(value: &str) -> Result<Upstream, String> {
+    if value.starts_with("https://") {
+        return Ok(Upstream::Https(value.to_string()));
+    }
+    if let Some(tls) = value.strip_prefix("tls://") {
+        let (addr, name) = match tls.split_once('#') {
+            Some((addr, name)) => (addr, Some(name)),
+            None => (tls, None),
+        };
+        let addr = parse_addr(addr, DOT_PORT).ok_or(format!("Invalid resolver {}", value))?;
+        let name = name
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| addr.ip().to_string());
+        return Ok(Upstream::Tls(addr, name));
+    }
+    parse_addr(value, DNS_PORT)
+        .map(Upstream::Udp)
+        .ok_or(format!("Invalid resolver {}", value))
+}
+
+// First name server of the system configuration
+fn system_resolver() -> Option<SocketAddr> {
+    fs::read_to_string(RESOLV_CONF)
+        .ok()?
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+}
+
+async fn exchange_tls(addr: &SocketAddr, name: &str, query: &[u8]) -> Result<Vec<u8>, String> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| format!("Connection error: {}", e))?;
+    let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+    let mut stream = TlsConnector::from(connector)
+        .connect(name, stream)
+        .await
+        .map_err(|e| format!("TLS error: {}", e))?;
+
+    // Messages prefixed by their length, as over TCP
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream
+        .write_all(&message)
+        .await
+        .map_err(|e| format!("Write error: {}", e))?;
+    let mut len = [0; 2];
+    stream
+        .read_exact(&mut len)
+        .await
+        .map_err(|e| format!("Read error: {}", e))?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream
+        .read_exact(&mut response)
+        .await
+        .map_err(|e| format!("Read error: {}", e))?;
+    Ok(response)
+}
+
+// DNS over HTTPS (RFC 8484), the message as the body of a POST request
+async fn exchange_https(url: &str, query: &[u8]) -> Result<Vec<u8>, String> {
+    let request = Request::post(url)
+        .header("Content-Type", "application/dns-message")
+        .header("Accept", "application/dns-message")
+        .body(Body::from(query.to_vec()))
+        .map_err(|e| format!("Invalid url: {}", e))?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let res = client
+        .request(request)
+        .await
+        .map_err(|e| format!("Request error: {}", e))?;
+    if !res.status().is_success() {
+        return Err(format!("Unexpected response status {}", res.status()));
+    }
+    hyper::body::to_bytes(res.into_body())
+        .await
+        .map(|body| body.to_vec())
+        .map_err(|e| format!("Response read error: {}", e))
+}
+
+struct Server {
+    upstream: Upstream,
+    // Instant of the next query allowed by the rate limit
+    next_query: Mutex<Instant>,
+}
+
+// Lookups of the scanner (domain targets, wildcards, name servers), through the configured
+// resolvers in turn (the next ones when a query fails), or the system one. The answers are cached
+// for the whole run
+pub struct Resolver {
+    servers: Vec<Server>,
+    // Min interval between the queries of each server (--resolver-rate)
+    interval: Option<Duration>,
+    next: AtomicUsize,
+    cache: Mutex<HashMap<(String, u16), Vec<ZoneRecord>>>,
+}
+
+impl Resolver {
+    // Queries per second of each resolver (0 = unlimited)
+    pub fn new(upstreams: Vec<Upstream>, rate: u64) -> Self {
+        let upstreams = if upstreams.is_empty() {
+            vec![Upstream::System]
+        } else {
+            upstreams
+        };
+        Resolver {
+            servers: upstreams
+                .into_iter()
+                .map(|upstream| Server {
+                    upstream,
+                    next_query: Mutex::new(Instant::now()),
+                })
+                .collect(),
+            interval: match rate {
+                0 => None,
+                rate => Some(Duration::from_secs(1) / rate as u32),
+            },
+            next: AtomicUsize::new(0),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn wait_for_slot(&self, server: &Server) {
+        if let Some(interval) = self.interval {
+            let slot = {
+                let mut next_query = server.next_query.lock().await;
+                let slot = (*next_query).max(Instant::now());
+                *next_query = slot + interval;
+                slot
+            };
+            time::sleep_until(slot).await;
+        }
+    }
+
+    async fn query_server(
+        &self,
+        server: &Server,
+        name: &str,
+        qtype: u16,
+    ) -> Result<Vec<ZoneRecord>, String> {
+        self.wait_for_slot(server).await;
+
+        // The system resolver (getaddrinfo) answers the addresses only, the other types are
+        // asked to the name server of resolv.conf
+        let addr = match &server.upstream {
+            Upstream::System if qtype == TYPE_A => {
+                let addrs = lookup_host((name, 0))
+                    .await
+                    .map_err(|e| format!("Lookup error: {}", e))?;
+                return Ok(addrs
+                    .filter(|addr| addr.is_ipv4())
+                    .map(|addr| ZoneRecord {
+                        name: name.to_string(),
+                        record_type: "A".to_string(),
+                        value: addr.ip().to_string(),
+                    })
+                    .collect());
+            }
+            Upstream::System => Some(system_resolver().ok_or("No name server in resolv.conf")?),
+            Upstream::Udp(addr) => Some(*addr),
+            _ => None,
+        };
+
+        let id: u16 = rand::thread_rng().gen();
+        let query = build_query(id, name, qtype, CLASS_IN, true);
+        let response = match (&server.upstream, addr) {
+            (Upstream::Tls(addr, tls_name), _) => exchange_tls(addr, tls_name, &query).await?,
+            (Upstream::Https(url), _) => exchange_https(url, &query).await?,
+            (_, Some(addr)) => {
+                let response = exchange(&addr, None, "udp", &query).await?;
+                match read_u16(&response, 2) {
+                    Some(flags) if flags & FLAG_TC != 0 => {
+                        exchange(&addr, None, "tcp", &query).await?
+                    }
+                    _ => response,
+                }
+            }
+            (_, None) => return Err("No resolver".to_string()),
+        };
+
+        let (rcode, records) = zone::parse_message(&response).ok_or("Invalid DNS response")?;
+        match rcode {
+            0 | RCODE_NXDOMAIN => Ok(records),
+            rcode => Err(format!("DNS error (rcode {})", rcode)),
+        }
+    }
+
+    // Records of the answer (e.g. the CNAMEs and the A records of a name), from the cache or
+    // the resolvers in turn
+    pub async fn query(&self, name: &str, qtype: u16) -> Result<Vec<ZoneRecord>, String> {
+        let key = (name.to_lowercase(), qtype);
+        if let Some(records) = self.cache.lock().await.get(&key) {
+            return Ok(records.clone());
+        }
+
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut error = String::new();
+        for i in 0..self.servers.len() {
+            let server = &self.servers[(first + i) % self.servers.len()];
+            let timeout = Duration::from_secs(RESOLVER_TIMEOUT);
+            match time::timeout(timeout, self.query_server(server, name, qtype)).await {
+                Ok(Ok(records)) => {
+                    self.cache.lock().await.insert(key, records.clone());
+                    return Ok(records);
+                }
+                Ok(Err(e)) => error = e,
+                Err(_) => error = "Query timed out".to_string(),
+            }
+        }
+        Err(error)
+    }
+
+    // IPv4 addresses of a name, none when it doesn't resolve
+    pub async fn lookup_ipv4(&self, name: &str) -> Vec<String> {
+        let mut ips: Vec<String> = self
+            .query(name, TYPE_A)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|record| record.record_type == "A")
+            .map(|record| record.value)
+            .collect();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac, NewMac};
+use ipnet::IpNet;
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::{conf, worker::ReqTarget};
+
+// Key of the scope files signatures (HMAC-SHA256)
+const SCOPE_KEY_ENV: &str = "LACHESIS_SCOPE_KEY";
+// Journal of the targets refused because out of scope, in the logs directory
+const AUDIT_FILE: &str = "scope-audit.jsonl";
+
+// Authorized networks of an engagement, e.g.
+// engagement = "ACME external pentest"
+// authorized = ["203.0.113.0/24", "2001:db8::/48"]
+// signature = "<hex HMAC-SHA256>"
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScopeFile {
+    engagement: String,
+    authorized: Vec<String>,
+    signature: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Scope {
+    pub engagement: String,
+    pub authorized: Vec<IpNet>,
+    // Audit journal, opened with the scope: a scan can't start without it
+    audit: Mutex<File>,
+}
+
+pub fn audit_path() -> PathBuf {
+    Path::new(&conf::logs_dir()).join(AUDIT_FILE)
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Of the length prefixed fields (engagement, number of networks, networks), so that no other
+// content has the same serialization
+fn scope_mac(key: &str, engagement: &str, authorized: &[String]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(&(engagement.len() as u64).to_be_bytes());
+    mac.update(engagement.as_bytes());
+    mac.update(&(authorized.len() as u64).to_be_bytes());
+    for net in authorized {
+        mac.update(&(net.len() as u64).to_be_bytes());
+        mac.update(net.as_bytes());
+    }
+    mac
+}
+
+fn read_scope_file(path: &str) -> Result<ScopeFile, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the scope file {}: {}", path, e))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid scope file {}: {}", path, e))
+}
+
+// Read from the environment by the callers, passed to sign and load
+pub fn key() -> Result<String, String> {
+    match env::var(SCOPE_KEY_ENV) {
+        Ok(key) if !key.is_empty() => Ok(key),
+        _ => Err(format!(
+            "The environment variable {} (scope files key) is not set",
+            SCOPE_KEY_ENV
+        )),
+    }
+}
+
+// Signature of the scope file content (engagement and authorized networks)
+pub fn sign(path: &str, key: &str) -> Result<String, String> {
+    let scope = read_scope_file(path)?;
+    let mac = scope_mac(key, &scope.engagement, &scope.authorized);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Scope {
+    pub fn new(
+        engagement: String,
+        authorized: Vec<IpNet>,
+        audit_path: &Path,
+    ) -> Result<Scope, String> {
+        let audit = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(audit_path)
+            .map_err(|e| {
+                format!(
+                    "Unable to open the scope audit file {}: {}",
+                    audit_path.display(),
+                    e
+                )
+            })?;
+        Ok(Scope {
+            engagement,
+            authorized,
+            audit: Mutex::new(audit),
+        })
+    }
+
+    // Only signed scope files are accepted, so that the authorized networks can't be changed
+    // without the key
+    pub fn load(path: &str, key: &str, audit_path: &Path) -> Result<Scope, String> {
+        let scope = read_scope_file(path)?;
+
+        let signature = match &scope.signature {
+            Some(signature) => hex::decode(signature)
+                .map_err(|_| "Invalid scope file signature (not hex encoded)".to_string())?,
+            None => return Err("The scope file is not signed".to_string()),
+        };
+        scope_mac(key, &scope.engagement, &scope.authorized)
+            .verify(&signature)
+            .map_err(|_| "Invalid scope file signature".to_string())?;
+
+        let mut authorized = Vec::new();
+        for net in &scope.authorized {
+            // Single addresses are accepted too
+            let net = match net.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => match net.parse::<IpAddr>() {
+                    Ok(ip) => IpNet::from(ip),
+                    Err(_) => return Err(format!("Invalid network in the scope file: {}", net)),
+                },
+            };
+            authorized.push(net);
+        }
+
+        Scope::new(scope.engagement, authorized, audit_path)
+    }
+
+    pub fn contains(&self, ip: &str) -> bool {
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => self.authorized.iter().any(|net| net.contains(&ip)),
+            Err(_) => false,
+        }
+    }
+
+    pub fn audit_refused(&self, target: &ReqTarget) -> Result<(), String> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let entry = json!({
+            "time": time,
+            "engagement": self.engagement,
+            "action": "refused",
+            "ip": target.ip,
+            "domain": target.domain,
+        });
+
+        let mut audit = self
+            .audit
+            .lock()
+            .map_err(|_| "Scope audit file poisoned".to_string())?;
+        writeln!(audit, "{}", entry).map_err(|e| e.to_string())
+    }
+}
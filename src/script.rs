@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::{conf, worker::ReqTarget};
+
+// Upper bound to the operations of a single script run (e.g. avoid infinite loops)
+const MAX_OPERATIONS: u64 = 100_000;
+
+thread_local! {
+    static ENGINE: Engine = build_engine();
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptResult {
+    pub service: Option<String>,
+    pub version: String,
+    pub description: String,
+}
+
+fn build_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+}
+
+// The script is searched in the resources scripts/ directory first, then used as a full/relative path
+pub fn compile(path: &str) -> Result<AST, String> {
+    let scripts_path = format!("{}/scripts/{}", conf::resources_dir(), path);
+    let full_path = if Path::new(&scripts_path).exists() {
+        scripts_path
+    } else {
+        path.to_string()
+    };
+
+    ENGINE.with(|engine| {
+        engine
+            .compile_file(full_path.clone().into())
+            .map_err(|err| format!("Script {} compile error: {}", full_path, err))
+    })
+}
+
+// The script receives the response and returns either nothing (not matching) or a map with
+// the (optional) keys: service, version, description
+pub fn run(ast: &AST, target: &ReqTarget) -> Result<Option<ScriptResult>, String> {
+    let mut headers = Map::new();
+    for (name, value) in &target.headers {
+        headers.insert(name.to_lowercase().into(), value.clone().into());
+    }
+
+    let mut scope = Scope::new();
+    scope.push("response", target.response.clone());
+    scope.push("body", target.body.clone());
+    scope.push("headers", headers);
+    scope.push("status", target.status.map(i64::from).unwrap_or(0));
+    scope.push("ip", target.ip.clone());
+    scope.push("domain", target.domain.clone());
+    scope.push("port", target.port as i64);
+    scope.push("protocol", target.protocol.clone());
+
+    let result = ENGINE
+        .with(|engine| engine.eval_ast_with_scope::<Dynamic>(&mut scope, ast))
+        .map_err(|err| format!("Script runtime error: {}", err))?;
+
+    if result.is_unit() {
+        return Ok(None);
+    }
+
+    let map = match result.try_cast::<Map>() {
+        Some(map) => map,
+        None => return Err("The script must return a map or nothing".to_string()),
+    };
+
+    let get = |key: &str| map.get(key).map(|v| v.to_string());
+
+    Ok(Some(ScriptResult {
+        service: get("service"),
+        version: get("version").unwrap_or_default(),
+        description: get("description").unwrap_or_default(),
+    }))
+}
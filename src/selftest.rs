@@ -0,0 +1,153 @@
+use std::{convert::Infallible, fs, net::SocketAddr};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::cli::SelftestArgs;
+
+// Fake services of the synthetic load (lachesis selftest, benchmarks): half of the listeners
+// serve HTTP, the other half answer the tcp/custom payloads
+pub struct Listeners {
+    pub http_ports: Vec<u16>,
+    pub tcp_ports: Vec<u16>,
+}
+
+async fn http_response(port: u16) -> Result<Response<Body>, Infallible> {
+    let body = format!(
+        "<html><head><title>Lachesis selftest</title></head>\
+        <body>Hello lachesis selftest {}</body></html>",
+        port
+    );
+    Ok(Response::builder()
+        .header("Server", "lachesis-selftest")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+async fn serve_http(listener: std::net::TcpListener) -> Result<(), String> {
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let make_svc = make_service_fn(move |_conn| async move {
+        Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| http_response(port)))
+    });
+    Server::from_tcp(listener)
+        .map_err(|e| e.to_string())?
+        .serve(make_svc)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Answers the first read of every connection (the payload) with a banner
+async fn serve_tcp(listener: TcpListener) {
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    while let Ok((mut socket, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            if let Ok(n) = socket.read(&mut buf).await {
+                if n > 0 {
+                    let banner = format!("LACHESIS-SELFTEST {}\r\n", port);
+                    let _ = socket.write_all(banner.as_bytes()).await;
+                }
+            }
+        });
+    }
+}
+
+// Binds the listeners on 127.0.0.1 (consecutive ports from base_port, or random ones if 0) and
+// serves them in background tasks
+pub async fn spawn(listeners: usize, base_port: u16) -> Result<Listeners, String> {
+    let mut http_ports = Vec::new();
+    let mut tcp_ports = Vec::new();
+
+    for n in 0..listeners {
+        let port = if base_port == 0 {
+            0
+        } else {
+            base_port
+                .checked_add(n as u16)
+                .ok_or("Not enough ports after the base port")?
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+        if n % 2 == 0 {
+            let listener = std::net::TcpListener::bind(addr)
+                .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+            listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+            http_ports.push(listener.local_addr().map_err(|e| e.to_string())?.port());
+            tokio::spawn(serve_http(listener));
+        } else {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+            tcp_ports.push(listener.local_addr().map_err(|e| e.to_string())?.port());
+            tokio::spawn(serve_tcp(listener));
+        }
+    }
+
+    Ok(Listeners {
+        http_ports,
+        tcp_ports,
+    })
+}
+
+// Definitions matching the fake services
+pub fn definitions(listeners: &Listeners) -> serde_json::Value {
+    json!([
+        {
+            "name": "Selftest HTTP",
+            "protocol": "http/s",
+            "options": {
+                "ports": listeners.http_ports,
+                "method": "GET",
+                "path": "/"
+            },
+            "service": {
+                "regex": "Hello lachesis selftest",
+                "log": false
+            }
+        },
+        {
+            "name": "Selftest TCP",
+            "protocol": "tcp/custom",
+            "options": {
+                "ports": listeners.tcp_ports,
+                "payload": "HELLO\r\n"
+            },
+            "service": {
+                "regex": "LACHESIS-SELFTEST",
+                "log": false
+            }
+        }
+    ])
+}
+
+// Serves the fake services until interrupted, the definitions to scan them are written to a file
+pub async fn run(args: &SelftestArgs) -> Result<(), String> {
+    if args.listeners < 2 {
+        return Err("At least 2 listeners are needed (one HTTP and one TCP)".to_string());
+    }
+    let listeners = spawn(args.listeners, args.port).await?;
+    let defs = serde_json::to_string_pretty(&definitions(&listeners)).unwrap();
+    fs::write(&args.definitions, defs)
+        .map_err(|e| format!("Unable to write {}: {}", args.definitions, e))?;
+
+    println!(
+        "{} HTTP and {} TCP services listening on 127.0.0.1, definitions written to {}",
+        listeners.http_ports.len(),
+        listeners.tcp_ports.len(),
+        args.definitions
+    );
+    println!(
+        "Scan them with: lachesis scan -S 127.0.0.1/32 -d {}",
+        args.definitions
+    );
+
+    std::future::pending::<()>().await;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use ipnet::Ipv4Net;
+use sha2::{Digest, Sha256};
+
+use crate::{cli::SplitArgs, worker};
+
+// Shard (1 to shards) of an ip, by its hash: the same on every host and for every order of the
+// input, and the domains of an ip stay in the same shard (e.g. for the per-host budget)
+pub fn shard_of(ip: &str, shards: u32) -> u32 {
+    let hash = Sha256::digest(ip.as_bytes());
+    let n = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    n % shards + 1
+}
+
+// Writes the targets of a shard: the records of the dataset (same format), or the hosts of the
+// subnets (one ip per line, e.g. for --target-stream). Returns the number of written targets
+pub fn run(args: &SplitArgs) -> Result<u64, String> {
+    if args.shards == 0 || args.shard == 0 || args.shard > args.shards {
+        return Err("Invalid value for parameter --shard (from 1 to --shards)".to_string());
+    }
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            Box::new(File::create(path).map_err(|e| format!("Unable to create {}: {}", path, e))?)
+        }
+        None => Box::new(io::stdout()),
+    };
+    let mut output = BufWriter::new(output);
+    let mut count = 0;
+
+    if let Some(dataset) = &args.dataset {
+        let file = File::open(dataset).map_err(|e| format!("Unable to read {}: {}", dataset, e))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            // The malformed records are skipped, like by the scans
+            let record = match worker::parse_dataset_record(&line) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if shard_of(&record.value, args.shards) == args.shard {
+                writeln!(output, "{}", line).map_err(|e| e.to_string())?;
+                count += 1;
+            }
+        }
+    } else {
+        let mut nets = Vec::new();
+        for subnet in args.subnet.iter().flatten() {
+            match subnet.parse::<Ipv4Net>() {
+                Ok(net) => nets.push(net),
+                Err(_) => return Err(format!("Invalid value for parameter --subnet: {}", subnet)),
+            }
+        }
+        // Every host once, also when the subnets overlap
+        for net in Ipv4Net::aggregate(&nets) {
+            for ip in net.hosts() {
+                let ip = ip.to_string();
+                if shard_of(&ip, args.shards) == args.shard {
+                    writeln!(output, "{}", ip).map_err(|e| e.to_string())?;
+                    count += 1;
+                }
+            }
+        }
+    }
+    output.flush().map_err(|e| e.to_string())?;
+
+    Ok(count)
+}
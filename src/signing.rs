@@ -0,0 +1,67 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+
+// Ed25519 signatures (definitions files, release binaries), hex encoded. Only the signer has the
+// secret key (a file with the hex seed), the public keys are compiled in or configured
+
+pub fn parse_public_key(key: &str) -> Result<PublicKey, String> {
+    hex::decode(key.trim())
+        .ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| format!("Invalid public key {}", key))
+}
+
+// Whether the signature of the content is valid for one of the public keys
+pub fn verify(public_keys: &[PublicKey], content: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature.trim())
+        .ok()
+        .and_then(|bytes| Signature::try_from(&bytes[..]).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    public_keys
+        .iter()
+        .any(|key| key.verify_strict(content, &signature).is_ok())
+}
+
+// Writes the secret key of a new key pair (never overwritten), returns the public key
+pub fn keygen(secret_key_path: &str) -> Result<String, String> {
+    let seed: [u8; 32] = rand::random();
+    let secret = SecretKey::from_bytes(&seed).unwrap();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(secret_key_path)
+        .map_err(|e| format!("Unable to create {}: {}", secret_key_path, e))?;
+    writeln!(file, "{}", hex::encode(secret.as_bytes()))
+        .map_err(|e| format!("Unable to write {}: {}", secret_key_path, e))?;
+    Ok(hex::encode(PublicKey::from(&secret).as_bytes()))
+}
+
+fn read_secret_key(path: &str) -> Result<Keypair, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the secret key {}: {}", path, e))?;
+    let secret = hex::decode(content.trim())
+        .ok()
+        .and_then(|bytes| SecretKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| format!("Invalid secret key {}", path))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+// Signature of the content with the secret key of the file
+pub fn sign(secret_key_path: &str, content: &[u8]) -> Result<String, String> {
+    let keypair = read_secret_key(secret_key_path)?;
+    Ok(hex::encode(keypair.sign(content).to_bytes()))
+}
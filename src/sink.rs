@@ -0,0 +1,221 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde_json::{json, Value};
+use tokio::time::sleep;
+
+use crate::{
+    detector::DetectorResponse, har, iana, net, persistence::Persister, plugins::BoxFuture,
+};
+
+const SINK_TIMEOUT: u64 = 10;
+// Attempts of the http sinks (Elasticsearch, webhook), with backoff
+const MAX_ATTEMPTS: u32 = 3;
+const BACKOFF_MILLIS: u64 = 500;
+
+// A destination of the matching services. Every sink gets every finding, a failing one doesn't
+// affect the others (e.g. the findings still reach a file while the db is down)
+pub trait OutputSink: Send + Sync {
+    fn name(&self) -> String;
+
+    fn write<'a>(&'a self, service: &'a DetectorResponse) -> BoxFuture<'a, Result<(), String>>;
+
+    // The open ports of a host of a port inventory (--ports-only), ignored by the sinks of the
+    // findings only (the db saves the port scans anyway)
+    fn write_ports<'a>(
+        &'a self,
+        _ip: &'a str,
+        _ports: &'a [u16],
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Extra sinks of the findings (--output-sink), besides the db
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkConf {
+    // JSON lines appended to a file
+    Jsonl(String),
+    // Documents indexed into an index (url of the index, e.g. http://localhost:9200/lachesis)
+    Elasticsearch(String),
+    // JSON body POSTed to a url
+    Webhook(String),
+}
+
+// "jsonl:<FILE>", "elasticsearch:<INDEX URL>" or "webhook:<URL>"
+pub fn parse_sink(value: &str) -> Result<SinkConf, String> {
+    let (kind, target) = value.split_once(':').ok_or(format!(
+        "Invalid output sink {} (expected <kind>:<target>)",
+        value
+    ))?;
+    if target.is_empty() {
+        return Err(format!("Invalid output sink {} (missing target)", value));
+    }
+    let is_url = target.starts_with("http://") || target.starts_with("https://");
+    match kind {
+        "jsonl" => Ok(SinkConf::Jsonl(target.to_string())),
+        "elasticsearch" | "webhook" if !is_url => {
+            Err(format!("Invalid output sink {} (not an http/s url)", value))
+        }
+        "elasticsearch" => Ok(SinkConf::Elasticsearch(
+            target.trim_end_matches('/').to_string(),
+        )),
+        "webhook" => Ok(SinkConf::Webhook(target.to_string())),
+        "postgres" => Err("The postgres sink is always enabled (see --db-conf)".to_string()),
+        _ => Err(format!(
+            "Unknown output sink {} (jsonl, elasticsearch or webhook)",
+            kind
+        )),
+    }
+}
+
+// A matching service as written by the sinks
+pub fn finding(service: &DetectorResponse) -> Value {
+    json!({
+        "time": har::iso_time(SystemTime::now()),
+        "protocol": service.target.protocol,
+        "ip": service.target.ip,
+        "domain": service.target.domain,
+        "port": service.target.port,
+        "service": service.service,
+        "version": service.version,
+        "description": service.description,
+        "confidence": service.confidence,
+        "truncated": service.target.truncated,
+        "tags": service.target.tags,
+        "request": service.request,
+        "attributes": service
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+            .collect::<serde_json::Map<String, Value>>(),
+    })
+}
+
+// The open ports of a host as written by the sinks, with their conventional service names
+pub fn open_ports(ip: &str, ports: &[u16]) -> Value {
+    json!({
+        "time": har::iso_time(SystemTime::now()),
+        "ip": ip,
+        "open_ports": ports,
+        "service_names": ports
+            .iter()
+            .filter_map(|port| iana::service_name(*port).map(|name| (port.to_string(), json!(name))))
+            .collect::<serde_json::Map<String, Value>>(),
+    })
+}
+
+pub struct JsonlSink {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl JsonlSink {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Error while opening {}: {}", path, e))?;
+        Ok(JsonlSink {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl OutputSink for JsonlSink {
+    fn name(&self) -> String {
+        format!("jsonl {}", self.path)
+    }
+
+    fn write<'a>(&'a self, service: &'a DetectorResponse) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let line = finding(service).to_string();
+            let mut file = self.file.lock().unwrap();
+            writeln!(file, "{}", line).map_err(|e| e.to_string())
+        })
+    }
+
+    fn write_ports<'a>(
+        &'a self,
+        ip: &'a str,
+        ports: &'a [u16],
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let line = open_ports(ip, ports).to_string();
+            let mut file = self.file.lock().unwrap();
+            writeln!(file, "{}", line).map_err(|e| e.to_string())
+        })
+    }
+}
+
+async fn post_with_retries(url: &str, body: &Value) -> Result<(), String> {
+    let mut last_err = String::new();
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            sleep(Duration::from_millis(BACKOFF_MILLIS << (attempt - 1))).await;
+        }
+        match net::post_json(url, body, SINK_TIMEOUT).await {
+            Ok(_) => return Ok(()),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+pub struct ElasticsearchSink {
+    index_url: String,
+}
+
+impl OutputSink for ElasticsearchSink {
+    fn name(&self) -> String {
+        format!("elasticsearch {}", self.index_url)
+    }
+
+    // A new document of the index per finding
+    fn write<'a>(&'a self, service: &'a DetectorResponse) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            post_with_retries(&format!("{}/_doc", self.index_url), &finding(service)).await
+        })
+    }
+}
+
+pub struct WebhookSink {
+    url: String,
+}
+
+impl OutputSink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    fn write<'a>(&'a self, service: &'a DetectorResponse) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { post_with_retries(&self.url, &finding(service)).await })
+    }
+}
+
+// The db, with its own retries and spool
+impl OutputSink for Persister {
+    fn name(&self) -> String {
+        "postgres".to_string()
+    }
+
+    fn write<'a>(&'a self, service: &'a DetectorResponse) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.insert_service(service))
+    }
+}
+
+pub fn open(conf: &SinkConf) -> Result<Arc<dyn OutputSink>, String> {
+    Ok(match conf {
+        SinkConf::Jsonl(path) => Arc::new(JsonlSink::open(path)?),
+        SinkConf::Elasticsearch(url) => Arc::new(ElasticsearchSink {
+            index_url: url.clone(),
+        }),
+        SinkConf::Webhook(url) => Arc::new(WebhookSink { url: url.clone() }),
+    })
+}
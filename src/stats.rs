@@ -1,11 +1,26 @@
-use std::{thread, time::Instant};
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use colored::Colorize;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
 
 use crate::{
+    breaker::BreakerEvent,
+    conf::Conf,
+    dedup::DedupReport,
     detector::DetectorResponse,
-    worker::{PortStatus, PortsTarget, ReqTarget},
+    enrichment::Enrichment,
+    error::{FailClass, TimeoutPhase},
+    iana,
+    net::RequestTimings,
+    worker::{self, PortStatus, PortsTarget, ReqTarget},
 };
 
 pub fn format_host(target: &ReqTarget) -> String {
@@ -16,11 +31,134 @@ pub fn format_host(target: &ReqTarget) -> String {
     }
 }
 
+// Seconds of the sliding window of the requests per second
+const RATE_WINDOW: u64 = 10;
+// Upper bounds (ms) of the response times histogram buckets, plus a last unbounded bucket
+const HISTOGRAM_BOUNDS: [u128; 8] = [10, 50, 100, 250, 500, 1000, 2500, 5000];
+const HISTOGRAM_WIDTH: u64 = 40;
+
+// Completed requests of the last RATE_WINDOW seconds, by second since the start. The averages
+// since the start hide the throughput drops (e.g. when the db falls behind)
+pub(crate) struct RateWindow {
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl RateWindow {
+    pub(crate) fn default() -> Self {
+        RateWindow {
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, second: u64) {
+        match self.buckets.back_mut() {
+            Some((s, count)) if *s == second => *count += 1,
+            _ => self.buckets.push_back((second, 1)),
+        }
+        while matches!(self.buckets.front(), Some((s, _)) if s + RATE_WINDOW <= second) {
+            self.buckets.pop_front();
+        }
+    }
+
+    pub(crate) fn rate(&self, second: u64) -> u64 {
+        let count: u64 = self
+            .buckets
+            .iter()
+            .filter(|(s, _)| s + RATE_WINDOW > second)
+            .map(|(_, count)| count)
+            .sum();
+        // Shorter window in the first seconds
+        count / RATE_WINDOW.min(second + 1)
+    }
+}
+
+fn histogram_bucket(millis: u128) -> usize {
+    HISTOGRAM_BOUNDS
+        .iter()
+        .position(|bound| millis < *bound)
+        .unwrap_or(HISTOGRAM_BOUNDS.len())
+}
+
+fn histogram_label(bucket: usize) -> String {
+    match HISTOGRAM_BOUNDS.get(bucket) {
+        Some(bound) => format!("<{}ms", bound),
+        None => format!(">={}ms", HISTOGRAM_BOUNDS[HISTOGRAM_BOUNDS.len() - 1]),
+    }
+}
+
+// Latencies (ms) recorded with a relative error under 1/LATENCY_SUB_BUCKETS, HDR histogram
+// style: exact up to 2*LATENCY_SUB_BUCKETS ms, then LATENCY_SUB_BUCKETS buckets for every power
+// of 2. The averages hide the long tail, the percentiles don't
+const LATENCY_SUB_BUCKETS: u64 = 32;
+const LATENCY_PERCENTILES: [(&str, f64); 3] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)];
+// Ports listed in the final report, the most requested first
+const TOP_LATENCY_PORTS: usize = 10;
+
+#[derive(Default)]
+struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, ms: u64) {
+        let index = latency_index(ms);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+        self.max = self.max.max(ms);
+    }
+
+    // Highest value of the bucket of the quantile (0-1)
+    fn percentile(&self, quantile: f64) -> u64 {
+        let rank = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return latency_upper(index).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn json(&self) -> Value {
+        let mut json = json!({ "count": self.total, "max": self.max });
+        for (name, quantile) in LATENCY_PERCENTILES.iter() {
+            json[*name] = json!(self.percentile(*quantile));
+        }
+        json
+    }
+}
+
+fn latency_index(ms: u64) -> usize {
+    if ms < 2 * LATENCY_SUB_BUCKETS {
+        return ms as usize;
+    }
+    // ms >> shift is in [LATENCY_SUB_BUCKETS, 2*LATENCY_SUB_BUCKETS)
+    let shift = (63 - ms.leading_zeros() - LATENCY_SUB_BUCKETS.trailing_zeros()) as u64;
+    (shift * LATENCY_SUB_BUCKETS + (ms >> shift)) as usize
+}
+
+fn latency_upper(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * LATENCY_SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / LATENCY_SUB_BUCKETS - 1;
+    let sub_bucket = index - shift * LATENCY_SUB_BUCKETS;
+    ((sub_bucket + 1) << shift) - 1
+}
+
 struct PortStats {
     open: u64,
     closed: u64,
     avg_time: u128,
     timedout: u64,
+    window: RateWindow,
 }
 
 impl PortStats {
@@ -30,6 +168,7 @@ impl PortStats {
             closed: 0,
             avg_time: 0,
             timedout: 0,
+            window: RateWindow::default(),
         }
     }
 
@@ -43,6 +182,7 @@ struct RequestStats {
     avg_time: u128,
     failed: u64,
     timedout: u64,
+    window: RateWindow,
 }
 
 impl RequestStats {
@@ -52,6 +192,7 @@ impl RequestStats {
             avg_time: 0,
             failed: 0,
             timedout: 0,
+            window: RateWindow::default(),
         }
     }
 
@@ -60,9 +201,21 @@ impl RequestStats {
     }
 }
 
+// Seconds between two stats lines in JSON logs mode
+const JSON_STATS_INTERVAL: u64 = 10;
+// Slowest networks listed in the debug stats
+const SLOWEST_NETWORKS: usize = 10;
+// Countries and ASes listed in the final report
+const TOP_GEO: usize = 10;
+
 pub struct Stats {
     start_time: Instant,
     progress_bars: Vec<ProgressBar>,
+    // JSON lines on stdout instead of the progress bars (e.g. when running in a container)
+    json_logs: bool,
+    // Only the findings (matches, open ports) as JSON lines on stdout, for the pipelines
+    stdout_ndjson: bool,
+    last_json_stats: Cell<Instant>,
     max_targets: u64,
     targets: u64,
     avg_reqs_per_sec: u64,
@@ -70,11 +223,39 @@ pub struct Stats {
     https: RequestStats,
     http: RequestStats,
     tcp_custom: RequestStats,
+    // Failed requests by class (all the protocols)
+    fail_classes: BTreeMap<FailClass, u64>,
+    // Timed out requests by phase (all the protocols)
+    timeout_phases: BTreeMap<TimeoutPhase, u64>,
+    // Matching services by definition
+    definition_matches: BTreeMap<String, u64>,
+    // Response times of the probes (all the protocols), by HISTOGRAM_BOUNDS bucket
+    response_times: [u64; HISTOGRAM_BOUNDS.len() + 1],
+    // Sum (ms) and count of each phase of the http/s requests (dns, connect, tls, ttfb)
+    request_timings: BTreeMap<&'static str, (u64, u64)>,
+    // Latencies of the open port checks and of the responses by protocol, and of the responses
+    // by port
+    latencies: BTreeMap<String, LatencyHistogram>,
+    port_latencies: BTreeMap<u16, LatencyHistogram>,
     matching: u64,
+    // Estimated timeouts (ms) of the port checks by network, kept in debug mode only
+    debug: bool,
+    network_timeouts: BTreeMap<String, u64>,
+    // Matching services by country and by AS (none when not in the databases), with the
+    // enrichment databases only (--geoip-db, --asn-db)
+    enrichment: Option<Arc<Enrichment>>,
+    country_matches: BTreeMap<Option<String>, u64>,
+    asn_matches: BTreeMap<Option<i64>, (Option<String>, u64)>,
+    // Pauses of the circuit breaker and their total time, if enabled (--breaker-threshold)
+    breaker: bool,
+    breaker_trips: u64,
+    breaker_paused: Duration,
 }
 
 impl Stats {
-    pub fn new(max_targets: u64) -> Self {
+    pub fn new(conf: &Conf) -> Self {
+        let max_targets = conf.max_targets;
+        let json_logs = conf.json_logs;
         let m = MultiProgress::new();
         let mut pbs = Vec::new();
         let pb0 = if max_targets != 0 {
@@ -103,18 +284,32 @@ impl Stats {
         pb4.set_style(ProgressStyle::default_spinner().template("{wide_msg}"));
         let pb5 = ProgressBar::new(0);
         pb5.set_style(ProgressStyle::default_spinner().template("{wide_msg}"));
-        pbs.push(m.add(pb0));
-        pbs.push(m.add(pb1));
-        pbs.push(m.add(pb2));
-        pbs.push(m.add(pb3));
-        pbs.push(m.add(pb4));
-        pbs.push(m.add(pb5));
+        let pb6 = ProgressBar::new(0);
+        pb6.set_style(ProgressStyle::default_spinner().template("{wide_msg}"));
+        if json_logs || conf.stdout_ndjson {
+            // No progress bars drawn, and no colors in the logged values
+            for _ in 0..7 {
+                pbs.push(ProgressBar::hidden());
+            }
+            colored::control::set_override(false);
+        } else {
+            pbs.push(m.add(pb0));
+            pbs.push(m.add(pb1));
+            pbs.push(m.add(pb2));
+            pbs.push(m.add(pb3));
+            pbs.push(m.add(pb4));
+            pbs.push(m.add(pb5));
+            pbs.push(m.add(pb6));
 
-        thread::spawn(move || m.join().unwrap());
+            thread::spawn(move || m.join().unwrap());
+        }
 
         Stats {
             start_time: Instant::now(),
             progress_bars: pbs,
+            json_logs,
+            stdout_ndjson: conf.stdout_ndjson,
+            last_json_stats: Cell::new(Instant::now()),
             max_targets,
             targets: 0,
             avg_reqs_per_sec: 0,
@@ -122,7 +317,22 @@ impl Stats {
             https: RequestStats::default(),
             http: RequestStats::default(),
             tcp_custom: RequestStats::default(),
+            fail_classes: BTreeMap::new(),
+            timeout_phases: BTreeMap::new(),
+            definition_matches: BTreeMap::new(),
+            response_times: [0; HISTOGRAM_BOUNDS.len() + 1],
             matching: 0,
+            debug: conf.debug,
+            network_timeouts: BTreeMap::new(),
+            enrichment: conf.enrichment.clone(),
+            country_matches: BTreeMap::new(),
+            asn_matches: BTreeMap::new(),
+            request_timings: BTreeMap::new(),
+            latencies: BTreeMap::new(),
+            port_latencies: BTreeMap::new(),
+            breaker: conf.breaker.is_some(),
+            breaker_trips: 0,
+            breaker_paused: Duration::default(),
         }
     }
 
@@ -130,6 +340,30 @@ impl Stats {
         self.ports.total() + self.https.total() + self.http.total() + self.tcp_custom.total()
     }
 
+    fn second(&self) -> u64 {
+        self.start_time.elapsed().as_secs()
+    }
+
+    // Requests per second of the sliding window
+    fn reqs_per_sec(&self) -> u64 {
+        let second = self.second();
+        self.ports.window.rate(second)
+            + self.https.window.rate(second)
+            + self.http.window.rate(second)
+            + self.tcp_custom.window.rate(second)
+    }
+
+    fn add_to_window(&mut self, protocol: &str) {
+        let second = self.second();
+        match protocol {
+            "port" => self.ports.window.add(second),
+            "https" => self.https.window.add(second),
+            "http" => self.http.window.add(second),
+            "tcp/custom" => self.tcp_custom.window.add(second),
+            _ => (),
+        }
+    }
+
     pub fn update_avg_reqs_per_sec(&mut self) {
         let elapsed_secs = self.start_time.elapsed().as_secs();
         if elapsed_secs > 0 {
@@ -141,16 +375,30 @@ impl Stats {
         for port in &ports_target.ports {
             match port.status {
                 PortStatus::Open => {
-                    self.update_req_avg_time(port.time, "port");
+                    self.update_req_avg_time(port.time, "port", port.port);
                     self.increment_successful("port", false);
                 }
                 PortStatus::Closed => self.increment_failed("port"),
                 PortStatus::Timedout => self.increment_timedout("port"),
             };
         }
+        if self.debug {
+            self.network_timeouts
+                .insert(worker::network_of(&ports_target.ip), ports_target.timeout);
+        }
+    }
+
+    // Networks with the longest estimated timeouts
+    fn slowest_networks(&self) -> Vec<(&String, &u64)> {
+        let mut networks: Vec<(&String, &u64)> = self.network_timeouts.iter().collect();
+        networks.sort_by(|a, b| b.1.cmp(a.1));
+        networks.truncate(SLOWEST_NETWORKS);
+        networks
     }
 
     pub fn increment_successful(&mut self, protocol: &str, matching: bool) {
+        self.add_to_window(protocol);
+
         match protocol {
             "port" => self.ports.open += 1,
             "https" => self.https.successful += 1,
@@ -167,6 +415,8 @@ impl Stats {
     }
 
     pub fn increment_failed(&mut self, protocol: &str) {
+        self.add_to_window(protocol);
+
         match protocol {
             "port" => self.ports.closed += 1,
             "https" => self.https.failed += 1,
@@ -178,7 +428,62 @@ impl Stats {
         self.update_messages();
     }
 
+    pub fn increment_fail_class(&mut self, class: FailClass) {
+        *self.fail_classes.entry(class).or_insert(0) += 1;
+
+        self.update_messages();
+    }
+
+    pub fn increment_timeout_phase(&mut self, phase: TimeoutPhase) {
+        *self.timeout_phases.entry(phase).or_insert(0) += 1;
+
+        self.update_messages();
+    }
+
+    pub fn increment_definition_match(&mut self, definition: &str) {
+        *self
+            .definition_matches
+            .entry(definition.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn increment_geo_match(&mut self, ip: &str) {
+        let geo = match &self.enrichment {
+            Some(enrichment) => enrichment.lookup(ip),
+            None => return,
+        };
+        *self.country_matches.entry(geo.country).or_insert(0) += 1;
+        let asn = self.asn_matches.entry(geo.asn).or_insert((None, 0));
+        if geo.as_name.is_some() {
+            asn.0 = geo.as_name;
+        }
+        asn.1 += 1;
+    }
+
+    // Countries and ASes by matches, the most matching first (same format of /api/services/geo)
+    fn country_matches(&self) -> Vec<(&Option<String>, u64)> {
+        let mut countries: Vec<(&Option<String>, u64)> = self
+            .country_matches
+            .iter()
+            .map(|(country, count)| (country, *count))
+            .collect();
+        countries.sort_by_key(|(_, count)| Reverse(*count));
+        countries
+    }
+
+    fn asn_matches(&self) -> Vec<(&Option<i64>, &Option<String>, u64)> {
+        let mut asns: Vec<(&Option<i64>, &Option<String>, u64)> = self
+            .asn_matches
+            .iter()
+            .map(|(asn, (name, count))| (asn, name, *count))
+            .collect();
+        asns.sort_by_key(|(_, _, count)| Reverse(*count));
+        asns
+    }
+
     pub fn increment_timedout(&mut self, protocol: &str) {
+        self.add_to_window(protocol);
+
         match protocol {
             "port" => self.ports.timedout += 1,
             "https" => self.https.timedout += 1,
@@ -200,7 +505,17 @@ impl Stats {
         self.update_messages();
     }
 
-    pub fn update_req_avg_time(&mut self, time: Instant, protocol: &str) {
+    pub fn update_req_avg_time(&mut self, time: Instant, protocol: &str, port: u16) {
+        let ms = time.elapsed().as_millis() as u64;
+        self.latencies
+            .entry(protocol.to_string())
+            .or_default()
+            .record(ms);
+        if protocol != "port" {
+            self.response_times[histogram_bucket(time.elapsed().as_millis())] += 1;
+            self.port_latencies.entry(port).or_default().record(ms);
+        }
+
         match protocol {
             "port" => {
                 self.ports.avg_time = (self.ports.avg_time * self.ports.open as u128
@@ -229,111 +544,569 @@ impl Stats {
         self.update_messages();
     }
 
+    pub fn update_request_timings(&mut self, timings: &RequestTimings) {
+        for (phase, ms) in timings.phases().iter() {
+            if let Some(ms) = ms {
+                let (sum, count) = self.request_timings.entry(phase).or_insert((0, 0));
+                *sum += ms;
+                *count += 1;
+            }
+        }
+    }
+
+    // Average (ms) of each phase of the http/s requests
+    fn avg_request_timings(&self) -> BTreeMap<&'static str, u64> {
+        self.request_timings
+            .iter()
+            .map(|(phase, (sum, count))| (*phase, sum / count))
+            .collect()
+    }
+
+    // All the stats, as logged in JSON logs mode and saved in the db (scan_stats)
+    pub fn json_stats(&self) -> Value {
+        let second = self.second();
+        let requests = |r: &RequestStats| {
+            json!({
+                "total": r.total(),
+                "successful": r.successful,
+                "failed": r.failed,
+                "timedout": r.timedout,
+                "avg_time_ms": r.avg_time as u64,
+                "reqs_per_sec": r.window.rate(second),
+            })
+        };
+
+        let mut stats = json!({
+            "type": "stats",
+            "elapsed_secs": self.start_time.elapsed().as_secs(),
+            "targets": self.targets,
+            "max_targets": self.max_targets,
+            "requests": self.total_requests(),
+            "reqs_per_sec": self.avg_reqs_per_sec,
+            "window_reqs_per_sec": self.reqs_per_sec(),
+            "matching": self.matching,
+            "definition_matches": self.definition_matches,
+            "ports": {
+                "tested": self.ports.total(),
+                "open": self.ports.open,
+                "closed": self.ports.closed,
+                "timedout": self.ports.timedout,
+                "avg_time_ms": self.ports.avg_time as u64,
+                "reqs_per_sec": self.ports.window.rate(second),
+            },
+            "tcp_custom": requests(&self.tcp_custom),
+            "http": requests(&self.http),
+            "https": requests(&self.https),
+            "failures": self
+                .fail_classes
+                .iter()
+                .map(|(class, count)| (class.as_str(), *count))
+                .collect::<BTreeMap<&str, u64>>(),
+            "timeouts": self
+                .timeout_phases
+                .iter()
+                .map(|(phase, count)| (phase.as_str(), *count))
+                .collect::<BTreeMap<&str, u64>>(),
+            "response_times": self
+                .response_times
+                .iter()
+                .enumerate()
+                .map(|(bucket, count)| json!([histogram_label(bucket), count]))
+                .collect::<Vec<Value>>(),
+            "request_timings_ms": self.avg_request_timings(),
+            "latency_ms": self
+                .latencies
+                .iter()
+                .map(|(protocol, histogram)| (protocol.clone(), histogram.json()))
+                .collect::<serde_json::Map<String, Value>>(),
+            "port_latency_ms": self
+                .port_latencies
+                .iter()
+                .map(|(port, histogram)| (port.to_string(), histogram.json()))
+                .collect::<serde_json::Map<String, Value>>(),
+        });
+        if self.enrichment.is_some() {
+            stats["geo_matches"] = json!({
+                "countries": self.country_matches(),
+                "asns": self.asn_matches(),
+            });
+        }
+        if self.breaker {
+            stats["breaker"] = json!({
+                "trips": self.breaker_trips,
+                "paused_secs": self.breaker_paused.as_secs(),
+            });
+        }
+        if self.debug {
+            stats["network_timeouts_ms"] = self
+                .slowest_networks()
+                .into_iter()
+                .map(|(network, timeout)| json!([network, timeout]))
+                .collect();
+        }
+        stats
+    }
+
+    // A progress bar line, or a JSON line in JSON logs mode. With the findings on stdout the
+    // other events are dropped, but the errors and the breaker alerts (on stderr)
+    fn print(&self, line: String, json_line: Value) {
+        if self.stdout_ndjson {
+            match json_line["type"].as_str() {
+                Some("match") | Some("open_ports") => println!("{}", json_line),
+                Some("error") | Some("breaker") => eprintln!("{}", line),
+                _ => (),
+            }
+        } else if self.json_logs {
+            println!("{}", json_line);
+        } else {
+            self.progress_bars[0].println(line);
+        }
+    }
+
     fn update_messages(&self) {
+        if self.stdout_ndjson {
+            return;
+        }
+        if self.json_logs {
+            if self.last_json_stats.get().elapsed().as_secs() >= JSON_STATS_INTERVAL {
+                self.last_json_stats.set(Instant::now());
+                println!("{}", self.json_stats());
+            }
+            return;
+        }
+
+        let second = self.second();
+
         self.progress_bars[1].set_message(format!(
-            "Targets: {} Requests: {} Req/sec: {} Matching: {}",
+            "Targets: {} Requests: {} Req/sec: {} (avg: {}) Matching: {}",
             self.targets.to_string().cyan(),
             self.total_requests().to_string().cyan(),
+            self.reqs_per_sec().to_string().cyan(),
             self.avg_reqs_per_sec.to_string().cyan(),
             self.matching.to_string().green(),
         ));
 
         self.progress_bars[2].set_message(format!(
-            "Ports [tested: {} open: {} closed: {} timedout: {} avg_time: {}ms]",
+            "Ports [tested: {} open: {} closed: {} timedout: {} avg_time: {}ms req/sec: {}]",
             self.ports.total().to_string().cyan(),
             self.ports.open.to_string().green(),
             self.ports.closed.to_string().red(),
             self.ports.timedout.to_string().yellow(),
             self.ports.avg_time.to_string().cyan(),
+            self.ports.window.rate(second).to_string().cyan(),
         ));
 
         self.progress_bars[3].set_message(format!(
-            "Tcp/custom [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms]",
+            "Tcp/custom [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms req/sec: {}]",
             self.tcp_custom.total().to_string().cyan(),
             self.tcp_custom.successful.to_string().green(),
             self.tcp_custom.failed.to_string().red(),
             self.tcp_custom.timedout.to_string().yellow(),
             self.tcp_custom.avg_time.to_string().cyan(),
+            self.tcp_custom.window.rate(second).to_string().cyan(),
         ));
 
         self.progress_bars[4].set_message(format!(
-            "Http [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms]",
+            "Http [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms req/sec: {}]",
             self.http.total().to_string().cyan(),
             self.http.successful.to_string().green(),
             self.http.failed.to_string().red(),
             self.http.timedout.to_string().yellow(),
             self.http.avg_time.to_string().cyan(),
+            self.http.window.rate(second).to_string().cyan(),
         ));
 
         self.progress_bars[5].set_message(format!(
-            "Https [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms]",
+            "Https [total: {} successful: {} failed: {} timedout: {} avg_time: {}ms req/sec: {}]",
             self.https.total().to_string().cyan(),
             self.https.successful.to_string().green(),
             self.https.failed.to_string().red(),
             self.https.timedout.to_string().yellow(),
             self.https.avg_time.to_string().cyan(),
+            self.https.window.rate(second).to_string().cyan(),
+        ));
+
+        let failures: Vec<String> = self
+            .fail_classes
+            .iter()
+            .map(|(class, count)| format!("{}: {}", class, count.to_string().red()))
+            .collect();
+        let timeouts: Vec<String> = self
+            .timeout_phases
+            .iter()
+            .map(|(phase, count)| format!("{}: {}", phase.as_str(), count.to_string().yellow()))
+            .collect();
+        self.progress_bars[6].set_message(format!(
+            "Failures [{}] Timeouts [{}]",
+            failures.join(" "),
+            timeouts.join(" ")
         ));
     }
 
     pub fn log_int_err(&mut self, message: String) {
-        self.progress_bars[0].println(format!("[{}] {}", "ERROR".red(), message));
+        self.print(
+            format!("[{}] {}", "ERROR".red(), message),
+            json!({ "type": "error", "message": message }),
+        );
     }
 
+    // With the conventional service names of the ports (only a guess before the fingerprinting)
     pub fn log_open_ports(&mut self, ip: &str, ports: &[u16]) {
-        self.progress_bars[0].println(format!(
-            "[{}][{}] Open ports: {}",
-            "OPEN_PORTS".blue(),
-            ip.cyan(),
-            format!("{:?}", ports).cyan()
-        ));
+        let annotated: Vec<String> = ports.iter().map(|port| iana::annotate(*port)).collect();
+        let names: BTreeMap<String, &str> = ports
+            .iter()
+            .filter_map(|port| iana::service_name(*port).map(|name| (port.to_string(), name)))
+            .collect();
+        self.print(
+            format!(
+                "[{}][{}] Open ports: {}",
+                "OPEN_PORTS".blue(),
+                ip.cyan(),
+                annotated.join(", ").cyan()
+            ),
+            json!({ "type": "open_ports", "ip": ip, "ports": ports, "service_names": names }),
+        );
     }
 
     pub fn log_response(&mut self, target: &ReqTarget) {
-        self.progress_bars[0].println(format!(
-            "[{}][{}][{}:{}] Received a response. Length: {}",
-            "RESPONSE".cyan(),
-            target.protocol.to_uppercase().blue(),
-            format_host(&target).cyan(),
-            target.port.to_string().cyan(),
-            target.response.len().to_string().cyan()
-        ));
+        self.print(
+            format!(
+                "[{}][{}][{}:{}] Received a response. Length: {}{}",
+                "RESPONSE".cyan(),
+                target.protocol.to_uppercase().blue(),
+                format_host(&target).cyan(),
+                target.port.to_string().cyan(),
+                target.response.len().to_string().cyan(),
+                if target.truncated { " (truncated)" } else { "" }
+            ),
+            json!({
+                "type": "response",
+                "protocol": target.protocol,
+                "ip": target.ip,
+                "domain": target.domain,
+                "port": target.port,
+                "length": target.response.len(),
+                "truncated": target.truncated,
+            }),
+        );
     }
 
-    pub fn log_timeout(&mut self, target: &ReqTarget) {
-        self.progress_bars[0].println(format!(
-            "[{}][{}][{}:{}] - Request timeout",
-            "TIMEOUT".yellow(),
-            target.protocol.to_uppercase().blue(),
-            format_host(&target).cyan(),
-            target.port.to_string().cyan(),
-        ));
+    pub fn log_timeout(&mut self, target: &ReqTarget, phase: TimeoutPhase) {
+        self.print(
+            format!(
+                "[{}][{}][{}:{}] - Request timeout ({})",
+                "TIMEOUT".yellow(),
+                target.protocol.to_uppercase().blue(),
+                format_host(&target).cyan(),
+                target.port.to_string().cyan(),
+                phase,
+            ),
+            json!({
+                "type": "timeout",
+                "protocol": target.protocol,
+                "ip": target.ip,
+                "domain": target.domain,
+                "port": target.port,
+                "phase": phase.as_str(),
+            }),
+        );
+    }
+
+    pub fn log_scan(&mut self, scan_id: i64) {
+        self.print(
+            format!(
+                "[{}] Scan id: {}",
+                "SCAN".blue(),
+                scan_id.to_string().cyan()
+            ),
+            json!({ "type": "scan", "id": scan_id }),
+        );
+    }
+
+    pub fn log_dataset_dedup(&mut self, dedup: &DedupReport) {
+        self.print(
+            format!(
+                "[{}] Dataset A records: {} duplicate ips skipped: {} (sha256 {})",
+                "SCAN".blue(),
+                dedup.records.to_string().cyan(),
+                dedup.duplicates.to_string().cyan(),
+                dedup.sha256
+            ),
+            json!({
+                "type": "dataset_dedup",
+                "records": dedup.records,
+                "duplicates": dedup.duplicates,
+                "sha256": dedup.sha256,
+            }),
+        );
+    }
+
+    pub fn log_breaker(&mut self, event: BreakerEvent) {
+        match event {
+            BreakerEvent::Tripped {
+                failure_rate,
+                cooldown,
+            } => {
+                self.breaker_trips += 1;
+                let percent = (failure_rate * 100.0).round() as u64;
+                self.print(
+                    format!(
+                        "[{}] Failed or timed out: {}%, targets paused for {}s",
+                        "BREAKER".red(),
+                        percent.to_string().red(),
+                        cooldown.as_secs().to_string().cyan()
+                    ),
+                    json!({
+                        "type": "breaker",
+                        "state": "paused",
+                        "failure_rate": percent,
+                        "cooldown_secs": cooldown.as_secs(),
+                    }),
+                );
+            }
+            BreakerEvent::Resumed { paused } => {
+                self.breaker_paused += paused;
+                self.print(
+                    format!(
+                        "[{}] Failure rate below the threshold, targets resumed after {}s",
+                        "BREAKER".green(),
+                        paused.as_secs().to_string().cyan()
+                    ),
+                    json!({
+                        "type": "breaker",
+                        "state": "resumed",
+                        "paused_secs": paused.as_secs(),
+                    }),
+                );
+            }
+        }
+    }
+
+    pub fn log_stale_services(&mut self, count: u64) {
+        self.print(
+            format!(
+                "[{}] Services not seen again, marked inactive: {}",
+                "SCAN".blue(),
+                count.to_string().cyan()
+            ),
+            json!({ "type": "stale_services", "count": count }),
+        );
+    }
+
+    pub fn log_vhost(&mut self, target: &ReqTarget) {
+        self.print(
+            format!(
+                "[{}][{}][{}:{}] Virtual host: {}",
+                "VHOST".green(),
+                target.protocol.to_uppercase().blue(),
+                target.ip.cyan(),
+                target.port.to_string().cyan(),
+                target.domain.cyan(),
+            ),
+            json!({
+                "type": "vhost",
+                "protocol": target.protocol,
+                "ip": target.ip,
+                "port": target.port,
+                "domain": target.domain,
+            }),
+        );
     }
 
-    pub fn log_fail(&mut self, target: &ReqTarget, error_context: String, error: Option<String>) {
+    pub fn log_out_of_scope(&mut self, target: &ReqTarget) {
+        self.print(
+            format!(
+                "[{}][{}] - Target refused (out of scope)",
+                "OUT_OF_SCOPE".red(),
+                format_host(&target).cyan(),
+            ),
+            json!({
+                "type": "out_of_scope",
+                "ip": target.ip,
+                "domain": target.domain,
+            }),
+        );
+    }
+
+    pub fn log_fail(
+        &mut self,
+        target: &ReqTarget,
+        class: FailClass,
+        error_context: String,
+        error: Option<String>,
+    ) {
+        self.print(
+            format!(
+                "[{}][{}][{}:{}][{}] - {}{}",
+                "FAIL".magenta(),
+                target.protocol.to_uppercase().blue(),
+                format_host(&target).cyan(),
+                target.port.to_string().cyan(),
+                class.to_string().magenta(),
+                error_context,
+                if let Some(e) = &error {
+                    format!(": {}", e)
+                } else {
+                    "".to_string()
+                },
+            ),
+            json!({
+                "type": "fail",
+                "protocol": target.protocol,
+                "ip": target.ip,
+                "domain": target.domain,
+                "port": target.port,
+                "class": class.as_str(),
+                "context": error_context,
+                "error": error,
+            }),
+        );
+    }
+
+    pub fn log_match(&mut self, dr: &DetectorResponse) {
+        self.print(
+            format!(
+                "[{}][{}] service: {} version: {} description: {}",
+                "MATCH".green(),
+                format_host(&dr.target).green(),
+                dr.service.green(),
+                dr.version.green(),
+                dr.description.green()
+            ),
+            json!({
+                "type": "match",
+                "protocol": dr.target.protocol,
+                "ip": dr.target.ip,
+                "domain": dr.target.domain,
+                "port": dr.target.port,
+                "service": dr.service,
+                "version": dr.version,
+                "description": dr.description,
+                "confidence": dr.confidence,
+                "truncated": dr.target.truncated,
+            }),
+        );
+    }
+
+    // Response times histogram, printed at the end of the scan
+    fn print_response_times(&self) {
+        let max = self.response_times.iter().max().cloned().unwrap_or(0);
+        if max == 0 {
+            return;
+        }
+
+        self.progress_bars[0].println("\nResponse times:");
+        for (bucket, count) in self.response_times.iter().enumerate() {
+            // At least one mark for the non-empty buckets
+            let width = match count {
+                0 => 0,
+                count => (count * HISTOGRAM_WIDTH / max).max(1),
+            };
+            self.progress_bars[0].println(format!(
+                "  {:>9} {:>10} {}",
+                histogram_label(bucket),
+                count,
+                "#".repeat(width as usize).cyan()
+            ));
+        }
+    }
+
+    // Latency percentiles by protocol and of the most requested ports, printed at the end of the
+    // scan
+    fn print_latencies(&self) {
+        if self.latencies.is_empty() {
+            return;
+        }
+
+        let mut ports: Vec<(&u16, &LatencyHistogram)> = self.port_latencies.iter().collect();
+        ports.sort_by_key(|(_, histogram)| Reverse(histogram.total));
+        ports.truncate(TOP_LATENCY_PORTS);
+        let rows = self
+            .latencies
+            .iter()
+            .map(|(protocol, histogram)| (protocol.clone(), histogram))
+            .chain(
+                ports
+                    .into_iter()
+                    .map(|(port, histogram)| (format!("port {}", port), histogram)),
+            );
+
         self.progress_bars[0].println(format!(
-            "[{}][{}][{}:{}] - {}{}",
-            "FAIL".magenta(),
-            target.protocol.to_uppercase().blue(),
-            format_host(&target).cyan(),
-            target.port.to_string().cyan(),
-            error_context,
-            if let Some(e) = error {
-                format!(": {}", e)
-            } else {
-                "".to_string()
-            },
+            "\nLatencies (ms):\n  {:>12} {:>10} {:>7} {:>7} {:>7} {:>7}",
+            "", "count", "p50", "p90", "p99", "max"
         ));
+        for (name, histogram) in rows {
+            let percentiles: Vec<String> = LATENCY_PERCENTILES
+                .iter()
+                .map(|(_, quantile)| format!("{:>7}", histogram.percentile(*quantile)))
+                .collect();
+            self.progress_bars[0].println(format!(
+                "  {:>12} {:>10} {} {:>7}",
+                name,
+                histogram.total,
+                percentiles.join(" ").cyan(),
+                histogram.max.to_string().yellow()
+            ));
+        }
     }
 
-    pub fn log_match(&mut self, dr: &DetectorResponse) {
+    // Average phases of the http/s requests, printed at the end of the scan
+    fn print_request_timings(&self) {
+        if self.request_timings.is_empty() {
+            return;
+        }
+
+        let timings: Vec<String> = self
+            .avg_request_timings()
+            .iter()
+            .map(|(phase, ms)| format!("{}: {}ms", phase, ms.to_string().cyan()))
+            .collect();
+        self.progress_bars[0].println(format!("\nRequest timings (avg): {}", timings.join(" ")));
+    }
+
+    // Matches of the most matching countries and ASes, printed at the end of the scan
+    fn print_geo_matches(&self) {
+        if self.matching == 0 || self.enrichment.is_none() {
+            return;
+        }
+
+        self.progress_bars[0].println("\nMatches by country:");
+        for (country, count) in self.country_matches().into_iter().take(TOP_GEO) {
+            self.progress_bars[0].println(format!(
+                "  {:>7} {:>10}",
+                country.as_deref().unwrap_or("unknown"),
+                count.to_string().green()
+            ));
+        }
+        self.progress_bars[0].println("\nMatches by AS:");
+        for (asn, name, count) in self.asn_matches().into_iter().take(TOP_GEO) {
+            self.progress_bars[0].println(format!(
+                "  {:>10} {:>10} {}",
+                asn.map(|asn| format!("AS{}", asn))
+                    .unwrap_or_else(|| "unknown".to_string()),
+                count.to_string().green(),
+                name.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+
+    // Estimated timeouts of the slowest networks, printed at the end of the scan in debug mode
+    fn print_network_timeouts(&self) {
+        if !self.debug || self.network_timeouts.is_empty() {
+            return;
+        }
+
         self.progress_bars[0].println(format!(
-            "[{}][{}] service: {} version: {} description: {}",
-            "MATCH".green(),
-            format_host(&dr.target).green(),
-            dr.service.green(),
-            dr.version.green(),
-            dr.description.green()
+            "\nEstimated timeouts ({} networks, slowest):",
+            self.network_timeouts.len()
         ));
+        for (network, timeout) in self.slowest_networks() {
+            self.progress_bars[0].println(format!(
+                "  {:>20} {:>7}ms",
+                network,
+                timeout.to_string().cyan()
+            ));
+        }
     }
 
     pub fn finish(&mut self) {
@@ -343,11 +1116,22 @@ impl Stats {
                 self.targets, self.max_targets
             ));
         }
+        if self.json_logs {
+            println!("{}", self.json_stats());
+        } else {
+            self.update_messages();
+            self.print_response_times();
+            self.print_latencies();
+            self.print_request_timings();
+            self.print_geo_matches();
+            self.print_network_timeouts();
+        }
         self.progress_bars[0].finish();
         self.progress_bars[1].finish();
         self.progress_bars[2].finish();
         self.progress_bars[3].finish();
         self.progress_bars[4].finish();
         self.progress_bars[5].finish();
+        self.progress_bars[6].finish();
     }
 }
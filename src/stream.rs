@@ -0,0 +1,83 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead},
+    net::Ipv4Addr,
+    thread,
+};
+
+use serde_derive::Deserialize;
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::{
+    convert,
+    worker::{self, ReqTarget},
+};
+
+// Targets read from stdin and not taken by the worker yet, the reader blocks (and so the
+// upstream scanner) when the buffer is full
+const STREAM_BUFFER: usize = 1000;
+
+// A target with its tags, e.g. from an asset inventory
+#[derive(Deserialize)]
+struct TaggedTarget {
+    ip: String,
+    #[serde(default)]
+    domain: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+// A target of the stream as (domain, ip, tags): a masscan -oL line ("open tcp 80 1.2.3.4
+// 1620000000"), an ip (e.g. zmap), "domain ip" or a JSON line ({"ip": "1.2.3.4", "domain":
+// "example.com", "tags": ["prod"]}). The other lines (comments, closed ports, headers) are skipped
+pub fn parse_stream_line(line: &str) -> Option<(String, String, Vec<String>)> {
+    if line.trim_start().starts_with('{') {
+        let target: TaggedTarget = serde_json::from_str(line).ok()?;
+        let ip = target.ip.parse::<Ipv4Addr>().ok()?;
+        return Some((
+            target.domain,
+            ip.to_string(),
+            worker::clean_tags(target.tags),
+        ));
+    }
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        ["open", _, _, ip, ..] => ip
+            .parse::<Ipv4Addr>()
+            .ok()
+            .map(|ip| (String::new(), ip.to_string(), Vec::new())),
+        _ => match convert::parse_host_line(line) {
+            Some(Ok(record)) => Some((record.name, record.value, Vec::new())),
+            _ => None,
+        },
+    }
+}
+
+// Reads the targets from stdin as they arrive, until it's closed. Every host is sent once (e.g.
+// masscan writes a line for each open port)
+pub fn stdin_targets() -> Receiver<ReqTarget> {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    thread::spawn(move || {
+        let mut seen = HashSet::new();
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let (name, ip, tags) = match parse_stream_line(&line) {
+                Some(host) => host,
+                None => continue,
+            };
+            if !seen.insert((name.clone(), ip.clone())) {
+                continue;
+            }
+            let mut target = ReqTarget::new(name, ip);
+            target.tags = tags;
+            if tx.blocking_send(target).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,55 @@
+use regex::{Captures, Regex};
+
+use crate::worker::ReqTarget;
+
+// Max length of the {rand_hex:N} values
+const MAX_RAND_HEX: usize = 64;
+
+// Replaces the known placeholders of a path or payload, the other braces are kept as they are
+// (e.g. the JSON payloads)
+fn expand_with(text: &str, value: impl Fn(&str, usize) -> String) -> String {
+    if !text.contains('{') {
+        return text.to_string();
+    }
+
+    let placeholder_re = Regex::new(r"\{(ip|domain|host|port|rand_hex:([0-9]+))\}").unwrap();
+    placeholder_re
+        .replace_all(text, |caps: &Captures| {
+            let len = caps
+                .get(2)
+                .and_then(|len| len.as_str().parse().ok())
+                .unwrap_or(0usize)
+                .min(MAX_RAND_HEX);
+            match caps.get(2) {
+                Some(_) => value("rand_hex", len),
+                None => value(&caps[1], 0),
+            }
+        })
+        .to_string()
+}
+
+// Placeholders of the definitions paths and payloads, expanded for each target before sending:
+// {ip}, {domain} (empty for the ips), {host} (the domain, or the ip), {port} and {rand_hex:N}
+// (N random hex chars, e.g. the canaries)
+pub fn expand(text: &str, target: &ReqTarget) -> String {
+    expand_with(text, |name, len| match name {
+        "ip" => target.ip.clone(),
+        "domain" => target.domain.clone(),
+        "host" if target.domain.is_empty() => target.ip.clone(),
+        "host" => target.domain.clone(),
+        "port" => target.port.to_string(),
+        _ => (0..len)
+            .map(|_| format!("{:x}", rand::random::<u8>() % 16))
+            .collect(),
+    })
+}
+
+// Expanded with sample values, to validate the definitions
+pub fn expand_sample(text: &str) -> String {
+    expand_with(text, |name, len| match name {
+        "ip" => "127.0.0.1".to_string(),
+        "domain" | "host" => "example.com".to_string(),
+        "port" => "80".to_string(),
+        _ => "0".repeat(len),
+    })
+}
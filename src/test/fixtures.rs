@@ -0,0 +1,165 @@
+use std::{
+    future::{self, Ready},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+    Uri,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    net::{Stream, Transport},
+    plugins::BoxFuture,
+};
+
+// What the mocked peer does: the response bytes, then the end of the stream, no answer at all
+// (the reads never complete, e.g. to test the timeouts) or a read error
+#[derive(Clone)]
+pub enum MockEnd {
+    Close,
+    Hang,
+    Error(io::ErrorKind),
+}
+
+// In-memory connection: the written bytes are recorded, the reads return the scripted response
+// once something has been written (like a peer answering the requests)
+pub struct MockStream {
+    response: Vec<u8>,
+    end: MockEnd,
+    written: Arc<Mutex<Vec<u8>>>,
+    requested: bool,
+    reader: Option<Waker>,
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.requested {
+            self.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if !self.response.is_empty() {
+            let n = self.response.len().min(buf.remaining());
+            buf.put_slice(&self.response[..n]);
+            self.response.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        match self.end {
+            MockEnd::Close => Poll::Ready(Ok(())),
+            MockEnd::Hang => Poll::Pending,
+            MockEnd::Error(kind) => Poll::Ready(Err(io::Error::from(kind))),
+        }
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        self.requested = true;
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for MockStream {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::from(([127, 0, 0, 1], 40000)))
+    }
+}
+
+impl Connection for MockStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+// Every connection gets the same scripted peer, or the connection error. The bytes written to
+// all the connections are recorded
+#[derive(Clone)]
+pub struct MockTransport {
+    response: Vec<u8>,
+    end: MockEnd,
+    connect_error: Option<io::ErrorKind>,
+    pub written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockTransport {
+    pub fn new(response: &[u8], end: MockEnd) -> Self {
+        MockTransport {
+            response: response.to_vec(),
+            end,
+            connect_error: None,
+            written: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn refused() -> Self {
+        MockTransport {
+            response: Vec::new(),
+            end: MockEnd::Close,
+            connect_error: Some(io::ErrorKind::ConnectionRefused),
+            written: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn stream(&self) -> io::Result<MockStream> {
+        match self.connect_error {
+            Some(kind) => Err(io::Error::from(kind)),
+            None => Ok(MockStream {
+                response: self.response.clone(),
+                end: self.end.clone(),
+                written: self.written.clone(),
+                requested: false,
+                reader: None,
+            }),
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    fn connect<'a>(&'a self, _addr: &'a SocketAddr) -> BoxFuture<'a, io::Result<Box<dyn Stream>>> {
+        let stream = self
+            .stream()
+            .map(|stream| Box::new(stream) as Box<dyn Stream>);
+        Box::pin(async move { stream })
+    }
+}
+
+// The same scripted peers as a hyper connector, for the http/s requests
+impl Service<Uri> for MockTransport {
+    type Response = MockStream;
+    type Error = io::Error;
+    type Future = Ready<io::Result<MockStream>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        future::ready(self.stream())
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::conf::Conf;
+
+// Spans of the workers, of the network and of the db layer (default filter), overridden by
+// RUST_LOG (e.g. RUST_LOG=lachesis::db=debug)
+const DEFAULT_FILTER: &str = "lachesis=debug";
+
+// The trace file, opened once and shared by all the events: each event is written whole, and a
+// failed write is dropped (the scan goes on)
+#[derive(Clone)]
+struct TraceWriter(Arc<Mutex<File>>);
+
+impl TraceWriter {
+    fn file(&self) -> io::Result<std::sync::MutexGuard<'_, File>> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Trace file poisoned"))
+    }
+}
+
+impl Write for TraceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file()?.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file()?.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file()?.flush()
+    }
+}
+
+// Writes the spans to the trace file (--trace) as they close, with their busy and idle times:
+// e.g. the long idle times of maybe_wait_for_permit tell the requests are starved by the
+// concurrency limits, the ones of the db spans a slow db
+pub fn init(conf: &Conf) -> Result<(), String> {
+    let path = match &conf.trace {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    let writer = TraceWriter(Arc::new(Mutex::new(file)));
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(move || writer.clone())
+        .try_init()
+        .map_err(|e| e.to_string())
+}
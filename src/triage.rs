@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+
+use regex::Regex;
+
+use crate::detector::DetectorResponse;
+
+// Severities of the definitions (service.severity), from the lowest
+pub const SEVERITIES: [&str; 5] = ["info", "low", "medium", "high", "critical"];
+pub const DEFAULT_SEVERITY: &str = "info";
+// Workflow states of the findings of the triage queue
+pub const STATES: [&str; 4] = ["new", "accepted", "false_positive", "resolved"];
+
+fn severity_weight(severity: &str) -> f32 {
+    match severity {
+        "low" => 2.0,
+        "medium" => 4.0,
+        "high" => 7.0,
+        "critical" => 10.0,
+        _ => 1.0,
+    }
+}
+
+// Reachable from the internet (not private, loopback, link-local, CGNAT, documentation...)
+pub fn is_routable(ip: &str) -> bool {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || first == 0x2001 && ip.segments()[1] == 0xdb8)
+        }
+        Err(_) => false,
+    }
+}
+
+// An admin page (by the service name or the page title) answering without asking credentials
+fn is_open_admin_panel(res: &DetectorResponse) -> bool {
+    let re = Regex::new(r"(?i)\b(admin|administration|dashboard|console|manager|control panel)\b")
+        .unwrap();
+    let title = res
+        .attributes
+        .iter()
+        .find(|(name, _)| name == "title")
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default();
+    let auth = res
+        .target
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("www-authenticate"));
+    res.target.protocol.starts_with("http")
+        && matches!(res.target.status, Some(status) if (200..300).contains(&status))
+        && !auth
+        && (re.is_match(&res.service) || re.is_match(title))
+}
+
+// Priority of a finding: severity × confidence × exposure (internet-routable ip, admin page
+// without authentication, default credentials accepted)
+pub fn score(res: &DetectorResponse) -> f32 {
+    let mut exposure = if is_routable(&res.target.ip) {
+        1.0
+    } else {
+        0.5
+    };
+    if is_open_admin_panel(res) {
+        exposure *= 1.5;
+    }
+    if res
+        .attributes
+        .iter()
+        .any(|(name, _)| name == "default_credentials")
+    {
+        exposure *= 2.0;
+    }
+    let score = severity_weight(&res.severity) * res.confidence * exposure;
+    (score * 100.0).round() / 100.0
+}
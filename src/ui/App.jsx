@@ -1,27 +1,112 @@
 'use strict'
 
-import React, { useState } from 'react'
+import React, { useState, useEffect } from 'react'
 import ReactDOM from 'react-dom'
-import { Container, Tab } from 'semantic-ui-react'
+import { Container, Tab, Dropdown, Grid } from 'semantic-ui-react'
+import { apiFetch } from './api'
 import Header from './components/Header'
 import DataTable from './components/DataTable'
+import HostView from './components/HostView'
+import ContentGroups from './components/ContentGroups'
+import SavedSearches from './components/SavedSearches'
+import TriageQueue from './components/TriageQueue'
+import UnknownServices from './components/UnknownServices'
 import Footer from './components/Footer'
 import 'semantic-ui-css/semantic.min.css'
 import './style/app.scss'
 
-const panes = [
-  {
-    menuItem: 'Records',
-    render: () => <Tab.Pane attached={false}><DataTable /></Tab.Pane>
-  },
-  {
-    menuItem: 'Map',
-    render: () => <Tab.Pane attached={false}>TODO</Tab.Pane>
-  }
-]
-
 function App () {
   const [active, setActive] = useState('Records')
+  const [host, setHost] = useState(null)
+  // Content group (body hash) the records are filtered by
+  const [group, setGroup] = useState(null)
+  // Projects accessible by the user, one at a time
+  const [projects, setProjects] = useState([])
+  const [project, setProject] = useState('default')
+  // Filters of the records (query string), kept in the url to share them
+  const [query, setQuery] = useState(window.location.hash.substring(1))
+  // Incremented to reload the records with a saved search
+  const [loaded, setLoaded] = useState(0)
+
+  async function getProjects () {
+    try {
+      const res = await apiFetch('api/projects').then((res) => res.json())
+      setProjects(res)
+      if (res.length && !res.includes(project)) {
+        setProject(res[0])
+      }
+    } catch (ex) { /* Intentionally left blank */ }
+  }
+
+  function selectHost (ip) {
+    setHost(ip)
+    setActive('Host')
+  }
+
+  function selectGroup (bodyHash) {
+    setGroup(bodyHash)
+    setActive('Records')
+  }
+
+  function changeQuery (newQuery) {
+    setQuery(newQuery)
+    window.history.replaceState(null, '', window.location.pathname + (newQuery ? `#${newQuery}` : ''))
+  }
+
+  function selectSearch (newQuery) {
+    changeQuery(newQuery)
+    setLoaded(loaded + 1)
+    setActive('Records')
+  }
+
+  function selectProject (name) {
+    setProject(name)
+    setHost(null)
+    setGroup(null)
+    setActive('Records')
+  }
+
+  useEffect(() => {
+    getProjects()
+  }, [])
+
+  const panes = [
+    {
+      menuItem: 'Records',
+      render: () => (
+        <Tab.Pane attached={false}>
+          <Grid>
+            <Grid.Column width={3}>
+              <SavedSearches project={project} query={query} onSelect={selectSearch} />
+            </Grid.Column>
+            <Grid.Column width={13}>
+              <DataTable key={`${project}-${group}-${loaded}`} project={project} bodyHash={group} query={query} onSelectHost={selectHost} onClearGroup={() => setGroup(null)} onQueryChange={changeQuery} />
+            </Grid.Column>
+          </Grid>
+        </Tab.Pane>
+      )
+    },
+    {
+      menuItem: 'Host',
+      render: () => <Tab.Pane attached={false}><HostView project={project} ip={host} onSelectHost={selectHost} /></Tab.Pane>
+    },
+    {
+      menuItem: 'Triage',
+      render: () => <Tab.Pane attached={false}><TriageQueue project={project} onSelectHost={selectHost} /></Tab.Pane>
+    },
+    {
+      menuItem: 'Groups',
+      render: () => <Tab.Pane attached={false}><ContentGroups project={project} onSelectGroup={selectGroup} /></Tab.Pane>
+    },
+    {
+      menuItem: 'Unknown',
+      render: () => <Tab.Pane attached={false}><UnknownServices project={project} onSelectHost={selectHost} /></Tab.Pane>
+    },
+    {
+      menuItem: 'Map',
+      render: () => <Tab.Pane attached={false}>TODO</Tab.Pane>
+    }
+  ]
 
   function handleTabChange (e, el) {
     setActive(panes[el.activeIndex].menuItem)
@@ -31,9 +116,18 @@ function App () {
     <div className='app'>
       <Container>
         <Header title='Lachesis UI' />
+        <Dropdown
+          className='project'
+          placeholder='Project'
+          selection
+          value={project}
+          options={projects.map((name) => ({ text: name, value: name }))}
+          onChange={(e, { value }) => selectProject(value)}
+        />
         <Tab
           menu={{ secondary: true, pointing: true }}
           panes={panes}
+          activeIndex={panes.findIndex((pane) => pane.menuItem === active)}
           onTabChange={handleTabChange}
           className={active === 'Records' ? 'nopadding' : ''}
         />
@@ -0,0 +1,17 @@
+/* global fetch, window, URLSearchParams */
+
+// Token of the user, given once in the url (?token=<token>) and sent with every API request
+const params = new URLSearchParams(window.location.search)
+if (params.has('token')) {
+  window.localStorage.setItem('token', params.get('token'))
+  window.history.replaceState(null, '', window.location.pathname + window.location.hash)
+}
+
+export function apiFetch (path, options = {}) {
+  const token = window.localStorage.getItem('token')
+  const headers = { ...(options.headers || {}) }
+  if (token !== null) {
+    headers.Authorization = `Bearer ${token}`
+  }
+  return fetch(path, { ...options, headers })
+}
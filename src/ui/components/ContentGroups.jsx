@@ -0,0 +1,91 @@
+import React, { useState, useEffect } from 'react'
+import {
+  Segment,
+  Dimmer,
+  Loader,
+  Label,
+  Table,
+  Button
+} from 'semantic-ui-react'
+import { timestampToDateString } from './DataTable'
+import { apiFetch } from '../api'
+import '../style/content-groups.scss'
+
+// Groups of the records with the same content (e.g. the same router login page on thousands of
+// hosts), to triage them once
+function ContentGroups ({ project, onSelectGroup }) {
+  const [loading, setLoading] = useState(true)
+  const [groups, setGroups] = useState(null)
+
+  async function getGroups () {
+    setLoading(true)
+
+    let res = null
+    try {
+      res = await apiFetch(`api/services/groups?project=${encodeURIComponent(project)}`)
+        .then((res) => res.ok ? res.json() : null)
+    } catch (ex) { /* Intentionally left blank */ }
+
+    setGroups(res)
+    setLoading(false)
+  }
+
+  useEffect(() => {
+    getGroups()
+  }, [project])
+
+  if (loading) {
+    return (
+      <div className='content-groups'>
+        <Segment>
+          <Dimmer active inverted>
+            <Loader size='massive' />
+          </Dimmer>
+        </Segment>
+      </div>
+    )
+  }
+
+  if (groups === null) {
+    return <p>Fetch error</p>
+  }
+
+  if (!groups.length) {
+    return <p>No records with the same content</p>
+  }
+
+  return (
+    <div className='content-groups'>
+      <Table celled compact>
+        <Table.Header>
+          <Table.Row>
+            <Table.HeaderCell>title</Table.HeaderCell>
+            <Table.HeaderCell>services</Table.HeaderCell>
+            <Table.HeaderCell>records</Table.HeaderCell>
+            <Table.HeaderCell>hosts</Table.HeaderCell>
+            <Table.HeaderCell>first seen</Table.HeaderCell>
+            <Table.HeaderCell>last seen</Table.HeaderCell>
+            <Table.HeaderCell />
+          </Table.Row>
+        </Table.Header>
+        <Table.Body>
+          {groups.map((group) => (
+            <Table.Row key={group.body_hash}>
+              <Table.Cell title={group.body_hash}>{group.title}</Table.Cell>
+              <Table.Cell>{group.names.map((name) => <Label key={name}>{name}</Label>)}</Table.Cell>
+              <Table.Cell>{group.services}</Table.Cell>
+              <Table.Cell>{group.hosts}</Table.Cell>
+              <Table.Cell>{timestampToDateString(group.first_seen)}</Table.Cell>
+              <Table.Cell>{timestampToDateString(group.last_seen)}</Table.Cell>
+              <Table.Cell collapsing>
+                <Button size='small' onClick={(e) => onSelectGroup(group.body_hash)}>Show records</Button>
+              </Table.Cell>
+            </Table.Row>
+          ))}
+        </Table.Body>
+      </Table>
+    </div>
+  )
+}
+
+export default ContentGroups
@@ -10,13 +10,14 @@ import {
   Pagination,
   Grid,
   Modal,
-  Dropdown
+  Dropdown,
+  Input,
+  Icon
 } from 'semantic-ui-react'
 import { v4 as uuid } from 'uuid'
+import { apiFetch } from '../api'
 import '../style/data-table.scss'
 
-/* global fetch */
-
 const rowsPerPageOptions = [
   {
     text: 25,
@@ -36,7 +37,7 @@ const rowsPerPageOptions = [
   }
 ]
 
-function timestampToDateString (timestamp) {
+export function timestampToDateString (timestamp) {
   const date = new Date(timestamp)
 
   return String(date.getDate()).padStart(2, '0') +
@@ -47,7 +48,29 @@ function timestampToDateString (timestamp) {
     ':' + String(date.getSeconds()).padStart(2, '0')
 }
 
-function DataTable () {
+// Filters of the records from/to a query string (e.g. country=IT&search=nginx), the one of the
+// saved searches and of the url of the UI
+export function parseFilterQuery (query) {
+  const params = new URLSearchParams(query)
+  return {
+    country: params.get('country'),
+    asn: params.has('asn') ? parseInt(params.get('asn')) : null,
+    search: params.get('search'),
+    tag: params.get('tag')
+  }
+}
+
+function filterQuery (filter) {
+  const params = new URLSearchParams()
+  for (const [key, value] of Object.entries(filter)) {
+    if (value !== null) {
+      params.set(key, value)
+    }
+  }
+  return params.toString()
+}
+
+function DataTable ({ project, bodyHash, query, onSelectHost, onClearGroup, onQueryChange }) {
   const [loading, setLoading] = useState(true)
   const [pagination, setPagination] = useState({
     page: 1,
@@ -56,12 +79,38 @@ function DataTable () {
     rows: 50
   })
   const [data, setData] = useState(null)
+  // Cursors of the pages (next_cursor of the previous page), they don't shift when new records
+  // are saved during the browsing
+  const [cursors, setCursors] = useState({})
   const [selection, setSelection] = useState({})
   const [deleteModal, setDeleteModal] = useState(false)
+  const [filter, setFilter] = useState(() => parseFilterQuery(query))
+  // Text of the search input, applied on submit
+  const [search, setSearch] = useState(filter.search || '')
+  const [geo, setGeo] = useState({ countries: [], asns: [] })
+
+  async function getGeo () {
+    try {
+      const res = await apiFetch(`api/services/geo?project=${encodeURIComponent(project)}`)
+        .then((res) => res.json())
+      // Services by country and AS, the unknown ones (no geo-IP/ASN databases) can't be filtered
+      setGeo({
+        countries: res.countries
+          .filter(([country]) => country !== null)
+          .map(([country, count]) => ({ text: `${country} (${count})`, value: country })),
+        asns: res.asns
+          .filter(([asn]) => asn !== null)
+          .map(([asn, name, count]) => ({ text: `AS${asn} ${name || ''} (${count})`, value: asn }))
+      })
+    } catch (ex) { /* Intentionally left blank */ }
+  }
 
   async function getData (page) {
     const newPagination = { ...pagination }
-    if (page > newPagination.page) {
+    if (page === 1) {
+      newPagination.offset = 0
+      newPagination.page = 1
+    } else if (page > newPagination.page) {
       while (page > newPagination.page) {
         newPagination.offset += newPagination.rows
         newPagination.page += 1
@@ -73,11 +122,34 @@ function DataTable () {
       }
     }
 
+    // The pages not reached from the previous one (e.g. jumps) fall back to the offset
+    const cursor = page === 1 ? undefined : cursors[newPagination.page]
+    let query = `project=${encodeURIComponent(project)}&rows=${newPagination.rows}`
+    if (cursor !== undefined) {
+      query += `&cursor=${encodeURIComponent(cursor)}`
+    } else {
+      query += `&offset=${newPagination.offset}`
+    }
+    if (filter.country !== null) {
+      query += `&country=${encodeURIComponent(filter.country)}`
+    }
+    if (filter.asn !== null) {
+      query += `&asn=${filter.asn}`
+    }
+    if (filter.search !== null) {
+      query += `&search=${encodeURIComponent(filter.search)}`
+    }
+    if (filter.tag !== null) {
+      query += `&tag=${encodeURIComponent(filter.tag)}`
+    }
+    // Records of a content group
+    if (bodyHash !== null) {
+      query += `&body_hash=${encodeURIComponent(bodyHash)}`
+    }
+
     let res = null
     try {
-      res = await fetch(
-        `api/services?offset=${newPagination.offset}&rows=${newPagination.rows}`
-      ).then((res) => res.json())
+      res = await apiFetch(`api/services?${query}`).then((res) => res.json())
     } catch (ex) { /* Intentionally left blank */ }
 
     setLoading(false)
@@ -93,6 +165,12 @@ function DataTable () {
         })
       })
 
+      const newCursors = page === 1 ? {} : { ...cursors }
+      if (res.next_cursor !== null) {
+        newCursors[newPagination.page + 1] = res.next_cursor
+      }
+      setCursors(newCursors)
+
       if (res.rows_count > newPagination.rows) {
         newPagination.of = res.rows_count / newPagination.rows
         if (newPagination.of % 1 !== 0) {
@@ -136,7 +214,7 @@ function DataTable () {
 
     let res = null
     try {
-      res = await fetch('api/services',
+      res = await apiFetch(`api/services?project=${encodeURIComponent(project)}`,
         {
           method: 'DELETE',
           headers: {
@@ -174,8 +252,17 @@ function DataTable () {
   }
 
   useEffect(() => {
-    getData(pagination.page)
-  }, [pagination.rows])
+    getGeo()
+  }, [])
+
+  // Back to the first page when the rows per page or the filters change
+  useEffect(() => {
+    getData(1)
+  }, [pagination.rows, filter])
+
+  useEffect(() => {
+    onQueryChange(filterQuery(filter))
+  }, [filter])
 
   if (loading) {
     return (
@@ -195,6 +282,46 @@ function DataTable () {
 
   return (
     <div className='data-table'>
+      <div className='filters'>
+        <Dropdown
+          placeholder='Country'
+          clearable
+          search
+          selection
+          value={filter.country}
+          options={geo.countries}
+          onChange={(e, { value }) => setFilter({ ...filter, country: value || null })}
+        />
+        <Dropdown
+          placeholder='AS'
+          clearable
+          search
+          selection
+          value={filter.asn}
+          options={geo.asns}
+          onChange={(e, { value }) => setFilter({ ...filter, asn: value || null })}
+        />
+        <form onSubmit={(e) => { e.preventDefault(); setFilter({ ...filter, search: search.trim() || null }) }}>
+          <Input
+            placeholder='Search (service, version, ip, domain, title...)'
+            value={search}
+            onChange={(e, { value }) => setSearch(value)}
+            action={{ icon: 'search', type: 'submit' }}
+          />
+        </form>
+        {bodyHash !== null && (
+          <Label className='group'>
+            Same content: {bodyHash.substring(0, 12)}
+            <Icon name='delete' onClick={(e) => onClearGroup()} />
+          </Label>
+        )}
+        {filter.tag !== null && (
+          <Label className='group'>
+            Tag: {filter.tag}
+            <Icon name='delete' onClick={(e) => setFilter({ ...filter, tag: null })} />
+          </Label>
+        )}
+      </div>
       <Table celled>
         <Table.Header>
           <Table.Row>
@@ -213,7 +340,25 @@ function DataTable () {
                 return data.rows.map((fields) => {
                   const cells = []
                   for (const field in fields) {
-                    cells.push(<Table.Cell key={uuid()}><Label>{fields[field]}</Label></Table.Cell>)
+                    // The ips open the host view
+                    if (data.headers[field] === 'ip') {
+                      cells.push(
+                        <Table.Cell key={uuid()}>
+                          <Label as='a' onClick={(e) => onSelectHost(fields[field])}>{fields[field]}</Label>
+                        </Table.Cell>
+                      )
+                    } else if (data.headers[field] === 'tags') {
+                      // The tags filter the records
+                      cells.push(
+                        <Table.Cell key={uuid()}>
+                          {fields[field].map((tag) => (
+                            <Label as='a' key={tag} onClick={(e) => setFilter({ ...filter, tag })}>{tag}</Label>
+                          ))}
+                        </Table.Cell>
+                      )
+                    } else {
+                      cells.push(<Table.Cell key={uuid()}><Label>{fields[field]}</Label></Table.Cell>)
+                    }
                   }
                   return (
                     <Table.Row key={fields[0]}>
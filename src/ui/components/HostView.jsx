@@ -0,0 +1,200 @@
+import React, { useState, useEffect } from 'react'
+import {
+  Segment,
+  Dimmer,
+  Loader,
+  Label,
+  Table,
+  Header,
+  List,
+  Button
+} from 'semantic-ui-react'
+import { timestampToDateString } from './DataTable'
+import ResponseView from './ResponseView'
+import { apiFetch } from '../api'
+import '../style/host-view.scss'
+
+function History ({ item }) {
+  return (
+    <>
+      <Table.Cell>{timestampToDateString(item.first_seen)}</Table.Cell>
+      <Table.Cell>{timestampToDateString(item.last_seen)}</Table.Cell>
+      <Table.Cell>{item.seen_count}</Table.Cell>
+    </>
+  )
+}
+
+function HostView ({ project, ip, onSelectHost }) {
+  const [loading, setLoading] = useState(true)
+  const [host, setHost] = useState(null)
+  // Service of the response shown
+  const [responseService, setResponseService] = useState(null)
+
+  async function getHost () {
+    setLoading(true)
+
+    let res = null
+    try {
+      res = await apiFetch(`api/hosts/${encodeURIComponent(ip)}?project=${encodeURIComponent(project)}`)
+        .then((res) => res.ok ? res.json() : null)
+    } catch (ex) { /* Intentionally left blank */ }
+
+    setHost(res)
+    setLoading(false)
+  }
+
+  useEffect(() => {
+    if (ip !== null) {
+      getHost()
+    }
+  }, [ip, project])
+
+  if (ip === null) {
+    return <p>Select an ip in the records table</p>
+  }
+
+  if (loading) {
+    return (
+      <div className='host-view'>
+        <Segment>
+          <Dimmer active inverted>
+            <Loader size='massive' />
+          </Dimmer>
+        </Segment>
+      </div>
+    )
+  }
+
+  if (host === null) {
+    return <p>Host {ip} not found</p>
+  }
+
+  return (
+    <div className='host-view'>
+      <Header as='h2'>
+        {host.ip}
+        <Header.Subheader>
+          {host.country !== null ? host.country : 'Unknown country'}
+          {host.asn !== null ? ` - AS${host.asn} ${host.as_name || ''}` : ''}
+        </Header.Subheader>
+      </Header>
+
+      <Table celled compact definition>
+        <Table.Body>
+          <Table.Row>
+            <Table.Cell collapsing>First seen</Table.Cell>
+            <Table.Cell>{timestampToDateString(host.first_seen)}</Table.Cell>
+          </Table.Row>
+          <Table.Row>
+            <Table.Cell>Last seen</Table.Cell>
+            <Table.Cell>{timestampToDateString(host.last_seen)} ({host.seen_count} times)</Table.Cell>
+          </Table.Row>
+          <Table.Row>
+            <Table.Cell>Services ports</Table.Cell>
+            <Table.Cell>{host.ports.map((port) => <Label key={port}>{port}</Label>)}</Table.Cell>
+          </Table.Row>
+          <Table.Row>
+            <Table.Cell>Open ports</Table.Cell>
+            <Table.Cell>
+              {host.open_ports.map((port) => (
+                <Label key={port} basic={host.ports.includes(port)}>
+                  {port}
+                  {host.port_names[port] !== undefined && <Label.Detail>{host.port_names[port]}?</Label.Detail>}
+                  {!host.ports.includes(port) && <Label.Detail>no fingerprint</Label.Detail>}
+                </Label>
+              ))}
+              {host.last_portscan !== null ? ` (port scan: ${timestampToDateString(host.last_portscan)})` : 'Not port scanned'}
+            </Table.Cell>
+          </Table.Row>
+        </Table.Body>
+      </Table>
+
+      <Header as='h3'>Services</Header>
+      <Table celled compact>
+        <Table.Header>
+          <Table.Row>
+            <Table.HeaderCell>port</Table.HeaderCell>
+            <Table.HeaderCell>service</Table.HeaderCell>
+            <Table.HeaderCell>version</Table.HeaderCell>
+            <Table.HeaderCell>protocol</Table.HeaderCell>
+            <Table.HeaderCell>domain</Table.HeaderCell>
+            <Table.HeaderCell>attributes</Table.HeaderCell>
+            <Table.HeaderCell>headers</Table.HeaderCell>
+            <Table.HeaderCell>response</Table.HeaderCell>
+            <Table.HeaderCell>first seen</Table.HeaderCell>
+            <Table.HeaderCell>last seen</Table.HeaderCell>
+            <Table.HeaderCell>seen</Table.HeaderCell>
+          </Table.Row>
+        </Table.Header>
+        <Table.Body>
+          {host.services.map((service) => (
+            <Table.Row key={service.id}>
+              <Table.Cell>{service.port}</Table.Cell>
+              <Table.Cell title={service.description}>
+                {service.service}
+                {!service.active && (
+                  <Label size='mini' title={`Not seen since ${timestampToDateString(service.inactive_since)}`}>inactive</Label>
+                )}
+              </Table.Cell>
+              <Table.Cell>{service.version}</Table.Cell>
+              <Table.Cell>{service.protocol}</Table.Cell>
+              <Table.Cell>{service.domain}</Table.Cell>
+              <Table.Cell>
+                <List>
+                  {service.attributes.map(([name, value]) => (
+                    <List.Item key={name}><b>{name}</b>: {value}</List.Item>
+                  ))}
+                </List>
+              </Table.Cell>
+              <Table.Cell>
+                <List>
+                  {service.headers.map(([name, value]) => (
+                    <List.Item key={name}><b>{name}</b>: {value}</List.Item>
+                  ))}
+                </List>
+              </Table.Cell>
+              <Table.Cell>
+                {(service.has_response || service.has_request) && (
+                  <Button size='small' onClick={(e) => setResponseService(service)}>View</Button>
+                )}
+              </Table.Cell>
+              <History item={service} />
+            </Table.Row>
+          ))}
+        </Table.Body>
+      </Table>
+      {responseService !== null && (
+        <ResponseView project={project} service={responseService} onClose={() => setResponseService(null)} />
+      )}
+
+      <Header as='h3'>Domains</Header>
+      <Table celled compact>
+        <Table.Header>
+          <Table.Row>
+            <Table.HeaderCell>domain</Table.HeaderCell>
+            <Table.HeaderCell>other ips</Table.HeaderCell>
+            <Table.HeaderCell>first seen</Table.HeaderCell>
+            <Table.HeaderCell>last seen</Table.HeaderCell>
+            <Table.HeaderCell>seen</Table.HeaderCell>
+          </Table.Row>
+        </Table.Header>
+        <Table.Body>
+          {host.domains.map((domain) => (
+            <Table.Row key={domain.domain}>
+              <Table.Cell>{domain.domain}</Table.Cell>
+              <Table.Cell>
+                {/* Pivot to the other ips sharing the domain */}
+                {domain.other_ips.map((other) => (
+                  <Label as='a' key={other} onClick={(e) => onSelectHost(other)}>{other}</Label>
+                ))}
+              </Table.Cell>
+              <History item={domain} />
+            </Table.Row>
+          ))}
+        </Table.Body>
+      </Table>
+    </div>
+  )
+}
+
+export default HostView
@@ -0,0 +1,152 @@
+import React, { useState, useEffect } from 'react'
+import {
+  Modal,
+  Button,
+  Segment,
+  Dimmer,
+  Loader,
+  Label,
+  Header
+} from 'semantic-ui-react'
+import { timestampToDateString } from './DataTable'
+import { apiFetch } from '../api'
+import '../style/response-view.scss'
+
+// Text split by the matches of the regex, the matches wrapped in spans of the class returned by
+// classOf (plain text if none)
+function tokenize (text, regex, classOf) {
+  const tokens = []
+  let last = 0
+  for (const match of text.matchAll(regex)) {
+    if (match.index > last) {
+      tokens.push(text.slice(last, match.index))
+    }
+    const className = classOf(match)
+    tokens.push(className ? <span key={match.index} className={className}>{match[0]}</span> : match[0])
+    last = match.index + match[0].length
+  }
+  tokens.push(text.slice(last))
+  return tokens
+}
+
+function highlightJson (body) {
+  let text = body
+  try {
+    text = JSON.stringify(JSON.parse(body), null, 2)
+  } catch (ex) { /* Invalid or truncated, shown as it is */ }
+  return tokenize(
+    text,
+    /"(?:\\.|[^"\\])*"(\s*:)?|\b(?:true|false|null)\b|-?\d+(?:\.\d+)?(?:[eE][+-]?\d+)?/g,
+    (match) => match[0].startsWith('"') ? (match[1] ? 'key' : 'string') : 'literal'
+  )
+}
+
+function highlightMarkup (body) {
+  return tokenize(
+    body,
+    /<!--[\s\S]*?-->|<\/?[\w:-]+|\/?>|"[^"<>]*"|'[^'<>]*'/g,
+    (match) => {
+      if (match[0].startsWith('<!--')) return 'comment'
+      if (match[0].startsWith('<') || match[0].endsWith('>')) return 'tag'
+      return 'string'
+    }
+  )
+}
+
+function highlightBody (body, contentType) {
+  const type = (contentType || '').toLowerCase()
+  const start = body.trimStart()
+  if (type.includes('json') || (!type && (start.startsWith('{') || start.startsWith('[')))) {
+    return highlightJson(body)
+  }
+  if (type.includes('html') || type.includes('xml') || (!type && start.startsWith('<'))) {
+    return highlightMarkup(body)
+  }
+  return body
+}
+
+// Status line and headers, the names highlighted
+function highlightHead (head) {
+  return head.split(/\r?\n/).map((line, i) => {
+    const colon = line.indexOf(':')
+    if (i === 0 || colon === -1) {
+      return <div key={i} className='status'>{line}</div>
+    }
+    return (
+      <div key={i}>
+        <span className='key'>{line.slice(0, colon)}</span>{line.slice(colon)}
+      </div>
+    )
+  })
+}
+
+// Last request sent to a service and its raw response (--store-responses)
+function ResponseView ({ project, service, onClose }) {
+  const [loading, setLoading] = useState(true)
+  const [response, setResponse] = useState(null)
+  const [request, setRequest] = useState(null)
+
+  async function get (what) {
+    try {
+      return await apiFetch(`api/services/${service.id}/${what}?project=${encodeURIComponent(project)}`)
+        .then((res) => res.ok ? res.json() : null)
+    } catch (ex) {
+      return null
+    }
+  }
+
+  async function getResponse () {
+    setLoading(true)
+    const [res, req] = await Promise.all([get('response'), get('request')])
+    setResponse(res)
+    setRequest(req)
+    setLoading(false)
+  }
+
+  useEffect(() => {
+    getResponse()
+  }, [service.id, project])
+
+  return (
+    <Modal open size='large' onClose={onClose} className='response-view'>
+      <Modal.Header>
+        {service.service} - port {service.port}
+        {response !== null && response.truncated && <Label color='orange'>truncated</Label>}
+      </Modal.Header>
+      <Modal.Content scrolling>
+        {loading && (
+          <Segment>
+            <Dimmer active inverted>
+              <Loader />
+            </Dimmer>
+          </Segment>
+        )}
+        {!loading && request !== null && (
+          <>
+            <Header as='h4'>Request</Header>
+            <pre className='head'>{request.request}</pre>
+          </>
+        )}
+        {!loading && response === null && <p>No response saved for this service</p>}
+        {!loading && response !== null && (
+          <>
+            <p>Saved {timestampToDateString(response.time)}</p>
+            {response.head !== '' && (
+              <>
+                <Header as='h4'>Headers</Header>
+                <pre className='head'>{highlightHead(response.head)}</pre>
+              </>
+            )}
+            <Header as='h4'>Body</Header>
+            <pre className='body'>{highlightBody(response.body, response.content_type)}</pre>
+          </>
+        )}
+      </Modal.Content>
+      <Modal.Actions>
+        <Button onClick={onClose}>Close</Button>
+      </Modal.Actions>
+    </Modal>
+  )
+}
+
+export default ResponseView
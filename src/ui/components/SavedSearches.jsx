@@ -0,0 +1,81 @@
+import React, { useState, useEffect } from 'react'
+import { Menu, Input, Icon } from 'semantic-ui-react'
+import { apiFetch } from '../api'
+import '../style/saved-searches.scss'
+
+// Named filters of the records of the project, saved in the DB and selected from the sidebar
+function SavedSearches ({ project, query, onSelect }) {
+  const [searches, setSearches] = useState([])
+  // Name of the search to save, the current filters
+  const [name, setName] = useState('')
+
+  async function getSearches () {
+    try {
+      const res = await apiFetch(`api/searches?project=${encodeURIComponent(project)}`)
+        .then((res) => res.ok ? res.json() : [])
+      setSearches(res)
+    } catch (ex) { /* Intentionally left blank */ }
+  }
+
+  async function saveSearch () {
+    if (!name.trim()) {
+      return
+    }
+    try {
+      await apiFetch(`api/searches?project=${encodeURIComponent(project)}`, {
+        method: 'POST',
+        headers: {
+          Accept: 'application/json',
+          'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ name: name.trim(), query })
+      })
+    } catch (ex) { /* Intentionally left blank */ }
+    setName('')
+    getSearches()
+  }
+
+  async function deleteSearch (id) {
+    try {
+      await apiFetch(`api/searches/${id}?project=${encodeURIComponent(project)}`, { method: 'DELETE' })
+    } catch (ex) { /* Intentionally left blank */ }
+    getSearches()
+  }
+
+  useEffect(() => {
+    getSearches()
+  }, [project])
+
+  return (
+    <Menu vertical fluid className='saved-searches'>
+      <Menu.Item header>Saved searches</Menu.Item>
+      {searches.map((search) => (
+        <Menu.Item
+          key={search.id}
+          active={search.query === query}
+          title={search.query}
+          onClick={(e) => onSelect(search.query)}
+        >
+          <Icon
+            name='delete'
+            onClick={(e) => { e.stopPropagation(); deleteSearch(search.id) }}
+          />
+          {search.name}
+        </Menu.Item>
+      ))}
+      <Menu.Item>
+        <form onSubmit={(e) => { e.preventDefault(); saveSearch() }}>
+          <Input
+            size='small'
+            placeholder='Save the filters as...'
+            value={name}
+            onChange={(e, { value }) => setName(value)}
+            action={{ icon: 'save', type: 'submit' }}
+          />
+        </form>
+      </Menu.Item>
+    </Menu>
+  )
+}
+
+export default SavedSearches
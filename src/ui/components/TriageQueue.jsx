@@ -0,0 +1,156 @@
+import React, { useState, useEffect } from 'react'
+import {
+  Segment,
+  Dimmer,
+  Loader,
+  Label,
+  Table,
+  Button,
+  Dropdown,
+  Pagination
+} from 'semantic-ui-react'
+import { timestampToDateString } from './DataTable'
+import { apiFetch } from '../api'
+import '../style/triage-queue.scss'
+
+const rows = 50
+
+const states = [
+  { text: 'New', value: 'new' },
+  { text: 'Accepted', value: 'accepted' },
+  { text: 'False positive', value: 'false_positive' },
+  { text: 'Resolved', value: 'resolved' }
+]
+
+const severityColors = {
+  critical: 'red',
+  high: 'orange',
+  medium: 'yellow',
+  low: 'olive'
+}
+
+// Findings by priority (severity × confidence × exposure), triaged one by one
+function TriageQueue ({ project, onSelectHost }) {
+  const [loading, setLoading] = useState(true)
+  const [queue, setQueue] = useState(null)
+  const [state, setState] = useState('new')
+  const [page, setPage] = useState(1)
+
+  async function getQueue () {
+    setLoading(true)
+
+    let query = `project=${encodeURIComponent(project)}&rows=${rows}&offset=${(page - 1) * rows}`
+    if (state !== null) {
+      query += `&state=${state}`
+    }
+    let res = null
+    try {
+      res = await apiFetch(`api/triage?${query}`).then((res) => res.ok ? res.json() : null)
+    } catch (ex) { /* Intentionally left blank */ }
+
+    setQueue(res)
+    setLoading(false)
+  }
+
+  async function updateState (id, newState) {
+    try {
+      await apiFetch(`api/services/${id}/triage?project=${encodeURIComponent(project)}`, {
+        method: 'PUT',
+        headers: {
+          Accept: 'application/json',
+          'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ state: newState })
+      })
+    } catch (ex) { /* Intentionally left blank */ }
+    getQueue()
+  }
+
+  useEffect(() => {
+    getQueue()
+  }, [project, state, page])
+
+  if (loading) {
+    return (
+      <div className='triage-queue'>
+        <Segment>
+          <Dimmer active inverted>
+            <Loader size='massive' />
+          </Dimmer>
+        </Segment>
+      </div>
+    )
+  }
+
+  if (queue === null) {
+    return <p>Fetch error</p>
+  }
+
+  return (
+    <div className='triage-queue'>
+      <Dropdown
+        placeholder='State'
+        clearable
+        selection
+        value={state}
+        options={states}
+        onChange={(e, { value }) => { setState(value || null); setPage(1) }}
+      />
+      <Table celled compact>
+        <Table.Header>
+          <Table.Row>
+            <Table.HeaderCell>score</Table.HeaderCell>
+            <Table.HeaderCell>severity</Table.HeaderCell>
+            <Table.HeaderCell>service</Table.HeaderCell>
+            <Table.HeaderCell>host</Table.HeaderCell>
+            <Table.HeaderCell>port</Table.HeaderCell>
+            <Table.HeaderCell>last seen</Table.HeaderCell>
+            <Table.HeaderCell>state</Table.HeaderCell>
+            <Table.HeaderCell />
+          </Table.Row>
+        </Table.Header>
+        <Table.Body>
+          {queue.findings.map((finding) => (
+            <Table.Row key={finding.id}>
+              <Table.Cell>{finding.score}</Table.Cell>
+              <Table.Cell>
+                <Label color={severityColors[finding.severity]}>{finding.severity || 'info'}</Label>
+              </Table.Cell>
+              <Table.Cell title={finding.description}>
+                {finding.service} {finding.version}
+              </Table.Cell>
+              <Table.Cell>
+                <Label as='a' onClick={(e) => onSelectHost(finding.ip)}>{finding.ip}</Label>
+                {finding.domain}
+              </Table.Cell>
+              <Table.Cell>{finding.port}</Table.Cell>
+              <Table.Cell>{timestampToDateString(finding.last_seen)}</Table.Cell>
+              <Table.Cell>{finding.state}</Table.Cell>
+              <Table.Cell collapsing>
+                <Button.Group size='mini'>
+                  {states
+                    .filter((s) => s.value !== finding.state)
+                    .map((s) => (
+                      <Button key={s.value} onClick={(e) => updateState(finding.id, s.value)}>
+                        {s.text}
+                      </Button>
+                    ))}
+                </Button.Group>
+              </Table.Cell>
+            </Table.Row>
+          ))}
+        </Table.Body>
+      </Table>
+      {queue.rows_count > rows && (
+        <Pagination
+          size='tiny'
+          activePage={page}
+          totalPages={Math.ceil(queue.rows_count / rows)}
+          onPageChange={(e, { activePage }) => setPage(activePage)}
+        />
+      )}
+    </div>
+  )
+}
+
+export default TriageQueue
@@ -0,0 +1,90 @@
+import React, { useState, useEffect } from 'react'
+import {
+  Segment,
+  Dimmer,
+  Loader,
+  Label,
+  Table
+} from 'semantic-ui-react'
+import { apiFetch } from '../api'
+import '../style/unknown-services.scss'
+
+// Clusters of the responses matching no definition (--collect-unknown), biggest first: the
+// candidates for new definitions
+function UnknownServices ({ project, onSelectHost }) {
+  const [loading, setLoading] = useState(true)
+  const [clusters, setClusters] = useState(null)
+
+  async function getClusters () {
+    setLoading(true)
+
+    let res = null
+    try {
+      res = await apiFetch(`api/unknown?project=${encodeURIComponent(project)}`)
+        .then((res) => res.ok ? res.json() : null)
+    } catch (ex) { /* Intentionally left blank */ }
+
+    setClusters(res)
+    setLoading(false)
+  }
+
+  useEffect(() => {
+    getClusters()
+  }, [project])
+
+  if (loading) {
+    return (
+      <div className='unknown-services'>
+        <Segment>
+          <Dimmer active inverted>
+            <Loader size='massive' />
+          </Dimmer>
+        </Segment>
+      </div>
+    )
+  }
+
+  if (clusters === null) {
+    return <p>Fetch error</p>
+  }
+
+  if (!clusters.length) {
+    return <p>No unknown responses (scan with --collect-unknown)</p>
+  }
+
+  return (
+    <div className='unknown-services'>
+      <Table celled compact>
+        <Table.Header>
+          <Table.Row>
+            <Table.HeaderCell>responses</Table.HeaderCell>
+            <Table.HeaderCell>hosts</Table.HeaderCell>
+            <Table.HeaderCell>ports</Table.HeaderCell>
+            <Table.HeaderCell>protocols</Table.HeaderCell>
+            <Table.HeaderCell>samples</Table.HeaderCell>
+          </Table.Row>
+        </Table.Header>
+        <Table.Body>
+          {clusters.map((cluster, i) => (
+            <Table.Row key={i} verticalAlign='top'>
+              <Table.Cell>{cluster.responses}</Table.Cell>
+              <Table.Cell>{cluster.hosts}</Table.Cell>
+              <Table.Cell>{cluster.ports.join(', ')}</Table.Cell>
+              <Table.Cell>{cluster.protocols.map((protocol) => <Label key={protocol}>{protocol}</Label>)}</Table.Cell>
+              <Table.Cell>
+                {cluster.samples.map((sample) => (
+                  <div key={`${sample.ip}:${sample.port}:${sample.protocol}`}>
+                    <Label as='a' onClick={(e) => onSelectHost(sample.ip)}>{sample.ip}:{sample.port}</Label>
+                    <pre>{sample.banner}</pre>
+                  </div>
+                ))}
+              </Table.Cell>
+            </Table.Row>
+          ))}
+        </Table.Body>
+      </Table>
+    </div>
+  )
+}
+
+export default UnknownServices
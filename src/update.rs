@@ -0,0 +1,129 @@
+use std::{env, fs};
+
+use semver::Version;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{net, signing};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/ps1dr3x/lachesis/releases/latest";
+const RELEASES_TIMEOUT: u64 = 30;
+const DOWNLOAD_TIMEOUT: u64 = 300;
+// Public key of the releases signatures (Ed25519 of the binary, <asset>.sig), compiled in
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("LACHESIS_RELEASE_PUBLIC_KEY");
+
+#[derive(Debug, Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<GithubAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+// A release newer than the running binary
+#[derive(Debug)]
+pub struct Release {
+    pub version: Version,
+    // Binary for this platform, with its checksum and signature
+    binary_url: Option<String>,
+    sha256_url: Option<String>,
+    sig_url: Option<String>,
+}
+
+// Binary of the releases for this platform (e.g. lachesis-x86_64-linux)
+pub fn asset_name() -> String {
+    format!("lachesis-{}-{}", env::consts::ARCH, env::consts::OS)
+}
+
+// Version of a release tag (e.g. v0.4.0), if newer than the current one
+pub fn newer_version(tag: &str, current: &str) -> Option<Version> {
+    let version = Version::parse(tag.trim_start_matches('v')).ok()?;
+    match Version::parse(current) {
+        Ok(current) if version > current => Some(version),
+        _ => None,
+    }
+}
+
+// The latest release, if newer than the running binary
+pub async fn check() -> Result<Option<Release>, String> {
+    let body = net::fetch(RELEASES_URL, RELEASES_TIMEOUT)
+        .await
+        .map_err(|e| format!("Unable to fetch the latest release: {}", e))?;
+    let release: GithubRelease =
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid release: {}", e))?;
+
+    let version = match newer_version(&release.tag_name, env!("CARGO_PKG_VERSION")) {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let asset_url = |name: String| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.clone())
+    };
+    Ok(Some(Release {
+        version,
+        binary_url: asset_url(asset_name()),
+        sha256_url: asset_url(format!("{}.sha256", asset_name())),
+        sig_url: asset_url(format!("{}.sig", asset_name())),
+    }))
+}
+
+async fn fetch_text(url: &Option<String>, what: &str) -> Result<String, String> {
+    let url = url
+        .as_ref()
+        .ok_or_else(|| format!("The release has no {} for {}", what, asset_name()))?;
+    let body = net::fetch(url, RELEASES_TIMEOUT)
+        .await
+        .map_err(|e| format!("Unable to fetch the {}: {}", what, e))?;
+    // The checksum files may also have the file name (sha256sum format)
+    match String::from_utf8_lossy(&body).split_whitespace().next() {
+        Some(text) => Ok(text.to_string()),
+        None => Err(format!("Empty {} of the release", what)),
+    }
+}
+
+// Replaces the running binary with the one of the release, once its checksum and signature are
+// verified
+pub async fn install(release: &Release) -> Result<(), String> {
+    let key = RELEASE_PUBLIC_KEY.ok_or_else(|| {
+        "This binary was built without the releases public key (LACHESIS_RELEASE_PUBLIC_KEY)"
+            .to_string()
+    })?;
+    let key = signing::parse_public_key(key)?;
+    let binary_url = release
+        .binary_url
+        .as_ref()
+        .ok_or_else(|| format!("The release has no binary for {}", asset_name()))?;
+
+    let sha256 = fetch_text(&release.sha256_url, "checksum").await?;
+    let signature = fetch_text(&release.sig_url, "signature").await?;
+    let binary = net::fetch(binary_url, DOWNLOAD_TIMEOUT)
+        .await
+        .map_err(|e| format!("Unable to download the binary: {}", e))?;
+    if !hex::encode(Sha256::digest(&binary)).eq_ignore_ascii_case(&sha256) {
+        return Err("Invalid binary (checksum mismatch)".to_string());
+    }
+    if !signing::verify(&[key], &binary, &signature) {
+        return Err("Invalid binary signature".to_string());
+    }
+
+    // Written next to the current binary and renamed over it, the running process is not
+    // affected
+    let exe = env::current_exe().map_err(|e| format!("Unable to find the binary: {}", e))?;
+    let new_exe = exe.with_extension("new");
+    fs::write(&new_exe, &binary).map_err(|e| format!("Unable to write the binary: {}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new_exe, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Unable to set the binary permissions: {}", e))?;
+    }
+    fs::rename(&new_exe, &exe).map_err(|e| format!("Unable to replace the binary: {}", e))
+}
@@ -1,16 +1,35 @@
 use hyper::Uri;
 use regex::Regex;
-use semver::Version;
+use semver::VersionReq;
 use validator::ValidationError;
 
-use crate::conf::{Definition, RegexVersion};
+use crate::{
+    conf::{Definition, Indicator, JsonCondition, RangeVersion, RegexVersion},
+    detector::{self, parse_json_path, parse_version},
+    plugins::Registry,
+    template, triage,
+};
 
-pub fn validate_protocol(protocol: &str) -> Result<(), ValidationError> {
-    match protocol {
-        "http/s" | "tcp/custom" => Ok(()),
-        _ => Err(ValidationError::new(
-            "Invalid protocol. Available options: 'http/s', 'tcp/custom'",
-        )),
+// Port knocking sequences (tcp/custom): max ports and max delay between the knocks (ms)
+const MAX_KNOCKS: usize = 16;
+const MAX_KNOCK_DELAY: u64 = 10_000;
+// Default credentials of a definition, a short list by design
+const MAX_CREDENTIALS: usize = 10;
+// Timeouts of a definition (ms)
+const MAX_TIMEOUT: u64 = 300_000;
+
+// Any protocol with a probe of the registry
+pub fn validate_protocol(protocol: &str, registry: &Registry) -> Result<(), ValidationError> {
+    if registry
+        .probes()
+        .iter()
+        .any(|probe| probe.handles(protocol))
+    {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "Invalid protocol. Available options: the protocols of the registered probes (e.g. 'http/s', 'tcp/custom')",
+        ))
     }
 }
 
@@ -23,13 +42,26 @@ pub fn validate_method(method: &str) -> Result<(), ValidationError> {
     }
 }
 
+// With the placeholders (e.g. {ip}) expanded
 pub fn validate_path(path: &str) -> Result<(), ValidationError> {
-    match path.parse::<Uri>() {
+    match template::expand_sample(path).parse::<Uri>() {
         Ok(_) => Ok(()),
         Err(_e) => Err(ValidationError::new("Invalid path")),
     }
 }
 
+pub fn validate_paths(paths: &[String]) -> Result<(), ValidationError> {
+    if paths.is_empty() {
+        return Err(ValidationError::new(
+            "Option field 'paths' can't be an empty list",
+        ));
+    }
+    for path in paths {
+        validate_path(path)?;
+    }
+    Ok(())
+}
+
 pub fn validate_regex(regex: &str) -> Result<(), ValidationError> {
     match Regex::new(regex) {
         Ok(_re) => Ok(()),
@@ -40,42 +72,403 @@ pub fn validate_regex(regex: &str) -> Result<(), ValidationError> {
 pub fn validate_regex_ver(rv: &[RegexVersion]) -> Result<(), ValidationError> {
     for re in rv {
         validate_regex(&re.regex)?;
+        if let Some(source) = &re.source {
+            validate_source(source)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_source(source: &str) -> Result<(), ValidationError> {
+    match source {
+        "response" | "headers" | "body" => Ok(()),
+        _ if source.starts_with("header:") && source.len() > "header:".len() => Ok(()),
+        _ => Err(ValidationError::new(
+            "Invalid source. Available options: 'response', 'headers', 'body', 'header:<name>'",
+        )),
+    }
+}
+
+pub fn validate_severity(severity: &str) -> Result<(), ValidationError> {
+    if triage::SEVERITIES.contains(&severity) {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "Invalid severity. Available options: 'info', 'low', 'medium', 'high', 'critical'",
+        ))
+    }
+}
+
+pub fn validate_indicator(indicator: &Indicator) -> Result<(), ValidationError> {
+    if indicator.regex.is_none()
+        && indicator.ports.is_none()
+        && indicator.status.is_none()
+        && indicator.favicon_hash.is_none()
+        && indicator.tls_cn.is_none()
+    {
+        return Err(ValidationError::new(
+            "An indicator needs at least one of the fields 'regex', 'ports', 'status', 'favicon_hash', 'tls_cn'",
+        ));
     }
+
+    if matches!(&indicator.favicon_hash, Some(hashes) if hashes.is_empty()) {
+        return Err(ValidationError::new(
+            "Indicator field 'favicon_hash' must be a list of hashes",
+        ));
+    }
+
+    if indicator.source.is_some() && indicator.regex.is_none() {
+        return Err(ValidationError::new(
+            "Indicator field 'source' can't be used without 'regex'",
+        ));
+    }
+
     Ok(())
 }
 
+pub fn validate_json_path(path: &str) -> Result<(), ValidationError> {
+    match parse_json_path(path) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new(
+            "Invalid JSON path. Supported syntax: $.key, $['key'], $[index] (e.g. $.data[0].version)",
+        )),
+    }
+}
+
+pub fn validate_json_condition(cond: &JsonCondition) -> Result<(), ValidationError> {
+    match (cond.op.as_str(), &cond.value) {
+        ("exists", None) => Ok(()),
+        ("exists", Some(_)) => Err(ValidationError::new(
+            "JSON condition 'exists' doesn't take a value",
+        )),
+        ("eq", Some(_)) | ("ne", Some(_)) => Ok(()),
+        ("gt", Some(v)) | ("lt", Some(v)) if v.is_number() => Ok(()),
+        ("regex", Some(serde_json::Value::String(re))) => validate_regex(re),
+        ("eq", None) | ("ne", None) | ("gt", _) | ("lt", _) | ("regex", _) => {
+            Err(ValidationError::new(
+                "Missing or invalid JSON condition value ('gt', 'lt' need a number, 'regex' a string)",
+            ))
+        }
+        _ => Err(ValidationError::new(
+            "Invalid JSON condition op. Available options: 'exists', 'eq', 'ne', 'gt', 'lt', 'regex'",
+        )),
+    }
+}
+
+fn definition_sources(def: &Definition) -> Vec<&String> {
+    let mut sources = Vec::new();
+
+    if let Some(source) = &def.service.source {
+        sources.push(source);
+    }
+
+    if let Some(indicators) = &def.service.indicators {
+        for indicator in indicators {
+            if let Some(source) = &indicator.source {
+                sources.push(source);
+            }
+        }
+    }
+
+    if let Some(extractors) = &def.extractors {
+        for extractor in extractors {
+            if let Some(source) = &extractor.source {
+                sources.push(source);
+            }
+        }
+    }
+
+    if let Some(versions) = &def.versions {
+        if let Some(source) = versions.semver.as_ref().and_then(|s| s.source.as_ref()) {
+            sources.push(source);
+        }
+        if let Some(regex) = &versions.regex {
+            for rv in regex {
+                if let Some(source) = &rv.source {
+                    sources.push(source);
+                }
+            }
+        }
+    }
+
+    sources
+}
+
 pub fn validate_semver(semver: &str) -> Result<(), ValidationError> {
-    match Version::parse(&semver) {
+    match parse_version(semver) {
+        Some(_) => Ok(()),
+        None => Err(ValidationError::new("Invalid semver")),
+    }
+}
+
+pub fn validate_version_req(req: &str) -> Result<(), ValidationError> {
+    match VersionReq::parse(req) {
         Ok(_) => Ok(()),
-        Err(_e) => Err(ValidationError::new("Invalid semver")),
+        Err(_e) => Err(ValidationError::new("Invalid version range")),
+    }
+}
+
+pub fn validate_range_version(rv: &RangeVersion) -> Result<(), ValidationError> {
+    if rv.range.is_some() && (rv.from.is_some() || rv.to.is_some()) {
+        return Err(ValidationError::new(
+            "Range field 'range' can't be used together with 'from' and 'to'",
+        ));
     }
+
+    if rv.range.is_none() && rv.from.is_none() && rv.to.is_none() {
+        return Err(ValidationError::new(
+            "Missing range, at least one of the fields 'range', 'from', 'to' is needed",
+        ));
+    }
+
+    Ok(())
 }
 
 pub fn validate_definition(def: &Definition) -> Result<(), ValidationError> {
-    if def.protocol.as_str() == "tcp/custom" {
-        if def.options.payload.is_none() {
+    if let Some(indicators) = &def.service.indicators {
+        if !indicators.is_empty() && indicators.iter().map(|i| i.weight).sum::<f32>() <= 0.0 {
+            return Err(ValidationError::new(
+                "The total weight of the indicators must be greater than 0",
+            ));
+        }
+        let indicator = |check: fn(&Indicator) -> bool| indicators.iter().any(check);
+        if indicator(|i| i.favicon_hash.is_some()) && !detector::is_http(&def.protocol) {
+            return Err(ValidationError::new(
+                "Indicator field 'favicon_hash' can only be used with the http/s protocols",
+            ));
+        }
+        if indicator(|i| i.tls_cn.is_some())
+            && !matches!(def.protocol.as_str(), "http/s" | "https" | "tls/custom")
+        {
             return Err(ValidationError::new(
-                "Missing mandatory option field 'payload' for protocol 'tcp/custom'",
+                "Indicator field 'tls_cn' can only be used with protocols 'http/s', 'https' and 'tls/custom'",
             ));
         }
+    }
+
+    if !detector::is_custom(&def.protocol) && def.options.payloads.is_some() {
+        return Err(ValidationError::new(
+            "Option field 'payloads' can only be used with protocols 'tcp/custom' and 'tls/custom'",
+        ));
+    }
+
+    if (def.options.sni.is_some() || def.options.alpn.is_some())
+        && def.protocol.as_str() != "tls/custom"
+    {
+        return Err(ValidationError::new(
+            "Option fields 'sni' and 'alpn' can only be used with protocol 'tls/custom'",
+        ));
+    }
 
-        if def.options.method.is_some() || def.options.path.is_some() {
+    if let Some(alpn) = &def.options.alpn {
+        if alpn.is_empty() || alpn.iter().any(|p| p.is_empty() || p.len() > 255) {
             return Err(ValidationError::new(
-                "Option fields 'method' and 'path' can't be used with protocol 'tcp/custom'",
+                "Option field 'alpn' must be a list of protocols (1 to 255 bytes each)",
             ));
         }
     }
 
-    if def.protocol.as_str() == "http/s" {
-        if def.options.method.is_none() {
+    if def.options.head_regex.is_some()
+        && (!detector::is_http(&def.protocol) || def.options.method.as_deref() != Some("GET"))
+    {
+        return Err(ValidationError::new(
+            "Option field 'head_regex' can only be used with the GET requests of the http/s protocols",
+        ));
+    }
+
+    if def.options.user_agent.is_some() && !detector::is_http(&def.protocol) {
+        return Err(ValidationError::new(
+            "Option field 'user_agent' can only be used with the http/s protocols",
+        ));
+    }
+
+    if def.options.knock.is_some() && def.protocol.as_str() != "tcp/custom" {
+        return Err(ValidationError::new(
+            "Option field 'knock' can only be used with protocol 'tcp/custom'",
+        ));
+    }
+
+    if let Some(knock) = &def.options.knock {
+        if knock.is_empty() || knock.len() > MAX_KNOCKS || knock.contains(&0) {
             return Err(ValidationError::new(
-                "Missing mandatory option field 'method' for protocol 'http/s'",
+                "Option field 'knock' must be a list of 1 to 16 ports (not 0)",
             ));
         }
+    }
 
+    match def.options.knock_delay {
+        Some(_) if def.options.knock.is_none() => {
+            return Err(ValidationError::new(
+                "Option field 'knock_delay' can only be used with 'knock'",
+            ));
+        }
+        Some(delay) if delay == 0 || delay > MAX_KNOCK_DELAY => {
+            return Err(ValidationError::new(
+                "Option field 'knock_delay' must be between 1 and 10000 (milliseconds)",
+            ));
+        }
+        _ => (),
+    }
+
+    if let Some(credentials) = &def.options.credentials {
+        if !detector::is_http(&def.protocol) {
+            return Err(ValidationError::new(
+                "Option field 'credentials' can only be used with the http/s protocols",
+            ));
+        }
+        if credentials.is_empty() || credentials.len() > MAX_CREDENTIALS {
+            return Err(ValidationError::new(
+                "Option field 'credentials' must be a list of 1 to 10 [user, password] pairs",
+            ));
+        }
+        if credentials.iter().any(|(user, _)| user.contains(':')) {
+            return Err(ValidationError::new(
+                "Option field 'credentials': the users can't contain ':'",
+            ));
+        }
+        if def.options.paths.is_some() {
+            return Err(ValidationError::new(
+                "Option fields 'credentials' and 'paths' can't be used together",
+            ));
+        }
+    }
+
+    match def.options.proxy_protocol.as_deref() {
+        Some(_) if def.protocol.as_str() != "tcp/custom" => {
+            return Err(ValidationError::new(
+                "Option field 'proxy_protocol' can only be used with protocol 'tcp/custom'",
+            ));
+        }
+        Some("v1") | Some("v2") | None => (),
+        Some(_) => {
+            return Err(ValidationError::new(
+                "Option field 'proxy_protocol' must be 'v1' or 'v2'",
+            ));
+        }
+    }
+
+    let timeouts = [
+        def.options.connect_timeout,
+        def.options.tls_timeout,
+        def.options.first_byte_timeout,
+        def.options.total_timeout,
+    ];
+    if timeouts.iter().any(Option::is_some)
+        && !detector::is_http(&def.protocol)
+        && !detector::is_custom(&def.protocol)
+        && !detector::is_websocket(&def.protocol)
+    {
+        return Err(ValidationError::new(
+            "Option fields '*_timeout' can only be used with the http/s, ws/s, tcp/custom and tls/custom protocols",
+        ));
+    }
+    if def.options.tls_timeout.is_some()
+        && !detector::is_http(&def.protocol)
+        && !detector::is_websocket(&def.protocol)
+        && def.protocol.as_str() != "tls/custom"
+    {
+        return Err(ValidationError::new(
+            "Option field 'tls_timeout' can only be used with the http/s, ws/s and tls/custom protocols",
+        ));
+    }
+    if timeouts
+        .iter()
+        .flatten()
+        .any(|timeout| *timeout == 0 || *timeout > MAX_TIMEOUT)
+    {
+        return Err(ValidationError::new(
+            "Option fields '*_timeout' must be between 1 and 300000 (milliseconds)",
+        ));
+    }
+
+    if detector::is_custom(&def.protocol) {
+        if def.options.payload.is_none() && def.options.payloads.is_none() {
+            return Err(ValidationError::new(
+                "Missing mandatory option field 'payload' (or 'payloads') for protocols 'tcp/custom' and 'tls/custom'",
+            ));
+        }
+
+        if def.options.payload.is_some() && def.options.payloads.is_some() {
+            return Err(ValidationError::new(
+                "Option fields 'payload' and 'payloads' can't be used together",
+            ));
+        }
+
+        if def.options.payloads.iter().any(|p| p.is_empty()) {
+            return Err(ValidationError::new(
+                "Option field 'payloads' can't be an empty list",
+            ));
+        }
+
+        if def.options.method.is_some() || def.options.path.is_some() || def.options.paths.is_some()
+        {
+            return Err(ValidationError::new(
+                "Option fields 'method', 'path' and 'paths' can't be used with protocols 'tcp/custom' and 'tls/custom'",
+            ));
+        }
+
+        if def.service.status.is_some()
+            || def.service.exclude_status.is_some()
+            || def
+                .service
+                .indicators
+                .iter()
+                .flatten()
+                .any(|i| i.status.is_some())
+        {
+            return Err(ValidationError::new(
+                "Status conditions ('status', 'exclude_status') can't be used with protocols 'tcp/custom' and 'tls/custom'",
+            ));
+        }
+
+        if definition_sources(def)
+            .iter()
+            .any(|s| *s == "headers" || s.starts_with("header:"))
+        {
+            return Err(ValidationError::new(
+                "Sources 'headers' and 'header:<name>' can't be used with protocols 'tcp/custom' and 'tls/custom'",
+            ));
+        }
+    }
+
+    if let (Some(min_length), Some(max_length)) = (def.service.min_length, def.service.max_length) {
+        if min_length > max_length {
+            return Err(ValidationError::new(
+                "Service field 'min_length' can't be greater than 'max_length'",
+            ));
+        }
+    }
+
+    if detector::is_websocket(&def.protocol) {
         if def.options.path.is_none() {
             return Err(ValidationError::new(
-                "Missing mandatory option field 'path' for protocol 'http/s'",
+                "Missing mandatory option field 'path' for protocols 'ws/s', 'ws' and 'wss'",
+            ));
+        }
+
+        if def.options.method.is_some() || def.options.paths.is_some() {
+            return Err(ValidationError::new(
+                "Option fields 'method' and 'paths' can't be used with protocols 'ws/s', 'ws' and 'wss'",
+            ));
+        }
+    }
+
+    if detector::is_http(&def.protocol) {
+        if def.options.method.is_none() {
+            return Err(ValidationError::new(
+                "Missing mandatory option field 'method' for protocols 'http/s', 'http' and 'https'",
+            ));
+        }
+
+        if def.options.path.is_none() && def.options.paths.is_none() {
+            return Err(ValidationError::new(
+                "Missing mandatory option field 'path' (or 'paths') for protocols 'http/s', 'http' and 'https'",
+            ));
+        }
+
+        if def.options.path.is_some() && def.options.paths.is_some() {
+            return Err(ValidationError::new(
+                "Option fields 'path' and 'paths' can't be used together",
             ));
         }
 
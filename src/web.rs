@@ -1,20 +1,36 @@
 use colored::Colorize;
-use rocket::{self, fs::NamedFile, http::Status, serde::json::Json, Request, State};
+use rocket::{
+    self,
+    fs::NamedFile,
+    http::Status,
+    request::{self, FromRequest},
+    serde::json::Json,
+    Build, Request, Rocket, State,
+};
+use serde::Deserialize;
+use serde_json::Value;
 use tokio::sync::{mpsc::Sender, Mutex};
 
 use std::{
+    fmt,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use crate::{
-    conf,
-    db::{DbMan, PaginatedServices},
+    conf::{self, DbConf, Role, User, DEFAULT_PROJECT},
+    db::{
+        ContentGroup, DbMan, DomainIp, GeoAggregate, HostSummary, PaginatedDomains,
+        PaginatedServices, PaginatedTriage, SavedSearch, ScanStats, SentRequest, ServicesCursor,
+        ServicesFilter, SimilarService, StoredResponse, UnknownCluster,
+    },
+    har, triage,
 };
 
 struct Shared {
     db: DbMan,
     tx: Arc<Mutex<Sender<UIMessage>>>,
+    users: Vec<User>,
 }
 
 #[derive(Debug, Clone)]
@@ -22,48 +38,547 @@ pub struct UIMessage {
     pub message: String,
 }
 
+// Projects the requests can access, by the user token (Authorization: Bearer <token>). Without
+// users in the conf there is no authentication
+enum Access {
+    All,
+    User(User),
+    Anonymous,
+}
+
+impl Access {
+    fn check(&self, project: &str) -> Result<(), Status> {
+        match self {
+            Access::All => Ok(()),
+            Access::User(user) if user.can_access(project) => Ok(()),
+            Access::User(_) => Err(Status::Forbidden),
+            Access::Anonymous => Err(Status::Unauthorized),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Access {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let users = match req.rocket().state::<Shared>() {
+            Some(shared) => &shared.users,
+            None => return request::Outcome::Success(Access::Anonymous),
+        };
+        if users.is_empty() {
+            return request::Outcome::Success(Access::All);
+        }
+
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        let user = token.and_then(|token| users.iter().find(|u| tokens_match(&u.token, token)));
+        let access = match user {
+            Some(user) if user.role == Role::Admin => Access::All,
+            Some(user) => Access::User(user.clone()),
+            None => Access::Anonymous,
+        };
+        request::Outcome::Success(access)
+    }
+}
+
+// In a time depending on the lengths of the tokens only, not on their first different byte
+fn tokens_match(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn db_error(state: &State<Shared>, err: impl fmt::Display) -> Status {
+    let msg = UIMessage {
+        message: format!("[{}] Db query error: {}", "ERROR".red(), err),
+    };
+    let _ = state.tx.lock().await.send(msg).await;
+    Status::InternalServerError
+}
+
+// Id of the requested project (the default one if not given), when accessible
+async fn project_id(
+    state: &State<Shared>,
+    access: &Access,
+    project: Option<String>,
+) -> Result<i64, Status> {
+    let project = project.unwrap_or_else(|| DEFAULT_PROJECT.to_string());
+    if let Err(status) = access.check(&project) {
+        if let Access::User(user) = access {
+            let msg = UIMessage {
+                message: format!(
+                    "[{}] User {} denied access to project {}",
+                    "WARNING".yellow(),
+                    user.name,
+                    project
+                ),
+            };
+            let _ = state.tx.lock().await.send(msg).await;
+        }
+        return Err(status);
+    }
+    match state.db.get_project_id(&project).await {
+        Ok(Some(project_id)) => Ok(project_id),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Id of the requested project for the requests changing its findings, not allowed to the viewers
+async fn writable_project_id(
+    state: &State<Shared>,
+    access: &Access,
+    project: Option<String>,
+) -> Result<i64, Status> {
+    if let Access::User(user) = access {
+        if !user.can_write() {
+            let msg = UIMessage {
+                message: format!(
+                    "[{}] User {} denied changes to project {}",
+                    "WARNING".yellow(),
+                    user.name,
+                    project.as_deref().unwrap_or(DEFAULT_PROJECT)
+                ),
+            };
+            let _ = state.tx.lock().await.send(msg).await;
+            return Err(Status::Forbidden);
+        }
+    }
+    project_id(state, access, project).await
+}
+
 #[get("/")]
 async fn home() -> Option<NamedFile> {
-    NamedFile::open(Path::new("resources/ui/index.html"))
+    NamedFile::open(Path::new(&conf::resources_dir()).join("ui/index.html"))
         .await
         .ok()
 }
 
 #[get("/<file..>")]
 async fn static_files(file: PathBuf) -> Result<NamedFile, Status> {
-    let path = Path::new("resources/ui").join(file);
+    let path = Path::new(&conf::resources_dir()).join("ui").join(file);
     NamedFile::open(&path).await.map_err(|_| Status::NotFound)
 }
 
-#[get("/services?<offset>&<rows>")]
+// Liveness probe
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "OK"
+}
+
+// Readiness probe, ready when the Db is reachable
+#[get("/readyz")]
+async fn readyz(state: &State<Shared>) -> Result<&'static str, Status> {
+    match state.db.ping().await {
+        Ok(_) => Ok("OK"),
+        Err(_) => Err(Status::ServiceUnavailable),
+    }
+}
+
+// Projects accessible by the user
+#[get("/projects")]
+async fn projects(state: &State<Shared>, access: Access) -> Result<Json<Vec<String>>, Status> {
+    match state.db.get_projects().await {
+        Ok(projects) => Ok(Json(
+            projects
+                .into_iter()
+                .filter(|project| access.check(project).is_ok())
+                .collect(),
+        )),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Pages of services, by cursor (next_cursor of the previous page) or by offset
+#[get("/services?<project>&<offset>&<rows>&<cursor>&<country>&<asn>&<search>&<body_hash>&<tag>")]
+#[allow(clippy::too_many_arguments)]
 async fn services(
     state: &State<Shared>,
-    offset: i64,
+    access: Access,
+    project: Option<String>,
+    offset: Option<i64>,
     rows: i64,
+    cursor: Option<String>,
+    country: Option<String>,
+    asn: Option<i64>,
+    search: Option<String>,
+    body_hash: Option<String>,
+    tag: Option<String>,
 ) -> Result<Json<PaginatedServices>, Status> {
-    match state.db.get_paginated_services(offset, rows).await {
+    let project_id = project_id(state, &access, project).await?;
+    let cursor = match cursor.as_deref().map(ServicesCursor::parse) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(_)) => return Err(Status::BadRequest),
+        None => None,
+    };
+    let filter = ServicesFilter {
+        project_id,
+        country,
+        asn,
+        search: search.filter(|search| !search.trim().is_empty()),
+        body_hash,
+        tag: tag.filter(|tag| !tag.trim().is_empty()),
+    };
+    match state
+        .db
+        .get_paginated_services(offset.unwrap_or(0), rows, cursor.as_ref(), &filter)
+        .await
+    {
         Ok(ps) => Ok(Json(ps)),
-        Err(err) => {
-            let msg = UIMessage {
-                message: format!("[{}] Db query error: {}", "ERROR".red(), err),
-            };
-            state.tx.lock().await.send(msg).await.unwrap();
-            Err(Status::InternalServerError)
-        }
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Number of services by country and by AS
+#[get("/services/geo?<project>")]
+async fn services_geo(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+) -> Result<Json<GeoAggregate>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_geo_aggregate(project_id).await {
+        Ok(aggregate) => Ok(Json(aggregate)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Groups of the services with the same content (e.g. the same login page on many hosts)
+#[get("/services/groups?<project>&<limit>")]
+async fn services_groups(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<ContentGroup>>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state
+        .db
+        .get_content_groups(project_id, limit.unwrap_or(100))
+        .await
+    {
+        Ok(groups) => Ok(Json(groups)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Services with a content similar to the one of a service (simhash distance, 0-64 bits)
+#[get("/services/<id>/similar?<project>&<distance>&<limit>")]
+async fn services_similar(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+    distance: Option<i32>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<SimilarService>>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state
+        .db
+        .get_similar_services(project_id, id, distance.unwrap_or(3), limit.unwrap_or(100))
+        .await
+    {
+        Ok(similar) => Ok(Json(similar)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Clusters of the responses matching no definition (--collect-unknown), the biggest first
+#[get("/unknown?<project>&<distance>&<limit>")]
+async fn unknown(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    distance: Option<u32>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<UnknownCluster>>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state
+        .db
+        .get_unknown_clusters(project_id, distance.unwrap_or(3), limit.unwrap_or(100))
+        .await
+    {
+        Ok(clusters) => Ok(Json(clusters)),
+        Err(err) => Err(db_error(state, err).await),
     }
 }
 
-#[delete("/services", format = "application/json", data = "<ids>")]
-async fn del_services(state: &State<Shared>, ids: Json<Vec<i64>>) -> Result<&str, Status> {
-    match state.db.delete_services(ids.to_vec()).await {
+// Last raw response of a service (--store-responses)
+#[get("/services/<id>/response?<project>")]
+async fn service_response(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<StoredResponse>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_service_response(project_id, id).await {
+        Ok(Some(response)) => Ok(Json(response)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Last request sent to a service, to reproduce the finding
+#[get("/services/<id>/request?<project>")]
+async fn service_request(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<SentRequest>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_service_request(project_id, id).await {
+        Ok(Some(request)) => Ok(Json(request)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Ports, services, domains and history of an ip (host view)
+#[get("/hosts/<ip>?<project>")]
+async fn host(
+    state: &State<Shared>,
+    access: Access,
+    ip: String,
+    project: Option<String>,
+) -> Result<Json<HostSummary>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_host(project_id, &ip).await {
+        Ok(Some(host)) => Ok(Json(host)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Pages of domains with their number of ips and services, by cursor (next_cursor of the
+// previous page) or by offset
+#[get("/domains?<project>&<offset>&<rows>&<cursor>&<search>")]
+async fn domains(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    offset: Option<i64>,
+    rows: i64,
+    cursor: Option<String>,
+    search: Option<String>,
+) -> Result<Json<PaginatedDomains>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    let search = search.filter(|search| !search.trim().is_empty());
+    match state
+        .db
+        .get_paginated_domains(
+            project_id,
+            offset.unwrap_or(0),
+            rows,
+            cursor.as_deref(),
+            search.as_deref(),
+        )
+        .await
+    {
+        Ok(pd) => Ok(Json(pd)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Ips a domain resolved to, with their number of services for the domain
+#[get("/domains/<id>/ips?<project>")]
+async fn domain_ips(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<Vec<DomainIp>>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_domain_ips(project_id, id).await {
+        Ok(Some(ips)) => Ok(Json(ips)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Stats snapshots of a scan (the scan id is logged when the scan starts)
+#[get("/scans/<id>/stats?<project>")]
+async fn scan_stats(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<ScanStats>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_scan_stats(project_id, id).await {
+        Ok(Some(stats)) => Ok(Json(stats)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Matches of a scan by country and by AS (--geoip-db, --asn-db)
+#[get("/scans/<id>/geo?<project>")]
+async fn scan_geo(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<GeoAggregate>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_scan_geo_matches(project_id, id).await {
+        Ok(Some(geo)) => Ok(Json(geo)),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// HAR file of the http/s matches of a scan (--har)
+#[get("/scans/<id>/har?<project>")]
+async fn scan_har(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<Json<Value>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_har_entries(project_id, id).await {
+        Ok(Some(entries)) => Ok(Json(har::log(entries))),
+        Ok(None) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+#[delete("/services?<project>", format = "application/json", data = "<ids>")]
+async fn del_services(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    ids: Json<Vec<i64>>,
+) -> Result<&'static str, Status> {
+    let project_id = writable_project_id(state, &access, project).await?;
+    match state.db.delete_services(project_id, ids.to_vec()).await {
         Ok(_ss) => Ok("OK"),
-        Err(err) => {
-            let msg = UIMessage {
-                message: format!("[{}] Db query error: {}", "ERROR".red(), err),
-            };
-            state.tx.lock().await.send(msg).await.unwrap();
-            Err(Status::InternalServerError)
-        }
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Findings by priority (triage score), optionally in a workflow state
+// (the shared state is named differently here, state is the query parameter)
+#[get("/triage?<project>&<state>&<offset>&<rows>")]
+async fn triage_queue(
+    shared: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    state: Option<String>,
+    offset: Option<i64>,
+    rows: i64,
+) -> Result<Json<PaginatedTriage>, Status> {
+    let project_id = project_id(shared, &access, project).await?;
+    if matches!(&state, Some(state) if !triage::STATES.contains(&state.as_str())) {
+        return Err(Status::BadRequest);
+    }
+    match shared
+        .db
+        .get_triage_queue(project_id, state.as_deref(), offset.unwrap_or(0), rows)
+        .await
+    {
+        Ok(pt) => Ok(Json(pt)),
+        Err(err) => Err(db_error(shared, err).await),
+    }
+}
+
+#[derive(Deserialize)]
+struct TriageUpdate {
+    state: String,
+}
+
+// Workflow state of a finding (new, accepted, false_positive or resolved)
+#[put(
+    "/services/<id>/triage?<project>",
+    format = "application/json",
+    data = "<update>"
+)]
+async fn update_triage(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+    update: Json<TriageUpdate>,
+) -> Result<&'static str, Status> {
+    let project_id = writable_project_id(state, &access, project).await?;
+    if !triage::STATES.contains(&update.state.as_str()) {
+        return Err(Status::BadRequest);
+    }
+    match state
+        .db
+        .update_triage_state(project_id, id, &update.state)
+        .await
+    {
+        Ok(true) => Ok("OK"),
+        Ok(false) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+// Saved searches of the project, by name
+#[get("/searches?<project>")]
+async fn searches(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+) -> Result<Json<Vec<SavedSearch>>, Status> {
+    let project_id = project_id(state, &access, project).await?;
+    match state.db.get_saved_searches(project_id).await {
+        Ok(searches) => Ok(Json(searches)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+#[derive(Deserialize)]
+struct NewSearch {
+    name: String,
+    // Query string of the filters (e.g. country=IT&search=nginx)
+    query: String,
+}
+
+// Saves a search, replacing the one with the same name
+#[post("/searches?<project>", format = "application/json", data = "<search>")]
+async fn save_search(
+    state: &State<Shared>,
+    access: Access,
+    project: Option<String>,
+    search: Json<NewSearch>,
+) -> Result<Json<SavedSearch>, Status> {
+    let project_id = writable_project_id(state, &access, project).await?;
+    let name = search.name.trim();
+    if name.is_empty() || name.chars().count() > 100 || search.query.chars().count() > 2000 {
+        return Err(Status::BadRequest);
+    }
+    match state
+        .db
+        .update_or_insert_saved_search(project_id, name, &search.query)
+        .await
+    {
+        Ok(saved) => Ok(Json(saved)),
+        Err(err) => Err(db_error(state, err).await),
+    }
+}
+
+#[delete("/searches/<id>?<project>")]
+async fn del_search(
+    state: &State<Shared>,
+    access: Access,
+    id: i64,
+    project: Option<String>,
+) -> Result<&'static str, Status> {
+    let project_id = writable_project_id(state, &access, project).await?;
+    match state.db.delete_saved_search(project_id, id).await {
+        Ok(true) => Ok("OK"),
+        Ok(false) => Err(Status::NotFound),
+        Err(err) => Err(db_error(state, err).await),
     }
 }
 
@@ -77,14 +592,48 @@ fn internal_server_error(_req: &Request) -> &'static str {
     "Internal server error :("
 }
 
-pub async fn run(tx: Sender<UIMessage>) -> Result<(), rocket::Error> {
-    let db_conf = match conf::load_db_conf() {
-        Ok(db_conf) => db_conf,
-        Err(err) => {
-            panic!("[{}] Db conf file error: {}", "ERROR".red(), err);
-        }
-    };
+// The web UI and its API, on the connected Db
+pub(crate) fn build(db: DbMan, tx: Sender<UIMessage>, users: Vec<User>) -> Rocket<Build> {
+    rocket::build()
+        .mount("/", routes![home, static_files, healthz, readyz])
+        .mount(
+            "/api",
+            routes![
+                projects,
+                services,
+                services_geo,
+                services_groups,
+                services_similar,
+                service_response,
+                service_request,
+                host,
+                domains,
+                domain_ips,
+                scan_stats,
+                scan_geo,
+                scan_har,
+                del_services,
+                searches,
+                save_search,
+                del_search,
+                triage_queue,
+                update_triage,
+                unknown
+            ],
+        )
+        .manage(Shared {
+            db,
+            tx: Arc::new(Mutex::new(tx)),
+            users,
+        })
+        .register("/", catchers![internal_server_error, not_found])
+}
 
+pub async fn run(
+    tx: Sender<UIMessage>,
+    db_conf: DbConf,
+    users: Vec<User>,
+) -> Result<(), rocket::Error> {
     let db = match DbMan::init(&db_conf).await {
         Ok(db) => db,
         Err(err) => {
@@ -96,16 +645,5 @@ pub async fn run(tx: Sender<UIMessage>) -> Result<(), rocket::Error> {
         }
     };
 
-    rocket::build()
-        .mount("/", routes![home, static_files])
-        .mount("/api", routes![services, del_services])
-        .manage(Shared {
-            db,
-            tx: Arc::new(Mutex::new(tx)),
-        })
-        .register("/", catchers![internal_server_error, not_found])
-        .ignite()
-        .await?
-        .launch()
-        .await
+    build(db, tx, users).ignite().await?.launch().await
 }
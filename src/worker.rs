@@ -1,80 +1,231 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
+    net::IpAddr,
     path::Path,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::Instant,
 };
 
 use easy_reader::EasyReader;
-use hyper::client::{Client, HttpConnector};
-use hyper_tls::HttpsConnector;
 use serde_derive::{Deserialize, Serialize};
 use tokio::{
-    sync::{mpsc::Sender, Mutex, Semaphore},
-    time::{sleep, Duration},
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex, Semaphore,
+    },
+    time::{sleep, sleep_until, Duration},
 };
+use tracing::instrument;
 
 use crate::{
-    conf::{Conf, Definition},
-    net::{self, HttpsOptions},
+    breaker::{Breaker, BreakerEvent},
+    conf::{Conf, Definition, PortLimit},
+    control,
+    db::PortscanRow,
+    detector,
+    error::{Error, FailClass, Result, TimeoutPhase},
+    har::HarRequest,
+    net,
+    pcap::Capture,
+    plugins::{HostBudget, ProbeContext, Registry},
+    stream, template,
 };
 
+// Max targets of the stream being probed at the same time, when the requests are not limited
+// (--max-concurrent-requests)
+const STREAM_PENDING_TARGETS: usize = 1000;
+// Random lines of the dataset drawn in a row without a valid A record, before giving up
+const MAX_DATASET_DRAWS: usize = 10000;
+
+// Timeout of the port checks before the first answer from a network, and its bounds
+const INITIAL_TIMEOUT_MS: f32 = 3000.0;
+const MIN_TIMEOUT_MS: f32 = 100.0;
+
 // Timeout estimation formula from nmap
 // nmap.org/book/port-scanning-algorithms.html
-fn estimate_timeout(oldsrtt: f32, curr_rtt: f32, oldrttvar: f32) -> f32 {
-    let newsrtt = oldsrtt + (curr_rtt - oldsrtt) / 8.0;
-    let newrttvar = oldrttvar + (f32::abs(curr_rtt - oldsrtt) - oldrttvar) / 4.0;
-    newsrtt + newrttvar * 4.0
+fn estimate_timeout(pt: &mut ProbeTime, curr_rtt: f32) {
+    if pt.samples == 0 {
+        pt.srtt = curr_rtt;
+        pt.rttvar = curr_rtt / 2.0;
+    } else {
+        pt.rttvar += (f32::abs(curr_rtt - pt.srtt) - pt.rttvar) / 4.0;
+        pt.srtt += (curr_rtt - pt.srtt) / 8.0;
+    }
+    pt.samples += 1;
+    pt.timeout = (pt.srtt + pt.rttvar * 4.0).max(MIN_TIMEOUT_MS);
 }
 
+// Network of the round trip time estimation: the /24 of the IPv4 addresses, the /48 of the IPv6
+// ones
+pub fn network_of(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+        }
+        Err(_) => ip.to_string(),
+    }
+}
+
+// Check of a port of the ip, the timed out ones are retried (--port-retries). The connect time
+// (ms) of the last attempt is measured as soon as it returns
+async fn check_port(
+    ws: &WorkerState,
+    ip: &str,
+    network: &str,
+    port: u16,
+) -> Result<(PortTarget, Option<TcpStream>, u64)> {
+    ws.maybe_wait_for_permit(port).await;
+
+    let now = Instant::now();
+    let timeout = ws.timeout_of(network);
+    let mut attempts = 0;
+    let result = loop {
+        match net::test_port(ip.to_string(), port, timeout as u64, ws.conf.source_ip).await {
+            Ok((port_target, _))
+                if port_target.status == PortStatus::Timedout
+                    && attempts < ws.conf.port_retries =>
+            {
+                attempts += 1;
+            }
+            result => {
+                break result.map(|(port_target, stream)| {
+                    let rtt = port_target.time.elapsed().as_millis() as u64;
+                    (port_target, stream, rtt)
+                })
+            }
+        }
+    };
+
+    // Only the answers (open or closed) are samples of the round trip time
+    if let Ok((port_target, _, _)) = &result {
+        if port_target.status != PortStatus::Timedout {
+            ws.update_timeout(network, now.elapsed().as_millis() as f32);
+        }
+    }
+
+    ws.maybe_release_permit(port).await;
+    result
+}
+
+#[instrument(level = "debug", skip(tx, ws, defs))]
 async fn check_ports(
     tx: Sender<WorkerMessage>,
     ws: WorkerState,
-    defs: &[Definition],
+    defs: &[&Definition],
     ip: String,
-) -> HashSet<u16> {
+) -> (HashSet<u16>, HashMap<u16, TcpStream>, HashMap<u16, u64>) {
     let mut unique_ports = HashSet::new();
+    // Only the connections of the ports probed over raw tcp are kept, the http/s client opens its own
+    let mut stream_ports = HashSet::new();
 
-    for def in defs {
+    // The ports of the knock sequences are closed until knocked
+    for def in defs.iter().filter(|def| !def.is_knock()) {
         for port in &def.options.ports {
             unique_ports.insert(*port);
+            if !detector::is_http(&def.protocol) {
+                stream_ports.insert(*port);
+            }
         }
     }
 
-    let mut open_ports = unique_ports.clone();
+    let network = network_of(&ip);
+    let mut results = Vec::new();
+    if ws.conf.ports_only {
+        // All the ports at once (only bounded by the limits), nothing else is sent to the target: the
+        // connections are closed as soon as checked
+        let tasks: Vec<_> = unique_ports
+            .iter()
+            .map(|port| {
+                let (ws, ip, network, port) = (ws.clone(), ip.clone(), network.clone(), *port);
+                let task = tokio::spawn(async move {
+                    check_port(&ws, &ip, &network, port)
+                        .await
+                        .map(|(port_target, _, rtt)| (port_target, None, rtt))
+                });
+                (port, task)
+            })
+            .collect();
+        for (port, task) in tasks {
+            match task.await {
+                Ok(result) => results.push((port, result)),
+                Err(e) => {
+                    let mut target = ReqTarget::new(String::new(), ip.clone());
+                    target.port = port;
+                    let _ = tx
+                        .send(WorkerMessage::Fail(
+                            target,
+                            FailClass::Other,
+                            "Port check error".to_string(),
+                            Some(e.to_string()),
+                        ))
+                        .await;
+                }
+            }
+        }
+    } else {
+        // In order, one at a time
+        let mut ports: Vec<u16> = unique_ports.iter().cloned().collect();
+        ports.sort_unstable();
+        for port in ports {
+            let result = check_port(&ws, &ip, &network, port).await;
+            let failed = result.is_err();
+            results.push((port, result));
+            if failed {
+                break;
+            }
+        }
+    }
+
+    let mut open_ports = unique_ports;
+    let mut streams = HashMap::new();
+    let mut connect_rtts = HashMap::new();
     let mut ports_target = PortsTarget {
         ip: ip.clone(),
         ports: Vec::new(),
+        timeout: 0,
     };
-    for port in unique_ports {
-        ws.maybe_wait_for_permit().await;
-
-        let now = Instant::now();
-        let timeout = ws.probe_time.lock().await.timeout;
-        let port_target = net::test_port(ip.clone(), port, timeout as u64).await;
+    for (port, result) in results {
+        let (port_target, stream, rtt) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        ReqTarget::new(String::new(), ip),
+                        FailClass::Other,
+                        "Invalid address".to_string(),
+                        Some(e.to_string()),
+                    ))
+                    .await;
+                return (HashSet::new(), HashMap::new(), HashMap::new());
+            }
+        };
 
-        if port_target.status != PortStatus::Open {
+        if port_target.status == PortStatus::Open {
+            connect_rtts.insert(port, rtt);
+        } else {
             open_ports.remove(&port);
         }
+        if let Some(stream) = stream.filter(|_| stream_ports.contains(&port)) {
+            streams.insert(port, stream);
+        }
 
         ports_target.ports.push(port_target);
-
-        let rtt = now.elapsed().as_millis() as f32;
-        let mut pt = ws.probe_time.lock().await;
-        pt.timeout = estimate_timeout(pt.srtt, rtt, pt.rttvar);
-
-        ws.maybe_release_permit().await;
     }
+    ports_target.timeout = ws.timeout_of(&network) as u64;
 
-    tx.send(WorkerMessage::PortsTarget(ports_target))
-        .await
-        .unwrap();
+    let _ = tx.send(WorkerMessage::PortsTarget(ports_target)).await;
 
-    open_ports
+    (open_ports, streams, connect_rtts)
 }
 
 #[derive(Debug, Clone)]
@@ -84,22 +235,75 @@ pub struct ReqTarget {
     pub port: u16,
     pub protocol: String,
     pub response: String,
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // The response exceeded the max size and was cut
+    pub truncated: bool,
     pub time: Instant,
+    // Exchanged bytes (tcp/custom, tls/custom), kept to save the matches as pcap files
+    // (--pcap-matches)
+    pub capture: Option<Capture>,
+    // TCP connect time (ms) of the port check, if the port was checked in this scan
+    pub connect_rtt: Option<u64>,
+    // Request of the http/s probes, kept to save the matches as HAR entries (--har)
+    pub http_request: Option<HarRequest>,
+    // Timing breakdown of the http/s requests
+    pub timings: Option<net::RequestTimings>,
+    // Tags of the target from the dataset or the target stream (e.g. prod, dmz), saved with the
+    // findings
+    pub tags: Vec<String>,
+    // Default credentials (user:password) sent with the request, from the definition
+    pub credentials: Option<String>,
+    // PROXY protocol version of the header sent before the payload (tcp/custom), from the
+    // definition
+    pub proxy_protocol: Option<String>,
+    // Only the status and the headers of the http/s response are read, the body is never
+    // downloaded (the definitions of the request match the headers only)
+    pub headers_only: bool,
+    // Scheme of the definitions when the request was retried with the other one (e.g. an https
+    // definition on a plain http port)
+    pub scheme_fallback: Option<String>,
+    // Request as sent (request line, headers and payload of the http/s and ws/s probes, payload
+    // of the tcp/custom ones), saved with the findings to reproduce them
+    pub sent_request: Option<String>,
+    // Hash of the favicon of the web server and common name of the TLS certificate of the port,
+    // fetched only for the definitions with such indicators
+    pub favicon_hash: Option<i32>,
+    pub tls_cn: Option<String>,
 }
 
-impl ReqTarget {
-    pub fn default() -> ReqTarget {
+impl Default for ReqTarget {
+    fn default() -> ReqTarget {
         ReqTarget {
             domain: String::new(),
             ip: String::new(),
             port: 0,
             protocol: String::new(),
             response: String::new(),
+            status: None,
+            headers: Vec::new(),
+            body: String::new(),
+            truncated: false,
             time: Instant::now(),
+            capture: None,
+            connect_rtt: None,
+            http_request: None,
+            timings: None,
+            tags: Vec::new(),
+            credentials: None,
+            headers_only: false,
+            proxy_protocol: None,
+            scheme_fallback: None,
+            sent_request: None,
+            favicon_hash: None,
+            tls_cn: None,
         }
     }
+}
 
-    fn new(domain: String, ip: String) -> Self {
+impl ReqTarget {
+    pub(crate) fn new(domain: String, ip: String) -> Self {
         ReqTarget {
             domain,
             ip,
@@ -108,99 +312,223 @@ impl ReqTarget {
     }
 }
 
+// Open ports of a recent port scan of the ip (--reuse-portscan), if it checked all the ports of
+// the definitions
+fn cached_open_ports(ws: &WorkerState, ip: &str) -> Option<HashSet<u16>> {
+    let portscan = ws.portscans.get(ip)?;
+    let all_checked = ws
+        .conf
+        .definitions
+        .iter()
+        .filter(|def| !def.is_knock() && !def.is_follow_up())
+        .flat_map(|def| def.options.ports.iter())
+        .all(|port| portscan.checked_ports.contains(port));
+    if !all_checked {
+        return None;
+    }
+    Some(portscan.open_ports.iter().cloned().collect())
+}
+
+#[instrument(level = "debug", skip(tx, ws, target), fields(ip = %target.ip))]
 async fn target_requests(tx: Sender<WorkerMessage>, ws: WorkerState, target: ReqTarget) {
-    let open_ports = check_ports(
-        tx.clone(),
-        ws.clone(),
-        &ws.conf.definitions,
-        target.ip.clone(),
+    // The CDN/WAF edges answer the same way on behalf of many sites, so only the web definitions
+    // are worth probing (--skip-cdn)
+    let cdn = ws.conf.skip_cdn && ws.conf.cdn.provider_by_ip(&target.ip).is_some();
+    let definitions: Vec<&Definition> = ws
+        .conf
+        .definitions
+        .iter()
+        .filter(|def| !cdn || detector::is_http(&def.protocol))
+        .filter(|def| !def.is_follow_up())
+        .collect();
+
+    // A port inventory (--ports-only) checks the ports again, and stops there
+    if ws.conf.ports_only {
+        check_ports(tx.clone(), ws.clone(), &definitions, target.ip.clone()).await;
+        ws.targets_completed.fetch_add(1, Ordering::SeqCst);
+        let _ = tx.send(WorkerMessage::NextTarget).await;
+        return;
+    }
+
+    let (open_ports, streams, connect_rtts) = match cached_open_ports(&ws, &target.ip) {
+        Some(open_ports) => (open_ports, HashMap::new(), HashMap::new()),
+        None => check_ports(tx.clone(), ws.clone(), &definitions, target.ip.clone()).await,
+    };
+
+    let ctx = ProbeContext {
+        ws: &ws,
+        tx: &tx,
+        target: &target,
+        open_ports: &open_ports,
+        streams: Mutex::new(streams),
+        connect_rtts,
+        budget: ws.host_budget(&target.ip),
+    };
+
+    // Every probe runs the definitions with its protocol
+    // (protocol field is already validated when conf is loaded)
+    for probe in ws.registry.probes() {
+        let defs: Vec<&Definition> = definitions
+            .iter()
+            .filter(|def| probe.handles(&def.protocol))
+            .cloned()
+            .collect();
+
+        // The host exceeded its budget (--host-max-requests, --host-max-auth)
+        if ctx.budget_exceeded() {
+            break;
+        }
+        if !defs.is_empty() {
+            probe.run(&ctx, &defs).await;
+        }
+    }
+    // The connections not reused by a probe (e.g. skipped by the budget) aren't kept open any longer
+    ctx.streams.lock().await.clear();
+
+    // The wordlist is tried on the ips only, the other names of a deduplicated dataset ip on it
+    let mut vhosts = if target.domain.is_empty() {
+        ws.conf.vhost_words.clone()
+    } else {
+        Vec::new()
+    };
+    if let Some(names) = ws.conf.dataset_vhosts.get(&target.ip) {
+        vhosts.extend(names.iter().cloned());
+    }
+    if !vhosts.is_empty() && !ctx.budget_exceeded() {
+        discover_vhosts(&ctx, &definitions, &vhosts).await;
+    }
+    ws.release_host_budget(&target.ip, !open_ports.is_empty());
+
+    ws.targets_completed.fetch_add(1, Ordering::SeqCst);
+    let _ = tx.send(WorkerMessage::NextTarget).await;
+}
+
+async fn vhost_get(
+    ctx: &ProbeContext<'_>,
+    target: &ReqTarget,
+    host: String,
+) -> Option<net::VhostResponse> {
+    let mut target = target.clone();
+    target.domain = host;
+    ctx.ws.maybe_wait_for_permit(target.port).await;
+    let response = net::vhost_get(
+        &ctx.ws.https_client,
+        &target,
+        ctx.ws.user_agents.next(),
+        ctx.ws.conf.req_timeout,
+        ctx.ws.conf.max_response_bytes,
     )
     .await;
+    ctx.ws.maybe_release_permit(target.port).await;
+    response
+}
 
-    let mut http_s_unique_opts = HashSet::new();
-    for def in &ws.conf.definitions {
-        match def.protocol.as_str() {
-            "http/s" => {
-                // Avoid duplicate requests (same port, method, path, headers and payload)
-                for port in &def.options.ports {
-                    if open_ports.contains(port) {
-                        let options = HttpsOptions {
-                            method: def
-                                .options
-                                .method
-                                .clone()
-                                .unwrap_or_else(|| "GET".to_string()),
-                            path: def.options.path.clone().unwrap_or_else(|| "/".to_string()),
-                            headers: def.options.headers.clone().unwrap_or_default(),
-                            payload: def
-                                .options
-                                .payload
-                                .clone()
-                                .unwrap_or_else(|| "".to_string()),
-                        };
-                        http_s_unique_opts.insert((*port, options));
-                    }
-                }
-            }
-            "tcp/custom" => {
-                for port in &def.options.ports {
-                    if !open_ports.contains(port) {
-                        continue;
-                    }
-
-                    ws.maybe_wait_for_permit().await;
-
-                    let mut target = target.clone();
-                    target.domain = String::new();
-                    target.protocol = "tcp/custom".to_string();
-                    target.port = *port;
-                    target.time = Instant::now();
-
-                    net::tcp_custom(
-                        tx.clone(),
-                        target,
-                        def.options.payload.clone().unwrap(),
-                        ws.conf.req_timeout,
-                    )
-                    .await;
+// Host names answering on the web ports of an ip differently than a random one
+// (the default virtual host), sent to the receiver loop. The requests to a port reuse the pooled
+// (keep-alive) connection of the client
+async fn discover_vhosts(ctx: &ProbeContext<'_>, definitions: &[&Definition], vhosts: &[String]) {
+    let mut ports: Vec<u16> = definitions
+        .iter()
+        .filter(|def| detector::is_http(&def.protocol))
+        .flat_map(|def| def.options.ports.iter().cloned())
+        .filter(|port| ctx.open_ports.contains(port))
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+
+    for port in ports {
+        if !ctx.spend_budget("tls", port, false).await {
+            return;
+        }
+        ctx.ws.maybe_wait_for_permit(port).await;
+        let tls = net::sniff_tls(
+            &ctx.target.ip,
+            port,
+            "",
+            ctx.ws.conf.req_timeout,
+            ctx.ws.conf.source_ip,
+        )
+        .await;
+        ctx.ws.maybe_release_permit(port).await;
+
+        let mut target = ctx.target.clone();
+        target.protocol = if tls == Some(true) { "https" } else { "http" }.to_string();
+        target.port = port;
 
-                    ws.maybe_release_permit().await;
+        if !ctx.spend_budget(&target.protocol, port, false).await {
+            return;
+        }
+        let random_host = template::expand("{rand_hex:16}.invalid", &target);
+        let baseline = match vhost_get(ctx, &target, random_host).await {
+            Some(baseline) => baseline,
+            None => continue,
+        };
+
+        for host in vhosts {
+            if !ctx.spend_budget(&target.protocol, port, false).await {
+                return;
+            }
+            match vhost_get(ctx, &target, host.clone()).await {
+                Some(response) if response.differs_from(&baseline) => {
+                    let mut vhost = target.clone();
+                    vhost.domain = host.clone();
+                    let _ = ctx.tx.send(WorkerMessage::Vhost(vhost)).await;
                 }
+                _ => (),
             }
-            // Protocol field is already validated when conf is loaded
-            _ => (),
         }
     }
+}
 
-    for protocol in ["https", "http"].iter() {
-        for (port, opts) in &http_s_unique_opts {
-            if (*port == 80 && *protocol == "https") || (*port == 443 && *protocol == "http") {
-                continue;
-            }
-
-            ws.maybe_wait_for_permit().await;
-
-            let mut target = target.clone();
-            target.protocol = protocol.to_string();
-            target.port = *port;
-            target.time = Instant::now();
+// Follow-up definitions (on_match) to probe on the port of a match of the target
+#[derive(Debug, Clone)]
+pub struct FollowUp {
+    pub target: ReqTarget,
+    pub definitions: Vec<String>,
+}
 
-            net::http_s(
-                tx.clone(),
-                ws.https_client.clone(),
-                target,
-                opts.clone(),
-                ws.conf.user_agent.clone(),
-                ws.conf.req_timeout,
-            )
-            .await;
+// The follow-up definitions probe the port of the match only, as open (it just answered), within
+// the budget and the delay of the host
+#[instrument(level = "debug", skip(tx, ws, follow_up), fields(ip = %follow_up.target.ip))]
+async fn follow_up_requests(tx: Sender<WorkerMessage>, ws: WorkerState, follow_up: FollowUp) {
+    let port = follow_up.target.port;
+    let definitions: Vec<Definition> = ws
+        .conf
+        .definitions
+        .iter()
+        .filter(|def| follow_up.definitions.contains(&def.name))
+        .map(|def| {
+            let mut def = def.clone();
+            def.options.ports = vec![port];
+            def
+        })
+        .collect();
+    let open_ports = vec![port].into_iter().collect();
+    let mut target = ReqTarget::new(follow_up.target.domain.clone(), follow_up.target.ip.clone());
+    target.tags = follow_up.target.tags.clone();
 
-            ws.maybe_release_permit().await;
+    let ctx = ProbeContext {
+        ws: &ws,
+        tx: &tx,
+        target: &target,
+        open_ports: &open_ports,
+        streams: Mutex::new(HashMap::new()),
+        connect_rtts: HashMap::new(),
+        budget: ws.host_budget(&target.ip),
+    };
+    for probe in ws.registry.probes() {
+        let defs: Vec<&Definition> = definitions
+            .iter()
+            .filter(|def| probe.handles(&def.protocol))
+            .collect();
+        if ctx.budget_exceeded() {
+            break;
+        }
+        if !defs.is_empty() {
+            probe.run(&ctx, &defs).await;
         }
     }
-
-    ws.targets_completed.fetch_add(1, Ordering::SeqCst);
-    tx.send(WorkerMessage::NextTarget).await.unwrap();
+    ws.release_host_budget(&target.ip, true);
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -209,24 +537,60 @@ pub struct DatasetRecord {
     #[serde(rename = "type")]
     pub record_type: String,
     pub value: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+// The tags as given, without the empty and the repeated ones
+pub fn clean_tags(tags: Vec<String>) -> Vec<String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !cleaned.iter().any(|other| other == tag) {
+            cleaned.push(tag.to_string());
+        }
+    }
+    cleaned
+}
+
+pub fn parse_dataset_record(line: &str) -> Result<DatasetRecord> {
+    serde_json::from_str(line).map_err(|e| Error::InvalidDatasetRecord(e.to_string()))
 }
 
 // Pick a random dns record from the dataset
-// (excluding records which are not of type A)
+// (excluding records which are not of type A, and the malformed ones). None when no valid record
+// is drawn in MAX_DATASET_DRAWS attempts (e.g. a dataset without A records)
 async fn get_next_dataset_target(dataset: &mut EasyReader<File>) -> Option<ReqTarget> {
-    loop {
-        let line_str = dataset.random_line().unwrap().unwrap();
-        let dataset_record: DatasetRecord = serde_json::from_str(&line_str).unwrap();
+    for _ in 0..MAX_DATASET_DRAWS {
+        let line_str = match dataset.random_line() {
+            Ok(Some(line)) => line,
+            _ => return None, // Empty or unreadable dataset
+        };
+        let dataset_record = match parse_dataset_record(&line_str) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
         if dataset_record.record_type != "a" {
             continue;
         }
-        return Some(ReqTarget::new(dataset_record.name, dataset_record.value));
+        let mut target = ReqTarget::new(dataset_record.name, dataset_record.value);
+        target.tags = clean_tags(dataset_record.tags);
+        return Some(target);
     }
+    None
 }
 
 // Pick the next ip in the specified subnets
 async fn get_next_subnet_target(conf: &Conf) -> Option<ReqTarget> {
+    if let Some(permutation) = &conf.permutation {
+        let ip = permutation.lock().await.next();
+        return ip.map(|ip| ReqTarget::new(String::new(), ip.to_string()));
+    }
+
     let mut current_subnet_idx = conf.subnets.lock().await.1;
+    if current_subnet_idx >= conf.subnets.lock().await.0.len() {
+        return None; // No subnets (e.g. domains only)
+    }
     let mut ip = conf.subnets.lock().await.0[current_subnet_idx].next();
 
     while ip.is_none() {
@@ -243,10 +607,22 @@ async fn get_next_subnet_target(conf: &Conf) -> Option<ReqTarget> {
 }
 
 #[derive(Debug, Clone)]
-struct WorkerProbeTime {
+struct ProbeTime {
     srtt: f32,
     rttvar: f32,
     timeout: f32,
+    samples: u64,
+}
+
+impl Default for ProbeTime {
+    fn default() -> Self {
+        ProbeTime {
+            srtt: 0.0,
+            rttvar: 0.0,
+            timeout: INITIAL_TIMEOUT_MS,
+            samples: 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -255,43 +631,263 @@ struct WorkerRequests {
     completed: u64,
 }
 
-#[derive(Debug, Clone)]
-struct WorkerState {
-    conf: Conf,
-    https_client: Client<HttpsConnector<HttpConnector>>,
+// Concurrency and rate limits of the running scan, adjustable until it's completed (e.g. to
+// throttle down when a network complains)
+pub struct Limits {
+    // Fixed when the scan starts: the requests of an unlimited scan don't take permits
+    limited: bool,
+    max_concurrent_requests: AtomicUsize,
+    max_rate: AtomicU64,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Limits {
+    pub fn new(max_concurrent_requests: usize, max_rate: u64) -> Self {
+        Limits {
+            limited: max_concurrent_requests != 0,
+            max_concurrent_requests: AtomicUsize::new(max_concurrent_requests),
+            max_rate: AtomicU64::new(max_rate),
+            semaphore: Arc::new(Semaphore::new(max_concurrent_requests)),
+        }
+    }
+
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn max_rate(&self) -> u64 {
+        self.max_rate.load(Ordering::SeqCst)
+    }
+
+    // The permits are added, or taken (as soon as the requests release them) and never released
+    pub fn set_max_concurrent_requests(&self, max: usize) -> std::result::Result<(), &'static str> {
+        if !self.limited {
+            return Err("The scan was started without a limit of concurrent requests");
+        }
+        if max == 0 {
+            return Err("The limit of concurrent requests can't be removed");
+        }
+
+        let previous = self.max_concurrent_requests.swap(max, Ordering::SeqCst);
+        if max > previous {
+            self.semaphore.add_permits(max - previous);
+        } else if max < previous {
+            let semaphore = self.semaphore.clone();
+            let excess = (previous - max) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // 0 = unlimited
+    pub fn set_max_rate(&self, max_rate: u64) {
+        self.max_rate.store(max_rate, Ordering::SeqCst);
+    }
+}
+
+// Limits of the probes of a port (--port-limit), fixed for the whole scan
+pub struct PortPolicy {
+    max_rate: u64,
+    // None when the concurrency of the port is unlimited
+    semaphore: Option<Semaphore>,
+    next_request: Mutex<Instant>,
+}
+
+impl PortPolicy {
+    pub fn new(limit: &PortLimit) -> Self {
+        PortPolicy {
+            max_rate: limit.max_rate,
+            semaphore: match limit.max_concurrent_requests {
+                0 => None,
+                max => Some(Semaphore::new(max)),
+            },
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+// Interval between the requests of a rate (per second, not 0), in nanoseconds so that any u64
+// rate is valid (the ones over 10^9 are not limited)
+pub fn rate_interval(max_rate: u64) -> Duration {
+    Duration::from_nanos(1_000_000_000 / max_rate)
+}
+
+// Every request takes the next free slot of the rate and waits for it
+async fn wait_for_slot(next_request: &Mutex<Instant>, max_rate: u64) {
+    if max_rate == 0 {
+        return;
+    }
+    let slot = {
+        let mut next_request = next_request.lock().await;
+        let slot = (*next_request).max(Instant::now());
+        *next_request = slot + rate_interval(max_rate);
+        slot
+    };
+    sleep_until(slot.into()).await;
+}
+
+#[derive(Clone)]
+pub struct WorkerState {
+    pub conf: Conf,
+    pub https_client: net::HttpsClient,
+    // Clients of the http/s definitions with their own connect or tls timeouts, by timeouts
+    https_clients: Arc<HashMap<(Duration, Duration), net::HttpsClient>>,
+    registry: Arc<Registry>,
+    portscans: Arc<HashMap<String, PortscanRow>>,
     targets_count: u64,
     targets_completed: Arc<AtomicU64>,
-    semaphore: Arc<Semaphore>,
-    probe_time: Arc<Mutex<WorkerProbeTime>>,
+    pub limits: Arc<Limits>,
+    pub user_agents: Arc<net::UserAgents>,
+    // Instant of the next request allowed by the rate limit (--max-rate)
+    next_request: Arc<Mutex<Instant>>,
+    // Limits of the sensitive ports, by port
+    port_policies: Arc<HashMap<u16, PortPolicy>>,
+    // Round trip time estimations by network, a slow network doesn't inflate the timeouts of the
+    // other ones
+    probe_times: Arc<std::sync::Mutex<HashMap<String, ProbeTime>>>,
+    // Budgets of the hosts being probed by ip (shared by the targets of an ip, e.g. the domains of
+    // a dataset, and their follow-up probes)
+    host_budgets: Arc<std::sync::Mutex<HashMap<String, HostEntry>>>,
+}
+
+// Budget of a host, with the number of its targets being probed and whether the follow-up probes
+// of its matches can still come
+#[derive(Default)]
+struct HostEntry {
+    budget: Arc<HostBudget>,
+    targets: usize,
+    kept: bool,
 }
 
 impl WorkerState {
-    fn new(conf: Conf, https_client: Client<HttpsConnector<HttpConnector>>) -> Self {
-        let max_concurrent_requests = conf.max_concurrent_requests;
+    fn new(
+        conf: Conf,
+        https_client: net::HttpsClient,
+        registry: Arc<Registry>,
+        portscans: Arc<HashMap<String, PortscanRow>>,
+    ) -> Self {
+        let limits = Limits::new(conf.max_concurrent_requests, conf.max_rate);
+        let user_agents = net::UserAgents::new(&conf.user_agent, &conf.user_agents);
+        let port_policies = conf
+            .port_limits
+            .iter()
+            .map(|limit| (limit.port, PortPolicy::new(limit)))
+            .collect();
+        let global = conf.timeouts(None);
+        let mut https_clients = HashMap::new();
+        for def in conf
+            .definitions
+            .iter()
+            .filter(|d| detector::is_http(&d.protocol))
+        {
+            let timeouts = conf.timeouts(Some(def));
+            if (timeouts.connect, timeouts.tls) != (global.connect, global.tls) {
+                https_clients
+                    .entry((timeouts.connect, timeouts.tls))
+                    .or_insert_with(|| net::build_https_client(conf.source_ip, &timeouts));
+            }
+        }
 
         Self {
             conf,
             https_client,
+            https_clients: Arc::new(https_clients),
+            registry,
+            portscans,
             targets_count: 0,
             targets_completed: Arc::new(AtomicU64::new(0)),
-            semaphore: Arc::new(Semaphore::new(max_concurrent_requests)),
-            probe_time: Arc::new(Mutex::new(WorkerProbeTime {
-                srtt: 0.0,
-                rttvar: 0.0,
-                timeout: 3000.0,
-            })),
+            limits: Arc::new(limits),
+            user_agents: Arc::new(user_agents),
+            next_request: Arc::new(Mutex::new(Instant::now())),
+            port_policies: Arc::new(port_policies),
+            probe_times: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            host_budgets: Arc::new(std::sync::Mutex::new(HashMap::new())),
         }
     }
 
-    async fn maybe_wait_for_permit(&self) {
-        if self.conf.max_concurrent_requests != 0 {
-            self.semaphore.acquire().await.unwrap().forget();
+    // Client of the requests with the given connect and tls timeouts
+    pub fn https_client_for(&self, timeouts: &net::Timeouts) -> &net::HttpsClient {
+        self.https_clients
+            .get(&(timeouts.connect, timeouts.tls))
+            .unwrap_or(&self.https_client)
+    }
+
+    fn timeout_of(&self, network: &str) -> f32 {
+        self.probe_times
+            .lock()
+            .unwrap()
+            .get(network)
+            .map(|pt| pt.timeout)
+            .unwrap_or(INITIAL_TIMEOUT_MS)
+    }
+
+    fn update_timeout(&self, network: &str, rtt: f32) {
+        let mut probe_times = self.probe_times.lock().unwrap();
+        let pt = probe_times.entry(network.to_string()).or_default();
+        estimate_timeout(pt, rtt);
+    }
+
+    // Budget of the host of the ip, taken by a target (or a follow-up probe) until it's done
+    fn host_budget(&self, ip: &str) -> Arc<HostBudget> {
+        let mut host_budgets = self.host_budgets.lock().unwrap();
+        let entry = host_budgets.entry(ip.to_string()).or_default();
+        entry.targets += 1;
+        entry.budget.clone()
+    }
+
+    // The budget of the host is dropped when its last target is done, unless the host answered
+    // and the follow-up probes of its matches (on_match) can still come: they're spent from the
+    // same budget
+    fn release_host_budget(&self, ip: &str, answered: bool) {
+        let follow_ups = answered
+            && self
+                .conf
+                .definitions
+                .iter()
+                .any(|def| def.on_match.is_some());
+        let mut host_budgets = self.host_budgets.lock().unwrap();
+        if let Some(entry) = host_budgets.get_mut(ip) {
+            entry.targets -= 1;
+            entry.kept |= follow_ups;
+            if entry.targets == 0 && !entry.kept {
+                host_budgets.remove(ip);
+            }
+        }
+    }
+
+    // Long waits (idle time of the span) tell the concurrency or rate limits are starving the
+    // requests. The limits of the port (if any) come first, a request waiting for a slow port
+    // doesn't hold a global permit meanwhile
+    #[instrument(level = "debug", skip(self))]
+    pub async fn maybe_wait_for_permit(&self, port: u16) {
+        if let Some(policy) = self.port_policies.get(&port) {
+            if let Some(semaphore) = &policy.semaphore {
+                semaphore.acquire().await.unwrap().forget();
+            }
+            wait_for_slot(&policy.next_request, policy.max_rate).await;
         }
+
+        if self.limits.limited {
+            self.limits.semaphore.acquire().await.unwrap().forget();
+        }
+
+        wait_for_slot(&self.next_request, self.limits.max_rate()).await;
     }
 
-    async fn maybe_release_permit(&self) {
-        if self.conf.max_concurrent_requests != 0 {
-            self.semaphore.add_permits(1);
+    pub async fn maybe_release_permit(&self, port: u16) {
+        if let Some(semaphore) = self
+            .port_policies
+            .get(&port)
+            .and_then(|policy| policy.semaphore.as_ref())
+        {
+            semaphore.add_permits(1);
+        }
+        if self.limits.limited {
+            self.limits.semaphore.add_permits(1);
         }
     }
 }
@@ -314,6 +910,8 @@ pub struct PortTarget {
 pub struct PortsTarget {
     pub ip: String,
     pub ports: Vec<PortTarget>,
+    // Estimated timeout (ms) of the network of the target after its checks
+    pub timeout: u64,
 }
 
 impl PortsTarget {
@@ -332,28 +930,137 @@ impl PortsTarget {
 pub enum WorkerMessage {
     PortsTarget(PortsTarget),
     Response(ReqTarget),
-    Fail(ReqTarget, String, Option<String>),
-    Timeout(ReqTarget),
+    // Failed request: class, context and error
+    Fail(ReqTarget, FailClass, String, Option<String>),
+    // Timed out request, and the phase that timed out
+    Timeout(ReqTarget, TimeoutPhase),
+    OutOfScope(ReqTarget),
+    // Virtual host (the domain of the target) answering on its port (--vhost-wordlist)
+    Vhost(ReqTarget),
+    // Circuit breaker of the half-open targets (--breaker-threshold)
+    Breaker(BreakerEvent),
     NextTarget,
+    // All the targets are completed, only the follow-up probes can follow
+    TargetsDone,
     Shutdown,
 }
 
-pub async fn run(tx: Sender<WorkerMessage>, conf: Conf) {
-    let mut ws = WorkerState::new(conf, net::build_https_client());
+fn open_dataset(path: &str) -> Result<EasyReader<File>> {
+    Ok(EasyReader::new(File::open(Path::new(path))?)?)
+}
+
+pub async fn run(
+    tx: Sender<WorkerMessage>,
+    conf: Conf,
+    registry: Arc<Registry>,
+    portscans: Arc<HashMap<String, PortscanRow>>,
+    mut follow_ups: Receiver<FollowUp>,
+    breaker: Option<Arc<Breaker>>,
+) {
+    let https_client = net::build_https_client(conf.source_ip, &conf.timeouts(None));
+    let mut ws = WorkerState::new(conf, https_client, registry, portscans);
 
-    let mut dataset = if !ws.conf.dataset.is_empty() {
-        EasyReader::new(File::open(Path::new(&ws.conf.dataset)).unwrap()).unwrap()
+    // No dataset in subnet mode, nor when it's deduplicated (its targets are the hosts)
+    let mut dataset = if !ws.conf.dataset.is_empty() && ws.conf.dedup.is_none() {
+        match open_dataset(&ws.conf.dataset) {
+            Ok(dataset) => Some(dataset),
+            Err(e) => {
+                let _ = tx
+                    .send(WorkerMessage::Fail(
+                        ReqTarget::default(),
+                        FailClass::Other,
+                        "Dataset error".to_string(),
+                        Some(e.to_string()),
+                    ))
+                    .await;
+                let _ = tx.send(WorkerMessage::Shutdown).await;
+                return;
+            }
+        }
     } else {
-        // When in subnet mode, open a test file here just as a workaround to avoid writing two
-        // different loops for the two modes or reopening the dataset file at every iteration
-        EasyReader::new(File::open("./resources/test-dataset.json").unwrap()).unwrap()
+        None
     };
 
-    while ws.conf.max_targets == 0 || ws.targets_count < ws.conf.max_targets {
-        let target = if !ws.conf.dataset.is_empty() {
-            get_next_dataset_target(&mut dataset).await
-        } else {
-            get_next_subnet_target(&ws.conf).await
+    // The targets of the stream (--target-stream) are taken as they arrive, while the ones being
+    // probed are less than the limit: the stream is not read further (backpressure) otherwise
+    let (mut stream, pending) = if ws.conf.target_stream {
+        let limit = match ws.conf.max_concurrent_requests {
+            0 => STREAM_PENDING_TARGETS,
+            max => max,
+        };
+        (
+            Some(stream::stdin_targets()),
+            Some(Arc::new(Semaphore::new(limit))),
+        )
+    } else {
+        (None, None)
+    };
+
+    // Limits adjustable while the scan runs (--control-listen, SIGUSR2)
+    let control = match control::spawn(ws.conf.control_listen, ws.limits.clone()) {
+        Ok(control) => control,
+        Err(e) => {
+            let _ = tx
+                .send(WorkerMessage::Fail(
+                    ReqTarget::default(),
+                    FailClass::Other,
+                    "Control API error".to_string(),
+                    Some(e),
+                ))
+                .await;
+            let _ = tx.send(WorkerMessage::Shutdown).await;
+            return;
+        }
+    };
+
+    // Follow-up probes of the matches, until the sender is dropped (no pending detection can
+    // trigger them anymore)
+    let (follow_up_tx, follow_up_ws) = (tx.clone(), ws.clone());
+    let follow_ups_handle = tokio::spawn(async move {
+        let mut tasks = Vec::new();
+        while let Some(follow_up) = follow_ups.recv().await {
+            tasks.push(tokio::spawn(follow_up_requests(
+                follow_up_tx.clone(),
+                follow_up_ws.clone(),
+                follow_up,
+            )));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+    });
+
+    // The domains targets come first, then the subnets
+    let mut next_host = 0;
+    // Targets refused because out of scope, counted toward --max-targets too (e.g. a dataset
+    // mostly out of scope, its random lines never end)
+    let mut refused = 0;
+    while ws.conf.max_targets == 0 || ws.targets_count + refused < ws.conf.max_targets {
+        // Paused while the failure rate is over the threshold (--breaker-threshold)
+        if let Some(breaker) = &breaker {
+            while let Some(event) = breaker.wait().await {
+                let _ = tx.send(WorkerMessage::Breaker(event)).await;
+            }
+        }
+
+        let permit = match &pending {
+            Some(pending) => Some(pending.clone().acquire_owned().await.unwrap()),
+            None => None,
+        };
+
+        let target = match (&mut stream, &mut dataset) {
+            (Some(stream), _) => stream.recv().await,
+            (None, Some(dataset)) => get_next_dataset_target(dataset).await,
+            (None, None) if next_host < ws.conf.hosts.len() => {
+                let (name, ip) = ws.conf.hosts[next_host].clone();
+                next_host += 1;
+                let mut target = ReqTarget::new(name, ip);
+                if let Some(tags) = ws.conf.host_tags.get(&target.ip) {
+                    target.tags = tags.clone();
+                }
+                Some(target)
+            }
+            (None, None) => get_next_subnet_target(&ws.conf).await,
         };
 
         let target = match target {
@@ -361,7 +1068,20 @@ pub async fn run(tx: Sender<WorkerMessage>, conf: Conf) {
             None => break, // All the targets have been consumed
         };
 
-        tokio::spawn(target_requests(tx.clone(), ws.clone(), target));
+        // Targets outside the scope are never probed
+        if let Some(scope) = &ws.conf.scope {
+            if !scope.contains(&target.ip) {
+                let _ = tx.send(WorkerMessage::OutOfScope(target)).await;
+                refused += 1;
+                continue;
+            }
+        }
+
+        let (target_tx, target_ws) = (tx.clone(), ws.clone());
+        tokio::spawn(async move {
+            target_requests(target_tx, target_ws, target).await;
+            drop(permit);
+        });
 
         ws.targets_count += 1;
     }
@@ -370,5 +1090,12 @@ pub async fn run(tx: Sender<WorkerMessage>, conf: Conf) {
         sleep(Duration::from_millis(500)).await;
     }
 
-    tx.send(WorkerMessage::Shutdown).await.unwrap();
+    let _ = tx.send(WorkerMessage::TargetsDone).await;
+    let _ = follow_ups_handle.await;
+
+    for handle in control {
+        handle.abort();
+    }
+
+    let _ = tx.send(WorkerMessage::Shutdown).await;
 }
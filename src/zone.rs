@@ -0,0 +1,284 @@
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+use crate::{
+    plugins::dns::{build_query, read_u16, CLASS_IN},
+    resolver::Resolver,
+    scope::Scope,
+    worker::ReqTarget,
+};
+
+const TYPE_NS: u16 = 2;
+const TYPE_SOA: u16 = 6;
+const TYPE_AXFR: u16 = 252;
+// Timeout of a whole zone transfer
+const ZONE_TIMEOUT: u64 = 10;
+const MAX_ZONE_RECORDS: usize = 100_000;
+// Compression pointers and labels followed while reading a name
+const MAX_NAME_STEPS: usize = 128;
+
+// A record of a transferred zone, with its data as text (e.g. the ip, the target name)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZoneRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub value: String,
+}
+
+// DNS findings of a domain target: its wildcard records (the ips of any name) and the zone,
+// when a name server allows the transfer
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DomainInfo {
+    pub domain: String,
+    pub wildcard_ips: Vec<String>,
+    pub zone_ns: Option<String>,
+    pub zone: Vec<ZoneRecord>,
+}
+
+// A (possibly compressed) domain name and the offset after it
+pub fn read_name(buf: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_NAME_STEPS {
+        let len = *buf.get(offset)? as usize;
+        if len & 0xC0 == 0xC0 {
+            end.get_or_insert(offset + 2);
+            offset = ((len & 0x3F) << 8) | *buf.get(offset + 1)? as usize;
+            continue;
+        }
+        if len == 0 {
+            return Some((labels.join(".").to_lowercase(), end.unwrap_or(offset + 1)));
+        }
+        let label = buf.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        offset += 1 + len;
+    }
+    None
+}
+
+fn record_type(rtype: u16) -> String {
+    match rtype {
+        1 => "A",
+        TYPE_NS => "NS",
+        5 => "CNAME",
+        TYPE_SOA => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        rtype => return format!("TYPE{}", rtype),
+    }
+    .to_string()
+}
+
+// Data of the common record types as text, empty for the other ones
+fn record_value(buf: &[u8], rtype: u16, offset: usize, rdata: &[u8]) -> Option<String> {
+    Some(match rtype {
+        1 if rdata.len() == 4 => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+        28 if rdata.len() == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(rdata);
+            Ipv6Addr::from(octets).to_string()
+        }
+        2 | 5 | 6 | 12 => read_name(buf, offset)?.0,
+        15 => format!(
+            "{} {}",
+            read_u16(buf, offset)?,
+            read_name(buf, offset + 2)?.0
+        ),
+        16 => {
+            let mut text = String::new();
+            let mut i = 0;
+            while i < rdata.len() {
+                let len = rdata[i] as usize;
+                text.push_str(&String::from_utf8_lossy(rdata.get(i + 1..i + 1 + len)?));
+                i += 1 + len;
+            }
+            text
+        }
+        33 => format!(
+            "{} {} {} {}",
+            read_u16(buf, offset)?,
+            read_u16(buf, offset + 2)?,
+            read_u16(buf, offset + 4)?,
+            read_name(buf, offset + 6)?.0
+        ),
+        _ => String::new(),
+    })
+}
+
+// Response code and answers of a DNS message
+pub fn parse_message(buf: &[u8]) -> Option<(u16, Vec<ZoneRecord>)> {
+    let rcode = read_u16(buf, 2)? & 0x000F;
+    let questions = read_u16(buf, 4)?;
+    let answers = read_u16(buf, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(buf, offset)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (name, next) = read_name(buf, offset)?;
+        let rtype = read_u16(buf, next)?;
+        let rdlength = read_u16(buf, next + 8)? as usize;
+        offset = next + 10;
+        let rdata = buf.get(offset..offset + rdlength)?;
+        records.push(ZoneRecord {
+            name,
+            record_type: record_type(rtype),
+            value: record_value(buf, rtype, offset, rdata)?,
+        });
+        offset += rdlength;
+    }
+
+    Some((rcode, records))
+}
+
+// Authoritative name servers of the domain
+async fn name_servers(domain: &str, resolver: &Resolver) -> Result<Vec<String>, String> {
+    Ok(resolver
+        .query(domain, TYPE_NS)
+        .await?
+        .into_iter()
+        .filter(|record| record.record_type == "NS")
+        .map(|record| record.value)
+        .collect())
+}
+
+// AXFR of the zone from a name server, the records until the closing SOA
+pub async fn axfr(addr: &SocketAddr, domain: &str) -> Result<Vec<ZoneRecord>, String> {
+    let transfer = async {
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("Connection error: {}", e))?;
+        let id: u16 = rand::thread_rng().gen();
+        let query = build_query(id, domain, TYPE_AXFR, CLASS_IN, false);
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&query);
+        stream
+            .write_all(&message)
+            .await
+            .map_err(|e| format!("Write error: {}", e))?;
+
+        // One or more messages, each prefixed by its length
+        let mut zone = Vec::new();
+        let mut soa = 0;
+        while soa < 2 && zone.len() < MAX_ZONE_RECORDS {
+            let mut len = [0; 2];
+            stream
+                .read_exact(&mut len)
+                .await
+                .map_err(|e| format!("Read error: {}", e))?;
+            let mut response = vec![0; u16::from_be_bytes(len) as usize];
+            stream
+                .read_exact(&mut response)
+                .await
+                .map_err(|e| format!("Read error: {}", e))?;
+
+            let (rcode, records) = parse_message(&response).ok_or("Invalid DNS response")?;
+            if rcode != 0 {
+                return Err(format!("Transfer refused (rcode {})", rcode));
+            }
+            if records.is_empty() {
+                return Err("Transfer refused (no records)".to_string());
+            }
+            soa += records.iter().filter(|r| r.record_type == "SOA").count();
+            zone.extend(records);
+        }
+        // The closing SOA repeats the first one
+        if soa >= 2 {
+            zone.pop();
+        }
+        Ok(zone)
+    };
+
+    match time::timeout(Duration::from_secs(ZONE_TIMEOUT), transfer).await {
+        Ok(zone) => zone,
+        Err(_) => Err("Transfer timed out".to_string()),
+    }
+}
+
+// IPv4 addresses of a random name of the domain: any name resolves with a wildcard record
+async fn wildcard_ips(domain: &str, resolver: &Resolver) -> Vec<String> {
+    let label: String = (0..16)
+        .map(|_| format!("{:x}", rand::random::<u8>() % 16))
+        .collect();
+    resolver.lookup_ipv4(&format!("{}.{}", label, domain)).await
+}
+
+// The name servers outside the engagement scope (--scope) are never connected to, audited as the
+// refused targets
+pub fn ns_in_scope(ns: &str, ip: &str, scope: Option<&Scope>) -> bool {
+    let scope = match scope {
+        Some(scope) if !scope.contains(ip) => scope,
+        _ => return true,
+    };
+    eprintln!("Zone transfer from {} ({}) skipped: out of scope", ns, ip);
+    if let Err(err) = scope.audit_refused(&ReqTarget::new(ns.to_string(), ip.to_string())) {
+        eprintln!("Scope audit error: {}", err);
+    }
+    false
+}
+
+// Wildcard detection and, if enabled (--axfr), the zone transfer from the first name server
+// allowing it
+pub async fn inspect(
+    domain: &str,
+    transfer: bool,
+    resolver: &Resolver,
+    scope: Option<&Scope>,
+) -> DomainInfo {
+    let mut info = DomainInfo {
+        domain: domain.to_string(),
+        wildcard_ips: wildcard_ips(domain, resolver).await,
+        ..Default::default()
+    };
+    if !transfer {
+        return info;
+    }
+
+    for ns in name_servers(domain, resolver).await.unwrap_or_default() {
+        for ip in resolver.lookup_ipv4(&ns).await {
+            let addr = match ip.parse::<Ipv4Addr>() {
+                Ok(ip) => SocketAddr::from((ip, 53)),
+                Err(_) => continue,
+            };
+            if !ns_in_scope(&ns, &ip, scope) {
+                continue;
+            }
+            if let Ok(zone) = axfr(&addr, domain).await {
+                info.zone_ns = Some(ns);
+                info.zone = zone;
+                return info;
+            }
+        }
+    }
+    info
+}
+
+// Names of the A records of the zone (the hosts to scan)
+pub fn zone_hosts(info: &DomainInfo) -> BTreeSet<String> {
+    let suffix = format!(".{}", info.domain);
+    info.zone
+        .iter()
+        .filter(|record| record.record_type == "A")
+        .map(|record| record.name.clone())
+        .filter(|name| *name == info.domain || name.ends_with(&suffix))
+        .filter(|name| !name.starts_with('*'))
+        .collect()
+}